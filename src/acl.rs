@@ -1,22 +1,42 @@
-use std::{collections::BTreeMap, fs, path::PathBuf, sync::OnceLock};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::{OnceLock, PoisonError, RwLock, RwLockReadGuard},
+};
 
 use serde_derive::{Deserialize, Serialize};
 use tracing::debug;
 
 use crate::{
-    config::AclSettings,
-    error::{AppResult, ErrorKind},
+    config::{AclSettings, AliasAcl},
+    error::{ApiErrorKind, ApiResult, AppResult, ErrorKind},
+    handlers::access_check::check_repo_path,
     typed_path::TpeKind,
 };
 
-// Static storage of our credentials
-pub static ACL: OnceLock<Acl> = OnceLock::new();
+// Static storage of our credentials, replaced when the ACL is changed at runtime
+pub static ACL: OnceLock<RwLock<Acl>> = OnceLock::new();
 
 pub fn init_acl(acl: Acl) -> AppResult<()> {
-    let _ = ACL.get_or_init(|| acl);
+    let _ = ACL.get_or_init(|| RwLock::new(acl));
     Ok(())
 }
 
+/// Returns the ACL currently in effect
+pub fn acl() -> RwLockReadGuard<'static, Acl> {
+    ACL.get()
+        .unwrap()
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Name of the ACL file section that applies to the repository in the root of
+/// the data directory
+///
+/// Internally, this repository is stored under its actual path, which is `""`.
+const DEFAULT_REPO: &str = "default";
+
 /// Access Types
 ///
 // IMPORTANT: The order of the variants is important, as it is used
@@ -46,6 +66,16 @@ pub enum AccessType {
     ///
     /// Can be used to modify data in a repository, also delete data
     Modify,
+
+    /// Delete access
+    ///
+    /// # Note
+    ///
+    /// This is only requested, never granted: deleting files needs `Append`
+    /// access, or `Modify` access if the repository is append-only, which
+    /// nobody has there.
+    #[serde(skip)]
+    Delete,
 }
 
 pub trait AclChecker: Send + Sync + 'static {
@@ -55,18 +85,88 @@ pub trait AclChecker: Send + Sync + 'static {
 type HtPasswdUsername = String;
 
 /// ACL for a repo
-#[derive(Clone, Serialize, Deserialize, Debug, Default)]
-pub struct RepoAcl(BTreeMap<HtPasswdUsername, AccessType>);
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct RepoAcl {
+    #[serde(flatten)]
+    users: BTreeMap<HtPasswdUsername, AccessType>,
+    /// Refuse `Modify` access to the repository, even to users who have it,
+    /// so nothing but locks can be deleted, or allow it on an append-only
+    /// server; overrides `--append-only` for the repository if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    append_only: Option<bool>,
+}
 
 impl RepoAcl {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn set_append_only(self, append_only: bool) -> Self {
+        Self {
+            append_only: Some(append_only),
+            ..self
+        }
+    }
+
+    /// Returns the access of `user` to the repository
+    ///
+    /// An entry for exactly this user takes precedence. Otherwise, entries
+    /// with the wildcards `*` (any characters) and `?` (one character) are
+    /// matched, e.g. `ci-*`, and the highest access of all matching ones is used.
+    pub fn access(&self, user: &str) -> Option<AccessType> {
+        if let Some(access) = self.get(user) {
+            return Some(*access);
+        }
+
+        self.iter()
+            .filter(|(pattern, _)| is_pattern(pattern) && matches_pattern(pattern, user))
+            .map(|(_, access)| *access)
+            .reduce(|a, b| if b > a { b } else { a })
+    }
+}
+
+/// Returns whether the user name of an ACL entry contains wildcards
+fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// Returns whether `name` matches the wildcard `pattern` completely
+pub(crate) fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` in the pattern and of the name when reaching it
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // let the last `*` match one more character
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
 impl std::ops::DerefMut for RepoAcl {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.users
     }
 }
 
@@ -74,18 +174,42 @@ impl std::ops::Deref for RepoAcl {
     type Target = BTreeMap<String, AccessType>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.users
     }
 }
 
 type Repository = String;
 
+/// Returns the repository path without `.` and trailing slashes, e.g. `repo`
+/// for `./repo/`
+fn normalize(repo: &Path) -> PathBuf {
+    repo.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
 /// `Acl` holds ACLs for all repos
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Acl {
     private_repo: bool,
     append_only: bool,
+    namespace_by_user: bool,
     repos: BTreeMap<Repository, RepoAcl>,
+    /// Repository aliases, mapping requested paths to the repositories they
+    /// stand for
+    aliases: BTreeMap<PathBuf, PathBuf>,
+    /// Whose ACL applies to requests for an alias
+    alias_acl: AliasAcl,
+    /// Repositories refusing all writes, with the repositories below them
+    read_only_repos: BTreeSet<PathBuf>,
+    /// ACL file the repository sections were read from, which changes are
+    /// written to
+    #[serde(skip)]
+    acl_path: Option<PathBuf>,
+    /// User of reads without credentials if authentication is enabled, who
+    /// may only read repositories with an entry for exactly this user
+    #[serde(skip)]
+    anonymous_user: Option<String>,
 }
 
 impl Default for Acl {
@@ -94,6 +218,12 @@ impl Default for Acl {
             repos: BTreeMap::new(),
             append_only: true,
             private_repo: true,
+            namespace_by_user: false,
+            aliases: BTreeMap::new(),
+            alias_acl: AliasAcl::default(),
+            read_only_repos: BTreeSet::new(),
+            acl_path: None,
+            anonymous_user: None,
         }
     }
 }
@@ -107,15 +237,11 @@ fn read_toml(file_path: &PathBuf) -> AppResult<BTreeMap<String, RepoAcl>> {
             err, file_path
         ))
     })?;
-    // make the contents static in memory
-    let s = Box::leak(s.into_boxed_str());
-
-    let mut repos: BTreeMap<String, RepoAcl> = toml::from_str(s)
+    let mut repos: BTreeMap<String, RepoAcl> = toml::from_str(&s)
         .map_err(|err| ErrorKind::Config.context(format!("Could not parse TOML: {}", err)))?;
 
-    // copy key "default" into ""
-    if let Some(default) = repos.get("default") {
-        let default = default.clone();
+    // the "default" section is stored under the path of the root repository
+    if let Some(default) = repos.remove(DEFAULT_REPO) {
         let _ = repos.insert(String::new(), default);
     }
 
@@ -128,8 +254,8 @@ impl Acl {
         private_repos: bool,
         file_path: Option<PathBuf>,
     ) -> AppResult<Self> {
-        let repos = match file_path {
-            Some(file_path) => read_toml(&file_path).map_err(|err| {
+        let repos = match &file_path {
+            Some(file_path) => read_toml(file_path).map_err(|err| {
                 ErrorKind::Config.context(format!("Could not read ACL file: {err}"))
             })?,
             None => BTreeMap::new(),
@@ -138,7 +264,13 @@ impl Acl {
         Ok(Self {
             append_only,
             private_repo: private_repos,
+            namespace_by_user: false,
             repos,
+            aliases: BTreeMap::new(),
+            alias_acl: AliasAcl::default(),
+            read_only_repos: BTreeSet::new(),
+            acl_path: file_path,
+            anonymous_user: None,
         })
     }
 
@@ -148,16 +280,11 @@ impl Acl {
             !settings.disable_acl || settings.private_repos,
             path,
         )
+        .map(|acl| acl.set_namespace_by_user(settings.namespace_by_user))
     }
 
-    // The default repo has not been removed from the self.repos list, so we do not need to add here
-    // But we still need to remove the ""-tag that was added during the from_file()
     pub fn to_file(&self, pth: &PathBuf) -> AppResult<()> {
-        let mut repos = self.repos.clone();
-
-        _ = repos.remove("");
-
-        let toml_string = toml::to_string(&repos).map_err(|err| {
+        let toml_string = toml::to_string(&self.sections()).map_err(|err| {
             ErrorKind::Config.context(format!(
                 "Could not serialize ACL config to TOML value: {err}"
             ))
@@ -169,6 +296,174 @@ impl Acl {
         Ok(())
     }
 
+    /// Returns the ACLs of all repositories, in sections named like in the ACL
+    /// file
+    ///
+    /// The section of the root repository is named `default`.
+    pub fn sections(&self) -> BTreeMap<Repository, RepoAcl> {
+        let mut repos = self.repos.clone();
+        if let Some(default) = repos.remove("") {
+            _ = repos.insert(DEFAULT_REPO.to_owned(), default);
+        }
+        repos
+    }
+
+    /// Replaces the ACLs of all repositories with the given sections, named
+    /// like in the ACL file, and writes them to the ACL file
+    ///
+    /// Nothing is changed if the sections are invalid or can't be written.
+    pub fn replace_sections(&mut self, sections: BTreeMap<Repository, RepoAcl>) -> ApiResult<()> {
+        let Some(path) = self.acl_path.clone() else {
+            return Err(ApiErrorKind::BadRequest(
+                "the ACL is disabled, so it can't be changed".to_string(),
+            ));
+        };
+
+        let mut repos = BTreeMap::new();
+        for (repo, repo_acl) in sections {
+            let repo = if repo == DEFAULT_REPO {
+                String::new()
+            } else {
+                check_repo_path(Path::new(&repo), None)?;
+                repo
+            };
+            if repo.is_empty() && repos.contains_key(&repo) {
+                return Err(ApiErrorKind::BadRequest(format!(
+                    "both `{DEFAULT_REPO}` and `` name the root repository"
+                )));
+            }
+            _ = repos.insert(repo, repo_acl);
+        }
+
+        let acl = Self {
+            repos,
+            ..self.clone()
+        };
+        acl.to_file(&path)
+            .map_err(|err| ApiErrorKind::WritingToFileFailed(err.to_string()))?;
+        *self = acl;
+
+        Ok(())
+    }
+
+    /// Returns the ACL with the repository sections read again from the ACL
+    /// file, e.g. after it was edited, and all other settings kept
+    ///
+    /// Without an ACL file, it is returned unchanged.
+    pub fn reloaded(&self) -> AppResult<Self> {
+        let Some(path) = &self.acl_path else {
+            return Ok(self.clone());
+        };
+
+        let repos = read_toml(path)
+            .map_err(|err| ErrorKind::Config.context(format!("Could not read ACL file: {err}")))?;
+
+        Ok(Self {
+            repos,
+            ..self.clone()
+        })
+    }
+
+    /// Returns the repository sections with the ones of `from` and of the
+    /// repositories below it renamed to `to`
+    ///
+    /// Fails with `RepositoryExists` if a renamed section would replace an
+    /// existing one, and with `RepositoryConfigured` if a read-only repository
+    /// or the target of an alias would be moved, or the repository would be
+    /// moved below a read-only one. These are set in the config file, which
+    /// isn't written.
+    fn renamed_repos(&self, from: &Path, to: &Path) -> ApiResult<BTreeMap<Repository, RepoAcl>> {
+        let (from, to) = (normalize(from), normalize(to));
+        if from.as_os_str().is_empty() || to.as_os_str().is_empty() {
+            return Err(ApiErrorKind::InvalidPath(
+                "the root repository can't be renamed".to_string(),
+            ));
+        }
+
+        if let Some(read_only) = self
+            .read_only_repos
+            .iter()
+            .find(|repo| repo.starts_with(&from) || from.starts_with(repo) || to.starts_with(repo))
+        {
+            return Err(ApiErrorKind::RepositoryConfigured(format!(
+                "`{}` is read-only",
+                read_only.display()
+            )));
+        }
+        if let Some((alias, target)) = self
+            .aliases
+            .iter()
+            .find(|(_, target)| target.starts_with(&from))
+        {
+            return Err(ApiErrorKind::RepositoryConfigured(format!(
+                "`{}` is the target of the alias `{}`",
+                target.display(),
+                alias.display()
+            )));
+        }
+
+        let (moved, mut repos): (BTreeMap<_, _>, BTreeMap<_, _>) = self
+            .repos
+            .clone()
+            .into_iter()
+            .partition(|(repo, _)| Path::new(repo).starts_with(&from));
+        for (repo, repo_acl) in moved {
+            let below = Path::new(&repo)
+                .strip_prefix(&from)
+                .unwrap_or(Path::new(""));
+            let renamed = if below.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(below)
+            };
+            let renamed = renamed.to_string_lossy().into_owned();
+            if repos.contains_key(&renamed) {
+                return Err(ApiErrorKind::RepositoryExists(format!(
+                    "ACL section `{renamed}`"
+                )));
+            }
+            _ = repos.insert(renamed, repo_acl);
+        }
+
+        Ok(repos)
+    }
+
+    /// Checks that the sections of `from` can be renamed to `to` without
+    /// replacing others, see [`Self::rename_repo`]
+    pub fn check_rename_repo(&self, from: &Path, to: &Path) -> ApiResult<()> {
+        self.renamed_repos(from, to).map(|_| ())
+    }
+
+    /// Renames the sections of the repository `from` and of the repositories
+    /// below it to `to`, and writes them to the ACL file, if there is one
+    ///
+    /// Nothing is changed if a section would replace another one or the ACL
+    /// file can't be written.
+    pub fn rename_repo(&mut self, from: &Path, to: &Path) -> ApiResult<()> {
+        let acl = Self {
+            repos: self.renamed_repos(from, to)?,
+            ..self.clone()
+        };
+        if let Some(path) = &self.acl_path {
+            acl.to_file(path)
+                .map_err(|err| ApiErrorKind::WritingToFileFailed(err.to_string()))?;
+        }
+        *self = acl;
+
+        Ok(())
+    }
+
+    /// Returns the users named in the ACLs of all repositories, without
+    /// patterns
+    pub fn users(&self) -> BTreeSet<&str> {
+        self.repos
+            .values()
+            .flat_map(|repo_acl| repo_acl.keys())
+            .filter(|user| !is_pattern(user))
+            .map(String::as_str)
+            .collect()
+    }
+
     pub fn set_append_only(self, append_only: bool) -> Self {
         Self {
             append_only,
@@ -176,22 +471,125 @@ impl Acl {
         }
     }
 
+    pub fn set_namespace_by_user(self, namespace_by_user: bool) -> Self {
+        Self {
+            namespace_by_user,
+            ..self
+        }
+    }
+
+    pub fn set_aliases(self, aliases: BTreeMap<PathBuf, PathBuf>, alias_acl: AliasAcl) -> Self {
+        let aliases = aliases
+            .into_iter()
+            .map(|(alias, target)| (normalize(&alias), normalize(&target)))
+            .collect();
+        Self {
+            aliases,
+            alias_acl,
+            ..self
+        }
+    }
+
+    pub fn set_read_only_repos(self, read_only_repos: BTreeSet<PathBuf>) -> Self {
+        let read_only_repos = read_only_repos.iter().map(|repo| normalize(repo)).collect();
+        Self {
+            read_only_repos,
+            ..self
+        }
+    }
+
+    /// Returns whether writes to the repository at `repo` in the storage are
+    /// refused, as it or a directory above it is read-only
+    pub fn is_read_only(&self, repo: &Path) -> bool {
+        normalize(repo)
+            .ancestors()
+            .any(|repo| self.read_only_repos.contains(repo))
+    }
+
+    pub fn set_anonymous_user(self, anonymous_user: Option<String>) -> Self {
+        Self {
+            anonymous_user,
+            ..self
+        }
+    }
+
+    /// Returns whether `user` is the user of reads without credentials
+    pub fn is_anonymous(&self, user: &str) -> bool {
+        self.anonymous_user.as_deref() == Some(user)
+    }
+
+    /// Returns the repository requests for `repo` go to, which is `repo`
+    /// itself unless it is an alias
+    pub fn resolve_alias<'a>(&'a self, repo: &'a Path) -> &'a Path {
+        self.aliases
+            .get(&normalize(repo))
+            .map_or(repo, PathBuf::as_path)
+    }
+
+    /// Returns the repository whose ACL applies to requests for `repo`
+    pub fn acl_repo<'a>(&'a self, repo: &'a Path) -> &'a Path {
+        match self.alias_acl {
+            AliasAcl::Target => self.resolve_alias(repo),
+            AliasAcl::Alias => repo,
+        }
+    }
+
+    /// Returns the path of the repository `repo` in the storage
+    ///
+    /// If repositories are namespaced by user, this is below the directory of
+    /// the user, so ACL sections have to be named like `<user>/<repo>`.
+    pub fn repo_path(&self, user: &str, repo: &Path) -> ApiResult<PathBuf> {
+        if !self.namespace_by_user {
+            return Ok(repo.to_path_buf());
+        }
+
+        // the user name becomes a directory, so it must not escape the data directory
+        let mut components = Path::new(user).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(Path::new(user).join(repo)),
+            _ => Err(ApiErrorKind::PermissionDenied(format!(
+                "user `{user}` can't have a repository namespace"
+            ))),
+        }
+    }
+
     pub fn default_repo_access(&mut self, user: &str, access: AccessType) {
-        // If we do not have a key with ""-value then "default" is also not a key
-        // Since we guarantee this during the reading of a acl-file
-        if !self.repos.contains_key("default") {
-            let mut acl = RepoAcl::new();
-            _ = acl.insert(user.into(), access);
-            _ = self.repos.insert("default".to_owned(), acl.clone());
-            _ = self.repos.insert(String::new(), acl);
-        } else {
-            _ = self
+        _ = self
+            .repos
+            .entry(String::new())
+            .or_default()
+            .insert(user.into(), access);
+    }
+
+    /// Returns the ACL for the repository at `path`, if any
+    ///
+    /// `default` is accepted as an alias for the root repository. Repositories
+    /// without a section of their own inherit the section of the closest parent
+    /// directory, e.g. `team-a` (or `team-a/`) for `team-a/proj1`. The root
+    /// repository's section isn't inherited.
+    fn repo_acl(&self, path: &str) -> Option<&RepoAcl> {
+        let path = if path == DEFAULT_REPO { "" } else { path };
+        if let Some(repo_acl) = self.repos.get(path) {
+            return Some(repo_acl);
+        }
+
+        let mut parent = path.trim_end_matches('/');
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            parent = dir.trim_end_matches('/');
+            if parent.is_empty() {
+                break;
+            }
+            if let Some(repo_acl) = self
                 .repos
-                .get_mut("default")
-                .unwrap()
-                .insert(user.into(), access);
-            _ = self.repos.get_mut("").unwrap().insert(user.into(), access);
+                .get(parent)
+                .or_else(|| self.repos.get(&format!("{parent}/")))
+            {
+                debug!(%parent, "Using ACL of parent directory.");
+                return Some(repo_acl);
+            }
         }
+
+        None
     }
 }
 
@@ -215,29 +613,60 @@ impl AclChecker for Acl {
             access_type
         };
 
-        self.repos.get(path).map_or_else(
+        let repo_acl = self.repo_acl(path);
+
+        // the flag of the repository overrides the global setting
+        let append_only = repo_acl
+            .and_then(|repo_acl| repo_acl.append_only)
+            .unwrap_or(self.append_only);
+        let access_type = match access_type {
+            AccessType::Delete if append_only => AccessType::Modify,
+            AccessType::Delete => AccessType::Append,
+            access_type => access_type,
+        };
+
+        // an append-only repository can't be changed by anybody
+        if append_only && access_type == AccessType::Modify {
+            debug!("Repository is append-only");
+            return false;
+        }
+
+        // only public repositories can be read without credentials, neither
+        // the default ACL nor wildcards apply
+        if self.is_anonymous(user) {
+            let access = access_type == AccessType::Read
+                && repo_acl
+                    .and_then(|repo_acl| repo_acl.get(user))
+                    .is_some_and(|user_access| *user_access >= access_type);
+
+            debug!(%access, "Anonymous access check");
+
+            return access;
+        }
+
+        repo_acl.map_or_else(
             || {
                 debug!("No ACL for repository found, applying default ACL.");
 
-                let is_user_path = user == path;
+                // with namespaces, users own all repositories below their directory
+                let is_user_path = user == path
+                    || (self.namespace_by_user
+                        && path
+                            .strip_prefix(user)
+                            .is_some_and(|rest| rest.starts_with('/')));
                 let is_not_private_repo = !self.private_repo;
-                let is_not_modify_access = access_type != AccessType::Modify;
-                let is_not_append_only = !self.append_only;
 
-                debug!(%is_user_path, %is_not_private_repo, %is_not_modify_access, %is_not_append_only);
+                debug!(%is_user_path, %is_not_private_repo);
 
-                // If the user is the path, and the repo is not private, or the user has modify access
-                // or the repo is not append only, then allow the access
-                let access = (is_user_path || is_not_private_repo)
-                    && (is_not_modify_access || is_not_append_only);
+                // If the user is the path, or the repo is not private, then allow the access
+                let access = is_user_path || is_not_private_repo;
 
                 debug!(%access, "Access check");
 
                 access
             },
             |repo_acl| {
-                let access =
-                    matches!(repo_acl.get(user), Some(user_access) if user_access >= &access_type);
+                let access = matches!(repo_acl.access(user), Some(user_access) if user_access >= access_type);
 
                 debug!(?repo_acl, %access, "Access check");
 
@@ -249,21 +678,24 @@ impl AclChecker for Acl {
 
 #[cfg(test)]
 mod tests {
-    use super::AccessType::{Append, Modify, Read};
+    use super::AccessType::{Append, Delete, Modify, Read};
     use super::*;
-    use crate::testing::server_config;
+    use crate::testing::{read_only_repos, server_config};
     use rstest::rstest;
 
-    use std::env;
-
     #[rstest]
     fn test_static_acl_access_passes() {
-        let acl = server_config().acl;
-        let auth = Acl::from_config(&acl.clone(), acl.acl_path).unwrap();
+        let config = server_config();
+        let acl = config.acl;
+        // like the test environment, which shares the global
+        let auth = Acl::from_config(&acl.clone(), acl.acl_path)
+            .unwrap()
+            .set_anonymous_user(Some(String::new()))
+            .set_read_only_repos(read_only_repos(&config.repos));
 
         init_acl(auth).unwrap();
 
-        let acl = ACL.get().unwrap();
+        let acl = super::acl();
         assert!(&acl.private_repo);
         assert!(!&acl.append_only);
         let access = acl.repos.get("test_repo").unwrap();
@@ -301,17 +733,100 @@ mod tests {
         assert!(acl.is_allowed("bob", "", Some(TpeKind::Data), Modify));
     }
 
+    #[test]
+    fn test_namespace_by_user_passes() {
+        let mut acl = Acl::default().set_namespace_by_user(true);
+
+        let repo = acl.repo_path("bob", Path::new("laptop")).unwrap();
+        assert_eq!(repo, PathBuf::from("bob/laptop"));
+        assert!(acl.repo_path("..", Path::new("laptop")).is_err());
+        assert!(acl.repo_path("bob/sam", Path::new("laptop")).is_err());
+        assert!(acl.repo_path("", Path::new("laptop")).is_err());
+
+        // users own their namespace, but not the ones of other users
+        assert!(acl.is_allowed("bob", "bob/laptop", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed("bob", "bob/laptop", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("bob", "bobby/laptop", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("sam", "bob/laptop", Some(TpeKind::Data), Read));
+
+        // ACL sections use the namespaced path
+        let mut acl_bob = RepoAcl::new();
+        _ = acl_bob.insert("bob".to_string(), Read);
+        _ = acl.repos.insert("bob/laptop".to_string(), acl_bob);
+        assert!(acl.is_allowed("bob", "bob/laptop", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("bob", "bob/laptop", Some(TpeKind::Data), Append));
+
+        // without namespaces, the path is used as is
+        let acl = Acl::default();
+        let repo = acl.repo_path("bob", Path::new("laptop")).unwrap();
+        assert_eq!(repo, PathBuf::from("laptop"));
+    }
+
+    #[test]
+    fn test_aliases_passes() {
+        let aliases = BTreeMap::from([(PathBuf::from("new/"), PathBuf::from("old"))]);
+        let acl = Acl::default().set_aliases(aliases.clone(), AliasAcl::Target);
+
+        assert_eq!(acl.resolve_alias(Path::new("new")), Path::new("old"));
+        assert_eq!(acl.resolve_alias(Path::new("./new/")), Path::new("old"));
+        assert_eq!(acl.resolve_alias(Path::new("other")), Path::new("other"));
+        assert_eq!(acl.acl_repo(Path::new("new")), Path::new("old"));
+
+        let acl = acl.set_aliases(aliases, AliasAcl::Alias);
+        assert_eq!(acl.resolve_alias(Path::new("new")), Path::new("old"));
+        assert_eq!(acl.acl_repo(Path::new("new")), Path::new("new"));
+    }
+
+    #[test]
+    fn test_read_only_repos_passes() {
+        let read_only = BTreeSet::from([PathBuf::from("team/"), PathBuf::from("frozen")]);
+        let acl = Acl::default().set_read_only_repos(read_only);
+
+        assert!(acl.is_read_only(Path::new("frozen")));
+        assert!(acl.is_read_only(Path::new("./team")));
+        assert!(acl.is_read_only(Path::new("team/project")));
+        assert!(!acl.is_read_only(Path::new("team-b")));
+        assert!(!acl.is_read_only(Path::new("other/frozen")));
+    }
+
+    #[test]
+    fn test_acl_file_round_trip_passes() {
+        let path = PathBuf::from("tests/generated/acl_round_trip.toml");
+        fs::write(
+            &path,
+            "[default]\nbob = \"Read\"\n\n[sam]\nsam = \"Append\"\n",
+        )
+        .unwrap();
+
+        let mut acl = Acl::from_file(true, true, Some(path.clone())).unwrap();
+        assert!(acl.is_allowed("bob", "", Some(TpeKind::Data), Read));
+        assert!(acl.is_allowed("bob", "default", Some(TpeKind::Data), Read));
+
+        acl.default_repo_access("sam", Append);
+        acl.to_file(&path).unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        let reread = Acl::from_file(true, true, Some(path.clone())).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(written.matches("[default]").count(), 1);
+        assert!(!written.contains("[\"\"]"));
+        assert_eq!(reread, acl);
+        assert!(reread.is_allowed("sam", "", Some(TpeKind::Data), Append));
+    }
+
     #[test]
     fn test_repo_acl_passes() {
         let mut acl = Acl::default();
 
-        let mut acl_all = RepoAcl::new();
+        // sections override the global append-only setting
+        let mut acl_all = RepoAcl::new().set_append_only(false);
         _ = acl_all.insert("bob".to_string(), Modify);
         _ = acl_all.insert("sam".to_string(), Append);
         _ = acl_all.insert("paul".to_string(), Read);
         _ = acl.repos.insert("all".to_string(), acl_all);
 
-        let mut acl_bob = RepoAcl::new();
+        let mut acl_bob = RepoAcl::new().set_append_only(false);
         _ = acl_bob.insert("bob".to_string(), Modify);
         _ = acl.repos.insert("bob".to_string(), acl_bob);
 
@@ -349,4 +864,306 @@ mod tests {
         assert!(acl.is_allowed("paul", "paul", Some(TpeKind::Data), Append));
         assert!(!acl.is_allowed("paul", "paul", Some(TpeKind::Data), Modify));
     }
+
+    #[test]
+    fn test_user_patterns_passes() {
+        let mut acl = Acl::default().set_append_only(false);
+
+        let mut repo_acl = RepoAcl::new();
+        _ = repo_acl.insert("ci-*".to_string(), Append);
+        _ = repo_acl.insert("ci-admin".to_string(), Read);
+        _ = repo_acl.insert("*-deploy".to_string(), Modify);
+        _ = repo_acl.insert("runner-?".to_string(), Read);
+        _ = acl.repos.insert("ci".to_string(), repo_acl);
+
+        assert!(acl.is_allowed("ci-runner-1", "ci", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed("ci-runner-1", "ci", Some(TpeKind::Data), Modify));
+        // the pattern doesn't match partially
+        assert!(!acl.is_allowed("my-ci-runner", "ci", Some(TpeKind::Data), Read));
+
+        // an exact entry takes precedence over patterns
+        assert!(acl.is_allowed("ci-admin", "ci", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("ci-admin", "ci", Some(TpeKind::Data), Append));
+
+        // the highest access of all matching patterns is used
+        assert!(acl.is_allowed("ci-deploy", "ci", Some(TpeKind::Data), Modify));
+
+        assert!(acl.is_allowed("runner-1", "ci", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("runner-12", "ci", Some(TpeKind::Data), Read));
+    }
+
+    #[test]
+    fn test_matches_pattern_passes() {
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(matches_pattern("a**", "a"));
+        assert!(matches_pattern("?b", "ab"));
+        assert!(!matches_pattern("a*b", "aXbY"));
+        assert!(!matches_pattern("?", ""));
+        assert!(!matches_pattern("abc", "ab"));
+    }
+
+    #[test]
+    fn test_parent_acl_is_inherited_passes() {
+        let mut acl = Acl::default();
+
+        let mut team = RepoAcl::new();
+        _ = team.insert("alice".to_string(), Append);
+        _ = acl.repos.insert("team-a/".to_string(), team);
+
+        let mut special = RepoAcl::new();
+        _ = special.insert("bob".to_string(), Read);
+        _ = acl.repos.insert("team-a/special".to_string(), special);
+
+        let mut root = RepoAcl::new();
+        _ = root.insert("alice".to_string(), Modify);
+        _ = acl.repos.insert(String::new(), root);
+
+        // inherited by all repositories below
+        assert!(acl.is_allowed("alice", "team-a/proj1", Some(TpeKind::Data), Append));
+        assert!(acl.is_allowed("alice", "team-a/x/y", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed("alice", "team-a/proj1", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("bob", "team-a/proj1", Some(TpeKind::Data), Read));
+
+        // a section of the repository itself overrides the parent
+        assert!(acl.is_allowed("bob", "team-a/special", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("alice", "team-a/special", Some(TpeKind::Data), Read));
+
+        // the root repository isn't a parent, so the flags apply
+        assert!(!acl.is_allowed("alice", "team-b/proj1", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("alice", "team-ab", Some(TpeKind::Data), Read));
+    }
+
+    #[test]
+    fn test_anonymous_access_passes() {
+        let mut acl = Acl::default()
+            .set_append_only(false)
+            .set_anonymous_user(Some(String::new()));
+        acl.private_repo = false;
+        let mut public = RepoAcl::new();
+        _ = public.insert(String::new(), Modify);
+        _ = acl.repos.insert("public".to_string(), public);
+        let mut shared = RepoAcl::new();
+        _ = shared.insert("*".to_string(), Modify);
+        _ = acl.repos.insert("shared".to_string(), shared);
+
+        // public repositories can only be read
+        assert!(acl.is_allowed("", "public", Some(TpeKind::Data), Read));
+        assert!(acl.is_allowed("", "public/sub", None, Read));
+        assert!(!acl.is_allowed("", "public", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed("", "public", None, Modify));
+
+        // wildcards and the default ACL don't apply
+        assert!(!acl.is_allowed("", "shared", Some(TpeKind::Data), Read));
+        assert!(!acl.is_allowed("", "other", Some(TpeKind::Data), Read));
+        assert!(acl.is_allowed("bob", "other", Some(TpeKind::Data), Read));
+
+        // without authentication, the anonymous user is like any other
+        let acl = acl.set_anonymous_user(None);
+        assert!(acl.is_allowed("", "shared", Some(TpeKind::Data), Modify));
+    }
+
+    #[test]
+    fn test_repo_append_only_passes() {
+        let repos: BTreeMap<String, RepoAcl> = toml::from_str(
+            "[prod]\nappend_only = true\nbob = \"Modify\"\n\n[staging]\nbob = \"Modify\"\n",
+        )
+        .unwrap();
+        let mut prod = RepoAcl::new().set_append_only(true);
+        _ = prod.insert("bob".to_string(), Modify);
+        assert_eq!(repos["prod"], prod);
+
+        // the flag of the repository applies instead of the global one
+        let acl = Acl {
+            repos: repos.clone(),
+            ..Acl::default()
+        }
+        .set_append_only(false);
+        assert!(acl.is_allowed("bob", "prod", Some(TpeKind::Data), Append));
+        assert!(!acl.is_allowed("bob", "prod", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("bob", "prod", Some(TpeKind::Data), Delete));
+        assert!(!acl.is_allowed("bob", "prod", Some(TpeKind::Config), Delete));
+        assert!(!acl.is_allowed("bob", "prod", None, Modify));
+        // clients still remove their own locks
+        assert!(acl.is_allowed("bob", "prod", Some(TpeKind::Locks), Modify));
+        assert!(acl.is_allowed("bob", "prod", Some(TpeKind::Locks), Delete));
+        assert!(acl.is_allowed("bob", "staging", Some(TpeKind::Data), Modify));
+        assert!(acl.is_allowed("bob", "staging", Some(TpeKind::Data), Delete));
+
+        // sections without the flag follow the global setting
+        let acl = acl.set_append_only(true);
+        assert!(!acl.is_allowed("bob", "staging", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("bob", "staging", Some(TpeKind::Data), Delete));
+        assert!(acl.is_allowed("bob", "staging", Some(TpeKind::Data), Append));
+
+        // and the flag opens a repository on an append-only server
+        let mut scratch = RepoAcl::new().set_append_only(false);
+        _ = scratch.insert("bob".to_string(), Append);
+        let mut repos = repos;
+        _ = repos.insert("scratch".to_string(), scratch);
+        let acl = Acl {
+            repos: repos.clone(),
+            ..acl
+        };
+        assert!(acl.is_allowed("bob", "scratch", Some(TpeKind::Data), Delete));
+        assert!(!acl.is_allowed("bob", "scratch", Some(TpeKind::Data), Modify));
+        assert!(!acl.is_allowed("bob", "prod", Some(TpeKind::Data), Delete));
+
+        // the flag is only written where it is set
+        let written = toml::to_string(&repos).unwrap();
+        assert_eq!(written.matches("append_only = true").count(), 1);
+        assert_eq!(written.matches("append_only = false").count(), 1);
+        assert_eq!(
+            toml::from_str::<BTreeMap<String, RepoAcl>>(&written).unwrap(),
+            repos
+        );
+    }
+
+    #[test]
+    fn test_replace_sections_passes() {
+        let dir = PathBuf::from("tests/generated/test_replace_acl");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("acl.toml");
+        fs::write(&path, "[default]\nalice = \"Read\"\n").unwrap();
+
+        let mut acl = Acl::from_file(false, true, Some(path.clone())).unwrap();
+        let mut sections = acl.sections();
+        assert_eq!(sections.keys().collect::<Vec<_>>(), ["default"]);
+        assert!(acl.is_allowed("alice", "", Some(TpeKind::Data), Read));
+
+        let mut team = RepoAcl::new();
+        _ = team.insert("bob".to_string(), Modify);
+        _ = sections.insert("team".to_string(), team);
+        acl.replace_sections(sections.clone()).unwrap();
+        assert!(acl.is_allowed("alice", "", Some(TpeKind::Data), Read));
+        assert!(acl.is_allowed("bob", "team", Some(TpeKind::Data), Modify));
+
+        // the root repository is written back as `default`
+        let reloaded = Acl::from_file(false, true, Some(path)).unwrap();
+        assert_eq!(reloaded, acl);
+        assert_eq!(reloaded.sections(), sections);
+
+        // invalid sections change nothing
+        let mut invalid = sections.clone();
+        _ = invalid.insert(String::new(), RepoAcl::new());
+        assert!(acl.replace_sections(invalid).is_err());
+        let mut invalid = sections.clone();
+        _ = invalid.insert("../escape".to_string(), RepoAcl::new());
+        assert!(acl.replace_sections(invalid).is_err());
+        assert_eq!(acl, reloaded);
+
+        // without an ACL file, there is nothing to change
+        assert!(Acl::default().replace_sections(sections).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reloaded_passes() {
+        let dir = PathBuf::from("tests/generated/test_reload_acl");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("acl.toml");
+        fs::write(&path, "[default]\nalice = \"Read\"\n").unwrap();
+
+        let acl = Acl::from_file(true, true, Some(path.clone()))
+            .unwrap()
+            .set_namespace_by_user(true);
+
+        fs::write(&path, "[team]\nbob = \"Modify\"\n").unwrap();
+        let reloaded = acl.reloaded().unwrap();
+        assert_eq!(reloaded.sections().keys().collect::<Vec<_>>(), ["team"]);
+        // the settings are kept
+        assert!(reloaded.append_only);
+        assert!(reloaded.namespace_by_user);
+
+        // an invalid file is reported
+        fs::write(&path, "[team]\nbob = \"Everything\"\n").unwrap();
+        let err = acl.reloaded().unwrap_err();
+        assert!(err.to_string().contains("Could not parse TOML"), "{err}");
+
+        // without an ACL file, there is nothing to read
+        assert_eq!(Acl::default().reloaded().unwrap(), Acl::default());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rename_repo_passes() {
+        let dir = PathBuf::from("tests/generated/test_rename_acl");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("acl.toml");
+        fs::write(
+            &path,
+            "[team]\nbob = \"Modify\"\n[\"team/repo\"]\nalice = \"Append\"\n[team2]\ncarol = \"Read\"\n[taken]\ndave = \"Read\"\n",
+        )
+        .unwrap();
+        let mut acl = Acl::from_file(false, true, Some(path.clone())).unwrap();
+
+        // nested sections are renamed along, others with the same prefix not
+        acl.rename_repo(Path::new("team"), Path::new("./new/team"))
+            .unwrap();
+        assert_eq!(
+            acl.sections().keys().collect::<Vec<_>>(),
+            ["new/team", "new/team/repo", "taken", "team2"]
+        );
+        assert!(acl.is_allowed("alice", "new/team/repo", Some(TpeKind::Data), Append));
+        assert_eq!(Acl::from_file(false, true, Some(path)).unwrap(), acl);
+
+        // existing sections are not replaced
+        let renamed = acl.clone();
+        assert!(matches!(
+            acl.check_rename_repo(Path::new("team2"), Path::new("taken")),
+            Err(ApiErrorKind::RepositoryExists(_))
+        ));
+        assert!(acl
+            .rename_repo(Path::new("team2"), Path::new("taken"))
+            .is_err());
+        assert!(acl.rename_repo(Path::new(""), Path::new("root")).is_err());
+        assert_eq!(acl, renamed);
+
+        // read-only repositories stay where they are configured
+        let acl = renamed.set_read_only_repos(BTreeSet::from([PathBuf::from("frozen/repo")]));
+        for (from, to) in [
+            ("frozen/repo", "thawed"),
+            ("frozen", "thawed"),
+            ("frozen/repo/sub", "thawed"),
+            ("taken", "frozen/repo/taken"),
+        ] {
+            assert!(
+                matches!(
+                    acl.check_rename_repo(Path::new(from), Path::new(to)),
+                    Err(ApiErrorKind::RepositoryConfigured(_))
+                ),
+                "{from} -> {to}"
+            );
+        }
+        acl.check_rename_repo(Path::new("frozen2"), Path::new("thawed"))
+            .unwrap();
+
+        // as do the targets of aliases
+        let aliases = BTreeMap::from([(PathBuf::from("latest"), PathBuf::from("releases/v2"))]);
+        let mut acl = acl.set_aliases(aliases, AliasAcl::Target);
+        for from in ["releases/v2", "releases"] {
+            assert!(
+                matches!(
+                    acl.rename_repo(Path::new(from), Path::new("moved")),
+                    Err(ApiErrorKind::RepositoryConfigured(_))
+                ),
+                "{from}"
+            );
+        }
+        acl.check_rename_repo(Path::new("latest"), Path::new("moved"))
+            .unwrap();
+
+        // without an ACL file, only the sections in memory are renamed
+        let mut acl = Acl::default();
+        acl.default_repo_access("alice", Read);
+        acl.rename_repo(Path::new("old"), Path::new("new")).unwrap();
+        assert_eq!(acl.sections().keys().collect::<Vec<_>>(), ["default"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
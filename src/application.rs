@@ -1,16 +1,25 @@
 //! `RusticServer` Abscissa Application
 
-use crate::{commands::EntryPoint, config::RusticServerConfig};
+use crate::{
+    commands::EntryPoint,
+    config::{ConfigSource, RusticServerConfig},
+    error::ExitCode,
+    log::{init_tracing, LogFlushComponent},
+};
 use abscissa_core::Config;
-use abscissa_core::FrameworkErrorKind::IoError;
+use abscissa_core::FrameworkErrorKind::{ConfigError, IoError};
 use abscissa_core::{
     application::{self, AppCell},
+    component::Component,
     config::{self, CfgCell},
     path::AbsPathBuf,
-    trace, Application, FrameworkError, StandardPaths,
+    status_err,
+    terminal::{component::Terminal, ColorChoice},
+    Application, Configurable, FrameworkError, Shutdown, StandardPaths,
 };
 use abscissa_tokio::TokioComponent;
-use std::path::Path;
+use std::{fmt::Display, path::Path, process};
+use tracing::error;
 
 /// Application state
 pub static RUSTIC_SERVER_APP: AppCell<RusticServerApp> = AppCell::new();
@@ -69,9 +78,38 @@ impl Application for RusticServerApp {
         // Create `TokioComponent` and add it to your app's components here:
         components.push(Box::new(TokioComponent::new()?));
 
+        // Flush buffered logs when shutting down, as the process exits right after
+        components.push(Box::new(LogFlushComponent));
+
         self.state.components_mut().register(components)
     }
 
+    /// Load this application's configuration and initialize its components.
+    ///
+    /// Unlike the framework's default, a configuration which can't be loaded
+    /// exits with [`ExitCode::Config`] instead of the code of a crash.
+    fn init(&mut self, command: &Self::Cmd) -> Result<(), FrameworkError> {
+        self.register_components(command)?;
+
+        let config = command
+            .config_path()
+            .map(|path| self.load_config(&path))
+            .transpose()
+            .and_then(|config| command.process_config(config.unwrap_or_default()));
+
+        match config {
+            Ok(config) => self.after_config(config),
+            Err(err) => {
+                status_err!("{}", err);
+                error!(
+                    exit_code = i32::from(ExitCode::Config),
+                    "Shutting down: {err}"
+                );
+                process::exit(ExitCode::Config.into());
+            }
+        }
+    }
+
     /// Post-configuration lifecycle callback.
     ///
     /// Called regardless of whether config is loaded to indicate this is the
@@ -84,10 +122,20 @@ impl Application for RusticServerApp {
         Ok(())
     }
 
-    /// Load configuration from the given path.
+    /// Load configuration from the given path, or from stdin (`-`) or an
+    /// environment variable (`env:<VAR>`).
     ///
     /// Returns an error if the configuration could not be loaded.
     fn load_config(&mut self, path: &Path) -> Result<Self::Cfg, FrameworkError> {
+        if let Some(source) = path.to_str().map(ConfigSource::parse) {
+            if !source.is_file() {
+                let toml_string = source
+                    .read()
+                    .map_err(|err| FrameworkError::from(ConfigError.context(err)))?;
+                return Self::Cfg::load_toml(toml_string);
+            }
+        }
+
         let canonical_path = AbsPathBuf::canonicalize(path).map_err(|_err| {
             FrameworkError::from(IoError.context(
                 "It seems like your configuration wasn't found! Please make sure it exists at the given location!"
@@ -97,12 +145,36 @@ impl Application for RusticServerApp {
         Self::Cfg::load_toml_file(canonical_path)
     }
 
-    /// Get tracing configuration from command-line options
-    fn tracing_config(&self, command: &EntryPoint) -> trace::Config {
-        if command.verbose {
-            trace::Config::verbose()
-        } else {
-            trace::Config::default()
-        }
+    /// Initialize the terminal and the tracing subsystem.
+    ///
+    /// Unlike the framework's default, the tracing subscriber also hands the
+    /// log events to the stream of `GET /admin/logs`.
+    fn framework_components(
+        &mut self,
+        command: &Self::Cmd,
+    ) -> Result<Vec<Box<dyn Component<Self>>>, FrameworkError> {
+        let colors = self.term_colors(command);
+        init_tracing(&log_filter(command), colors != ColorChoice::Never)?;
+
+        Ok(vec![Box::new(Terminal::new(colors))])
     }
 }
+
+/// Get the log filter from command-line options
+fn log_filter(command: &EntryPoint) -> String {
+    if command.verbose {
+        "debug".to_string()
+    } else if command.prints_metrics() {
+        // informational logs would end up in the printed metrics
+        "warn".to_string()
+    } else {
+        std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string())
+    }
+}
+
+/// Shut down after a command failed, logging the reason and exiting with `code`
+pub fn shutdown_on_error(err: &dyn Display, code: ExitCode) -> ! {
+    status_err!("{}", err);
+    error!(exit_code = i32::from(code), "Shutting down: {err}");
+    RUSTIC_SERVER_APP.shutdown_with_exitcode(Shutdown::Crash, code.into())
+}
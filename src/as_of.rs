@@ -0,0 +1,152 @@
+//! Reading repositories as they were at a point in time
+//!
+//! When recent writes corrupted a repository, it can still be read as it was
+//! before: with an `X-Repo-As-Of: <rfc3339>` header, only files last modified
+//! at or before that time are listed and served. Newer files are reported as
+//! not found. Such requests are read-only, so writes carrying the header are
+//! refused.
+
+use std::{path::Path, time::SystemTime};
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderMap, HeaderName, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::DateTime;
+
+use crate::{
+    error::{ApiErrorKind, ApiResult},
+    storage::Storage,
+};
+
+/// Header selecting the point in time a repository is read at
+pub static AS_OF_HEADER: HeaderName = HeaderName::from_static("x-repo-as-of");
+
+/// Point in time a request reads the repository at, if any
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AsOf(pub Option<SystemTime>);
+
+impl AsOf {
+    /// Parses the `X-Repo-As-Of` header of a request
+    pub fn from_headers(headers: &HeaderMap) -> ApiResult<Self> {
+        let Some(value) = headers.get(&AS_OF_HEADER) else {
+            return Ok(Self(None));
+        };
+
+        let as_of = value
+            .to_str()
+            .ok()
+            .and_then(|value| DateTime::parse_from_rfc3339(value.trim()).ok())
+            .ok_or_else(|| {
+                ApiErrorKind::BadRequest(format!(
+                    "`{AS_OF_HEADER}` must be an RFC 3339 timestamp, got `{}`",
+                    String::from_utf8_lossy(value.as_bytes())
+                ))
+            })?;
+
+        Ok(Self(Some(as_of.into())))
+    }
+
+    /// Returns whether a file last modified at `modified` existed at this
+    /// point in time
+    ///
+    /// Files without a known modification time are only included if no
+    /// point in time is requested.
+    pub fn includes(self, modified: Option<SystemTime>) -> bool {
+        match (self.0, modified) {
+            (None, _) => true,
+            (Some(as_of), Some(modified)) => modified <= as_of,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Returns whether the file existed at this point in time, looking up its
+    /// modification time only if a point in time is requested
+    pub fn includes_file(
+        self,
+        storage: &dyn Storage,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> bool {
+        self.0.is_none() || self.includes(storage.modified(path, tpe, name))
+    }
+
+    /// Fails with `FileNotFound` if the file didn't exist at this point in time
+    pub fn check_file(
+        self,
+        storage: &dyn Storage,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<()> {
+        if self.includes_file(storage, path, tpe, name) {
+            Ok(())
+        } else {
+            Err(ApiErrorKind::FileNotFound(name.unwrap_or(tpe).to_string()))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AsOf {
+    type Rejection = ApiErrorKind;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> ApiResult<Self> {
+        Self::from_headers(&parts.headers)
+    }
+}
+
+/// Refuses all requests but reads which carry an `X-Repo-As-Of` header, as
+/// the past of a repository can't be changed
+pub async fn reject_writes_as_of(request: Request, next: Next) -> Response {
+    let read = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if !read && request.headers().contains_key(&AS_OF_HEADER) {
+        return ApiErrorKind::BadRequest(format!("`{AS_OF_HEADER}` is only allowed when reading"))
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use crate::as_of::{AsOf, AS_OF_HEADER};
+
+    #[test]
+    fn test_as_of_from_headers_passes() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(AsOf::from_headers(&headers).unwrap(), AsOf(None));
+
+        let _ = headers.insert(
+            &AS_OF_HEADER,
+            HeaderValue::from_static("2024-01-02T03:04:05+01:00"),
+        );
+        let as_of = SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_161_045);
+        assert_eq!(AsOf::from_headers(&headers).unwrap(), AsOf(Some(as_of)));
+
+        let _ = headers.insert(&AS_OF_HEADER, HeaderValue::from_static("yesterday"));
+        assert!(AsOf::from_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn test_as_of_includes_passes() {
+        let as_of = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+
+        assert!(AsOf(None).includes(None));
+        assert!(AsOf(None).includes(Some(SystemTime::now())));
+        assert!(AsOf(Some(as_of)).includes(Some(as_of)));
+        assert!(AsOf(Some(as_of)).includes(Some(as_of - Duration::from_secs(1))));
+        assert!(!AsOf(Some(as_of)).includes(Some(as_of + Duration::from_secs(1))));
+        assert!(!AsOf(Some(as_of)).includes(None));
+    }
+}
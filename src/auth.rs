@@ -1,40 +1,147 @@
-use std::{borrow::Borrow, path::PathBuf};
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
 
 use abscissa_core::SecretString;
-use axum::{extract::FromRequestParts, http::request::Parts};
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, Method},
+    response::{IntoResponse, Response},
+};
 use axum_auth::AuthBasic;
+use rand::{thread_rng, RngCore};
 use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
 use std::sync::OnceLock;
 
 use crate::{
-    config::HtpasswdSettings,
+    config::{AdminSettings, HtpasswdSettings},
     error::{ApiErrorKind, ApiResult, AppResult},
     htpasswd::{CredentialMap, Htpasswd},
+    sessions::ActiveSession,
 };
 
 // Static storage of our credentials
 pub static AUTH: OnceLock<Auth> = OnceLock::new();
 
+// Static storage of the credentials of the admin endpoints, if they have their own
+pub static ADMIN_AUTH: OnceLock<Option<Auth>> = OnceLock::new();
+
 pub(crate) fn init_auth(auth: Auth) -> AppResult<()> {
     let _ = AUTH.get_or_init(|| auth);
     Ok(())
 }
 
+pub(crate) fn init_admin_auth(admin_auth: Option<Auth>) -> AppResult<()> {
+    let _ = ADMIN_AUTH.get_or_init(|| admin_auth);
+    Ok(())
+}
+
+/// Realm sent in the `WWW-Authenticate` header if none is configured
+pub const DEFAULT_REALM: &str = "rustic";
+
+/// Realm sent in the `WWW-Authenticate` header of the admin endpoints if none
+/// is configured
+pub const DEFAULT_ADMIN_REALM: &str = "rustic-admin";
+
+/// Returns the value of the `WWW-Authenticate` header we send along with a 401
+pub(crate) fn www_authenticate_value() -> String {
+    let realm = AUTH.get().map_or(DEFAULT_REALM, Auth::realm);
+    format!("Basic realm=\"{realm}\"")
+}
+
+/// Longest time a verified password may be accepted again without checking
+/// its hash, so revoked credentials can't be used for long
+pub const MAX_AUTH_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Recently verified passwords, so the requests following each other, e.g.
+/// those of a connection, don't check the expensive password hash again
+#[derive(Debug)]
+struct AuthCache {
+    ttl: Duration,
+    /// Random salt of the keys, so no password can be looked up by its hash
+    salt: [u8; 32],
+    /// Time of the verification and the stored credential, by the salted hash
+    /// of user and password
+    verified: Mutex<HashMap<[u8; 32], (Instant, String)>>,
+}
+
+impl AuthCache {
+    fn new(ttl: Duration) -> Self {
+        let mut salt = [0; 32];
+        thread_rng().fill_bytes(&mut salt);
+        Self {
+            ttl,
+            salt,
+            verified: Mutex::default(),
+        }
+    }
+
+    fn key(&self, user: &str, passwd: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(self.salt)
+            .chain_update(user)
+            .chain_update([0])
+            .chain_update(passwd)
+            .finalize()
+            .into()
+    }
+
+    /// Returns whether the password was verified within the TTL against the
+    /// credential which is still stored for the user
+    fn contains(&self, key: &[u8; 32], credential: &str) -> bool {
+        let verified = self.verified.lock().unwrap_or_else(PoisonError::into_inner);
+        verified
+            .get(key)
+            .is_some_and(|(at, verified)| at.elapsed() < self.ttl && verified == credential)
+    }
+
+    /// Keeps the verified password, dropping those outside the TTL
+    fn insert(&self, key: [u8; 32], credential: String) {
+        let mut verified = self.verified.lock().unwrap_or_else(PoisonError::into_inner);
+        verified.retain(|_, (at, _)| at.elapsed() < self.ttl);
+        let _ = verified.insert(key, (Instant::now(), credential));
+    }
+
+    fn clear(&self) {
+        self.verified
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Auth {
-    users: Option<CredentialMap>,
+    /// Credentials of all users, replaced when users are changed at runtime
+    users: Option<Arc<RwLock<CredentialMap>>>,
+    /// Recently verified passwords, if they are cached
+    cache: Option<Arc<AuthCache>>,
+    /// htpasswd file the credentials were loaded from
+    htpasswd_path: Option<PathBuf>,
+    realm: Option<String>,
+    admins: Vec<String>,
+    anonymous_user: String,
 }
 
 impl From<CredentialMap> for Auth {
     fn from(users: CredentialMap) -> Self {
-        Self { users: Some(users) }
+        Self {
+            users: Some(Arc::new(RwLock::new(users))),
+            ..Default::default()
+        }
     }
 }
 
 impl From<Htpasswd> for Auth {
     fn from(htpasswd: Htpasswd) -> Self {
         Self {
-            users: Some(htpasswd.credentials),
+            htpasswd_path: Some(htpasswd.path),
+            ..htpasswd.credentials.into()
         }
     }
 }
@@ -49,7 +156,61 @@ impl Auth {
     }
 
     pub fn from_config(settings: &HtpasswdSettings, path: PathBuf) -> AppResult<Self> {
-        Self::from_file(settings.is_disabled(), &path)
+        Ok(Self::from_file(settings.is_disabled(), &path)?
+            .set_realm(settings.realm.clone())
+            .set_admins(settings.admin_users.clone())
+            .set_anonymous_user(settings.anonymous_user.clone().unwrap_or_default())
+            .set_cache_ttl(settings.auth_cache_ttl.map(Duration::from_secs)))
+    }
+
+    /// Returns the credentials of the admin endpoints, if a separate htpasswd
+    /// file is configured for them
+    pub fn from_admin_config(settings: &AdminSettings) -> AppResult<Option<Self>> {
+        let Some(path) = &settings.htpasswd_file else {
+            return Ok(None);
+        };
+
+        let realm = settings
+            .realm
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ADMIN_REALM.to_string());
+        Ok(Some(Self::from_file(false, path)?.set_realm(Some(realm))))
+    }
+
+    pub fn set_realm(self, realm: Option<String>) -> Self {
+        Self { realm, ..self }
+    }
+
+    pub fn set_admins(self, admins: Vec<String>) -> Self {
+        Self { admins, ..self }
+    }
+
+    pub fn set_anonymous_user(self, anonymous_user: String) -> Self {
+        Self {
+            anonymous_user,
+            ..self
+        }
+    }
+
+    /// Accepts verified passwords again for `ttl` without checking their hash
+    pub fn set_cache_ttl(self, ttl: Option<Duration>) -> Self {
+        Self {
+            cache: ttl
+                .filter(|ttl| !ttl.is_zero())
+                .map(|ttl| Arc::new(AuthCache::new(ttl))),
+            ..self
+        }
+    }
+
+    /// Returns whether the (authenticated) user may use the admin endpoints.
+    ///
+    /// Without authentication, nobody is an admin.
+    pub fn is_admin(&self, user: &str) -> bool {
+        !self.is_disabled() && self.admins.iter().any(|admin| admin == user)
+    }
+
+    pub fn realm(&self) -> &str {
+        self.realm.as_deref().unwrap_or(DEFAULT_REALM)
     }
 
     // verify verifies user/passwd against the credentials saved in users.
@@ -58,7 +219,54 @@ impl Auth {
         let user = user.into();
         let passwd = passwd.into();
 
-        self.users.as_ref().map_or(true, |users| matches!(users.get(&user), Some(passwd_data) if htpasswd_verify::Htpasswd::from(passwd_data.to_string().borrow()).check(user, passwd)))
+        self.users.as_ref().map_or(true, |users| {
+            let users = users.read().unwrap_or_else(PoisonError::into_inner);
+            let Some(credential) = users.get(&user).map(ToString::to_string) else {
+                return false;
+            };
+
+            let cached = self
+                .cache
+                .as_ref()
+                .map(|cache| (cache, cache.key(&user, &passwd)));
+            if cached
+                .as_ref()
+                .is_some_and(|(cache, key)| cache.contains(key, &credential))
+            {
+                return true;
+            }
+
+            let verified = htpasswd_verify::Htpasswd::from(credential.borrow()).check(user, passwd);
+            if let (true, Some((cache, key))) = (verified, cached) {
+                cache.insert(key, credential);
+            }
+            verified
+        })
+    }
+
+    /// Returns the names of all users with credentials
+    pub fn users(&self) -> Vec<String> {
+        self.users.as_ref().map_or_else(Vec::new, |users| {
+            let users = users.read().unwrap_or_else(PoisonError::into_inner);
+            users.keys().cloned().collect()
+        })
+    }
+
+    /// Returns the htpasswd file the credentials were loaded from
+    pub fn htpasswd_path(&self) -> Option<&Path> {
+        self.htpasswd_path.as_deref()
+    }
+
+    /// Replaces the credentials, e.g. after the htpasswd file was changed.
+    ///
+    /// Without authentication, this does nothing.
+    pub fn set_credentials(&self, credentials: CredentialMap) {
+        if let Some(users) = &self.users {
+            *users.write().unwrap_or_else(PoisonError::into_inner) = credentials;
+        }
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
     }
 
     pub const fn is_disabled(&self) -> bool {
@@ -76,39 +284,115 @@ pub struct BasicAuthFromRequest {
 impl<S: Send + Sync> FromRequestParts<S> for BasicAuthFromRequest {
     type Rejection = ApiErrorKind;
 
-    // FIXME: We also have a configuration flag do run without authentication
-    // This must be handled here too ... otherwise we get an Auth header missing error.
     async fn from_request_parts(parts: &mut Parts, state: &S) -> ApiResult<Self> {
-        let checker = AUTH.get().unwrap();
+        Self::authenticate(AUTH.get().unwrap(), parts, state, true).await
+    }
+}
+
+impl BasicAuthFromRequest {
+    /// Authenticates the request against `checker`
+    ///
+    /// Without authentication, the `Authorization` header is ignored and all
+    /// requests are made by the anonymous user. With `allow_anonymous`, reads
+    /// without an `Authorization` header are made by the anonymous user, too,
+    /// whom the ACL only grants access to public repositories.
+    async fn authenticate<S: Send + Sync>(
+        checker: &Auth,
+        parts: &mut Parts,
+        state: &S,
+        allow_anonymous: bool,
+    ) -> ApiResult<Self> {
+        let anonymous_read = allow_anonymous
+            && matches!(parts.method, Method::GET | Method::HEAD)
+            && !parts.headers.contains_key(header::AUTHORIZATION);
+
+        if checker.is_disabled() || anonymous_read {
+            return Ok(Self {
+                user: checker.anonymous_user.clone(),
+                _password: String::new().into(),
+            });
+        }
 
         let auth_result = AuthBasic::from_request_parts(parts, state).await;
 
         tracing::debug!(?auth_result, "[AUTH]");
 
-        return match auth_result {
-            Ok(auth) => {
-                let AuthBasic((user, passw)) = auth;
-                let password = passw.unwrap_or_else(String::new);
-                if checker.verify(user.as_str(), password.as_str()) {
-                    Ok(Self {
-                        user,
-                        _password: password.into(),
-                    })
-                } else {
-                    Err(ApiErrorKind::UserAuthenticationError(user))
-                }
-            }
-            Err(_) => {
-                let user = String::new();
-                if checker.verify("", "") {
-                    return Ok(Self {
-                        user,
-                        _password: String::new().into(),
-                    });
-                }
-                Err(ApiErrorKind::AuthenticationHeaderError)
-            }
+        let Ok(AuthBasic((user, passw))) = auth_result else {
+            return Err(ApiErrorKind::AuthenticationHeaderError);
+        };
+
+        let password = passw.unwrap_or_else(String::new);
+        if !checker.verify(user.as_str(), password.as_str()) {
+            return Err(ApiErrorKind::UserAuthenticationError(user));
+        }
+
+        if let Some(session) = parts.extensions.get::<ActiveSession>() {
+            session.set_user(&user);
+        }
+        Ok(Self {
+            user,
+            _password: password.into(),
+        })
+    }
+}
+
+/// Authenticated user of the admin endpoints
+///
+/// With a separate htpasswd file for the admin endpoints, the request is
+/// authenticated against it. Otherwise, it is authenticated against the
+/// regular credentials and the user must be one of the `admin-users`.
+#[derive(Debug)]
+pub struct AdminAuthFromRequest {
+    pub(crate) user: String,
+}
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminAuthFromRequest {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Response> {
+        Self::authenticate(ADMIN_AUTH.get().and_then(Option::as_ref), parts, state).await
+    }
+}
+
+impl AdminAuthFromRequest {
+    async fn authenticate<S: Send + Sync>(
+        admin_auth: Option<&Auth>,
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, Response> {
+        let Some(admin_auth) = admin_auth else {
+            let BasicAuthFromRequest { user, .. } =
+                BasicAuthFromRequest::authenticate(AUTH.get().unwrap(), parts, state, false)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+            check_admin(&user).map_err(IntoResponse::into_response)?;
+            return Ok(Self { user });
         };
+
+        let BasicAuthFromRequest { user, .. } =
+            BasicAuthFromRequest::authenticate(admin_auth, parts, state, false)
+                .await
+                .map_err(|err| {
+                    err.into_challenge_response(format!("Basic realm=\"{}\"", admin_auth.realm()))
+                })?;
+        tracing::debug!(name: "admin", %user, allowed = true);
+
+        Ok(Self { user })
+    }
+}
+
+/// Checks that the user may use the admin endpoints
+fn check_admin(user: &str) -> ApiResult<()> {
+    let allowed = AUTH.get().unwrap().is_admin(user);
+    tracing::debug!(name: "admin", %user, allowed);
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(ApiErrorKind::PermissionDenied(format!(
+            "user `{user}` is not an admin"
+        )))
     }
 }
 
@@ -116,12 +400,14 @@ impl<S: Send + Sync> FromRequestParts<S> for BasicAuthFromRequest {
 mod test {
     use super::*;
 
-    use crate::testing::{basic_auth_header_value, init_test_environment, server_config};
+    use crate::testing::{
+        basic_auth_header_value, init_test_environment, server_config, test_data_path,
+    };
 
     use anyhow::Result;
     use axum::{
         body::Body,
-        http::{Method, Request, StatusCode},
+        http::{header, Method, Request, StatusCode},
         routing::get,
         Router,
     };
@@ -144,8 +430,68 @@ mod test {
     }
 
     #[rstest]
-    fn test_auth_from_file_passes(auth: Auth) {
-        init_auth(auth).unwrap();
+    fn test_auth_cache_passes(auth: Auth) {
+        let auth = auth.set_cache_ttl(Some(Duration::from_secs(60)));
+        let cache = auth.cache.clone().unwrap();
+        let key = cache.key("rustic", "rustic");
+
+        assert!(auth.verify("rustic", "rustic"));
+        assert_eq!(cache.verified.lock().unwrap().len(), 1);
+        assert!(auth.verify("rustic", "rustic"));
+        // other passwords are still checked
+        assert!(!auth.verify("rustic", "_rustic"));
+
+        // a changed credential isn't accepted from the cache
+        let credential = cache.verified.lock().unwrap()[&key].1.clone();
+        assert!(cache.contains(&key, &credential));
+        assert!(!cache.contains(&key, "$2y$05$changed"));
+
+        // neither are revoked ones
+        let mut htpasswd = Htpasswd::new();
+        htpasswd.update("new_user", "secret").unwrap();
+        auth.set_credentials(htpasswd.credentials);
+        assert!(!auth.verify("rustic", "rustic"));
+        assert!(cache.verified.lock().unwrap().is_empty());
+    }
+
+    #[rstest]
+    fn test_auth_cache_expires_passes(auth: Auth) {
+        let auth = auth.set_cache_ttl(Some(Duration::from_millis(50)));
+        let cache = auth.cache.clone().unwrap();
+        let key = cache.key("rustic", "rustic");
+
+        assert!(auth.verify("rustic", "rustic"));
+        let credential = cache.verified.lock().unwrap()[&key].1.clone();
+        assert!(cache.contains(&key, &credential));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!cache.contains(&key, &credential));
+        assert!(auth.verify("rustic", "rustic"));
+
+        // a TTL of zero disables the cache
+        assert!(auth.set_cache_ttl(Some(Duration::ZERO)).cache.is_none());
+    }
+
+    #[rstest]
+    fn test_set_credentials_passes(auth: Auth) {
+        let mut htpasswd = Htpasswd::new();
+        htpasswd.update("new_user", "secret").unwrap();
+
+        // clones share the credentials, like the global does
+        auth.clone().set_credentials(htpasswd.credentials);
+        assert!(auth.verify("new_user", "secret"));
+        assert!(!auth.verify("rustic", "rustic"));
+
+        assert_eq!(
+            auth.htpasswd_path(),
+            Some(Path::new("tests/fixtures/test_data/.htpasswd"))
+        );
+    }
+
+    #[test]
+    fn test_auth_from_file_passes() {
+        // share the global with the other tests, which also set the admin users
+        init_test_environment(server_config());
 
         let auth = AUTH.get().unwrap();
         assert!(auth.verify("rustic", "rustic"));
@@ -236,21 +582,168 @@ mod test {
 
         let resp = app.oneshot(request).await.unwrap();
 
-        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Basic realm=\"rustic\""
+        );
 
         // -----------------------------------------
-        // Try without authentication header
+        // Try to write without authentication header
         // -----------------------------------------
-        let app = Router::new().route("/rustic_server", get(format_handler_from_auth_request));
+        let app = Router::new().route(
+            "/rustic_server",
+            get(format_handler_from_auth_request).post(format_handler_from_auth_request),
+        );
 
         let request = Request::builder()
             .uri("/rustic_server")
-            .method(Method::GET)
+            .method(Method::POST)
             .body(Body::empty())
             .unwrap();
 
         let resp = app.oneshot(request).await.unwrap();
 
-        assert_eq!(resp.status().as_u16(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+
+    #[tokio::test]
+    async fn test_no_auth_needs_no_header_passes() {
+        let auth = Auth::default().set_anonymous_user("anonymous".to_string());
+        assert!(auth.is_disabled());
+
+        // no `Authorization` header at all
+        let (mut parts, ()) = Request::builder()
+            .uri("/rustic_server")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let result = BasicAuthFromRequest::authenticate(&auth, &mut parts, &(), true)
+            .await
+            .unwrap();
+        assert_eq!(result.user, "anonymous");
+
+        // the header is ignored, too
+        let (mut parts, ()) = Request::builder()
+            .uri("/rustic_server")
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("wrong")),
+            )
+            .body(())
+            .unwrap()
+            .into_parts();
+        let result = BasicAuthFromRequest::authenticate(&auth, &mut parts, &(), true)
+            .await
+            .unwrap();
+        assert_eq!(result.user, "anonymous");
+
+        // the anonymous user defaults to ""
+        let (mut parts, ()) = Request::builder().body(()).unwrap().into_parts();
+        let result = BasicAuthFromRequest::authenticate(&Auth::default(), &mut parts, &(), true)
+            .await
+            .unwrap();
+        assert_eq!(result.user, "");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_read_passes() {
+        let auth = auth().set_anonymous_user("guest".to_string());
+        let request = |method: Method| {
+            Request::builder()
+                .method(method)
+                .body(())
+                .unwrap()
+                .into_parts()
+                .0
+        };
+
+        // reads without credentials are made by the anonymous user
+        for method in [Method::GET, Method::HEAD] {
+            let result = BasicAuthFromRequest::authenticate(&auth, &mut request(method), &(), true)
+                .await
+                .unwrap();
+            assert_eq!(result.user, "guest");
+        }
+
+        // but not writes or requests to the admin endpoints
+        for method in [Method::POST, Method::DELETE] {
+            assert!(matches!(
+                BasicAuthFromRequest::authenticate(&auth, &mut request(method), &(), true).await,
+                Err(ApiErrorKind::AuthenticationHeaderError)
+            ));
+        }
+        assert!(matches!(
+            BasicAuthFromRequest::authenticate(&auth, &mut request(Method::GET), &(), false).await,
+            Err(ApiErrorKind::AuthenticationHeaderError)
+        ));
+
+        // wrong credentials are never ignored
+        let mut parts = admin_request("rustic", "_rustic");
+        assert!(matches!(
+            BasicAuthFromRequest::authenticate(&auth, &mut parts, &(), true).await,
+            Err(ApiErrorKind::UserAuthenticationError(_))
+        ));
+    }
+
+    fn admin_request(user: &str, password: &str) -> Parts {
+        Request::builder()
+            .uri("/admin/sessions")
+            .header(
+                "Authorization",
+                basic_auth_header_value(user, Some(password)),
+            )
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[tokio::test]
+    async fn test_admin_auth_passes() {
+        init_test_environment(server_config());
+
+        let settings = AdminSettings {
+            htpasswd_file: Some(test_data_path().join("admin.htpasswd")),
+            realm: None,
+        };
+        let admin_auth = Auth::from_admin_config(&settings).unwrap().unwrap();
+        assert_eq!(admin_auth.realm(), DEFAULT_ADMIN_REALM);
+
+        let mut parts = admin_request("admin", "admin");
+        let result = AdminAuthFromRequest::authenticate(Some(&admin_auth), &mut parts, &())
+            .await
+            .unwrap();
+        assert_eq!(result.user, "admin");
+
+        // regular users can't use the admin endpoints, even if listed in `admin-users`
+        let mut parts = admin_request("hurl", "hurl");
+        let resp = AdminAuthFromRequest::authenticate(Some(&admin_auth), &mut parts, &())
+            .await
+            .unwrap_err();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Basic realm=\"rustic-admin\""
+        );
+
+        // without a separate htpasswd file, `admin-users` are admins
+        let mut parts = admin_request("hurl", "hurl");
+        let result = AdminAuthFromRequest::authenticate(None, &mut parts, &())
+            .await
+            .unwrap();
+        assert_eq!(result.user, "hurl");
+
+        let mut parts = admin_request("rustic", "rustic");
+        let resp = AdminAuthFromRequest::authenticate(None, &mut parts, &())
+            .await
+            .unwrap_err();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // no separate htpasswd file configured
+        assert!(Auth::from_admin_config(&AdminSettings::default())
+            .unwrap()
+            .is_none());
     }
 }
@@ -11,11 +11,20 @@
 //! application's configuration file.
 
 mod auth;
+mod bench;
+mod replicate;
+mod scrub;
 mod serve;
+mod stats;
+
+pub(crate) use self::auth::parse_age;
 
 use crate::{
-    commands::{auth::AuthCmd, serve::ServeCmd},
-    config::RusticServerConfig,
+    commands::{
+        auth::AuthCmd, bench::BenchCmd, replicate::ReplicateCmd, scrub::ScrubCmd, serve::ServeCmd,
+        stats::StatsCmd,
+    },
+    config::{ConfigSource, RusticServerConfig},
 };
 use abscissa_core::{
     config::Override, tracing::info, Command, Configurable, FrameworkError, Runnable,
@@ -32,12 +41,26 @@ pub const CONFIG_FILE: &str = "rustic_server.toml";
 /// `RusticServer` Subcommands
 /// Subcommands need to be listed in an enum.
 #[derive(clap::Parser, Command, Debug, Runnable)]
+// The command is only constructed once at startup, so its size doesn't matter
+#[allow(clippy::large_enum_variant)]
 pub enum RusticServerCmd {
     /// Authentication for users. Add, update, delete, or list users.
     Auth(AuthCmd),
 
     /// Start a server with the specified configuration
     Serve(ServeCmd),
+
+    /// Verify the integrity of the repositories in a data directory, without starting a server
+    Scrub(ScrubCmd),
+
+    /// Measure the throughput of a storage backend by writing, reading and deleting blobs
+    Bench(BenchCmd),
+
+    /// Print the number and size of the files of the repositories in a data directory, without starting a server
+    Stats(StatsCmd),
+
+    /// Copy the repositories from one storage configuration to another, without starting a server
+    Replicate(ReplicateCmd),
 }
 
 fn styles() -> Styles {
@@ -60,11 +83,20 @@ pub struct EntryPoint {
     #[arg(short, long, global = true, env = "RUSTIC_SERVER_VERBOSE")]
     pub verbose: bool,
 
-    /// Use the specified config file
+    /// Use the specified config file, `-` to read it from stdin, or
+    /// `env:<VAR>` to read it from an environment variable
     #[arg(short, long, global = true, env = "RUSTIC_SERVER_CONFIG_PATH")]
     pub config: Option<String>,
 }
 
+impl EntryPoint {
+    /// Returns whether the command prints metrics to stdout, which must not be
+    /// mixed with logs
+    pub(crate) const fn prints_metrics(&self) -> bool {
+        matches!(&self.cmd, RusticServerCmd::Stats(cmd) if cmd.prints_metrics())
+    }
+}
+
 impl Runnable for EntryPoint {
     fn run(&self) {
         self.cmd.run();
@@ -81,6 +113,14 @@ impl Configurable<RusticServerConfig> for EntryPoint {
             return None;
         }
 
+        // stdin and environment variables are read in `load_config`
+        if let Some(config) = &self.config {
+            if !ConfigSource::parse(config).is_file() {
+                info!("Using configuration from: `{config}`");
+                return Some(PathBuf::from(config));
+            }
+        }
+
         let filename = self
             .config
             .as_ref()
@@ -2,11 +2,12 @@
 
 use std::path::PathBuf;
 
-use abscissa_core::{status_err, Application, Command, Runnable, Shutdown};
-use anyhow::{bail, Result};
+use abscissa_core::{Command, Runnable};
+use anyhow::{anyhow, bail, Result};
+use chrono::{TimeDelta, Utc};
 use clap::{Args, Parser, Subcommand};
 
-use crate::{htpasswd::Htpasswd, prelude::RUSTIC_SERVER_APP};
+use crate::{application::shutdown_on_error, auth::Auth, error::ExitCode, htpasswd::Htpasswd};
 
 /// `auth` subcommand
 ///
@@ -25,8 +26,7 @@ impl Runnable for AuthCmd {
     /// Start the application.
     fn run(&self) {
         if let Err(err) = self.inner_run() {
-            status_err!("{}", err);
-            RUSTIC_SERVER_APP.shutdown(Shutdown::Crash);
+            shutdown_on_error(&err, ExitCode::from(&err));
         }
     }
 }
@@ -42,6 +42,11 @@ enum Commands {
     Delete(DelArg),
     /// List all users known in the .htpasswd file.
     List(PrintArg),
+    /// Delete all credentials whose password is older than the given age.
+    /// The age is tracked in a `.meta` file next to the .htpasswd file.
+    Prune(PruneArg),
+    /// Check whether a user could log in with the given password.
+    Verify(VerifyArg),
 }
 
 #[derive(Args, Debug)]
@@ -74,6 +79,32 @@ struct PrintArg {
     pub config_path: PathBuf,
 }
 
+#[derive(Args, Debug)]
+struct VerifyArg {
+    ///Path to authorization file
+    #[arg(short = 'f')]
+    pub config_path: PathBuf,
+    /// Name of the user to be checked.
+    #[arg(short = 'u')]
+    user: String,
+    /// Password.
+    #[arg(short = 'p')]
+    password: String,
+}
+
+#[derive(Args, Debug)]
+struct PruneArg {
+    ///Path to authorization file
+    #[arg(short = 'f')]
+    pub config_path: PathBuf,
+    /// Maximum age of a password, e.g. `90d`, `12h` or `2w`.
+    #[arg(long, value_parser = parse_age)]
+    older_than: TimeDelta,
+    /// Only list the credentials that would be deleted.
+    #[arg(long)]
+    dry_run: bool,
+}
+
 /// The server configuration file should point us to the `.htpasswd` file.
 /// If not we complain to the user.
 ///
@@ -94,6 +125,12 @@ impl AuthCmd {
             Commands::List(arg) => {
                 print(arg)?;
             }
+            Commands::Prune(arg) => {
+                prune(arg)?;
+            }
+            Commands::Verify(arg) => {
+                verify(arg)?;
+            }
         };
         Ok(())
     }
@@ -211,6 +248,96 @@ fn print(arg: &PrintArg) -> Result<()> {
     Ok(())
 }
 
+fn prune(arg: &PruneArg) -> Result<()> {
+    let ht_access_path = PathBuf::from(&arg.config_path);
+    check(&ht_access_path)?;
+    let mut ht_access = Htpasswd::from_file(&ht_access_path)?;
+
+    for user in ht_access.users_without_meta() {
+        println!(
+            "Skipping user {}: creation date unknown. Use update to track it.",
+            user
+        );
+    }
+
+    let expired = ht_access.expired_users(Utc::now() - arg.older_than);
+    if expired.is_empty() {
+        println!("No credentials are older than the given age. No changes were made.");
+        return Ok(());
+    }
+
+    for user in &expired {
+        if arg.dry_run {
+            println!("Would delete user with name {}.", user);
+        } else {
+            println!("Deleting user with name {}.", user);
+            let _ = ht_access.delete(user);
+        }
+    }
+
+    if !arg.dry_run {
+        ht_access.to_file()?;
+    }
+    Ok(())
+}
+
+fn verify(arg: &VerifyArg) -> Result<()> {
+    // Don't use `check`, as it creates missing files
+    if !arg.config_path.is_file() {
+        bail!(
+            "Could not find htpasswd file: {}",
+            arg.config_path.to_string_lossy()
+        );
+    }
+    let ht_access = Htpasswd::from_file(&arg.config_path)?;
+
+    let Some(credential) = ht_access.credentials.get(&arg.user) else {
+        bail!(
+            "Could not find a user with name {}. Authentication would fail.",
+            arg.user.as_str()
+        );
+    };
+    let scheme = credential.hash_scheme();
+
+    // This is the same check the server does
+    let auth = Auth::from(ht_access.clone());
+    if !auth.verify(arg.user.as_str(), arg.password.as_str()) {
+        bail!(
+            "Password of user {} does not match (hash scheme: {scheme}). Authentication would fail.",
+            arg.user.as_str()
+        );
+    }
+
+    println!(
+        "Credentials of user {} are valid (hash scheme: {scheme}).",
+        arg.user.as_str()
+    );
+    Ok(())
+}
+
+/// Parses an age like `90d` into a duration.
+///
+/// Supported units are `s`, `m`, `h`, `d` and `w`.
+pub(crate) fn parse_age(age: &str) -> Result<TimeDelta> {
+    let age = age.trim();
+    let split = age.len() - age.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (number, unit) = age.split_at(split);
+    let number: i64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid age '{age}', expected e.g. '90d'"))?;
+
+    let delta = match unit {
+        "s" => TimeDelta::try_seconds(number),
+        "m" => TimeDelta::try_minutes(number),
+        "h" => TimeDelta::try_hours(number),
+        "d" => TimeDelta::try_days(number),
+        "w" => TimeDelta::try_weeks(number),
+        _ => bail!("Invalid unit in age '{age}', expected one of s, m, h, d, w"),
+    };
+
+    delta.ok_or_else(|| anyhow!("Age '{age}' is too large"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -220,4 +347,31 @@ mod tests {
     fn verify_auth() {
         AuthCmd::command().debug_assert();
     }
+
+    #[test]
+    fn test_verify_passes() {
+        let arg = |user: &str, password: &str| VerifyArg {
+            config_path: PathBuf::from("tests/fixtures/test_data/.htpasswd"),
+            user: user.to_string(),
+            password: password.to_string(),
+        };
+
+        assert!(verify(&arg("rustic", "rustic")).is_ok());
+
+        let err = verify(&arg("rustic", "wrong")).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{err}");
+
+        let err = verify(&arg("nobody", "rustic")).unwrap_err();
+        assert!(err.to_string().contains("Could not find a user"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_age_passes() {
+        assert_eq!(parse_age("90d").unwrap(), TimeDelta::days(90));
+        assert_eq!(parse_age("12h").unwrap(), TimeDelta::hours(12));
+        assert_eq!(parse_age("2w").unwrap(), TimeDelta::weeks(2));
+        assert!(parse_age("90").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("90y").is_err());
+    }
 }
@@ -0,0 +1,287 @@
+//! `bench` subcommand
+
+use std::{
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use abscissa_core::{Command, Runnable};
+use anyhow::{bail, Result};
+use clap::Parser;
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    application::shutdown_on_error,
+    config::StorageBackend,
+    error::{ApiErrorKind, ExitCode},
+    prelude::RUSTIC_SERVER_APP,
+    storage::{LocalStorage, PackedLocalStorage, Storage},
+    typed_path::TpeKind,
+};
+
+/// `bench` subcommand
+///
+/// Writes, reads and deletes synthetic blobs through the storage backend the
+/// server uses, and reports the throughput of each phase.
+#[derive(Command, Debug, Parser)]
+pub struct BenchCmd {
+    /// Path to the data directory to benchmark
+    #[arg(long = "path")]
+    data_dir: PathBuf,
+
+    /// Total size of the blobs to write, e.g. `1G` or `512M`
+    #[arg(long, default_value = "1G", value_parser = parse_size)]
+    size: u64,
+
+    /// Size of each blob, e.g. `4M`
+    #[arg(long, default_value = "4M", value_parser = parse_size)]
+    blob_size: u64,
+
+    /// Storage backend to benchmark
+    #[arg(long, value_enum, default_value = "local")]
+    backend: StorageBackend,
+}
+
+impl Runnable for BenchCmd {
+    fn run(&self) {
+        if let Err(tokio_err) = abscissa_tokio::run(&RUSTIC_SERVER_APP, async {
+            if let Err(err) = self.inner_run().await {
+                shutdown_on_error(&err, ExitCode::from(&err));
+            }
+        }) {
+            shutdown_on_error(&tokio_err, ExitCode::Crash);
+        };
+    }
+}
+
+impl BenchCmd {
+    pub async fn inner_run(&self) -> Result<()> {
+        if !self.data_dir.is_dir() {
+            bail!(
+                "Data directory does not exist: {}",
+                self.data_dir.to_string_lossy()
+            );
+        }
+        if self.blob_size == 0 || self.size < self.blob_size {
+            bail!("`--blob-size` must be at least 1 byte and at most `--size`.");
+        }
+
+        let report = match self.backend {
+            StorageBackend::Local => self.bench(&LocalStorage::init(&self.data_dir)?).await?,
+            StorageBackend::PackedLocal => {
+                self.bench(&PackedLocalStorage::init(&self.data_dir)?)
+                    .await?
+            }
+        };
+
+        println!("{report}");
+        Ok(())
+    }
+
+    async fn bench(&self, storage: &impl Storage) -> Result<BenchReport> {
+        let blobs = self.size / self.blob_size;
+        let blob_size = usize::try_from(self.blob_size)?;
+
+        // a repository of its own, so existing repositories aren't touched
+        let repo = PathBuf::from(format!("bench-{}", uuid::Uuid::new_v4()));
+        println!(
+            "Benchmarking {blobs} blobs of {blob_size} bytes in {}",
+            self.data_dir.join(&repo).display()
+        );
+
+        storage.create_dir(&repo, None).await?;
+        let report = bench_repository(storage, &repo, blobs, blob_size).await;
+        storage.remove_repository(&repo).await?;
+
+        report
+    }
+}
+
+/// Throughput of a benchmark phase
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaseResult {
+    pub files: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl PhaseResult {
+    /// Returns the throughput in MB/s
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / 1_000_000.0 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Returns the files processed per second
+    pub fn iops(&self) -> f64 {
+        self.files as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Results of all benchmark phases
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchReport {
+    pub write: PhaseResult,
+    pub read: PhaseResult,
+    pub delete: PhaseResult,
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (phase, result) in [
+            ("write", self.write),
+            ("read", self.read),
+            ("delete", self.delete),
+        ] {
+            writeln!(
+                f,
+                "{phase:>6}: {:>10.2} MB/s {:>10.2} IOPS ({} files in {:.2?})",
+                result.mb_per_sec(),
+                result.iops(),
+                result.files,
+                result.elapsed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes, reads and deletes `blobs` random blobs of `blob_size` bytes in
+/// the repository at `repo`
+pub async fn bench_repository(
+    storage: &impl Storage,
+    repo: &Path,
+    blobs: u64,
+    blob_size: usize,
+) -> Result<BenchReport> {
+    let tpe = TpeKind::Data.into_str();
+    let mut content = vec![0; blob_size];
+    thread_rng().fill_bytes(&mut content);
+
+    // blobs are named after their content, like restic does
+    let mut names = Vec::new();
+    let start = Instant::now();
+    for blob in 0..blobs {
+        let prefix = blob.to_le_bytes();
+        let len = prefix.len().min(blob_size);
+        content[..len].copy_from_slice(&prefix[..len]);
+        let name = format!("{:x}", Sha256::digest(&content));
+
+        let mut file = storage.create_file(repo, tpe, Some(&name)).await?;
+        file.write_all(&content).await?;
+        file.finalize().await?;
+        names.push(name);
+    }
+    let write = phase_result(&names, blob_size, start);
+
+    let mut buffer = Vec::with_capacity(blob_size);
+    let start = Instant::now();
+    for name in &names {
+        buffer.clear();
+        let read = storage
+            .open_file(repo, tpe, Some(name))
+            .await?
+            .read_to_end(&mut buffer)
+            .await?;
+        if read != blob_size {
+            return Err(ApiErrorKind::ReadingFromStreamFailed.into());
+        }
+    }
+    let read = phase_result(&names, blob_size, start);
+
+    let start = Instant::now();
+    for name in &names {
+        storage.remove_file(repo, tpe, Some(name)).await?;
+    }
+    let delete = PhaseResult {
+        bytes: 0,
+        ..phase_result(&names, blob_size, start)
+    };
+
+    Ok(BenchReport {
+        write,
+        read,
+        delete,
+    })
+}
+
+fn phase_result(names: &[String], blob_size: usize, start: Instant) -> PhaseResult {
+    let files = names.len() as u64;
+    PhaseResult {
+        files,
+        bytes: files * blob_size as u64,
+        elapsed: start.elapsed(),
+    }
+}
+
+/// Parses a size in bytes with an optional binary unit, e.g. `512K` or `1GiB`
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("`{size}` is not a size like `512M` or `1G`"))?;
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("unknown unit `{unit}` in size `{size}`")),
+    };
+
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{size}` is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_bench() {
+        BenchCmd::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_size_passes() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("4k"), Ok(4 * 1024));
+        assert_eq!(parse_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("1GiB"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bench_repository_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_bench");
+        if data_dir.exists() {
+            fs::remove_dir_all(&data_dir).unwrap();
+        }
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        let repo = Path::new("bench");
+        storage.create_dir(repo, None).await.unwrap();
+
+        let report = bench_repository(&storage, repo, 4, 1024).await.unwrap();
+
+        let remaining = storage.read_dir(repo, Some("data")).count();
+        fs::remove_dir_all(&data_dir).unwrap();
+
+        assert_eq!(report.write.files, 4);
+        assert_eq!(report.write.bytes, 4 * 1024);
+        assert_eq!(report.read.bytes, 4 * 1024);
+        assert_eq!(report.delete.files, 4);
+        assert_eq!(remaining, 0);
+    }
+}
@@ -0,0 +1,325 @@
+//! `replicate` subcommand
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+};
+
+use abscissa_core::{Command, Runnable};
+use anyhow::{bail, Result};
+use clap::Parser;
+
+use crate::{
+    application::shutdown_on_error,
+    config::{default_data_dir, RusticServerConfig, StorageBackend},
+    error::{ApiErrorKind, ApiResult, ExitCode},
+    handlers::{file_helpers::Finalizer, repository::create_repository_dirs},
+    prelude::RUSTIC_SERVER_APP,
+    storage::{LocalStorage, PackedLocalStorage, Storage, GZIP_SUFFIX},
+    typed_path::TpeKind,
+};
+
+/// Types of files replicated besides the config
+///
+/// Locks belong to the clients of the source, so they aren't replicated.
+const REPLICATE_TPES: [TpeKind; 4] = [
+    TpeKind::Data,
+    TpeKind::Index,
+    TpeKind::Keys,
+    TpeKind::Snapshots,
+];
+
+/// `replicate` subcommand
+///
+/// Copies the repositories from the storage of one server configuration to
+/// the storage of another without starting the server, e.g. to migrate to
+/// another backend. Files already present with the same size are skipped.
+#[derive(Command, Debug, Parser)]
+pub struct ReplicateCmd {
+    /// Configuration file whose `[storage]` section is copied from
+    #[arg(long)]
+    from: PathBuf,
+
+    /// Configuration file whose `[storage]` section is copied to
+    #[arg(long)]
+    to: PathBuf,
+
+    /// Only replicate the repository with the given name
+    #[arg(long)]
+    repo: Option<String>,
+}
+
+impl Runnable for ReplicateCmd {
+    fn run(&self) {
+        if let Err(tokio_err) = abscissa_tokio::run(&RUSTIC_SERVER_APP, async {
+            if let Err(err) = self.inner_run().await {
+                shutdown_on_error(&err, ExitCode::from(&err));
+            }
+        }) {
+            shutdown_on_error(&tokio_err, ExitCode::Crash);
+        };
+    }
+}
+
+impl ReplicateCmd {
+    pub async fn inner_run(&self) -> Result<()> {
+        let from = open_storage(&self.from)?;
+        let to = open_storage(&self.to)?;
+
+        if from.path().canonicalize().ok() == to.path().canonicalize().ok() {
+            bail!(
+                "Source and destination use the same data directory: {}",
+                from.path().to_string_lossy()
+            );
+        }
+
+        let repos = match &self.repo {
+            Some(repo) => vec![PathBuf::from(repo)],
+            None => from.repositories(),
+        };
+
+        let mut total = ReplicateReport::default();
+
+        for repo in repos {
+            println!("Replicating repository: {}", repo.to_string_lossy());
+
+            let report = replicate_repository(from.as_ref(), to.as_ref(), &repo, |tpe, report| {
+                println!("\t{tpe}: {report}");
+            })
+            .await?;
+
+            total.add(&report);
+        }
+
+        println!("Done. {total}");
+        Ok(())
+    }
+}
+
+/// Opens the storage configured in the `[storage]` section of the
+/// configuration file at `config`
+fn open_storage(config: &Path) -> Result<Box<dyn Storage>> {
+    let settings = RusticServerConfig::from_file(config)?.storage;
+    let data_dir = settings.data_dir.clone().unwrap_or_else(default_data_dir);
+
+    if !data_dir.is_dir() {
+        bail!(
+            "Data directory does not exist: {}",
+            data_dir.to_string_lossy()
+        );
+    }
+
+    Ok(match settings.backend.unwrap_or_default() {
+        StorageBackend::Local => Box::new(LocalStorage::from_settings(&data_dir, &settings)?),
+        StorageBackend::PackedLocal => {
+            Box::new(PackedLocalStorage::from_settings(&data_dir, &settings)?)
+        }
+    })
+}
+
+/// Number of files copied and skipped while replicating
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicateReport {
+    pub copied: usize,
+    pub skipped: usize,
+    pub bytes: u64,
+}
+
+impl ReplicateReport {
+    fn add(&mut self, other: &Self) {
+        self.copied += other.copied;
+        self.skipped += other.skipped;
+        self.bytes += other.bytes;
+    }
+}
+
+impl Display for ReplicateReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files copied ({} bytes), {} files skipped.",
+            self.copied, self.bytes, self.skipped
+        )
+    }
+}
+
+/// Copies the config and all files of the repository at `repo` which the
+/// destination doesn't have with the same size
+///
+/// `progress` is called with the report of each type once it is replicated.
+pub async fn replicate_repository(
+    from: &dyn Storage,
+    to: &dyn Storage,
+    repo: &Path,
+    mut progress: impl FnMut(&str, &ReplicateReport) + Send,
+) -> ApiResult<ReplicateReport> {
+    create_repository_dirs(to, repo).await?;
+
+    let mut total = ReplicateReport::default();
+
+    let config = TpeKind::Config.into_str();
+    if let Ok(size) = from.file_length(repo, config, None).await {
+        let mut report = ReplicateReport::default();
+        if to.file_length(repo, config, None).await.ok() == Some(size) {
+            report.skipped += 1;
+        } else {
+            copy_file(from, to, repo, config, None).await?;
+            report.copied += 1;
+            report.bytes += size;
+        }
+        progress(config, &report);
+        total.add(&report);
+    }
+
+    for tpe in REPLICATE_TPES {
+        let tpe = tpe.into_str();
+        let present: HashMap<_, _> = to
+            .read_dir(repo, Some(tpe))
+            .map(|entry| (entry.name, entry.size))
+            .collect();
+
+        let mut report = ReplicateReport::default();
+        // precompressed variants are no files of the repository
+        for entry in from
+            .read_dir(repo, Some(tpe))
+            .filter(|entry| !entry.name.ends_with(GZIP_SUFFIX))
+        {
+            if present.get(&entry.name) == Some(&entry.size) {
+                report.skipped += 1;
+                continue;
+            }
+            copy_file(from, to, repo, tpe, Some(&entry.name)).await?;
+            report.copied += 1;
+            report.bytes += entry.size;
+        }
+        progress(tpe, &report);
+        total.add(&report);
+    }
+
+    Ok(total)
+}
+
+/// Streams a file from one storage to the other
+async fn copy_file(
+    from: &dyn Storage,
+    to: &dyn Storage,
+    repo: &Path,
+    tpe: &str,
+    name: Option<&str>,
+) -> ApiResult<()> {
+    let mut file = from.open_file(repo, tpe, name).await?;
+    let mut writer = to.create_file(repo, tpe, name).await?;
+
+    let _ = tokio::io::copy(&mut file, &mut writer)
+        .await
+        .map_err(|err| {
+            ApiErrorKind::WritingToFileFailed(format!(
+                "Could not copy {}/{tpe}/{}: {err}",
+                repo.display(),
+                name.unwrap_or_default()
+            ))
+        })?;
+
+    writer.finalize().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_replicate() {
+        ReplicateCmd::command().debug_assert();
+    }
+
+    #[tokio::test]
+    async fn test_replicate_repository_passes() {
+        let generated = PathBuf::from("tests/generated");
+        let (from_dir, to_dir) = (
+            generated.join("replicate_from"),
+            generated.join("replicate_to"),
+        );
+        for dir in [&from_dir, &to_dir] {
+            if dir.exists() {
+                fs::remove_dir_all(dir).unwrap();
+            }
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        // from one backend to the other
+        let from = LocalStorage::init(&from_dir).unwrap();
+        let to = PackedLocalStorage::init(&to_dir).unwrap();
+
+        let repo = Path::new("repo");
+        create_repository_dirs(&from, repo).await.unwrap();
+        let files = [
+            ("config", None, "config"),
+            ("data", Some("ab01"), "data blob"),
+            ("keys", Some("cd02"), "key"),
+            ("snapshots", Some("ef03"), "snapshot"),
+            ("locks", Some("0123"), "lock"),
+        ];
+        for (tpe, name, content) in files {
+            let mut file = from.create_file(repo, tpe, name).await.unwrap();
+            file.write_all(content.as_bytes()).await.unwrap();
+            file.finalize().await.unwrap();
+        }
+
+        let mut progress = Vec::new();
+        let report = replicate_repository(&from, &to, repo, |tpe, report| {
+            progress.push((tpe.to_string(), report.copied));
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(report.copied, 4);
+        assert_eq!(report.bytes, 26);
+        assert_eq!(
+            progress,
+            [
+                ("config", 1),
+                ("data", 1),
+                ("index", 0),
+                ("keys", 1),
+                ("snapshots", 1)
+            ]
+            .map(|(tpe, copied)| (tpe.to_string(), copied))
+        );
+
+        for (tpe, name, content) in &files[..4] {
+            let mut read = String::new();
+            let _ = to
+                .open_file(repo, tpe, *name)
+                .await
+                .unwrap()
+                .read_to_string(&mut read)
+                .await
+                .unwrap();
+            assert_eq!(read, *content);
+        }
+        // locks are left behind
+        assert_eq!(to.read_dir(repo, Some("locks")).count(), 0);
+
+        // nothing left to copy
+        let report = replicate_repository(&from, &to, repo, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ReplicateReport {
+                copied: 0,
+                skipped: 4,
+                bytes: 0
+            }
+        );
+
+        fs::remove_dir_all(from_dir).unwrap();
+        fs::remove_dir_all(to_dir).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+//! `scrub` subcommand
+
+use std::{
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+};
+
+use abscissa_core::{Command, Runnable};
+use anyhow::{bail, Result};
+use clap::Parser;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    application::shutdown_on_error,
+    config::StorageBackend,
+    error::{ApiErrorKind, ApiResult, ExitCode},
+    handlers::file_exchange::is_sha256_hex,
+    prelude::RUSTIC_SERVER_APP,
+    storage::{LocalStorage, PackedLocalStorage, Storage},
+    typed_path::TpeKind,
+};
+
+/// Types of content-addressed files we can verify
+const SCRUB_TPES: [TpeKind; 4] = [
+    TpeKind::Data,
+    TpeKind::Index,
+    TpeKind::Keys,
+    TpeKind::Snapshots,
+];
+
+/// `scrub` subcommand
+///
+/// Walks the repositories in the data directory without starting the server
+/// and verifies that every blob's content matches its SHA256 name.
+#[derive(Command, Debug, Parser)]
+pub struct ScrubCmd {
+    /// Path to the data directory
+    #[arg(long = "path")]
+    data_dir: PathBuf,
+
+    /// Only scrub the repository with the given name
+    #[arg(long)]
+    repo: Option<String>,
+
+    /// Storage backend the data directory was written with
+    #[arg(long, value_enum, default_value = "local")]
+    backend: StorageBackend,
+}
+
+impl Runnable for ScrubCmd {
+    fn run(&self) {
+        if let Err(tokio_err) = abscissa_tokio::run(&RUSTIC_SERVER_APP, async {
+            if let Err(err) = self.inner_run().await {
+                shutdown_on_error(&err, ExitCode::from(&err));
+            }
+        }) {
+            shutdown_on_error(&tokio_err, ExitCode::Crash);
+        };
+    }
+}
+
+impl ScrubCmd {
+    pub async fn inner_run(&self) -> Result<()> {
+        if !self.data_dir.is_dir() {
+            bail!(
+                "Data directory does not exist: {}",
+                self.data_dir.to_string_lossy()
+            );
+        }
+
+        match self.backend {
+            StorageBackend::Local => self.scrub(&LocalStorage::init(&self.data_dir)?).await,
+            StorageBackend::PackedLocal => {
+                self.scrub(&PackedLocalStorage::init(&self.data_dir)?).await
+            }
+        }
+    }
+
+    async fn scrub(&self, storage: &impl Storage) -> Result<()> {
+        let repos = match &self.repo {
+            Some(repo) => vec![PathBuf::from(repo)],
+            None => storage.repositories(),
+        };
+
+        let mut problems = 0;
+
+        for repo in repos {
+            println!("Scrubbing repository: {}", repo.to_string_lossy());
+
+            let report = scrub_repository(storage, &repo).await;
+
+            for problem in &report.problems {
+                println!("\t{problem}");
+            }
+
+            println!(
+                "\t{} files checked, {} problems found.",
+                report.checked,
+                report.problems.len()
+            );
+
+            problems += report.problems.len();
+        }
+
+        if problems > 0 {
+            bail!("Scrub found {problems} problems.");
+        }
+
+        println!("Done.");
+        Ok(())
+    }
+}
+
+/// A problem found while scrubbing a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScrubProblem {
+    /// File content doesn't hash to the file name
+    HashMismatch { path: PathBuf, actual: String },
+
+    /// File name isn't a valid blob name
+    Orphaned { path: PathBuf },
+
+    /// File is empty
+    ZeroByte { path: PathBuf },
+
+    /// File could not be read
+    Unreadable { path: PathBuf, error: String },
+}
+
+impl Display for ScrubProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HashMismatch { path, actual } => {
+                write!(f, "hash mismatch: {} (actual: {actual})", path.display())
+            }
+            Self::Orphaned { path } => write!(f, "orphaned file: {}", path.display()),
+            Self::ZeroByte { path } => write!(f, "zero-byte file: {}", path.display()),
+            Self::Unreadable { path, error } => {
+                write!(f, "unreadable file: {} ({error})", path.display())
+            }
+        }
+    }
+}
+
+/// Result of scrubbing a single repository
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub checked: usize,
+    pub problems: Vec<ScrubProblem>,
+}
+
+/// Verifies all content-addressed files of the repository at `repo`.
+///
+/// Problems are reported with the path of the file within the data directory.
+pub async fn scrub_repository(storage: &impl Storage, repo: &Path) -> ScrubReport {
+    let mut report = ScrubReport::default();
+
+    for tpe in SCRUB_TPES {
+        let tpe = tpe.into_str();
+
+        for entry in storage.read_dir(repo, Some(tpe)) {
+            report.checked += 1;
+
+            let path = repo.join(tpe).join(&entry.name);
+
+            if !is_sha256_hex(&entry.name) {
+                report.problems.push(ScrubProblem::Orphaned { path });
+                continue;
+            }
+
+            if entry.size == 0 {
+                report.problems.push(ScrubProblem::ZeroByte { path });
+                continue;
+            }
+
+            match sha256_of_file(storage, repo, tpe, &entry.name).await {
+                Ok(actual) if actual == entry.name => {}
+                Ok(actual) => report
+                    .problems
+                    .push(ScrubProblem::HashMismatch { path, actual }),
+                Err(err) => report.problems.push(ScrubProblem::Unreadable {
+                    path,
+                    error: err.to_string(),
+                }),
+            }
+        }
+    }
+
+    report
+}
+
+async fn sha256_of_file(
+    storage: &impl Storage,
+    repo: &Path,
+    tpe: &str,
+    name: &str,
+) -> ApiResult<String> {
+    let mut file = storage.open_file(repo, tpe, Some(name)).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .await
+            .map_err(|_| ApiErrorKind::ReadingFromStreamFailed)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_scrub() {
+        ScrubCmd::command().debug_assert();
+    }
+
+    #[tokio::test]
+    async fn test_scrub_repository_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage");
+        let storage = LocalStorage::init(&data_dir).unwrap();
+
+        assert!(storage.repositories().contains(&PathBuf::from("test_repo")));
+
+        let report = scrub_repository(&storage, Path::new("test_repo")).await;
+        assert!(report.checked >= 1);
+        assert!(report
+            .problems
+            .iter()
+            .all(|problem| !matches!(problem, ScrubProblem::HashMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_scrub_repository_finds_problems_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage");
+        let repo = Path::new("repo_scrub_me");
+        let keys = data_dir.join(repo).join("keys");
+
+        if data_dir.join(repo).exists() {
+            fs::remove_dir_all(data_dir.join(repo)).unwrap();
+        }
+        fs::create_dir_all(&keys).unwrap();
+
+        let bad_hash = "0".repeat(64);
+        let empty = "1".repeat(64);
+        fs::write(keys.join(&bad_hash), "Hello World").unwrap();
+        fs::write(keys.join(&empty), "").unwrap();
+        fs::write(keys.join("not_a_blob"), "junk").unwrap();
+
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        let report = scrub_repository(&storage, repo).await;
+
+        fs::remove_dir_all(data_dir.join(repo)).unwrap();
+
+        assert_eq!(report.checked, 3);
+        let keys = repo.join("keys");
+        assert!(report.problems.contains(&ScrubProblem::HashMismatch {
+            path: keys.join(&bad_hash),
+            actual: "a591a6d40bf420404a011733cfb7b190d62c65bf0bcda32b57b277d9ad9f146e".to_string(),
+        }));
+        assert!(report.problems.contains(&ScrubProblem::ZeroByte {
+            path: keys.join(&empty),
+        }));
+        assert!(report.problems.contains(&ScrubProblem::Orphaned {
+            path: keys.join("not_a_blob"),
+        }));
+    }
+}
@@ -1,18 +1,26 @@
 //! `serve` subcommand
 
+use std::sync::Arc;
+
 use abscissa_core::{
     config::Override,
-    status_err,
-    tracing::{debug, info},
+    tracing::{debug, warn},
     Application, Command, FrameworkError, Runnable, Shutdown,
 };
 use anyhow::Result;
 use clap::Parser;
 use conflate::Merge;
 
+#[cfg(unix)]
+use crate::log::log_level_signal_handler;
 use crate::{
-    config::RusticServerConfig, context::ServerRuntimeContext, error::AppResult,
-    prelude::RUSTIC_SERVER_APP, storage::LocalStorage, web::start_web_server,
+    application::shutdown_on_error,
+    config::{RusticServerConfig, StorageBackend},
+    context::ServerRuntimeContext,
+    error::{AppResult, ExitCode},
+    prelude::RUSTIC_SERVER_APP,
+    storage::{LocalStorage, PackedLocalStorage, Storage},
+    web::{shutdown_gracefully, start_web_server},
 };
 
 /// `serve` subcommand
@@ -27,6 +35,11 @@ pub struct ServeCmd {
     /// Server settings
     #[clap(flatten)]
     context: RusticServerConfig,
+
+    /// Print the effective configuration as TOML, with secrets redacted, and
+    /// exit without serving
+    #[arg(long)]
+    print_config: bool,
 }
 
 impl Override<RusticServerConfig> for ServeCmd {
@@ -34,8 +47,8 @@ impl Override<RusticServerConfig> for ServeCmd {
         &self,
         mut config: RusticServerConfig,
     ) -> Result<RusticServerConfig, FrameworkError> {
-        debug!(?config, "ServerConfig before merge.");
-        debug!(?self.context, "Command context from CLI.");
+        debug!(config = ?config.redacted(), "ServerConfig before merge.");
+        debug!(context = ?self.context.redacted(), "Command context from CLI.");
 
         // Merge the command-line context into the config
         // This will override the config with the command-line values,
@@ -50,14 +63,22 @@ impl Override<RusticServerConfig> for ServeCmd {
 impl Runnable for ServeCmd {
     /// Start the application.
     fn run(&self) {
+        if self.print_config {
+            match RUSTIC_SERVER_APP.config().redacted().to_toml() {
+                Ok(config) => print!("{config}"),
+                Err(err) => {
+                    shutdown_on_error(&err, ExitCode::Crash);
+                }
+            }
+            return;
+        }
+
         if let Err(tokio_err) = abscissa_tokio::run(&RUSTIC_SERVER_APP, async {
             if let Err(err) = self.inner_run().await {
-                status_err!("{}", err);
-                RUSTIC_SERVER_APP.shutdown(Shutdown::Crash);
+                shutdown_on_error(&err, ExitCode::from(&err));
             }
         }) {
-            status_err!("{}", tokio_err);
-            RUSTIC_SERVER_APP.shutdown(Shutdown::Crash);
+            shutdown_on_error(&tokio_err, ExitCode::Crash);
         };
     }
 }
@@ -66,10 +87,7 @@ impl ServeCmd {
     pub async fn inner_run(&self) -> AppResult<()> {
         let server_config = RUSTIC_SERVER_APP.config();
 
-        debug!(?server_config, "Loaded ServerConfig.");
-
-        let runtime_ctx: ServerRuntimeContext<LocalStorage> =
-            ServerRuntimeContext::from_config(server_config.clone())?;
+        debug!(server_config = ?server_config.redacted(), "Loaded ServerConfig.");
 
         _ = tokio::spawn(async move {
             // If we're running in test mode, we want to shutdown after
@@ -77,21 +95,46 @@ impl ServeCmd {
             // `CI=1` is set.
             if std::env::var("CI").is_ok() {
                 tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
-                info!("Shutting down gracefully ...");
-                RUSTIC_SERVER_APP.shutdown(Shutdown::Graceful);
+            } else {
+                tokio::signal::ctrl_c().await.unwrap();
             }
+            // The server returns once in-flight requests finished or the
+            // shutdown timeout passed
+            shutdown_gracefully();
 
+            // Don't make an impatient admin wait for the timeout
             tokio::signal::ctrl_c().await.unwrap();
-            info!("Shutting down gracefully ...");
-            RUSTIC_SERVER_APP.shutdown(Shutdown::Graceful);
+            warn!("Shutting down without waiting for in-flight requests ...");
+            RUSTIC_SERVER_APP.shutdown(Shutdown::Forced);
         });
 
-        start_web_server(runtime_ctx).await?;
-
-        Ok(())
+        // Allow raising/lowering the log level at runtime with SIGUSR1/SIGUSR2
+        #[cfg(unix)]
+        {
+            _ = tokio::spawn(async {
+                if let Err(err) = log_level_signal_handler().await {
+                    warn!("Could not install log level signal handlers: {err}");
+                }
+            });
+        }
+
+        match server_config.storage.backend.unwrap_or_default() {
+            StorageBackend::Local => serve::<LocalStorage>(server_config).await,
+            StorageBackend::PackedLocal => serve::<PackedLocalStorage>(server_config).await,
+        }
     }
 }
 
+/// Start the web server with the given storage backend
+async fn serve<S>(server_config: Arc<RusticServerConfig>) -> AppResult<()>
+where
+    S: Storage + Clone + std::fmt::Debug,
+{
+    let runtime_ctx: ServerRuntimeContext<S> = ServerRuntimeContext::from_config(server_config)?;
+
+    start_web_server(runtime_ctx).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,103 @@
+//! `stats` subcommand
+
+use std::path::PathBuf;
+
+use abscissa_core::{Command, Runnable};
+use anyhow::{bail, Result};
+use clap::{Parser, ValueEnum};
+
+use crate::{
+    application::shutdown_on_error,
+    config::StorageBackend,
+    error::ExitCode,
+    stats::RepoFiles,
+    storage::{LocalStorage, PackedLocalStorage, Storage},
+};
+
+/// Output formats of the `stats` subcommand
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+    /// Human readable summary
+    #[default]
+    Text,
+
+    /// Prometheus text format, e.g. for the textfile collector of the node exporter
+    Prometheus,
+}
+
+/// `stats` subcommand
+///
+/// Counts the files of the repositories in the data directory without
+/// starting the server, and prints their number and size per type.
+#[derive(Command, Debug, Parser)]
+pub struct StatsCmd {
+    /// Path to the data directory
+    #[arg(long = "path")]
+    data_dir: PathBuf,
+
+    /// Storage backend the data directory was written with
+    #[arg(long, value_enum, default_value = "local")]
+    backend: StorageBackend,
+
+    /// Output format
+    #[arg(long, value_enum, default_value = "text")]
+    format: StatsFormat,
+}
+
+impl Runnable for StatsCmd {
+    fn run(&self) {
+        if let Err(err) = self.inner_run() {
+            shutdown_on_error(&err, ExitCode::from(&err));
+        }
+    }
+}
+
+impl StatsCmd {
+    /// Returns whether metrics in the Prometheus text format are printed
+    pub const fn prints_metrics(&self) -> bool {
+        matches!(self.format, StatsFormat::Prometheus)
+    }
+
+    pub fn inner_run(&self) -> Result<()> {
+        if !self.data_dir.is_dir() {
+            bail!(
+                "Data directory does not exist: {}",
+                self.data_dir.to_string_lossy()
+            );
+        }
+
+        match self.backend {
+            StorageBackend::Local => self.print(&LocalStorage::init(&self.data_dir)?),
+            StorageBackend::PackedLocal => self.print(&PackedLocalStorage::init(&self.data_dir)?),
+        }
+
+        Ok(())
+    }
+
+    fn print(&self, storage: &impl Storage) {
+        let files = RepoFiles::count(storage);
+
+        match self.format {
+            StatsFormat::Prometheus => print!("{}", files.render_metrics()),
+            StatsFormat::Text => {
+                for (repo, types) in files.all() {
+                    println!("{repo}");
+                    for (tpe, count) in types {
+                        println!("\t{tpe}: {} files, {} bytes", count.files, count.bytes);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_stats() {
+        StatsCmd::command().debug_assert();
+    }
+}
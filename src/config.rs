@@ -5,15 +5,17 @@
 //! for specifying it.
 
 use std::{
+    collections::BTreeMap,
     fs::{self},
-    net::SocketAddr,
+    io::Read,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
 };
 
-use clap::{ArgAction, Args, Parser};
+use clap::{ArgAction, Args, Parser, ValueEnum};
 use conflate::Merge;
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::error::{AppResult, ErrorKind};
 
@@ -33,6 +35,10 @@ pub struct RusticServerConfig {
     #[command(flatten)]
     pub auth: HtpasswdSettings,
 
+    /// Admin endpoint settings
+    #[command(flatten)]
+    pub admin: AdminSettings,
+
     /// Acl Settings
     #[command(flatten)]
     pub acl: AclSettings,
@@ -41,9 +47,38 @@ pub struct RusticServerConfig {
     #[command(flatten)]
     pub tls: TlsSettings,
 
+    /// Background maintenance settings
+    #[command(flatten)]
+    pub maintenance: MaintenanceSettings,
+
     /// Optional Logging settings
     #[command(flatten)]
     pub log: LogSettings,
+
+    /// Repository aliases, e.g. `new-name = "old-name"`
+    ///
+    /// Requests for an alias go to the repository it stands for, e.g. to
+    /// rename a repository without moving its data.
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[merge(strategy = conflate::btreemap::append_or_overwrite)]
+    pub aliases: BTreeMap<String, String>,
+
+    /// Settings of single repositories, e.g. `[repos.team-a]`
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[merge(strategy = conflate::btreemap::append_or_overwrite)]
+    pub repos: BTreeMap<String, RepoSettings>,
+}
+
+/// Settings of a single repository
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RepoSettings {
+    /// Refuse all writes to the repository and the repositories below it,
+    /// e.g. during maintenance
+    #[serde(default, alias = "frozen")]
+    pub read_only: bool,
 }
 
 /// Overwrite the left value with the right value unconditionally.
@@ -52,23 +87,234 @@ fn overwrite_left<T>(left: &mut T, right: T) {
     *left = right;
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, Merge, Parser, Copy)]
+/// Overwrite the left value with the right value if the right value is not empty.
+fn overwrite_with_non_empty<T>(left: &mut Vec<T>, right: Vec<T>) {
+    if !right.is_empty() {
+        *left = right;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Merge, Parser)]
 #[serde(deny_unknown_fields, default, rename_all = "kebab-case")]
 pub struct ConnectionSettings {
     /// IP address and port to bind to
     #[arg(long, env = "RUSTIC_SERVER_LISTEN")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub listen: Option<SocketAddr>,
+
+    /// Optional IP address and port of the JSON-RPC management interface
+    /// (disabled by default)
+    #[arg(long, env = "RUSTIC_SERVER_MANAGEMENT_LISTEN")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub management_listen: Option<SocketAddr>,
+
+    /// Optional maximum size of the request headers in bytes (minimum: 8192)
+    ///
+    /// For HTTP/1 this is the size of the connection's read buffer, which the
+    /// request headers must fit into.
+    #[arg(long, env = "RUSTIC_SERVER_MAX_HEADER_BYTES")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub max_header_bytes: Option<usize>,
+
+    /// Optional time in seconds an idle connection may stay open before the
+    /// next request headers have to be received completely
+    #[arg(long, env = "RUSTIC_SERVER_IDLE_TIMEOUT")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub idle_timeout: Option<u64>,
+
+    /// Optional time in seconds in-flight requests may take to finish after
+    /// a shutdown was requested, before their connections are closed
+    /// (default: 30). With `0`, all connections are closed right away.
+    #[arg(long, env = "RUSTIC_SERVER_SHUTDOWN_TIMEOUT")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Optional maximum number of simultaneous connections from a single
+    /// client address. Requests on further connections are answered with
+    /// `429 Too Many Requests`.
+    #[arg(long, env = "RUSTIC_SERVER_MAX_CONNECTIONS_PER_IP")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Optional maximum number of requests handled at the same time. Further
+    /// requests are answered with `503 Service Unavailable` right away
+    /// instead of waiting.
+    #[arg(long, env = "RUSTIC_SERVER_MAX_INFLIGHT")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub max_inflight: Option<usize>,
+
+    /// Optional maximum number of ranges in the `Range` header of a download
+    /// (default: 16). Requests with more are refused with `400 Bad Request`.
+    #[arg(long, env = "RUSTIC_SERVER_MAX_RANGES")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub max_ranges: Option<usize>,
+
+    /// Compress file listings and other JSON responses with gzip or zstd,
+    /// whichever the client prefers, at this level (default: no compression)
+    ///
+    /// Lower levels use less CPU, higher ones save more bandwidth. Levels
+    /// outside the range of an algorithm are clamped to it.
+    #[arg(long, env = "RUSTIC_SERVER_COMPRESSION_LEVEL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub compression_level: Option<u8>,
+
+    /// Optional time in seconds the status of uploads and deletions with an
+    /// `Idempotency-Key` header is kept, so retries of them get the same
+    /// answer instead of being executed again
+    #[arg(long, env = "RUSTIC_SERVER_IDEMPOTENCY_WINDOW")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub idempotency_window: Option<u64>,
+
+    /// IP addresses of reverse proxies whose `X-Forwarded-Prefix` header is
+    /// stripped from the request path before routing
+    #[arg(
+        long = "trusted-proxies",
+        value_delimiter = ',',
+        env = "RUSTIC_SERVER_TRUSTED_PROXIES"
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[merge(strategy = overwrite_with_non_empty)]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Patterns of the `User-Agent` headers whose requests are served, e.g.
+    /// `restic/*`, with the wildcards `*` and `?`. Requests from other clients
+    /// are refused with `403 Forbidden`. (default: all are served)
+    #[arg(
+        long = "allowed-user-agents",
+        value_delimiter = ',',
+        env = "RUSTIC_SERVER_ALLOWED_USER_AGENTS"
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[merge(strategy = overwrite_with_non_empty)]
+    pub allowed_user_agents: Vec<String>,
+
+    /// Use the socket passed by systemd socket activation (`LISTEN_FDS`) instead
+    /// of binding to `listen`, if there is one
+    #[arg(long, env = "RUSTIC_SERVER_SYSTEMD_SOCKET")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub systemd_socket: bool,
+
+    /// Set `SO_REUSEADDR` and `SO_REUSEPORT` on the listening socket, so
+    /// several server processes can listen on the same port, e.g. a new one
+    /// while the old one drains its connections (not supported on Windows)
+    #[arg(long, env = "RUSTIC_SERVER_REUSE_PORT")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub reuse_port: bool,
+
+    /// Answer successful uploads and deletions with `204 No Content` instead of
+    /// `200 OK`. restic only accepts `200 OK`, so this is off by default.
+    #[arg(long, env = "RUSTIC_SERVER_NO_CONTENT_ON_SUCCESS")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub no_content_on_success: bool,
+
+    /// Reject uploads whose `Content-Type` is not `application/octet-stream`,
+    /// as sent by restic, with `415 Unsupported Media Type`
+    #[arg(long, env = "RUSTIC_SERVER_STRICT_CONTENT_TYPE")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub strict_content_type: bool,
+
+    /// Accept uploads of several files of a type in one multipart request to
+    /// `POST /:repo/:tpe/_batch`. This is not part of the REST API of restic.
+    #[arg(long, env = "RUSTIC_SERVER_BATCH_UPLOADS")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub batch_uploads: bool,
+
+    /// Lowest API version of file listings clients may use (default: 1)
+    ///
+    /// With `2`, clients only accepting V1 listings are refused with
+    /// `406 Not Acceptable`, so they have to use V2, which includes the sizes.
+    #[arg(long, env = "RUSTIC_SERVER_MIN_API_VERSION")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub min_api_version: Option<u8>,
+
+    /// Highest API version of file listings served (default: 2)
+    ///
+    /// With `1`, clients asking for V2 listings get V1 ones, as from servers
+    /// without V2.
+    #[arg(long, env = "RUSTIC_SERVER_MAX_API_VERSION")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub max_api_version: Option<u8>,
+
+    /// Optional text `GET /` responds with (default: server name and version)
+    #[arg(long, env = "RUSTIC_SERVER_BANNER")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub banner: Option<String>,
+
+    /// Respond to `GET /` with `403 Forbidden` instead of the banner
+    #[arg(long, env = "RUSTIC_SERVER_DENY_ROOT")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub deny_root: bool,
+
+    /// Headers added to all responses, e.g. `Strict-Transport-Security`.
+    /// They replace headers of the same name set by the server.
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[merge(strategy = conflate::btreemap::append_or_overwrite)]
+    pub response_headers: BTreeMap<String, String>,
 }
 
 impl Default for ConnectionSettings {
     fn default() -> Self {
         Self {
             listen: Some(default_socket_address()),
+            management_listen: None,
+            max_header_bytes: None,
+            idle_timeout: None,
+            shutdown_timeout: None,
+            max_connections_per_ip: None,
+            max_inflight: None,
+            max_ranges: None,
+            compression_level: None,
+            idempotency_window: None,
+            trusted_proxies: Vec::new(),
+            allowed_user_agents: Vec::new(),
+            systemd_socket: false,
+            reuse_port: false,
+            no_content_on_success: false,
+            strict_content_type: false,
+            batch_uploads: false,
+            min_api_version: None,
+            max_api_version: None,
+            banner: None,
+            deny_root: false,
+            response_headers: BTreeMap::new(),
         }
     }
 }
 
+/// Default value for `shutdown_timeout` in seconds
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
+/// Default value for `max_ranges`
+pub(crate) const DEFAULT_MAX_RANGES: usize = 16;
+
+/// Minimum value for `max_header_bytes`, as required by hyper
+pub(crate) const MIN_HEADER_BYTES: usize = 8192;
+
+/// Default value for `io_buffer_size`
+pub(crate) const DEFAULT_IO_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Minimum value for `io_buffer_size`
+pub(crate) const MIN_IO_BUFFER_SIZE: usize = 4096;
+
 pub(crate) fn default_socket_address() -> SocketAddr {
     SocketAddr::from(([127, 0, 0, 1], 8000))
 }
@@ -91,6 +337,20 @@ pub struct LogSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub log_file: Option<PathBuf>,
+
+    /// Log the summary of the effective configuration at startup as JSON
+    #[arg(long, env = "RUSTIC_SERVER_JSON_STARTUP_SUMMARY")]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub json_startup_summary: bool,
+
+    /// Optional share of requests logged in detail at the `debug` level,
+    /// between 0.0 and 1.0 (default: 1.0)
+    ///
+    /// Failed requests are always logged.
+    #[arg(long = "log-sample-rate", env = "RUSTIC_SERVER_LOG_SAMPLE_RATE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub sample_rate: Option<f64>,
 }
 
 impl LogSettings {
@@ -114,6 +374,151 @@ pub struct StorageSettings {
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub data_dir: Option<PathBuf>,
 
+    /// Optional storage backend (default: "local")
+    #[arg(long, value_enum, env = "RUSTIC_SERVER_STORAGE_BACKEND")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub backend: Option<StorageBackend>,
+
+    /// Optional list of directories (e.g. mount points) to spread repositories across
+    ///
+    /// Each repository is placed in one of these directories, selected by hashing
+    /// the repository name. If empty, all repositories are stored in the data directory.
+    ///
+    /// # Caution
+    ///
+    /// Changing this list moves repositories to other directories. Existing
+    /// repositories must then be moved manually.
+    #[arg(
+        long = "data-dirs",
+        value_delimiter = ',',
+        env = "RUSTIC_SERVER_DATA_DIRS"
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[merge(strategy = overwrite_with_non_empty)]
+    pub data_dirs: Vec<PathBuf>,
+
+    /// Optional number of nested shard directories for `data` files of new
+    /// repositories (default: 1)
+    ///
+    /// The shard layout is recorded when a repository is created, so existing
+    /// repositories keep their layout.
+    #[arg(long, env = "RUSTIC_SERVER_SHARD_DEPTH")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub shard_depth: Option<u8>,
+
+    /// Optional number of hex characters of the file name used per shard
+    /// directory for `data` files of new repositories (default: 2)
+    #[arg(long, env = "RUSTIC_SERVER_SHARD_WIDTH")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub shard_width: Option<u8>,
+
+    /// Optional maximum number of directories a repository path may consist of,
+    /// e.g. 3 for `team/project/repo` (default: unlimited)
+    #[arg(long, env = "RUSTIC_SERVER_MAX_REPO_DEPTH")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub max_repo_depth: Option<usize>,
+
+    /// Repository names that can't be used, in addition to the built-in ones
+    /// (`admin`, `metrics` and `.packs`)
+    ///
+    /// A repository path is refused if any of its directories has such a name.
+    #[arg(
+        long = "reserved-repo-names",
+        value_delimiter = ',',
+        env = "RUSTIC_SERVER_RESERVED_REPO_NAMES"
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[merge(strategy = overwrite_with_non_empty)]
+    pub reserved_repo_names: Vec<String>,
+
+    /// Optional directory to write uploads to until they are complete
+    ///
+    /// Finished uploads are moved into the data directory, which means copying
+    /// them if the directory is on another filesystem.
+    #[arg(long, env = "RUSTIC_SERVER_TEMP_DIR")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Optional directory deleted repositories are moved to, so they can be
+    /// restored until they are purged (disabled by default)
+    ///
+    /// Repositories are moved by renaming them, so the directory must be on
+    /// the same filesystem as the data directories.
+    #[arg(long, env = "RUSTIC_SERVER_TRASH_DIR")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub trash_dir: Option<PathBuf>,
+
+    /// Sync the directory after a file was added to or removed from it, so the
+    /// change survives a crash or power loss (only supported on Unix)
+    #[arg(long, env = "RUSTIC_SERVER_FSYNC_DIRS")]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub fsync_dirs: bool,
+
+    /// How the size of repositories is computed (default: logical)
+    #[arg(long, value_enum, env = "RUSTIC_SERVER_SIZE_ACCOUNTING")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub size_accounting: Option<SizeAccounting>,
+
+    /// Reject empty uploads of all files except the `config` with
+    /// `400 Bad Request` (default: true)
+    ///
+    /// All other files are named after the hash of their encrypted content,
+    /// which is never empty, so an empty upload is a bug of the client.
+    #[arg(long, env = "RUSTIC_SERVER_REJECT_EMPTY_UPLOADS", value_name = "BOOL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub reject_empty_uploads: Option<bool>,
+
+    /// Number of previous versions of each repository config to keep as
+    /// `config.bak.<n>` when it is deleted (default: 0)
+    #[arg(long, env = "RUSTIC_SERVER_CONFIG_HISTORY")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub config_history: Option<usize>,
+
+    /// Reject uploads of a repository `config` which can't be one with
+    /// `400 Bad Request`
+    ///
+    /// Unencrypted configs must be JSON with a supported `version`, an `id`
+    /// and a `chunker_polynomial`. As restic encrypts the config, only the
+    /// size of encrypted ones is checked.
+    #[arg(long, env = "RUSTIC_SERVER_VALIDATE_CONFIG")]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub validate_config: bool,
+
+    /// Reject uploads to repositories which weren't created with
+    /// `POST /<repo>/?create=true` with `404 Not Found`
+    ///
+    /// This keeps typos in repository names from silently creating new
+    /// repositories.
+    #[arg(long, env = "RUSTIC_SERVER_REQUIRE_EXPLICIT_CREATE")]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub require_explicit_create: bool,
+
+    /// Record who uploaded each file and when, in a sidecar file below
+    /// `<repo>/.provenance`, served by `GET /<repo>/<type>/<name>/meta`
+    #[arg(long, env = "RUSTIC_SERVER_RECORD_PROVENANCE")]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub record_provenance: bool,
+
+    /// Optional size in bytes of the buffer between the network and the disk
+    /// when uploading and downloading files (default: 65536, minimum: 4096)
+    ///
+    /// At most this much of a file is held in memory per transfer, and the
+    /// faster side waits for the slower one once it is full. Larger buffers
+    /// mean fewer, larger writes to the disk.
+    #[arg(long, env = "RUSTIC_SERVER_IO_BUFFER_SIZE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub io_buffer_size: Option<usize>,
+
     /// Optional maximum size (quota) of a repository in bytes
     #[arg(long = "max-size", env = "RUSTIC_SERVER_QUOTA")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -121,6 +526,70 @@ pub struct StorageSettings {
     pub quota: Option<usize>,
 }
 
+/// Storage backends
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    /// Store every file on its own
+    #[default]
+    Local,
+
+    /// Pack small files into larger pack files, to save inodes
+    PackedLocal,
+}
+
+impl StorageBackend {
+    /// Returns the name of the backend, as used in the configuration
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::PackedLocal => "packed-local",
+        }
+    }
+}
+
+/// How the size of a file is accounted for
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SizeAccounting {
+    /// The length of the file, as seen by clients
+    #[default]
+    Logical,
+
+    /// The disk space allocated for the file (only supported on Unix)
+    ///
+    /// This is smaller than the logical size for sparse files and on
+    /// filesystems with compression, e.g. ZFS or btrfs.
+    Allocated,
+}
+
+/// Whose ACL applies to requests for a repository alias
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AliasAcl {
+    /// The ACL of the repository the alias stands for
+    #[default]
+    Target,
+
+    /// The ACL of the alias itself
+    Alias,
+}
+
+/// How users in the ACL without credentials are handled at startup
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AclUserCheck {
+    /// Don't check the users
+    #[default]
+    Off,
+
+    /// Log a warning for each user without credentials
+    Warn,
+
+    /// Refuse to start if any user has no credentials
+    Strict,
+}
+
 pub(crate) fn default_data_dir() -> PathBuf {
     std::env::temp_dir().join("rustic")
 }
@@ -129,6 +598,22 @@ impl Default for StorageSettings {
     fn default() -> Self {
         Self {
             data_dir: Some(default_data_dir()),
+            backend: None,
+            data_dirs: Vec::new(),
+            shard_depth: None,
+            shard_width: None,
+            max_repo_depth: None,
+            reserved_repo_names: Vec::new(),
+            temp_dir: None,
+            trash_dir: None,
+            fsync_dirs: false,
+            size_accounting: None,
+            reject_empty_uploads: None,
+            config_history: None,
+            validate_config: false,
+            require_explicit_create: false,
+            record_provenance: false,
+            io_buffer_size: None,
             quota: None,
         }
     }
@@ -145,13 +630,14 @@ pub struct TlsSettings {
     /// Disable TLS support
     // This is a bit of a hack to allow us to set the default value to false
     // and disable TLS support by default.
+    //
+    // Key and certificate may also come from the config file, so they are
+    // validated after merging, see `ServerRuntimeContext::check_tls_settings`.
     #[arg(
         long = "tls",
         action=ArgAction::SetFalse,
         default_value = "true",
         help = "Enable TLS support",
-        requires = "tls_key",
-        requires = "tls_cert",
         env = "RUSTIC_SERVER_DISABLE_TLS"
     )]
     #[serde(default = "default_true")]
@@ -159,14 +645,108 @@ pub struct TlsSettings {
     pub disable_tls: bool,
 
     /// Optional path to the TLS key file
-    #[arg(long, requires = "disable_tls", env = "RUSTIC_SERVER_TLS_KEY")]
+    #[arg(long, env = "RUSTIC_SERVER_TLS_KEY")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub tls_key: Option<PathBuf>,
 
     /// Optional path to the TLS certificate file
-    #[arg(long, requires = "disable_tls", env = "RUSTIC_SERVER_TLS_CERT")]
+    #[arg(long, env = "RUSTIC_SERVER_TLS_CERT")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub tls_cert: Option<PathBuf>,
+
+    /// Optional format of the TLS key and certificate files
+    ///
+    /// If not set, it is detected by the extension of each file: `.der` and
+    /// `.cer` files are read as DER, all others as PEM.
+    #[arg(long, value_enum, env = "RUSTIC_SERVER_TLS_FORMAT")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub tls_format: Option<TlsFormat>,
+
+    /// Optional path to a PKCS#12 bundle (`.p12`/`.pfx`) with the TLS
+    /// certificate chain and key, used instead of `tls-cert` and `tls-key`
+    #[arg(long, env = "RUSTIC_SERVER_TLS_PKCS12")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub tls_pkcs12: Option<PathBuf>,
+
+    /// Optional password of the PKCS#12 bundle
+    #[arg(
+        long,
+        env = "RUSTIC_SERVER_TLS_PKCS12_PASSWORD",
+        hide_env_values = true
+    )]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub tls_pkcs12_password: Option<String>,
+
+    /// Optional path to a DER encoded OCSP response for the default
+    /// certificate, which is stapled to the TLS handshake
+    ///
+    /// The file is not fetched by the server, but has to be renewed by an
+    /// external tool, e.g. `openssl ocsp`, before the response expires.
+    #[arg(long, env = "RUSTIC_SERVER_OCSP_RESPONSE")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ocsp_response: Option<PathBuf>,
+
+    /// Optional time between two reloads of the OCSP response, e.g. `1h`
+    /// (units: s, m, h, d, w; default: loaded once at startup)
+    #[arg(long, env = "RUSTIC_SERVER_OCSP_REFRESH_INTERVAL")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub ocsp_refresh_interval: Option<String>,
+
+    /// Refuse to start with authentication enabled unless TLS is enabled
+    ///
+    /// Basic authentication sends credentials in cleartext, so they must not
+    /// go over a plaintext connection.
+    #[arg(long, env = "RUSTIC_SERVER_REQUIRE_TLS")]
+    #[serde(default)]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub require_tls: bool,
+
+    /// Additional certificates, selected by the host name the client asks for
+    /// (SNI)
+    ///
+    /// Clients asking for another host name, or none, get the certificate of
+    /// `tls-cert`. Only available in the config file.
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[merge(strategy = overwrite_with_non_empty)]
+    pub certificates: Vec<SniCertificate>,
+}
+
+/// Formats of TLS key and certificate files
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsFormat {
+    /// Base64 encoded, between `-----BEGIN ...-----` lines
+    Pem,
+
+    /// Binary encoded, a single certificate or key per file
+    Der,
+}
+
+impl TlsFormat {
+    /// Returns the format of the file at `path`, as told by its extension
+    pub fn detect(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("der") || ext.eq_ignore_ascii_case("cer") => {
+                Self::Der
+            }
+            _ => Self::Pem,
+        }
+    }
+}
+
+/// A TLS certificate served to clients asking for a certain host name
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SniCertificate {
+    /// Host name the certificate is served for, e.g. `backup.example.com`
+    pub hostname: String,
+
+    /// Path to the TLS certificate file
+    pub cert: PathBuf,
+
+    /// Path to the TLS key file
+    pub key: PathBuf,
 }
 
 impl TlsSettings {
@@ -181,6 +761,13 @@ impl Default for TlsSettings {
             disable_tls: true,
             tls_cert: None,
             tls_key: None,
+            tls_format: None,
+            tls_pkcs12: None,
+            tls_pkcs12_password: None,
+            ocsp_response: None,
+            ocsp_refresh_interval: None,
+            require_tls: false,
+            certificates: Vec::new(),
         }
     }
 }
@@ -200,6 +787,41 @@ pub struct HtpasswdSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub htpasswd_file: Option<PathBuf>,
+
+    /// Optional realm sent in the `WWW-Authenticate` header (default: "rustic")
+    #[arg(long, env = "RUSTIC_SERVER_AUTH_REALM")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub realm: Option<String>,
+
+    /// Optional list of users allowed to use the admin endpoints
+    #[arg(
+        long = "admin-users",
+        value_delimiter = ',',
+        env = "RUSTIC_SERVER_ADMIN_USERS"
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[merge(strategy = overwrite_with_non_empty)]
+    pub admin_users: Vec<String>,
+
+    /// Optional user name all requests are treated as if authentication is
+    /// disabled, e.g. for the ACL (default: "")
+    ///
+    /// With authentication, reads without credentials are made by this user,
+    /// who may only read repositories with an ACL entry for exactly this name.
+    #[arg(long, env = "RUSTIC_SERVER_ANONYMOUS_USER")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub anonymous_user: Option<String>,
+
+    /// Optional time in seconds a verified password is accepted again without
+    /// checking its hash (at most 300, default: always checked)
+    ///
+    /// Changed or removed credentials are never accepted from the cache.
+    #[arg(long, env = "RUSTIC_SERVER_AUTH_CACHE_TTL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub auth_cache_ttl: Option<u64>,
 }
 
 impl HtpasswdSettings {
@@ -242,6 +864,66 @@ impl HtpasswdSettings {
     }
 }
 
+/// Credentials of the admin endpoints
+///
+/// If no htpasswd file is configured, the admin endpoints authenticate
+/// against the regular htpasswd file and only allow the `admin-users`.
+#[derive(Clone, Serialize, Deserialize, Debug, Merge, Default, Parser)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case", default)]
+#[group(id = "admin")]
+pub struct AdminSettings {
+    /// Optional location of a separate .htpasswd file for the admin endpoints
+    ///
+    /// All users in this file are admins, and no user of the regular
+    /// htpasswd file can use the admin endpoints.
+    #[arg(
+        id = "admin_htpasswd_file",
+        long = "admin-htpasswd-file",
+        env = "RUSTIC_SERVER_ADMIN_HTPASSWD_FILE"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub htpasswd_file: Option<PathBuf>,
+
+    /// Optional realm sent in the `WWW-Authenticate` header of the admin
+    /// endpoints (default: "rustic-admin")
+    #[arg(
+        id = "admin_realm",
+        long = "admin-realm",
+        env = "RUSTIC_SERVER_ADMIN_REALM"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub realm: Option<String>,
+}
+
+/// Background maintenance of the repositories
+#[derive(Clone, Serialize, Deserialize, Debug, Merge, Default, Parser)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case", default)]
+#[group(id = "maintenance")]
+pub struct MaintenanceSettings {
+    /// Optional age after which locks are removed in the background, e.g. `1h`
+    /// (units: s, m, h, d, w; disabled by default)
+    #[arg(long, env = "RUSTIC_SERVER_LOCK_MAX_AGE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub lock_max_age: Option<String>,
+
+    /// Optional time between two searches for stale locks, e.g. `10m`
+    /// (default: 5m)
+    #[arg(long, env = "RUSTIC_SERVER_LOCK_SWEEP_INTERVAL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub lock_sweep_interval: Option<String>,
+
+    /// Optional time deleted repositories are kept in the trash directory,
+    /// e.g. `30d` (units: s, m, h, d, w; default: 7d)
+    #[arg(long, env = "RUSTIC_SERVER_TRASH_RETENTION")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub trash_retention: Option<String>,
+}
+
 // This assumes that it makes no sense to have one but not the other
 // So we if acl_path is given, we require the auth_path too.
 #[derive(Clone, Serialize, Deserialize, Debug, Merge, Parser)]
@@ -265,16 +947,43 @@ pub struct AclSettings {
     #[merge(strategy = conflate::bool::overwrite_false)]
     pub append_only: bool,
 
+    /// Store the repositories of each user below a directory named after the
+    /// user, i.e. `<data directory>/<user>/<repo>`
+    #[arg(long, env = "RUSTIC_SERVER_NAMESPACE_BY_USER")]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub namespace_by_user: bool,
+
     /// Full path including file name to read from. Governs per-repo ACLs.
     /// (default: "<data directory>/acl.toml")
     #[arg(long, requires = "private_repos", env = "RUSTIC_SERVER_ACL_PATH")]
     #[serde(skip_serializing_if = "Option::is_none")]
     #[merge(strategy = conflate::option::overwrite_with_some)]
     pub acl_path: Option<PathBuf>,
+
+    /// Whose ACL applies to requests for a repository alias (default: target)
+    #[arg(long, value_enum, env = "RUSTIC_SERVER_ALIAS_ACL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub alias_acl: Option<AliasAcl>,
+
+    /// Check at startup that all users in the ACL file have credentials in the
+    /// htpasswd file (default: off)
+    #[arg(long, value_enum, env = "RUSTIC_SERVER_CHECK_ACL_USERS")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[merge(strategy = conflate::option::overwrite_with_some)]
+    pub check_acl_users: Option<AclUserCheck>,
+
+    /// Start with the default ACL if the ACL file is missing, instead of
+    /// refusing to start
+    #[arg(long = "allow-missing-acl", env = "RUSTIC_SERVER_ALLOW_MISSING_ACL")]
+    #[merge(strategy = conflate::bool::overwrite_false)]
+    pub allow_missing: bool,
 }
 
 impl AclSettings {
-    pub fn acl_file_or_default(&self, data_dir: PathBuf) -> AppResult<PathBuf> {
+    /// Returns the path of the ACL file, or `None` if it is missing and
+    /// `allow_missing` is set
+    pub fn acl_file_or_default(&self, data_dir: PathBuf) -> AppResult<Option<PathBuf>> {
         let default_file_name = "acl.toml";
         let path = self.acl_path.clone().unwrap_or_else(|| {
             let mut path = data_dir;
@@ -283,6 +992,14 @@ impl AclSettings {
             path
         });
 
+        if self.allow_missing && !path.exists() {
+            warn!(
+                "No ACL file found at `{}`, using the default ACL until it is added and the server is restarted.",
+                path.display()
+            );
+            return Ok(None);
+        }
+
         if path
             .canonicalize()
             .map_err(|err| {
@@ -293,7 +1010,7 @@ impl AclSettings {
             })?
             .exists()
         {
-            Ok(path)
+            Ok(Some(path))
         } else {
             Err(ErrorKind::Io
                 .context(format!(
@@ -315,7 +1032,74 @@ impl Default for AclSettings {
             private_repos: true,
             disable_acl: false,
             append_only: true,
+            namespace_by_user: false,
             acl_path: None,
+            alias_acl: None,
+            check_acl_users: None,
+            allow_missing: false,
+        }
+    }
+}
+
+/// Where the configuration is read from, as given with `--config`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// A TOML file
+    File(PathBuf),
+
+    /// Standard input, given as `-`
+    Stdin,
+
+    /// An environment variable, given as `env:<name>`
+    Env(String),
+
+    /// A URL, which isn't supported
+    Url(String),
+}
+
+impl ConfigSource {
+    pub fn parse(source: &str) -> Self {
+        if source == "-" {
+            Self::Stdin
+        } else if let Some(name) = source.strip_prefix("env:") {
+            Self::Env(name.to_string())
+        } else if source.starts_with("http://") || source.starts_with("https://") {
+            Self::Url(source.to_string())
+        } else {
+            Self::File(PathBuf::from(source))
+        }
+    }
+
+    /// Returns whether the configuration is read from a file
+    pub const fn is_file(&self) -> bool {
+        matches!(self, Self::File(_))
+    }
+
+    /// Reads the TOML configuration from stdin or the environment variable
+    ///
+    /// Files are loaded by abscissa, so they aren't read here.
+    pub fn read(&self) -> AppResult<String> {
+        match self {
+            Self::File(path) => Ok(fs::read_to_string(path)?),
+            Self::Stdin => {
+                let mut config = String::new();
+                let _ = std::io::stdin().read_to_string(&mut config).map_err(|err| {
+                    ErrorKind::Io.context(format!("Could not read configuration from stdin: `{err}`"))
+                })?;
+                Ok(config)
+            }
+            Self::Env(name) => std::env::var(name).map_err(|err| {
+                ErrorKind::Config
+                    .context(format!(
+                        "Could not read configuration from environment variable `{name}`: `{err}`"
+                    ))
+                    .into()
+            }),
+            Self::Url(url) => Err(ErrorKind::Config
+                .context(format!(
+                    "Reading the configuration from a URL is not supported. Pipe it to stdin instead, e.g. `curl -sf {url} | rustic-server --config - serve`"
+                ))
+                .into()),
         }
     }
 }
@@ -347,8 +1131,51 @@ impl RusticServerConfig {
 
         Ok(())
     }
+
+    /// Returns the configuration with secrets replaced by [`REDACTED`], so it
+    /// can be shown
+    ///
+    /// Passwords and keys are only referenced by their paths, so the only
+    /// secrets are the values of credential headers in `response-headers`.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        for (name, value) in &mut config.server.response_headers {
+            if SECRET_HEADERS
+                .iter()
+                .any(|secret| name.eq_ignore_ascii_case(secret))
+            {
+                *value = REDACTED.to_string();
+            }
+        }
+        if config.tls.tls_pkcs12_password.is_some() {
+            config.tls.tls_pkcs12_password = Some(REDACTED.to_string());
+        }
+        config
+    }
+
+    /// Serializes the configuration to TOML, as in a config file
+    pub fn to_toml(&self) -> AppResult<String> {
+        toml::to_string_pretty(self).map_err(|err| {
+            ErrorKind::Config
+                .context(format!(
+                    "Could not serialize configuration to toml due to {err}"
+                ))
+                .into()
+        })
+    }
 }
 
+/// Replacement of secrets in [`RusticServerConfig::redacted`]
+pub const REDACTED: &str = "<redacted>";
+
+/// Headers whose values are credentials
+const SECRET_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
 #[cfg(test)]
 mod test {
     use std::path::{Path, PathBuf};
@@ -357,7 +1184,7 @@ mod test {
     use insta::{assert_debug_snapshot, assert_toml_snapshot};
     use rstest::{fixture, rstest};
 
-    use crate::config::RusticServerConfig;
+    use crate::config::{ConfigSource, RusticServerConfig};
 
     #[fixture]
     fn rustic_server_config() -> PathBuf {
@@ -388,6 +1215,35 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_config_source_passes() -> Result<()> {
+        assert_eq!(ConfigSource::parse("-"), ConfigSource::Stdin);
+        assert_eq!(
+            ConfigSource::parse("env:CONFIG"),
+            ConfigSource::Env("CONFIG".to_string())
+        );
+        assert_eq!(
+            ConfigSource::parse("rustic_server.toml"),
+            ConfigSource::File(PathBuf::from("rustic_server.toml"))
+        );
+        assert!(matches!(
+            ConfigSource::parse("https://example.com/rustic_server.toml"),
+            ConfigSource::Url(_)
+        ));
+
+        std::env::set_var("RUSTIC_SERVER_TEST_CONFIG_SOURCE", "[server]");
+        let config = ConfigSource::Env("RUSTIC_SERVER_TEST_CONFIG_SOURCE".to_string()).read()?;
+        assert_eq!(config, "[server]");
+
+        assert!(
+            ConfigSource::Env("RUSTIC_SERVER_TEST_CONFIG_MISSING".to_string())
+                .read()
+                .is_err()
+        );
+        assert!(ConfigSource::parse("https://example.com").read().is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_optional_explicit_parse_config_passes() -> Result<()> {
         let toml_string = r#"
@@ -0,0 +1,247 @@
+//! Limit of simultaneous connections per client address
+//!
+//! A single host opening hundreds of connections can starve all other
+//! clients, whether it is authenticated or not. If `max-connections-per-ip` is
+//! configured, the connections of each client address are counted when they
+//! are accepted. Requests on connections beyond the limit are answered with
+//! `429 Too Many Requests`, and the connection is closed.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::future::{BoxFuture, FutureExt};
+use tower::Service;
+
+use crate::proxy::canonical_ip;
+
+/// Active connections of all client addresses
+#[derive(Debug)]
+pub struct PeerConnections {
+    max: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PeerConnections {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            active: Mutex::default(),
+        })
+    }
+
+    /// Registers a connection from `ip`, unless it has `max` connections
+    /// open already
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<PeerConnection> {
+        let ip = canonical_ip(ip);
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = active.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+
+        *count += 1;
+        Some(PeerConnection {
+            connections: self.clone(),
+            ip,
+        })
+    }
+
+    /// Returns the number of open connections from `ip`
+    pub fn count(&self, ip: IpAddr) -> usize {
+        self.active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&canonical_ip(ip))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// A registered connection, which is released when dropped
+#[derive(Debug)]
+pub struct PeerConnection {
+    connections: Arc<PeerConnections>,
+    ip: IpAddr,
+}
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        let mut active = self
+            .connections
+            .active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                let _ = active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Service making the services of accepted connections, limiting the
+/// connections per client address if `max` is given
+#[derive(Debug, Clone)]
+pub struct LimitPeerConnections<M> {
+    inner: M,
+    connections: Option<Arc<PeerConnections>>,
+}
+
+impl<M> LimitPeerConnections<M> {
+    pub fn new(inner: M, max: Option<usize>) -> Self {
+        Self {
+            inner,
+            connections: max.map(PeerConnections::new),
+        }
+    }
+}
+
+impl<M, S> Service<SocketAddr> for LimitPeerConnections<M>
+where
+    M: Service<SocketAddr, Response = S, Error = Infallible>,
+    M::Future: Send + 'static,
+{
+    type Response = PeerLimited<S>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, peer: SocketAddr) -> Self::Future {
+        let (connection, rejected) = match &self.connections {
+            Some(connections) => {
+                let connection = connections.acquire(peer.ip());
+                if connection.is_none() {
+                    tracing::warn!(client = %peer.ip(), "Too many connections from client.");
+                }
+                let rejected = connection.is_none();
+                (connection.map(Arc::new), rejected)
+            }
+            None => (None, false),
+        };
+
+        self.inner
+            .call(peer)
+            .map(move |inner| {
+                inner.map(|inner| PeerLimited {
+                    inner,
+                    rejected,
+                    _connection: connection,
+                })
+            })
+            .boxed()
+    }
+}
+
+/// Service of a connection, which is rejected if the client has too many
+/// connections open
+///
+/// The service is cloned for every request, so the connection stays
+/// registered until the connection and all its requests are done.
+#[derive(Debug, Clone)]
+pub struct PeerLimited<S> {
+    inner: S,
+    rejected: bool,
+    _connection: Option<Arc<PeerConnection>>,
+}
+
+impl<S, B> Service<Request<B>> for PeerLimited<S>
+where
+    S: Service<Request<B>, Response = Response, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        if self.rejected {
+            let res = (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::CONNECTION, "close")],
+                "too many connections",
+            )
+                .into_response();
+            return async move { Ok(res) }.boxed();
+        }
+
+        self.inner.call(req).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use axum::{
+        body::Body,
+        extract::{ConnectInfo, Request},
+        http::StatusCode,
+        routing::get,
+        Router,
+    };
+    use tower::{Service, ServiceExt};
+
+    use crate::connections::LimitPeerConnections;
+
+    #[tokio::test]
+    async fn test_limit_peer_connections_passes() {
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.to_string() }),
+        );
+        let mut make_service = LimitPeerConnections::new(
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+            Some(2),
+        );
+
+        let client: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let other: SocketAddr = "192.0.2.2:1234".parse().unwrap();
+        // IPv4 clients of dual-stack sockets are the same client
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:1234".parse().unwrap();
+
+        let status = |service: &super::PeerLimited<_>| {
+            let service = service.clone();
+            async move {
+                let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+                service.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        let first = make_service.call(client).await.unwrap();
+        let second = make_service.call(mapped).await.unwrap();
+        let third = make_service.call(client).await.unwrap();
+        let unrelated = make_service.call(other).await.unwrap();
+
+        assert_eq!(status(&first).await, StatusCode::OK);
+        assert_eq!(status(&second).await, StatusCode::OK);
+        assert_eq!(status(&third).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(&unrelated).await, StatusCode::OK);
+        let connections = make_service.connections.clone().unwrap();
+        assert_eq!(connections.count(client.ip()), 2);
+
+        // closed connections make room for new ones
+        drop(first);
+        drop(third);
+        let fourth = make_service.call(client).await.unwrap();
+        assert_eq!(status(&fourth).await, StatusCode::OK);
+        assert_eq!(connections.count(client.ip()), 2);
+    }
+}
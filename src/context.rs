@@ -1,33 +1,107 @@
 use std::{
-    fs::create_dir_all,
-    net::SocketAddr,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::{self, Display, Formatter},
+    fs::{self, create_dir_all},
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use abscissa_core::prelude::{debug, info};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use rustls::pki_types::DnsName;
 use serde::{Deserialize, Serialize};
 use tracing::warn;
 
 use crate::{
     acl::Acl,
-    auth::Auth,
+    auth::{Auth, MAX_AUTH_CACHE_TTL},
+    commands::parse_age,
     config::{
-        default_data_dir, default_socket_address, AclSettings, HtpasswdSettings, LogSettings,
-        RusticServerConfig, TlsSettings,
+        default_data_dir, default_socket_address, AclSettings, AclUserCheck, AdminSettings,
+        ConnectionSettings, HtpasswdSettings, LogSettings, MaintenanceSettings, RepoSettings,
+        RusticServerConfig, SniCertificate, StorageBackend, StorageSettings, TlsFormat,
+        TlsSettings, DEFAULT_IO_BUFFER_SIZE, DEFAULT_MAX_RANGES, DEFAULT_SHUTDOWN_TIMEOUT,
+        MIN_HEADER_BYTES, MIN_IO_BUFFER_SIZE, REDACTED,
     },
-    error::{AppResult, ErrorKind},
-    storage::Storage,
+    error::{is_cross_device, AppResult, ErrorKind},
+    handlers::{
+        access_check::check_repo_path, files_list::SUPPORTED_API_VERSIONS, root::RootResponse,
+    },
+    maintenance::{LockSweeper, TrashPurger},
+    storage::{Storage, MAX_SHARD_CHARS},
 };
 
-#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+/// Time between two searches for stale locks if none is configured
+const DEFAULT_LOCK_SWEEP_INTERVAL: &str = "5m";
+
+/// Time deleted repositories are kept in the trash if none is configured
+const DEFAULT_TRASH_RETENTION: &str = "7d";
+
+/// Time between two purges of the trash directory
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub struct TlsOptions {
-    /// Optional path to the TLS key file
-    pub tls_key: PathBuf,
+    /// Default certificate and its key
+    pub identity: TlsIdentity,
+
+    /// Additional certificates selected by SNI
+    #[serde(default)]
+    pub certificates: Vec<SniCertificate>,
+
+    /// OCSP response stapled to the default certificate
+    #[serde(default)]
+    pub ocsp: Option<OcspStapling>,
+}
+
+/// OCSP response stapled to the TLS handshake
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OcspStapling {
+    /// DER encoded OCSP response
+    pub response: PathBuf,
+
+    /// Time between two reloads of the response, loaded only once if not set
+    #[serde(default)]
+    pub refresh_interval: Option<Duration>,
+}
 
-    /// Optional path to the TLS certificate file
-    pub tls_cert: PathBuf,
+/// Source of the default TLS certificate and its key
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsIdentity {
+    /// Certificate chain and key in separate files
+    Files {
+        cert: PathBuf,
+        key: PathBuf,
+        /// Format of both files, detected by their extensions if not set
+        format: Option<TlsFormat>,
+    },
+
+    /// PKCS#12 bundle holding the certificate chain and the key
+    Pkcs12 { path: PathBuf, password: String },
+}
+
+impl fmt::Debug for TlsIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Files { cert, key, format } => f
+                .debug_struct("Files")
+                .field("cert", cert)
+                .field("key", key)
+                .field("format", format)
+                .finish(),
+            Self::Pkcs12 { path, .. } => f
+                .debug_struct("Pkcs12")
+                .field("path", path)
+                .field("password", &REDACTED)
+                .finish_non_exhaustive(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -37,10 +111,118 @@ where
 {
     pub(crate) acl: Acl,
     pub(crate) auth: Auth,
+    pub(crate) admin_auth: Option<Auth>,
     pub(crate) _quota: usize,
     pub(crate) socket_address: SocketAddr,
+    pub(crate) management_address: Option<SocketAddr>,
     pub(crate) storage: S,
     pub(crate) tls: Option<TlsOptions>,
+    pub(crate) limits: ConnectionLimits,
+    pub(crate) trusted_proxies: Vec<IpAddr>,
+    pub(crate) allowed_user_agents: Vec<String>,
+    pub(crate) systemd_socket: bool,
+    pub(crate) reuse_port: bool,
+    pub(crate) no_content_on_success: bool,
+    pub(crate) reject_empty_uploads: bool,
+    pub(crate) config_history: usize,
+    pub(crate) validate_config: bool,
+    pub(crate) require_explicit_create: bool,
+    pub(crate) record_provenance: bool,
+    pub(crate) io_buffer_size: usize,
+    pub(crate) strict_content_type: bool,
+    pub(crate) batch_uploads: bool,
+    pub(crate) api_versions: RangeInclusive<u8>,
+    pub(crate) response_headers: HeaderMap,
+    pub(crate) compression_level: Option<u8>,
+    pub(crate) idempotency_window: Option<Duration>,
+    pub(crate) root_response: RootResponse,
+    pub(crate) lock_sweeper: Option<LockSweeper>,
+    pub(crate) trash_purger: Option<TrashPurger>,
+    pub(crate) log_sample_rate: f64,
+    /// Effective configuration, with secrets redacted
+    pub(crate) effective_config: RusticServerConfig,
+}
+
+/// Limits applied to every client connection
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionLimits {
+    /// Maximum size of the request headers in bytes
+    pub max_header_bytes: Option<usize>,
+
+    /// Time an idle connection may stay open before the next request headers
+    /// have to be received completely
+    pub idle_timeout: Option<Duration>,
+
+    /// Time in-flight requests may take to finish on shutdown, before their
+    /// connections are closed
+    pub shutdown_timeout: Duration,
+
+    /// Maximum number of simultaneous connections from a single client address
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Maximum number of requests handled at the same time
+    pub max_inflight: Option<usize>,
+
+    /// Maximum number of ranges in the `Range` header of a download
+    pub max_ranges: usize,
+}
+
+/// Effective configuration after merging the config file and command line,
+/// logged once at startup
+#[derive(Clone, Debug, Serialize)]
+pub struct StartupSummary {
+    pub data_dir: PathBuf,
+    pub data_dirs: Vec<PathBuf>,
+    pub backend: StorageBackend,
+    pub listen: SocketAddr,
+    pub management_listen: Option<SocketAddr>,
+    pub systemd_socket: bool,
+    pub tls: bool,
+    pub auth: bool,
+    pub acl: bool,
+    pub append_only: bool,
+    pub namespace_by_user: bool,
+    pub quota: Option<usize>,
+}
+
+impl StartupSummary {
+    /// Logs the summary as a single line, as JSON if `json` is set
+    pub fn log(&self, json: bool) {
+        if json {
+            match serde_json::to_string(self) {
+                Ok(summary) => info!("Startup summary: {summary}"),
+                Err(err) => warn!("Could not serialize startup summary: {err}"),
+            }
+        } else {
+            info!("Startup summary: {self}");
+        }
+    }
+}
+
+impl Display for StartupSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let enabled = |on: bool| if on { "enabled" } else { "disabled" };
+
+        write!(f, "data-dir=`{}`", self.data_dir.display())?;
+        for dir in &self.data_dirs {
+            write!(f, " data-dir=`{}`", dir.display())?;
+        }
+        write!(
+            f,
+            " backend={} listen={} management-listen={} systemd-socket={} tls={} auth={} acl={} append-only={} namespace-by-user={} quota={}",
+            self.backend.name(),
+            self.listen,
+            self.management_listen
+                .map_or_else(|| "none".to_string(), |address| address.to_string()),
+            self.systemd_socket,
+            enabled(self.tls),
+            enabled(self.auth),
+            enabled(self.acl),
+            self.append_only,
+            self.namespace_by_user,
+            self.quota.map_or_else(|| "none".to_string(), |quota| quota.to_string()),
+        )
+    }
 }
 
 impl<S> ServerRuntimeContext<S>
@@ -48,6 +230,12 @@ where
     S: Storage + Clone + std::fmt::Debug,
 {
     pub fn from_config(config: Arc<RusticServerConfig>) -> AppResult<Self> {
+        Self::check_tls_required(&config.tls, &config.auth)?;
+
+        Self::check_tls_settings(&config.tls)?;
+
+        Self::check_namespace_by_user(&config.acl, &config.auth)?;
+
         let storage_dir = Self::data_dir(
             config
                 .storage
@@ -61,30 +249,340 @@ where
 
         let quota = Self::quota(config.storage.quota);
 
-        let acl = Self::acl(config.acl.clone(), storage_dir.clone())?;
+        let limits = Self::limits(&config.server)?;
+
+        let response_headers = Self::response_headers(&config.server)?;
+
+        let acl = Self::acl(config.acl.clone(), storage_dir.clone())?
+            .set_aliases(
+                Self::aliases(&config.aliases)?,
+                config.acl.alias_acl.unwrap_or_default(),
+            )
+            .set_read_only_repos(Self::read_only_repos(&config.repos)?);
 
         let auth = Self::auth(config.auth.clone(), storage_dir.clone())?;
 
+        let acl = acl.set_anonymous_user(Self::anonymous_user(&config.auth, &auth));
+
+        Self::check_acl_users(&acl, &auth, &config.acl)?;
+
+        let admin_auth = Self::admin_auth(&config.admin)?;
+
+        let lock_sweeper = Self::lock_sweeper(&config.maintenance)?;
+
+        let trash_purger = Self::trash_purger(&config.storage, &config.maintenance)?;
+
+        let log_sample_rate = Self::log_sample_rate(&config.log)?;
+
+        let api_versions = Self::api_versions(&config.server)?;
+
         let tls = Self::tls(config.tls.clone())?;
 
-        let storage = Self::storage(storage_dir)?;
+        let storage = Self::storage(storage_dir, &config.storage)?;
+
+        let io_buffer_size = Self::io_buffer_size(&config.storage)?;
+
+        let trusted_proxies = config.server.trusted_proxies.clone();
+        if !trusted_proxies.is_empty() {
+            info!(
+                ?trusted_proxies,
+                "Trusting `X-Forwarded-Prefix` from proxies."
+            );
+        }
+
+        let allowed_user_agents = config.server.allowed_user_agents.clone();
+        if !allowed_user_agents.is_empty() {
+            info!(
+                ?allowed_user_agents,
+                "Only serving requests from allowed user agents."
+            );
+        }
+
+        let summary = StartupSummary {
+            data_dir: storage.path().to_path_buf(),
+            data_dirs: config.storage.data_dirs.clone(),
+            backend: config.storage.backend.unwrap_or_default(),
+            listen: socket_address,
+            management_listen: config.server.management_listen,
+            systemd_socket: config.server.systemd_socket,
+            tls: tls.is_some(),
+            auth: !auth.is_disabled(),
+            acl: !config.acl.is_disabled(),
+            append_only: config.acl.append_only,
+            namespace_by_user: config.acl.namespace_by_user,
+            quota: config.storage.quota,
+        };
+        summary.log(config.log.json_startup_summary);
 
         Ok(Self {
             acl,
             auth,
+            admin_auth,
             _quota: quota,
             socket_address,
+            management_address: config.server.management_listen,
             storage,
             tls,
+            limits,
+            trusted_proxies,
+            allowed_user_agents,
+            systemd_socket: config.server.systemd_socket,
+            reuse_port: config.server.reuse_port,
+            no_content_on_success: config.server.no_content_on_success,
+            reject_empty_uploads: config.storage.reject_empty_uploads.unwrap_or(true),
+            config_history: config.storage.config_history.unwrap_or_default(),
+            validate_config: config.storage.validate_config,
+            require_explicit_create: config.storage.require_explicit_create,
+            record_provenance: config.storage.record_provenance,
+            io_buffer_size,
+            strict_content_type: config.server.strict_content_type,
+            batch_uploads: config.server.batch_uploads,
+            api_versions,
+            response_headers,
+            compression_level: config.server.compression_level,
+            idempotency_window: config.server.idempotency_window.map(Duration::from_secs),
+            root_response: RootResponse::new(config.server.banner.clone(), config.server.deny_root),
+            lock_sweeper,
+            trash_purger,
+            log_sample_rate,
+            effective_config: config.redacted(),
         })
     }
 
+    fn check_namespace_by_user(
+        acl_settings: &AclSettings,
+        htpasswd_settings: &HtpasswdSettings,
+    ) -> AppResult<()> {
+        if acl_settings.namespace_by_user && htpasswd_settings.is_disabled() {
+            return Err(ErrorKind::Config
+                .context("Repositories are namespaced by user, but authentication is disabled. Enable authentication or remove `--namespace-by-user`.")
+                .into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the user of reads without credentials, who may read the
+    /// repositories the ACL grants them access to, if authentication is enabled
+    fn anonymous_user(htpasswd_settings: &HtpasswdSettings, auth: &Auth) -> Option<String> {
+        if auth.is_disabled() {
+            return None;
+        }
+
+        let anonymous_user = htpasswd_settings.anonymous_user.clone().unwrap_or_default();
+        if auth.users().contains(&anonymous_user) {
+            warn!(user = %anonymous_user, "The anonymous user has credentials, but is only allowed to read public repositories.");
+        }
+        Some(anonymous_user)
+    }
+
+    /// Checks that all users in the ACL have credentials, as they can never
+    /// authenticate otherwise
+    fn check_acl_users(acl: &Acl, auth: &Auth, acl_settings: &AclSettings) -> AppResult<()> {
+        let check = acl_settings.check_acl_users.unwrap_or_default();
+        if check == AclUserCheck::Off || acl_settings.is_disabled() || auth.is_disabled() {
+            return Ok(());
+        }
+
+        let users = auth.users();
+        let unknown: Vec<_> = acl
+            .users()
+            .into_iter()
+            .filter(|user| !users.iter().any(|known| known == user) && !acl.is_anonymous(user))
+            .collect();
+
+        if check == AclUserCheck::Strict && !unknown.is_empty() {
+            return Err(ErrorKind::Config
+                .context(format!(
+                    "The ACL grants access to users without credentials: `{}`. Add them to the htpasswd file or remove them from the ACL.",
+                    unknown.join("`, `")
+                ))
+                .into());
+        }
+        for user in unknown {
+            warn!(%user, "The ACL grants access to a user without credentials, who can't authenticate.");
+        }
+
+        Ok(())
+    }
+
+    fn check_shard_layout(storage_settings: &StorageSettings) -> AppResult<()> {
+        let depth = storage_settings.shard_depth.unwrap_or(1);
+        let width = storage_settings.shard_width.unwrap_or(2);
+
+        if depth == 0
+            || width == 0
+            || u16::from(depth) * u16::from(width) > u16::from(MAX_SHARD_CHARS)
+        {
+            return Err(ErrorKind::Config
+                .context(format!(
+                    "`shard-depth` and `shard-width` must be at least 1 and use at most {MAX_SHARD_CHARS} characters in total, but are {depth} and {width}."
+                ))
+                .into());
+        }
+
+        Ok(())
+    }
+
+    fn limits(connection_settings: &ConnectionSettings) -> AppResult<ConnectionLimits> {
+        if let Some(max_header_bytes) = connection_settings.max_header_bytes {
+            if max_header_bytes < MIN_HEADER_BYTES {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "`max-header-bytes` must be at least {MIN_HEADER_BYTES}, but is {max_header_bytes}."
+                    ))
+                    .into());
+            }
+        }
+
+        if connection_settings.max_connections_per_ip == Some(0) {
+            return Err(ErrorKind::Config
+                .context("`max-connections-per-ip` must be at least 1.")
+                .into());
+        }
+
+        if connection_settings.max_inflight == Some(0) {
+            return Err(ErrorKind::Config
+                .context("`max-inflight` must be at least 1.")
+                .into());
+        }
+
+        if connection_settings.max_ranges == Some(0) {
+            return Err(ErrorKind::Config
+                .context("`max-ranges` must be at least 1.")
+                .into());
+        }
+
+        let limits = ConnectionLimits {
+            max_header_bytes: connection_settings.max_header_bytes,
+            idle_timeout: connection_settings.idle_timeout.map(Duration::from_secs),
+            shutdown_timeout: Duration::from_secs(
+                connection_settings
+                    .shutdown_timeout
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            ),
+            max_connections_per_ip: connection_settings.max_connections_per_ip,
+            max_inflight: connection_settings.max_inflight,
+            max_ranges: connection_settings.max_ranges.unwrap_or(DEFAULT_MAX_RANGES),
+        };
+
+        debug!(?limits, "Loaded connection limits.");
+
+        Ok(limits)
+    }
+
+    fn api_versions(connection_settings: &ConnectionSettings) -> AppResult<RangeInclusive<u8>> {
+        let min = connection_settings
+            .min_api_version
+            .unwrap_or(*SUPPORTED_API_VERSIONS.start());
+        let max = connection_settings
+            .max_api_version
+            .unwrap_or(*SUPPORTED_API_VERSIONS.end());
+
+        for (name, version) in [("min-api-version", min), ("max-api-version", max)] {
+            if !SUPPORTED_API_VERSIONS.contains(&version) {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "`{name}` must be {} or {}, but is {version}.",
+                        SUPPORTED_API_VERSIONS.start(),
+                        SUPPORTED_API_VERSIONS.end()
+                    ))
+                    .into());
+            }
+        }
+        if min > max {
+            return Err(ErrorKind::Config
+                .context(format!(
+                    "`min-api-version` {min} must not be above `max-api-version` {max}."
+                ))
+                .into());
+        }
+
+        if (min..=max) != SUPPORTED_API_VERSIONS {
+            info!("Serving file listings with API versions {min} to {max} only.");
+        }
+
+        Ok(min..=max)
+    }
+
+    fn response_headers(connection_settings: &ConnectionSettings) -> AppResult<HeaderMap> {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in &connection_settings.response_headers {
+            let name = HeaderName::try_from(name.as_str()).map_err(|err| {
+                ErrorKind::Config.context(format!("Invalid response header name `{name}`: `{err}`"))
+            })?;
+            let value = HeaderValue::try_from(value.as_str()).map_err(|err| {
+                ErrorKind::Config.context(format!(
+                    "Invalid value of response header `{name}`: `{err}`"
+                ))
+            })?;
+            _ = headers.insert(name, value);
+        }
+
+        if !headers.is_empty() {
+            info!(?headers, "Adding headers to all responses.");
+        }
+
+        Ok(headers)
+    }
+
     fn quota(quota: Option<usize>) -> usize {
         quota.unwrap_or(0)
     }
 
-    fn storage(data_dir: PathBuf) -> AppResult<S> {
-        let storage = S::init(&data_dir).map_err(|err| {
+    fn io_buffer_size(storage_settings: &StorageSettings) -> AppResult<usize> {
+        let size = storage_settings
+            .io_buffer_size
+            .unwrap_or(DEFAULT_IO_BUFFER_SIZE);
+
+        if size < MIN_IO_BUFFER_SIZE {
+            return Err(ErrorKind::Config
+                .context(format!(
+                    "`io-buffer-size` must be at least {MIN_IO_BUFFER_SIZE}, but is {size}."
+                ))
+                .into());
+        }
+
+        Ok(size)
+    }
+
+    fn storage(data_dir: PathBuf, storage_settings: &StorageSettings) -> AppResult<S> {
+        for dir in &storage_settings.data_dirs {
+            _ = Self::data_dir(dir)?;
+        }
+
+        Self::check_shard_layout(storage_settings)?;
+
+        if let Some(temp_dir) = &storage_settings.temp_dir {
+            create_dir_all(temp_dir).map_err(|err| {
+                ErrorKind::GeneralStorageError
+                    .context(format!("Could not create temporary directory: `{err}`"))
+            })?;
+            Self::check_writable(temp_dir)?;
+            info!(
+                "Using temporary directory for uploads: `{}`",
+                temp_dir.display()
+            );
+        }
+
+        if let Some(trash_dir) = &storage_settings.trash_dir {
+            create_dir_all(trash_dir).map_err(|err| {
+                ErrorKind::GeneralStorageError
+                    .context(format!("Could not create trash directory: `{err}`"))
+            })?;
+            Self::check_writable(trash_dir)?;
+            Self::check_trash_dir(trash_dir, &data_dir, &storage_settings.data_dirs)?;
+            if storage_settings.data_dirs.is_empty() {
+                Self::check_trash_reachable(trash_dir, &data_dir)?;
+            }
+            for dir in &storage_settings.data_dirs {
+                Self::check_trash_reachable(trash_dir, dir)?;
+            }
+            info!("Moving deleted repositories to: `{}`", trash_dir.display());
+        }
+
+        let storage = S::from_settings(&data_dir, storage_settings).map_err(|err| {
             ErrorKind::GeneralStorageError.context(format!("Could not create storage: {}", err))
         })?;
 
@@ -93,21 +591,183 @@ where
         Ok(storage)
     }
 
+    fn check_tls_required(
+        tls_settings: &TlsSettings,
+        htpasswd_settings: &HtpasswdSettings,
+    ) -> AppResult<()> {
+        if tls_settings.require_tls
+            && tls_settings.is_disabled()
+            && !htpasswd_settings.is_disabled()
+        {
+            return Err(ErrorKind::Config
+                .context("TLS is required, but disabled while authentication is enabled. Enable TLS with `--tls` or disable authentication with `--no-auth`.")
+                .into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that key and certificate are given and exist, if TLS is enabled.
+    ///
+    /// They may come from the config file or the command line, so this can
+    /// only be checked after merging both.
+    fn check_tls_settings(tls_settings: &TlsSettings) -> AppResult<()> {
+        let files = [
+            ("key", "--tls-key", &tls_settings.tls_key),
+            ("certificate", "--tls-cert", &tls_settings.tls_cert),
+        ];
+
+        if tls_settings.is_disabled() {
+            if files.iter().any(|(_, _, path)| path.is_some())
+                || tls_settings.tls_pkcs12.is_some()
+                || !tls_settings.certificates.is_empty()
+                || tls_settings.ocsp_response.is_some()
+            {
+                warn!("TLS is disabled, so the TLS keys and certificates are ignored. Enable TLS with `--tls`.");
+            }
+            return Ok(());
+        }
+
+        if let Some(pkcs12) = &tls_settings.tls_pkcs12 {
+            if files.iter().any(|(_, _, path)| path.is_some()) {
+                return Err(ErrorKind::Config
+                    .context("TLS key and certificate are given both as files and as PKCS#12 bundle. Use either `--tls-key` and `--tls-cert` or `--tls-pkcs12`.")
+                    .into());
+            }
+            if !pkcs12.is_file() {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "TLS PKCS#12 bundle `{}` does not exist.",
+                        pkcs12.display()
+                    ))
+                    .into());
+            }
+        } else {
+            for (name, arg, path) in files {
+                let Some(path) = path else {
+                    return Err(ErrorKind::Config
+                        .context(format!(
+                            "TLS is enabled, but no {name} was provided. Add `{arg}` or set `{}` in the `[tls]` section of the config file.",
+                            arg.trim_start_matches("--")
+                        ))
+                        .into());
+                };
+
+                if !path.is_file() {
+                    return Err(ErrorKind::Config
+                        .context(format!(
+                            "TLS {name} file `{}` does not exist.",
+                            path.display()
+                        ))
+                        .into());
+                }
+            }
+        }
+
+        if let Some(ocsp_response) = &tls_settings.ocsp_response {
+            if !ocsp_response.is_file() {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "OCSP response `{}` does not exist.",
+                        ocsp_response.display()
+                    ))
+                    .into());
+            }
+        }
+
+        let mut hostnames = HashSet::new();
+        for certificate in &tls_settings.certificates {
+            let hostname = &certificate.hostname;
+            if DnsName::try_from(hostname.as_str()).is_err() {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "`{hostname}` in `[[tls.certificates]]` is no valid host name."
+                    ))
+                    .into());
+            }
+            if !hostnames.insert(hostname.to_ascii_lowercase()) {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "`{hostname}` is listed more than once in `[[tls.certificates]]`."
+                    ))
+                    .into());
+            }
+
+            for (name, path) in [
+                ("key", &certificate.key),
+                ("certificate", &certificate.cert),
+            ] {
+                if !path.is_file() {
+                    return Err(ErrorKind::Config
+                        .context(format!(
+                            "TLS {name} file `{}` of `{hostname}` does not exist.",
+                            path.display()
+                        ))
+                        .into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn tls(tls_settings: TlsSettings) -> AppResult<Option<TlsOptions>> {
-        // TODO: Do we need to validate the TLS settings?
         let tls = if tls_settings.is_disabled() {
             info!("TLS is disabled.");
             None
         } else {
-            let (Some(tls_key), Some(tls_cert)) = (tls_settings.tls_key, tls_settings.tls_cert)
-            else {
-                return Err(ErrorKind::GeneralStorageError
-                    .context("TLS is enabled but no key or certificate was provided.")
-                    .into());
+            let identity = match (
+                tls_settings.tls_pkcs12,
+                tls_settings.tls_key,
+                tls_settings.tls_cert,
+            ) {
+                (Some(path), _, _) => TlsIdentity::Pkcs12 {
+                    path,
+                    password: tls_settings.tls_pkcs12_password.unwrap_or_default(),
+                },
+                (None, Some(key), Some(cert)) => TlsIdentity::Files {
+                    cert,
+                    key,
+                    format: tls_settings.tls_format,
+                },
+                _ => {
+                    return Err(ErrorKind::Config
+                        .context("TLS is enabled but no key or certificate was provided.")
+                        .into());
+                }
             };
             info!("TLS is enabled.");
 
-            Some(TlsOptions { tls_key, tls_cert })
+            let ocsp = match (
+                tls_settings.ocsp_response,
+                tls_settings.ocsp_refresh_interval,
+            ) {
+                (Some(response), interval) => {
+                    let refresh_interval = interval
+                        .map(|interval| Self::duration("ocsp-refresh-interval", &interval))
+                        .transpose()?;
+                    if refresh_interval.is_some_and(|interval| interval.is_zero()) {
+                        return Err(ErrorKind::Config
+                            .context("`ocsp-refresh-interval` must be longer than 0s")
+                            .into());
+                    }
+                    Some(OcspStapling {
+                        response,
+                        refresh_interval,
+                    })
+                }
+                (None, Some(_)) => {
+                    warn!("No OCSP response is configured, so `ocsp-refresh-interval` is ignored.");
+                    None
+                }
+                (None, None) => None,
+            };
+
+            Some(TlsOptions {
+                identity,
+                certificates: tls_settings.certificates,
+                ocsp,
+            })
         };
 
         debug!(?tls, "Loaded TLS settings.");
@@ -117,6 +777,19 @@ where
 
     #[allow(clippy::cognitive_complexity)]
     fn auth(htpasswd_settings: HtpasswdSettings, data_dir: PathBuf) -> AppResult<Auth> {
+        if let Some(ttl) = htpasswd_settings.auth_cache_ttl {
+            if Duration::from_secs(ttl) > MAX_AUTH_CACHE_TTL {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "`auth-cache-ttl` must be at most {} seconds, but is {ttl}.",
+                        MAX_AUTH_CACHE_TTL.as_secs()
+                    ))
+                    .into());
+            }
+        }
+
+        Self::check_realm("realm", htpasswd_settings.realm.as_deref())?;
+
         let auth = if htpasswd_settings.is_disabled() {
             info!("Authentication is disabled.");
             warn!("This allows anyone to push to your repositories. This should be considered insecure and is not recommended for production use.");
@@ -139,6 +812,168 @@ where
         Ok(auth)
     }
 
+    fn admin_auth(admin_settings: &AdminSettings) -> AppResult<Option<Auth>> {
+        Self::check_realm("admin-realm", admin_settings.realm.as_deref())?;
+
+        let admin_auth = Auth::from_admin_config(admin_settings).map_err(|err| {
+            ErrorKind::Config.context(format!(
+                "Could not load the htpasswd file of the admin endpoints due to `{err}`"
+            ))
+        })?;
+
+        if admin_auth.is_some() {
+            info!("The admin endpoints use their own htpasswd file.");
+        }
+
+        Ok(admin_auth)
+    }
+
+    /// Checks that the realm can be sent in the quoted string of a
+    /// `WWW-Authenticate` header without escaping
+    fn check_realm(option: &str, realm: Option<&str>) -> AppResult<()> {
+        if let Some(realm) = realm {
+            if realm
+                .chars()
+                .any(|c| c == '"' || c == '\\' || c.is_control())
+            {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "`{option}` must not contain quotes, backslashes or control characters, but is {realm:?}."
+                    ))
+                    .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the trash directory and the data directories don't contain
+    /// each other, as trashed repositories would otherwise still be served,
+    /// or purged repositories taken for trash.
+    fn check_trash_dir(trash_dir: &Path, data_dir: &Path, data_dirs: &[PathBuf]) -> AppResult<()> {
+        let canonical = |dir: &Path| fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        let trash_dir = canonical(trash_dir);
+
+        for dir in std::iter::once(data_dir).chain(data_dirs.iter().map(PathBuf::as_path)) {
+            let dir = canonical(dir);
+            if trash_dir.starts_with(&dir) || dir.starts_with(&trash_dir) {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "`trash-dir` `{}` must not contain or be inside the data directory `{}`",
+                        trash_dir.display(),
+                        dir.display()
+                    ))
+                    .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves a file from the data directory into the trash directory and
+    /// removes it there.
+    ///
+    /// Repositories can only be renamed into a trash on the same filesystem.
+    /// Otherwise they are copied, which takes a while for large ones.
+    fn check_trash_reachable(trash_dir: &Path, data_dir: &Path) -> AppResult<()> {
+        let id = uuid::Uuid::new_v4();
+        let name = format!(".rustic-server-self-test-{id}");
+        let (path, trashed) = (data_dir.join(&name), trash_dir.join(&name));
+
+        let result = fs::write(&path, id.as_bytes()).and_then(|()| fs::rename(&path, &trashed));
+        let removed = fs::remove_file(&trashed).or_else(|_| fs::remove_file(&path));
+
+        match result.and(removed) {
+            Ok(()) => Ok(()),
+            Err(err) if is_cross_device(&err) => {
+                warn!(
+                    "Trash directory `{}` is on another filesystem than `{}`, so deleted repositories are copied into it.",
+                    trash_dir.display(),
+                    data_dir.display()
+                );
+                Ok(())
+            }
+            Err(err) => Err(ErrorKind::GeneralStorageError
+                .context(format!(
+                    "Could not move files from `{}` into the trash directory `{}`: `{err}`",
+                    data_dir.display(),
+                    trash_dir.display()
+                ))
+                .into()),
+        }
+    }
+
+    /// Parses the duration of the setting `name`, e.g. `5m`
+    fn duration(name: &str, value: &str) -> AppResult<Duration> {
+        parse_age(value)
+            .and_then(|age| Ok(age.to_std()?))
+            .map_err(|err| {
+                ErrorKind::Config
+                    .context(format!("Invalid `{name}`: {err}"))
+                    .into()
+            })
+    }
+
+    fn lock_sweeper(maintenance: &MaintenanceSettings) -> AppResult<Option<LockSweeper>> {
+        let Some(max_age) = &maintenance.lock_max_age else {
+            return Ok(None);
+        };
+
+        let interval = Self::duration(
+            "lock-sweep-interval",
+            maintenance
+                .lock_sweep_interval
+                .as_deref()
+                .unwrap_or(DEFAULT_LOCK_SWEEP_INTERVAL),
+        )?;
+        if interval.is_zero() {
+            return Err(ErrorKind::Config
+                .context("`lock-sweep-interval` must be longer than 0s")
+                .into());
+        }
+
+        Ok(Some(LockSweeper {
+            max_age: Self::duration("lock-max-age", max_age)?,
+            interval,
+        }))
+    }
+
+    fn trash_purger(
+        storage: &StorageSettings,
+        maintenance: &MaintenanceSettings,
+    ) -> AppResult<Option<TrashPurger>> {
+        if storage.trash_dir.is_none() {
+            if maintenance.trash_retention.is_some() {
+                warn!("No trash directory is configured, so `trash-retention` is ignored.");
+            }
+            return Ok(None);
+        }
+
+        Ok(Some(TrashPurger {
+            retention: Self::duration(
+                "trash-retention",
+                maintenance
+                    .trash_retention
+                    .as_deref()
+                    .unwrap_or(DEFAULT_TRASH_RETENTION),
+            )?,
+            interval: TRASH_PURGE_INTERVAL,
+        }))
+    }
+
+    fn log_sample_rate(log_settings: &LogSettings) -> AppResult<f64> {
+        let rate = log_settings.sample_rate.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(ErrorKind::Config
+                .context(format!(
+                    "`sample-rate` must be between 0.0 and 1.0, but is {rate}."
+                ))
+                .into());
+        }
+
+        Ok(rate)
+    }
+
     fn data_dir(data_dir: impl Into<PathBuf>) -> AppResult<PathBuf> {
         let data_dir = data_dir.into();
 
@@ -151,6 +986,8 @@ where
             })?;
         }
 
+        Self::check_writable(&data_dir)?;
+
         info!(
             "Using directory for storing repositories: `{}`",
             data_dir.display()
@@ -159,6 +996,37 @@ where
         Ok(data_dir)
     }
 
+    /// Writes, reads back and deletes a file in the data directory.
+    ///
+    /// A read-only or full volume would otherwise only show up on the first
+    /// upload. The file gets a random name, so concurrently starting servers
+    /// sharing the directory don't interfere.
+    fn check_writable(data_dir: &Path) -> AppResult<()> {
+        let id = uuid::Uuid::new_v4();
+        let path = data_dir.join(format!(".rustic-server-self-test-{id}"));
+        let content = id.as_bytes();
+
+        let result = fs::write(&path, content)
+            .and_then(|()| fs::read(&path))
+            .and_then(|read| {
+                if read == content {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::other("content read back differs"))
+                }
+            });
+        let removed = fs::remove_file(&path);
+
+        result.and(removed).map_err(|err| {
+            ErrorKind::GeneralStorageError
+                .context(format!(
+                    "Data directory `{}` is not writable: `{err}`",
+                    data_dir.display()
+                ))
+                .into()
+        })
+    }
+
     fn socket_address(address: SocketAddr) -> AppResult<SocketAddr> {
         debug!(?address, "Parsed socket address.");
 
@@ -168,13 +1036,15 @@ where
     fn acl(acl_settings: AclSettings, data_dir: PathBuf) -> AppResult<Acl> {
         let acl = if acl_settings.is_disabled() {
             info!("ACL is disabled.");
-            Acl::default().set_append_only(acl_settings.append_only)
+            Acl::default()
+                .set_append_only(acl_settings.append_only)
+                .set_namespace_by_user(acl_settings.namespace_by_user)
         } else {
             info!("ACL is enabled.");
 
             let valid_acl_path = acl_settings.acl_file_or_default(data_dir)?;
 
-            Acl::from_config(&acl_settings, Some(valid_acl_path)).map_err(|err| {
+            Acl::from_config(&acl_settings, valid_acl_path).map_err(|err| {
                 ErrorKind::GeneralStorageError
                     .context(format!("Could not create ACL due to `{err}`"))
             })?
@@ -185,6 +1055,80 @@ where
         Ok(acl)
     }
 
+    /// Validates the repository aliases
+    ///
+    /// Aliases must not stand for other aliases, so every request is resolved
+    /// in a single step.
+    fn aliases(aliases: &BTreeMap<String, String>) -> AppResult<BTreeMap<PathBuf, PathBuf>> {
+        let invalid = |msg: String| ErrorKind::Config.context(msg).into();
+        let is_repository = |path: &Path| {
+            check_repo_path(path, None).is_ok()
+                && path
+                    .components()
+                    .any(|component| matches!(component, Component::Normal(_)))
+        };
+
+        for (alias, target) in aliases {
+            for path in [alias, target] {
+                if !is_repository(Path::new(path)) {
+                    return Err(invalid(format!(
+                        "`{path}` in `[aliases]` is no valid repository path."
+                    )));
+                }
+            }
+            if aliases.contains_key(target) {
+                return Err(invalid(format!(
+                    "The alias `{alias}` stands for `{target}`, which is an alias itself."
+                )));
+            }
+        }
+
+        if !aliases.is_empty() {
+            info!(?aliases, "Using repository aliases.");
+        }
+
+        Ok(aliases
+            .iter()
+            .map(|(alias, target)| (PathBuf::from(alias), PathBuf::from(target)))
+            .collect())
+    }
+
+    /// Validates the settings of single repositories and returns those
+    /// configured as read-only
+    pub(crate) fn read_only_repos(
+        repos: &BTreeMap<String, RepoSettings>,
+    ) -> AppResult<BTreeSet<PathBuf>> {
+        for repo in repos.keys() {
+            let path = Path::new(repo);
+            let is_repository = check_repo_path(path, None).is_ok()
+                && path
+                    .components()
+                    .any(|component| matches!(component, Component::Normal(_)));
+            if !is_repository {
+                return Err(ErrorKind::Config
+                    .context(format!(
+                        "`{repo}` in `[repos]` is no valid repository path."
+                    ))
+                    .into());
+            }
+        }
+
+        let read_only_repos: BTreeSet<_> = repos
+            .iter()
+            .filter(|(_, settings)| settings.read_only)
+            .map(|(repo, _)| PathBuf::from(repo))
+            .collect();
+
+        if !read_only_repos.is_empty() {
+            info!(
+                ?read_only_repos,
+                "Refusing writes to read-only repositories."
+            );
+        }
+
+        Ok(read_only_repos)
+    }
+
     fn _log(log_settings: LogSettings) -> AppResult<LogSettings> {
         let log = if log_settings.is_disabled() {
             info!("Logging is set to default.");
@@ -202,3 +1146,490 @@ where
         self.storage.path()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::LocalStorage, testing::server_config};
+    use clap::Parser;
+    use conflate::Merge;
+
+    #[test]
+    fn test_check_writable_passes() {
+        let check = ServerRuntimeContext::<LocalStorage>::check_writable;
+        let dir = PathBuf::from("tests/generated/self_test");
+        create_dir_all(&dir).unwrap();
+
+        check(&dir).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // a file can't be used as data directory
+        let file = dir.join("file");
+        fs::write(&file, "").unwrap();
+        let err = check(&file).unwrap_err();
+        assert!(err.to_string().contains("not writable"), "{err}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_acl_file_passes() {
+        let acl = ServerRuntimeContext::<LocalStorage>::acl;
+        let mut settings = server_config().acl;
+        settings.acl_path = Some(PathBuf::from("tests/generated/missing_acl.toml"));
+
+        assert!(acl(settings.clone(), default_data_dir()).is_err());
+
+        settings.allow_missing = true;
+        let missing = acl(settings.clone(), default_data_dir()).unwrap();
+        assert!(missing.sections().is_empty());
+        assert_eq!(missing, Acl::from_config(&settings, None).unwrap());
+    }
+
+    #[test]
+    fn test_check_acl_users_passes() {
+        let check = ServerRuntimeContext::<LocalStorage>::check_acl_users;
+        let mut settings = server_config().acl;
+        // the anonymous user of the public repository needs no credentials
+        let acl = Acl::from_config(&settings, settings.acl_path.clone())
+            .unwrap()
+            .set_anonymous_user(Some(String::new()));
+
+        let mut htpasswd = crate::htpasswd::Htpasswd::new();
+        htpasswd.update("rustic", "rustic").unwrap();
+        let auth = Auth::from(htpasswd.credentials);
+
+        // `restic` and `hurl` are in the ACL, but can't authenticate
+        check(&acl, &auth, &settings).unwrap();
+        settings.check_acl_users = Some(AclUserCheck::Warn);
+        check(&acl, &auth, &settings).unwrap();
+        settings.check_acl_users = Some(AclUserCheck::Strict);
+        let err = check(&acl, &auth, &settings).unwrap_err();
+        assert!(err.to_string().contains("`hurl`, `restic`"), "{err}");
+
+        // all users of the test ACL have credentials
+        let config = server_config();
+        let auth = Auth::from_config(&config.auth, config.auth.htpasswd_file.clone().unwrap());
+        check(&acl, &auth.unwrap(), &settings).unwrap();
+    }
+
+    #[test]
+    fn test_require_tls_refuses_plaintext_auth_passes() {
+        let mut config = server_config();
+        config.tls.require_tls = true;
+
+        let result = ServerRuntimeContext::<LocalStorage>::from_config(Arc::new(config.clone()));
+        assert!(result.is_err());
+
+        // without authentication there are no credentials to protect
+        config.auth.disable_auth = true;
+        assert!(ServerRuntimeContext::<LocalStorage>::check_tls_required(
+            &config.tls,
+            &config.auth
+        )
+        .is_ok());
+    }
+
+    /// Merges the config file with the command line, like `ServeCmd::override_config`
+    fn merged_tls_settings(toml: &str, args: &[&str]) -> TlsSettings {
+        let mut config: RusticServerConfig = toml::from_str(toml).unwrap();
+        let cli = RusticServerConfig::try_parse_from(
+            std::iter::once("rustic-server").chain(args.iter().copied()),
+        )
+        .unwrap();
+        config.merge(cli);
+        config.tls
+    }
+
+    #[test]
+    fn test_tls_settings_merge_passes() {
+        let check = ServerRuntimeContext::<LocalStorage>::check_tls_settings;
+        let key = "tests/fixtures/test_data/certs/test.key";
+        let cert = "tests/fixtures/test_data/certs/test.crt";
+
+        // TLS disabled by default
+        let tls = merged_tls_settings("", &[]);
+        assert!(tls.is_disabled());
+        assert!(check(&tls).is_ok());
+
+        // everything from the command line
+        let tls = merged_tls_settings("", &["--tls", "--tls-key", key, "--tls-cert", cert]);
+        assert!(!tls.is_disabled());
+        assert!(check(&tls).is_ok());
+
+        // everything from the config file
+        let toml = format!("[tls]\ndisable-tls = false\ntls-key = '{key}'\ntls-cert = '{cert}'");
+        let tls = merged_tls_settings(&toml, &[]);
+        assert!(!tls.is_disabled());
+        assert!(check(&tls).is_ok());
+
+        // enabled on the command line, key and certificate from the config file
+        let toml = format!("[tls]\ntls-key = '{key}'\ntls-cert = '{cert}'");
+        let tls = merged_tls_settings(&toml, &["--tls"]);
+        assert!(!tls.is_disabled());
+        assert!(check(&tls).is_ok());
+
+        // enabled in the config file, but certificate missing
+        let toml = format!("[tls]\ndisable-tls = false\ntls-key = '{key}'");
+        let tls = merged_tls_settings(&toml, &[]);
+        let err = check(&tls).unwrap_err();
+        assert!(err.to_string().contains("--tls-cert"), "{err}");
+
+        // enabled on the command line, but key missing
+        let tls = merged_tls_settings("", &["--tls", "--tls-cert", cert]);
+        let err = check(&tls).unwrap_err();
+        assert!(err.to_string().contains("--tls-key"), "{err}");
+
+        // certificate doesn't exist
+        let tls = merged_tls_settings(
+            "",
+            &["--tls", "--tls-key", key, "--tls-cert", "missing.crt"],
+        );
+        let err = check(&tls).unwrap_err();
+        assert!(err.to_string().contains("missing.crt"), "{err}");
+
+        // PKCS#12 bundle instead of key and certificate
+        let pkcs12 = "tests/fixtures/test_data/certs/test.p12";
+        let tls = merged_tls_settings("", &["--tls", "--tls-pkcs12", pkcs12]);
+        assert!(check(&tls).is_ok());
+
+        let tls = merged_tls_settings("", &["--tls", "--tls-pkcs12", pkcs12, "--tls-key", key]);
+        let err = check(&tls).unwrap_err();
+        assert!(err.to_string().contains("either"), "{err}");
+
+        let tls = merged_tls_settings("", &["--tls", "--tls-pkcs12", "missing.p12"]);
+        let err = check(&tls).unwrap_err();
+        assert!(err.to_string().contains("missing.p12"), "{err}");
+
+        // additional certificates by host name
+        let sni = |hostname: &str| {
+            format!(
+                "\n[[tls.certificates]]\nhostname = '{hostname}'\ncert = '{cert}'\nkey = '{key}'"
+            )
+        };
+        let toml = format!(
+            "[tls]\ntls-key = '{key}'\ntls-cert = '{cert}'{}",
+            sni("a.example.com")
+        );
+        let tls = merged_tls_settings(&toml, &["--tls"]);
+        assert_eq!(tls.certificates.len(), 1);
+        assert!(check(&tls).is_ok());
+
+        let tls = merged_tls_settings(&format!("{toml}{}", sni("A.example.com")), &["--tls"]);
+        let err = check(&tls).unwrap_err();
+        assert!(err.to_string().contains("more than once"), "{err}");
+
+        let tls = merged_tls_settings(&format!("{toml}{}", sni("not a host")), &["--tls"]);
+        let err = check(&tls).unwrap_err();
+        assert!(err.to_string().contains("no valid host name"), "{err}");
+    }
+
+    #[test]
+    fn test_auth_cache_ttl_fails() {
+        let settings = HtpasswdSettings {
+            disable_auth: true,
+            auth_cache_ttl: Some(301),
+            ..Default::default()
+        };
+
+        let err = ServerRuntimeContext::<LocalStorage>::auth(settings, PathBuf::new()).unwrap_err();
+        assert!(err.to_string().contains("at most 300 seconds"), "{err}");
+    }
+
+    #[test]
+    fn test_realm_fails() {
+        for realm in ["say \"hi\"", "back\\slash", "new\nline"] {
+            let settings = HtpasswdSettings {
+                disable_auth: true,
+                realm: Some(realm.to_string()),
+                ..Default::default()
+            };
+            let err =
+                ServerRuntimeContext::<LocalStorage>::auth(settings, PathBuf::new()).unwrap_err();
+            assert!(err.to_string().contains("`realm`"), "{err}");
+
+            let settings = AdminSettings {
+                realm: Some(realm.to_string()),
+                ..Default::default()
+            };
+            assert!(ServerRuntimeContext::<LocalStorage>::admin_auth(&settings).is_err());
+        }
+
+        let settings = HtpasswdSettings {
+            disable_auth: true,
+            realm: Some("Backups of ACME, Inc.".to_string()),
+            ..Default::default()
+        };
+        assert!(ServerRuntimeContext::<LocalStorage>::auth(settings, PathBuf::new()).is_ok());
+    }
+
+    #[test]
+    fn test_connection_limits_passes() {
+        let mut settings = ConnectionSettings {
+            max_header_bytes: Some(16 * 1024),
+            idle_timeout: Some(30),
+            max_connections_per_ip: Some(8),
+            max_inflight: Some(256),
+            ..Default::default()
+        };
+
+        let limits = ServerRuntimeContext::<LocalStorage>::limits(&settings).unwrap();
+        assert_eq!(limits.max_header_bytes, Some(16 * 1024));
+        assert_eq!(limits.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(limits.max_connections_per_ip, Some(8));
+        assert_eq!(limits.max_inflight, Some(256));
+        assert_eq!(limits.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(limits.max_ranges, 16);
+
+        settings.shutdown_timeout = Some(0);
+        let limits = ServerRuntimeContext::<LocalStorage>::limits(&settings).unwrap();
+        assert_eq!(limits.shutdown_timeout, Duration::ZERO);
+
+        // no client could connect at all
+        settings.max_connections_per_ip = Some(0);
+        assert!(ServerRuntimeContext::<LocalStorage>::limits(&settings).is_err());
+        settings.max_connections_per_ip = None;
+
+        // no request would be handled at all
+        settings.max_inflight = Some(0);
+        assert!(ServerRuntimeContext::<LocalStorage>::limits(&settings).is_err());
+        settings.max_inflight = None;
+
+        // no range could be downloaded at all
+        settings.max_ranges = Some(0);
+        assert!(ServerRuntimeContext::<LocalStorage>::limits(&settings).is_err());
+        settings.max_ranges = None;
+
+        // hyper doesn't accept buffers smaller than 8 KiB
+        settings.max_header_bytes = Some(1024);
+        assert!(ServerRuntimeContext::<LocalStorage>::limits(&settings).is_err());
+    }
+
+    #[test]
+    fn test_io_buffer_size_passes() {
+        let mut settings = StorageSettings::default();
+        let io_buffer_size = ServerRuntimeContext::<LocalStorage>::io_buffer_size;
+
+        assert_eq!(io_buffer_size(&settings).unwrap(), 64 * 1024);
+
+        settings.io_buffer_size = Some(1024 * 1024);
+        assert_eq!(io_buffer_size(&settings).unwrap(), 1024 * 1024);
+
+        // too small to be worth a buffer
+        settings.io_buffer_size = Some(512);
+        assert!(io_buffer_size(&settings).is_err());
+    }
+
+    #[test]
+    fn test_api_versions_passes() {
+        let mut settings = ConnectionSettings::default();
+        let api_versions = ServerRuntimeContext::<LocalStorage>::api_versions;
+
+        assert_eq!(api_versions(&settings).unwrap(), 1..=2);
+
+        settings.min_api_version = Some(2);
+        assert_eq!(api_versions(&settings).unwrap(), 2..=2);
+
+        // no version left
+        settings.max_api_version = Some(1);
+        assert!(api_versions(&settings).is_err());
+
+        settings.min_api_version = None;
+        assert_eq!(api_versions(&settings).unwrap(), 1..=1);
+
+        settings.max_api_version = Some(3);
+        let err = api_versions(&settings).unwrap_err();
+        assert!(err.to_string().contains("must be 1 or 2"), "{err}");
+    }
+
+    #[test]
+    fn test_response_headers_passes() {
+        let mut settings = ConnectionSettings::default();
+        _ = settings.response_headers.insert(
+            "Strict-Transport-Security".to_string(),
+            "max-age=31536000".to_string(),
+        );
+
+        let headers = ServerRuntimeContext::<LocalStorage>::response_headers(&settings).unwrap();
+        assert_eq!(headers["strict-transport-security"], "max-age=31536000");
+
+        // header names can't contain spaces
+        _ = settings
+            .response_headers
+            .insert("X Frame Options".to_string(), "DENY".to_string());
+        let err = ServerRuntimeContext::<LocalStorage>::response_headers(&settings).unwrap_err();
+        assert!(err.to_string().contains("X Frame Options"), "{err}");
+    }
+
+    #[test]
+    fn test_startup_summary_passes() {
+        let summary = StartupSummary {
+            data_dir: PathBuf::from("/data"),
+            data_dirs: vec![PathBuf::from("/data2")],
+            backend: StorageBackend::PackedLocal,
+            listen: "127.0.0.1:8000".parse().unwrap(),
+            management_listen: None,
+            systemd_socket: false,
+            tls: true,
+            auth: true,
+            acl: false,
+            append_only: false,
+            namespace_by_user: false,
+            quota: Some(1024),
+        };
+
+        let line = summary.to_string();
+        assert!(line.starts_with("data-dir=`/data` data-dir=`/data2` backend=packed-local"));
+        assert!(
+            line.contains("listen=127.0.0.1:8000 management-listen=none"),
+            "{line}"
+        );
+        assert!(
+            line.contains("tls=enabled auth=enabled acl=disabled"),
+            "{line}"
+        );
+        assert!(line.ends_with("quota=1024"), "{line}");
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["backend"], "packed-local");
+        assert_eq!(json["listen"], "127.0.0.1:8000");
+        assert_eq!(json["acl"], false);
+    }
+
+    #[test]
+    fn test_lock_sweeper_settings_passes() {
+        let lock_sweeper = ServerRuntimeContext::<LocalStorage>::lock_sweeper;
+
+        let mut settings = MaintenanceSettings::default();
+        assert_eq!(lock_sweeper(&settings).unwrap(), None);
+
+        settings.lock_max_age = Some("1h".to_string());
+        let sweeper = lock_sweeper(&settings).unwrap().unwrap();
+        assert_eq!(sweeper.max_age, Duration::from_secs(3600));
+        assert_eq!(sweeper.interval, Duration::from_secs(300));
+
+        settings.lock_sweep_interval = Some("0s".to_string());
+        assert!(lock_sweeper(&settings).is_err());
+
+        settings.lock_sweep_interval = Some("1x".to_string());
+        let err = lock_sweeper(&settings).unwrap_err();
+        assert!(err.to_string().contains("lock-sweep-interval"), "{err}");
+    }
+
+    #[test]
+    fn test_trash_purger_settings_passes() {
+        let trash_purger = ServerRuntimeContext::<LocalStorage>::trash_purger;
+
+        let mut storage = StorageSettings::default();
+        let mut maintenance = MaintenanceSettings {
+            trash_retention: Some("1d".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(trash_purger(&storage, &maintenance).unwrap(), None);
+
+        storage.trash_dir = Some(PathBuf::from("trash"));
+        let purger = trash_purger(&storage, &maintenance).unwrap().unwrap();
+        assert_eq!(purger.retention, Duration::from_secs(86400));
+
+        maintenance.trash_retention = None;
+        let purger = trash_purger(&storage, &maintenance).unwrap().unwrap();
+        assert_eq!(purger.retention, Duration::from_secs(7 * 86400));
+
+        maintenance.trash_retention = Some("1x".to_string());
+        assert!(trash_purger(&storage, &maintenance).is_err());
+    }
+
+    #[test]
+    fn test_check_trash_dir_fails() {
+        let check_trash_dir = ServerRuntimeContext::<LocalStorage>::check_trash_dir;
+        let data_dir = Path::new("/srv/rustic");
+
+        assert!(check_trash_dir(Path::new("/srv/trash"), data_dir, &[]).is_ok());
+        assert!(check_trash_dir(Path::new("/srv/rustic/.trash"), data_dir, &[]).is_err());
+        assert!(check_trash_dir(Path::new("/srv"), data_dir, &[]).is_err());
+        assert!(check_trash_dir(
+            Path::new("/mnt/disk1/trash"),
+            data_dir,
+            &[PathBuf::from("/mnt/disk1")]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_trash_reachable_passes() {
+        let check = ServerRuntimeContext::<LocalStorage>::check_trash_reachable;
+        let data_dir = PathBuf::from("tests/generated/trash_reachable");
+        let trash_dir = PathBuf::from("tests/generated/trash_reachable_trash");
+        for dir in [&data_dir, &trash_dir] {
+            create_dir_all(dir).unwrap();
+        }
+
+        check(&trash_dir, &data_dir).unwrap();
+        assert_eq!(fs::read_dir(&data_dir).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(&trash_dir).unwrap().count(), 0);
+
+        // nothing can be moved into a missing trash
+        fs::remove_dir(&trash_dir).unwrap();
+        assert!(check(&trash_dir, &data_dir).is_err());
+        assert_eq!(fs::read_dir(&data_dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_aliases_passes() {
+        let aliases = ServerRuntimeContext::<LocalStorage>::aliases;
+        let map = |entries: &[(&str, &str)]| -> BTreeMap<String, String> {
+            entries
+                .iter()
+                .map(|(alias, target)| (alias.to_string(), target.to_string()))
+                .collect()
+        };
+
+        let valid = aliases(&map(&[("new", "old"), ("team/new", "team/old")])).unwrap();
+        assert_eq!(valid[Path::new("new")], Path::new("old"));
+
+        for invalid in [
+            map(&[("new", "../old")]),
+            map(&[("", "old")]),
+            map(&[("a", "b"), ("b", "c")]),
+            map(&[("a", "a")]),
+        ] {
+            assert!(aliases(&invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_read_only_repos_passes() {
+        let read_only_repos = ServerRuntimeContext::<LocalStorage>::read_only_repos;
+        let map = |entries: &[(&str, bool)]| -> BTreeMap<String, RepoSettings> {
+            entries
+                .iter()
+                .map(|&(repo, read_only)| (repo.to_string(), RepoSettings { read_only }))
+                .collect()
+        };
+
+        let valid = read_only_repos(&map(&[("frozen", true), ("team/open", false)])).unwrap();
+        assert_eq!(valid, BTreeSet::from([PathBuf::from("frozen")]));
+
+        for invalid in [map(&[("../frozen", true)]), map(&[("", false)])] {
+            assert!(read_only_repos(&invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_log_sample_rate_passes() {
+        let log_sample_rate = ServerRuntimeContext::<LocalStorage>::log_sample_rate;
+
+        let mut settings = LogSettings::default();
+        assert_eq!(log_sample_rate(&settings).unwrap(), 1.0);
+
+        settings.sample_rate = Some(0.1);
+        assert_eq!(log_sample_rate(&settings).unwrap(), 0.1);
+
+        for invalid in [-0.1, 1.5, f64::NAN] {
+            settings.sample_rate = Some(invalid);
+            assert!(log_sample_rate(&settings).is_err());
+        }
+    }
+}
@@ -1,7 +1,7 @@
 //! Error types
 
 use abscissa_core::error::{BoxError, Context};
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use std::{
     fmt::{self, Display},
@@ -10,6 +10,8 @@ use std::{
     result::Result,
 };
 
+use crate::auth::www_authenticate_value;
+
 pub type AppResult<T> = Result<T, Error>;
 pub type ApiResult<T> = Result<T, ApiErrorKind>;
 
@@ -24,6 +26,10 @@ pub enum ErrorKind {
     #[error("I/O error")]
     Io,
 
+    /// Address could not be bound or socket could not be used
+    #[error("bind error")]
+    Bind,
+
     /// General storage error
     #[error("storage error")]
     GeneralStorageError,
@@ -33,6 +39,57 @@ pub enum ErrorKind {
     MissingUserInput,
 }
 
+/// Exit codes of the process, following `sysexits.h` where one fits
+///
+/// Usage errors on the command line are reported by `clap`, which exits with 2.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitCode {
+    /// Graceful shutdown
+    Success = 0,
+
+    /// Unexpected failure
+    Crash = 1,
+
+    /// Address could not be bound, e.g. as it is in use (`EX_UNAVAILABLE`)
+    Bind = 69,
+
+    /// Storage could not be used (`EX_IOERR`)
+    Storage = 74,
+
+    /// Invalid configuration (`EX_CONFIG`)
+    Config = 78,
+}
+
+impl From<ExitCode> for i32 {
+    fn from(code: ExitCode) -> Self {
+        code as Self
+    }
+}
+
+impl From<ErrorKind> for ExitCode {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Config => Self::Config,
+            ErrorKind::Bind => Self::Bind,
+            ErrorKind::GeneralStorageError => Self::Storage,
+            ErrorKind::Io | ErrorKind::MissingUserInput => Self::Crash,
+        }
+    }
+}
+
+impl From<&Error> for ExitCode {
+    fn from(err: &Error) -> Self {
+        (*err.kind()).into()
+    }
+}
+
+impl From<&anyhow::Error> for ExitCode {
+    /// Uses the kind of the application error, if the error is one
+    fn from(err: &anyhow::Error) -> Self {
+        err.downcast_ref::<Error>().map_or(Self::Crash, Self::from)
+    }
+}
+
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum ApiErrorKind {
     /// Internal server error: `{0}`
@@ -57,8 +114,10 @@ pub enum ApiErrorKind {
     FileNotFound(String),
     /// Getting file metadata failed: `{0}`
     GettingFileMetadataFailed(String),
-    /// Range not valid
-    RangeNotValid,
+    /// Range not valid: {0}
+    RangeNotValid(String),
+    /// No range satisfiable for a file of `{0}` bytes
+    RangeNotSatisfiable(u64),
     /// Seeking file failed
     SeekingFileFailed,
     /// Multipart range not implemented
@@ -89,13 +148,30 @@ pub enum ApiErrorKind {
     GeneralStorageError(String),
     /// Invalid API version: `{0}`
     InvalidApiVersion(String),
+    /// Permission denied: `{0}`
+    PermissionDenied(String),
+    /// Insufficient storage: `{0}`
+    InsufficientStorage(String),
+    /// Storage is read-only: `{0}`
+    StorageReadOnly(String),
+    /// Unsupported media type: `{0}`
+    UnsupportedMediaType(String),
+    /// Repository not found: `{0}`
+    RepositoryNotFound(String),
+    /// Repository already exists: `{0}`
+    RepositoryExists(String),
+    /// Repository is configured in the server config: `{0}`
+    RepositoryConfigured(String),
 }
 
 impl IntoResponse for ApiErrorKind {
     fn into_response(self) -> Response {
         let response = match self {
+            Self::AuthenticationHeaderError | Self::UserAuthenticationError(_) => {
+                return self.into_unauthorized_response();
+            }
             Self::InvalidApiVersion(err) => (
-                StatusCode::BAD_REQUEST,
+                StatusCode::NOT_ACCEPTABLE,
                 format!("Invalid API version: {err}"),
             ),
             Self::InternalError(err) => (
@@ -137,7 +213,17 @@ impl IntoResponse for ApiErrorKind {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("error getting file metadata: {err}"),
             ),
-            Self::RangeNotValid => (StatusCode::BAD_REQUEST, "range not valid".to_string()),
+            Self::RangeNotValid(err) => {
+                (StatusCode::BAD_REQUEST, format!("range not valid: {err}"))
+            }
+            Self::RangeNotSatisfiable(length) => {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{length}"))],
+                    format!("range not satisfiable: the file has {length} bytes"),
+                )
+                    .into_response();
+            }
             Self::SeekingFileFailed => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "error seeking file".to_string(),
@@ -179,24 +265,139 @@ impl IntoResponse for ApiErrorKind {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("error removing repository folder: {:?}", err),
             ),
-            Self::AuthenticationHeaderError => (
-                StatusCode::FORBIDDEN,
-                "Bad authentication header".to_string(),
-            ),
-            Self::UserAuthenticationError(err) => (
-                StatusCode::FORBIDDEN,
-                format!("Failed to authenticate user: {:?}", err),
-            ),
             Self::GeneralStorageError(err) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Storage error: {:?}", err),
             ),
+            Self::PermissionDenied(err) => {
+                (StatusCode::FORBIDDEN, format!("permission denied: {err}"))
+            }
+            Self::InsufficientStorage(err) => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!("insufficient storage: {err}"),
+            ),
+            Self::StorageReadOnly(err) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("storage is read-only, please contact the administrator: {err}"),
+            ),
+            Self::UnsupportedMediaType(err) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported media type: {err}"),
+            ),
+            Self::RepositoryNotFound(repo) => (
+                StatusCode::NOT_FOUND,
+                format!("repository not found: {repo}"),
+            ),
+            Self::RepositoryExists(repo) => (
+                StatusCode::CONFLICT,
+                format!("repository already exists: {repo}"),
+            ),
+            Self::RepositoryConfigured(err) => (
+                StatusCode::CONFLICT,
+                format!("repository is configured in the server config: {err}"),
+            ),
         };
 
         response.into_response()
     }
 }
 
+impl ApiErrorKind {
+    /// Maps an I/O error from the storage to the matching error kind
+    ///
+    /// Errors that clients can act on (missing files, missing permissions, full
+    /// disks) get their own kind, everything else is wrapped by `fallback`.
+    /// A read-only filesystem usually means the disk failed and was remounted,
+    /// so it is also logged as an error for the administrator.
+    pub(crate) fn from_io_error(
+        err: &io::Error,
+        message: impl Into<String>,
+        fallback: fn(String) -> Self,
+    ) -> Self {
+        let message = format!("{}: {err}", message.into());
+
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::FileNotFound(message),
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(message),
+            _ if is_storage_full(err) => Self::InsufficientStorage(message),
+            _ if is_read_only_filesystem(err) => {
+                tracing::error!(
+                    "The storage is read-only, uploads will fail until it is writable again: {message}"
+                );
+                Self::StorageReadOnly(message)
+            }
+            _ => fallback(message),
+        }
+    }
+
+    /// Missing or invalid credentials are answered with a `401` and a
+    /// `WWW-Authenticate` challenge, so clients know they should (re-)authenticate.
+    fn into_unauthorized_response(self) -> Response {
+        self.into_challenge_response(www_authenticate_value())
+    }
+
+    /// Like `into_response`, but answers missing or invalid credentials with the
+    /// given `WWW-Authenticate` challenge
+    pub(crate) fn into_challenge_response(self, www_authenticate: String) -> Response {
+        let message = match self {
+            Self::UserAuthenticationError(err) => format!("Failed to authenticate user: {:?}", err),
+            Self::AuthenticationHeaderError => "Bad authentication header".to_string(),
+            _ => return self.into_response(),
+        };
+
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, www_authenticate)],
+            message,
+        )
+            .into_response()
+    }
+}
+
+/// Returns whether the error was caused by a full disk or an exceeded disk quota
+// `io::ErrorKind::StorageFull` is only stable since Rust 1.83, which is above
+// our MSRV, so we check the OS error codes instead.
+fn is_storage_full(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    const STORAGE_FULL_CODES: [i32; 1] = [28]; // ENOSPC
+    #[cfg(windows)]
+    const STORAGE_FULL_CODES: [i32; 2] = [39, 112]; // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL
+    #[cfg(not(any(unix, windows)))]
+    const STORAGE_FULL_CODES: [i32; 0] = [];
+
+    err.raw_os_error()
+        .is_some_and(|code| STORAGE_FULL_CODES.contains(&code))
+}
+
+/// Returns whether the error was caused by a read-only filesystem
+// `io::ErrorKind::ReadOnlyFilesystem` is only stable since Rust 1.83, see above.
+fn is_read_only_filesystem(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    const READ_ONLY_CODES: [i32; 1] = [30]; // EROFS
+    #[cfg(windows)]
+    const READ_ONLY_CODES: [i32; 1] = [19]; // ERROR_WRITE_PROTECT
+    #[cfg(not(any(unix, windows)))]
+    const READ_ONLY_CODES: [i32; 0] = [];
+
+    err.raw_os_error()
+        .is_some_and(|code| READ_ONLY_CODES.contains(&code))
+}
+
+/// Returns whether the error was caused by renaming or linking a file across
+/// filesystems
+// `io::ErrorKind::CrossesDevices` is only stable since Rust 1.85, see above.
+pub(crate) fn is_cross_device(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    const CROSS_DEVICE_CODES: [i32; 1] = [18]; // EXDEV
+    #[cfg(windows)]
+    const CROSS_DEVICE_CODES: [i32; 1] = [17]; // ERROR_NOT_SAME_DEVICE
+    #[cfg(not(any(unix, windows)))]
+    const CROSS_DEVICE_CODES: [i32; 0] = [];
+
+    err.raw_os_error()
+        .is_some_and(|code| CROSS_DEVICE_CODES.contains(&code))
+}
+
 impl ErrorKind {
     /// Create an error context from this error
     pub fn context(self, source: impl Into<BoxError>) -> Context<Self> {
@@ -245,3 +446,54 @@ impl From<io::Error> for Error {
         ErrorKind::Io.context(err).into()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    use crate::error::{ApiErrorKind, Error, ErrorKind, ExitCode};
+
+    #[cfg(unix)]
+    #[test]
+    fn test_from_io_error_read_only_passes() {
+        let err = io::Error::from_raw_os_error(30); // EROFS
+        let kind =
+            ApiErrorKind::from_io_error(&err, "Could not write", ApiErrorKind::WritingToFileFailed);
+        assert!(matches!(kind, ApiErrorKind::StorageReadOnly(_)));
+        assert_eq!(
+            kind.into_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let err = io::Error::from_raw_os_error(28); // ENOSPC
+        let kind =
+            ApiErrorKind::from_io_error(&err, "Could not write", ApiErrorKind::WritingToFileFailed);
+        assert!(matches!(kind, ApiErrorKind::InsufficientStorage(_)));
+
+        let err = io::Error::other("broken");
+        let kind =
+            ApiErrorKind::from_io_error(&err, "Could not write", ApiErrorKind::WritingToFileFailed);
+        assert!(matches!(kind, ApiErrorKind::WritingToFileFailed(_)));
+    }
+
+    #[test]
+    fn test_exit_code_passes() {
+        assert_eq!(i32::from(ExitCode::Success), 0);
+        assert_eq!(ExitCode::from(ErrorKind::Config), ExitCode::Config);
+        assert_eq!(i32::from(ExitCode::from(ErrorKind::Bind)), 69);
+        assert_eq!(
+            ExitCode::from(ErrorKind::GeneralStorageError),
+            ExitCode::Storage
+        );
+        assert_eq!(ExitCode::from(ErrorKind::Io), ExitCode::Crash);
+
+        let err: Error = ErrorKind::Config.context("invalid").into();
+        assert_eq!(ExitCode::from(&anyhow::Error::from(err)), ExitCode::Config);
+        assert_eq!(
+            ExitCode::from(&anyhow::anyhow!("unexpected")),
+            ExitCode::Crash
+        );
+    }
+}
@@ -1,11 +1,19 @@
 // web server response handler modules
+pub(crate) mod admin;
+pub(crate) mod file_batch;
 pub(crate) mod file_config;
 pub(crate) mod file_exchange;
 pub(crate) mod file_length;
+pub(crate) mod file_meta;
 pub(crate) mod files_list;
 pub(crate) mod health;
+pub(crate) mod management;
+pub(crate) mod manifest;
 pub(crate) mod repository;
+pub(crate) mod root;
+pub(crate) mod stats;
 
 // Support modules
-mod access_check;
+pub(crate) mod access_check;
 pub(crate) mod file_helpers;
+pub(crate) mod file_range;
@@ -1,25 +1,33 @@
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
-use axum::{http::StatusCode, response::IntoResponse};
 use tracing::debug;
 
 // used for using auto-generated TpeKind variant names
 use strum::VariantNames;
 
 use crate::{
-    acl::{AccessType, AclChecker, ACL},
+    acl::{acl, AccessType, AclChecker},
     error::{ApiErrorKind, ApiResult},
+    storage::STORAGE,
     typed_path::TpeKind,
 };
 
+/// Checks that the user may access the repository at `path`.
+///
+/// Returns the path of the repository in the storage, which differs from the
+/// requested path if it is an alias or repositories are namespaced by user.
 pub fn check_auth_and_acl(
     user: String,
     tpe: impl Into<Option<TpeKind>>,
     path: &Path,
     access_type: AccessType,
-) -> ApiResult<impl IntoResponse> {
+) -> ApiResult<PathBuf> {
     let tpe = tpe.into();
 
+    let storage = STORAGE.get().unwrap();
+    check_repo_path(path, storage.max_repo_depth())?;
+    check_repo_name(path, storage.reserved_repo_names())?;
+
     // don't allow paths that includes any of the defined types
     for part in path.iter() {
         //FIXME: Rewrite to?? -> if TYPES.contains(part) {}
@@ -33,8 +41,22 @@ pub fn check_auth_and_acl(
         }
     }
 
-    let acl = ACL.get().unwrap();
-    let path = if let Some(path) = path.to_str() {
+    let acl = acl();
+    // requests without credentials are asked for them, unless the repository
+    // is public
+    let denied = |err| {
+        if acl.is_anonymous(&user) {
+            ApiErrorKind::AuthenticationHeaderError
+        } else {
+            err
+        }
+    };
+
+    let repo_path = acl
+        .repo_path(&user, acl.resolve_alias(path))
+        .map_err(denied)?;
+    let acl_path = acl.repo_path(&user, acl.acl_repo(path)).map_err(denied)?;
+    let path = if let Some(path) = acl_path.to_str() {
         path
     } else {
         return Err(ApiErrorKind::NonUnicodePath(path.display().to_string()));
@@ -42,8 +64,162 @@ pub fn check_auth_and_acl(
     let allowed = acl.is_allowed(&user, path, tpe, access_type);
     tracing::debug!(name: "auth", %user, %path, "type" = ?tpe, allowed);
 
-    match allowed {
-        true => Ok(StatusCode::OK),
-        false => Err(ApiErrorKind::PathNotAllowed(path.to_string())),
+    if !allowed {
+        return Err(denied(ApiErrorKind::PathNotAllowed(path.to_string())));
+    }
+
+    // locks are still allowed, so clients can read read-only repositories
+    let writes = access_type > AccessType::Read && tpe != Some(TpeKind::Locks);
+    if writes && acl.is_read_only(&repo_path) {
+        debug!(?repo_path, "Refusing write to read-only repository.");
+        return Err(ApiErrorKind::PermissionDenied(format!(
+            "repository `{}` is read-only",
+            repo_path.display()
+        )));
+    }
+
+    Ok(repo_path)
+}
+
+/// Checks that the repository path stays below the data directory and is not
+/// nested deeper than `max_depth` directories
+pub fn check_repo_path(path: &Path, max_depth: Option<usize>) -> ApiResult<()> {
+    let mut depth = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(ApiErrorKind::InvalidPath(path.display().to_string()));
+            }
+        }
+    }
+
+    if max_depth.is_some_and(|max_depth| depth > max_depth) {
+        debug!(depth, ?max_depth, "Repository path nested too deeply.");
+        return Err(ApiErrorKind::InvalidPath(path.display().to_string()));
+    }
+
+    Ok(())
+}
+
+/// Repository names that can't be used, as they are used by the server itself
+const BUILTIN_RESERVED_REPO_NAMES: [&str; 4] = ["admin", "metrics", ".packs", ".provenance"];
+
+/// Checks that no directory of the repository path has a reserved name
+pub fn check_repo_name(path: &Path, reserved: &[String]) -> ApiResult<()> {
+    for part in path.iter() {
+        if BUILTIN_RESERVED_REPO_NAMES.iter().any(|name| part == *name)
+            || reserved.iter().any(|name| part == name.as_str())
+        {
+            debug!("Reserved repository name: {:?}", part);
+            return Err(ApiErrorKind::PathNotAllowed(path.display().to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{init_test_environment, server_config};
+
+    #[test]
+    fn test_anonymous_access_passes() {
+        init_test_environment(server_config());
+        let public = Path::new("repo_public_me");
+
+        assert_eq!(
+            check_auth_and_acl(String::new(), TpeKind::Keys, public, AccessType::Read).unwrap(),
+            public
+        );
+
+        // everything else asks for credentials
+        for (path, access) in [
+            (public, AccessType::Append),
+            (Path::new("test_repo"), AccessType::Read),
+            (Path::new("no_acl_repo"), AccessType::Read),
+        ] {
+            assert!(
+                matches!(
+                    check_auth_and_acl(String::new(), TpeKind::Keys, path, access),
+                    Err(ApiErrorKind::AuthenticationHeaderError)
+                ),
+                "{path:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_only_repo_fails() {
+        init_test_environment(server_config());
+        let frozen = Path::new("repo_frozen_me");
+        let check = |tpe, access| check_auth_and_acl("rustic".to_string(), tpe, frozen, access);
+
+        assert!(matches!(
+            check(TpeKind::Data, AccessType::Append),
+            Err(ApiErrorKind::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            check(TpeKind::Keys, AccessType::Modify),
+            Err(ApiErrorKind::PermissionDenied(_))
+        ));
+
+        // reads and locks are still allowed
+        assert_eq!(check(TpeKind::Data, AccessType::Read).unwrap(), frozen);
+        assert_eq!(check(TpeKind::Locks, AccessType::Modify).unwrap(), frozen);
+    }
+
+    #[test]
+    fn test_check_repo_name_passes() {
+        let reserved = ["tooling".to_string()];
+        assert!(check_repo_name(Path::new("repo"), &reserved).is_ok());
+        assert!(check_repo_name(Path::new("team/admins"), &reserved).is_ok());
+
+        for path in [
+            "admin",
+            "metrics/",
+            "team/.packs",
+            "tooling",
+            "team/tooling/repo",
+        ] {
+            assert!(
+                matches!(
+                    check_repo_name(Path::new(path), &reserved),
+                    Err(ApiErrorKind::PathNotAllowed(_))
+                ),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_repo_path_passes() {
+        assert!(check_repo_path(Path::new(""), Some(0)).is_ok());
+        assert!(check_repo_path(Path::new("repo"), None).is_ok());
+        assert!(check_repo_path(Path::new("team/project/repo"), Some(3)).is_ok());
+        assert!(check_repo_path(Path::new("team/project/repo/"), Some(3)).is_ok());
+        assert!(check_repo_path(Path::new("a/b/c/d/e/f/g/h"), None).is_ok());
+    }
+
+    #[test]
+    fn test_check_repo_path_fails() {
+        for (path, max_depth) in [
+            ("team/project/repo", Some(2)),
+            ("a/b/c/d/e/f/g/h", Some(3)),
+            ("repo", Some(0)),
+            ("../repo", None),
+            ("team/../../repo", None),
+            ("/etc", None),
+        ] {
+            assert!(
+                matches!(
+                    check_repo_path(Path::new(path), max_depth),
+                    Err(ApiErrorKind::InvalidPath(_))
+                ),
+                "{path} with {max_depth:?}"
+            );
+        }
     }
 }
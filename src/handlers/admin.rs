@@ -0,0 +1,668 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path as StdPath, PathBuf},
+    sync::{OnceLock, PoisonError},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
+    Json,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    acl::{acl, RepoAcl, ACL},
+    auth::{AdminAuthFromRequest, Auth, ADMIN_AUTH, AUTH},
+    config::{RusticServerConfig, StorageBackend},
+    error::{ApiErrorKind, ApiResult},
+    handlers::{
+        access_check::{check_repo_name, check_repo_path},
+        health::uptime,
+    },
+    htpasswd::{CredentialMap, Htpasswd},
+    log_stream::log_stream,
+    sessions::sessions,
+    storage::STORAGE,
+    uploads::uploads,
+};
+
+// Effective configuration of the server, with secrets redacted
+static EFFECTIVE_CONFIG: OnceLock<RusticServerConfig> = OnceLock::new();
+
+pub(crate) fn init_effective_config(config: RusticServerConfig) {
+    let _ = EFFECTIVE_CONFIG.set(config);
+}
+
+/// `list_sessions`
+/// Interface: GET /admin/sessions
+///
+/// Returns the requests currently served, including this one.
+pub async fn list_sessions(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(%user, "[list_sessions]");
+
+    Ok(Json(sessions().list()))
+}
+
+/// `revoke_session`
+/// Interface: DELETE /admin/sessions/{id}
+///
+/// Aborts the request with the given id and closes its connection.
+pub async fn revoke_session(
+    Path(id): Path<u64>,
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(id, %user, "[revoke_session]");
+
+    if sessions().revoke(id) {
+        Ok(StatusCode::OK)
+    } else {
+        Err(ApiErrorKind::FileNotFound(format!("session {id}")))
+    }
+}
+
+/// `list_uploads`
+/// Interface: GET /admin/uploads
+///
+/// Returns the files currently written, which were neither finalized nor
+/// aborted yet.
+pub async fn list_uploads(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(%user, "[list_uploads]");
+
+    Ok(Json(uploads().list()))
+}
+
+/// `stream_logs`
+/// Interface: GET /admin/logs
+///
+/// Streams the recent log events and all new ones as server-sent events with
+/// a JSON object each, until the client disconnects. Events a slow client
+/// missed are reported in a comment.
+pub async fn stream_logs(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::info!(%user, "[stream_logs]");
+
+    let (recent, receiver) = log_stream().subscribe();
+    let recent = stream::iter(recent).map(|event| Event::default().json_data(event));
+    let new = stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => Event::default().json_data(event),
+            Err(RecvError::Lagged(skipped)) => {
+                Ok(Event::default().comment(format!("{skipped} events skipped")))
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, receiver))
+    });
+
+    Ok(Sse::new(recent.chain(new)).keep_alive(KeepAlive::default()))
+}
+
+/// `get_acl`
+/// Interface: GET /admin/acl
+///
+/// Returns the ACLs of all repositories as JSON, in sections named like in the
+/// ACL file.
+pub async fn get_acl(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(%user, "[get_acl]");
+
+    Ok(Json(acl().sections()))
+}
+
+/// `put_acl`
+/// Interface: PUT /admin/acl
+///
+/// Replaces the ACLs of all repositories with the JSON body, in the format
+/// `get_acl` returns, and writes them to the ACL file.
+pub async fn put_acl(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+    body: Bytes,
+) -> ApiResult<impl IntoResponse> {
+    tracing::info!(%user, "[put_acl]");
+
+    let sections: BTreeMap<String, RepoAcl> = serde_json::from_slice(&body)
+        .map_err(|err| ApiErrorKind::BadRequest(format!("invalid ACL: {err}")))?;
+    ACL.get()
+        .unwrap()
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .replace_sections(sections)?;
+
+    Ok(StatusCode::OK)
+}
+
+/// New path of a repository, as given to `rename_repository`
+#[derive(Debug, Deserialize)]
+pub struct RenameTo {
+    to: String,
+}
+
+/// `rename_repository`
+/// Interface: POST /admin/repos/{repo}/rename?to={path}
+///
+/// Moves the repository, with all repositories below it, to `to` and renames
+/// their ACL sections, writing them to the ACL file. Nested repositories are
+/// given with `%2F` between their directories, e.g. `team%2Frepo`.
+///
+/// Fails with `409 Conflict` if the repository or an ACL section at `to`
+/// exists, or if a read-only repository or the target of an alias would be
+/// moved. With several data directories, fails with `400 Bad Request` if
+/// the repository or one below it would belong to another data directory under
+/// its new name. If the ACL file can't be written, the repository is moved back.
+pub async fn rename_repository(
+    Path(repo): Path<String>,
+    Query(RenameTo { to }): Query<RenameTo>,
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::info!(%repo, %to, %user, "[rename_repository]");
+
+    let storage = STORAGE.get().unwrap();
+    let (from, to) = (StdPath::new(&repo), StdPath::new(&to));
+    check_repo_path(from, None)?;
+    check_repo_path(to, storage.max_repo_depth())?;
+    check_repo_name(to, storage.reserved_repo_names())?;
+
+    // refuse before moving anything, the ACL is checked again when renaming
+    acl().check_rename_repo(from, to)?;
+
+    storage.rename_repository(from, to).await?;
+
+    let renamed = ACL
+        .get()
+        .unwrap()
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .rename_repo(from, to);
+    if let Err(err) = renamed {
+        if let Err(undo) = storage.rename_repository(to, from).await {
+            tracing::error!(%repo, "Could not move the repository back: {undo}");
+        }
+        return Err(err);
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Numbers of users and repositories in effect
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LoadedCounts {
+    pub users: usize,
+    /// Users of the admin endpoints, if they have their own htpasswd file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_users: Option<usize>,
+    pub repos: usize,
+}
+
+impl LoadedCounts {
+    /// Counts the users and ACL sections currently in effect
+    fn current() -> Self {
+        Self {
+            users: AUTH.get().unwrap().users().len(),
+            admin_users: ADMIN_AUTH
+                .get()
+                .and_then(Option::as_ref)
+                .map(|admin_auth| admin_auth.users().len()),
+            repos: acl().sections().len(),
+        }
+    }
+}
+
+/// `reload`
+/// Interface: POST /admin/reload
+///
+/// Reads the htpasswd and ACL files again and uses them, e.g. after they were
+/// edited where changes aren't noticed. If one of them can't be read, the
+/// error is returned and nothing is changed.
+pub async fn reload(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::info!(%user, "[reload]");
+
+    let auth = AUTH.get().unwrap();
+    let admin_auth = ADMIN_AUTH.get().and_then(Option::as_ref);
+
+    let credentials = read_credentials(auth)?;
+    let admin_credentials = admin_auth.map(read_credentials).transpose()?;
+
+    // requests go on with the current ACL while the file is read
+    let current = acl().clone();
+    let reloaded = current
+        .reloaded()
+        .map_err(|err| ApiErrorKind::InternalError(format!("Could not reload: {err}")))?;
+
+    // all files were read, so swap them in together
+    if let Some(credentials) = credentials {
+        auth.set_credentials(credentials);
+    }
+    if let (Some(admin_auth), Some(Some(credentials))) = (admin_auth, admin_credentials) {
+        admin_auth.set_credentials(credentials);
+    }
+    *ACL.get()
+        .unwrap()
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = reloaded;
+
+    let counts = LoadedCounts::current();
+    tracing::info!(?counts, "Reloaded htpasswd and ACL files.");
+
+    Ok(Json(counts))
+}
+
+/// Reads the credentials from the htpasswd file of `auth`, if it has one
+fn read_credentials(auth: &Auth) -> ApiResult<Option<CredentialMap>> {
+    let Some(path) = auth.htpasswd_path() else {
+        return Ok(None);
+    };
+
+    Htpasswd::from_file(&path.to_path_buf())
+        .map(|htpasswd| Some(htpasswd.credentials))
+        .map_err(|err| ApiErrorKind::InternalError(format!("Could not reload: {err}")))
+}
+
+/// Runtime state of the server, as returned by `diagnostics`
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    version: &'static str,
+    /// Seconds since the server started
+    uptime: u64,
+    backend: StorageBackend,
+    data_dir: PathBuf,
+    #[serde(flatten)]
+    loaded: LoadedCounts,
+    /// Effective configuration, with secrets redacted
+    config: RusticServerConfig,
+}
+
+/// `diagnostics`
+/// Interface: GET /admin/diagnostics
+///
+/// Returns the effective configuration, with secrets redacted, and the state
+/// of the running server as JSON, so support can check it remotely.
+pub async fn diagnostics(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(%user, "[diagnostics]");
+
+    let config = EFFECTIVE_CONFIG
+        .get()
+        .ok_or_else(|| ApiErrorKind::InternalError("configuration not loaded".to_string()))?
+        .clone();
+
+    Ok(Json(Diagnostics {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: uptime().as_secs(),
+        backend: config.storage.backend.unwrap_or_default(),
+        data_dir: STORAGE.get().unwrap().path().to_path_buf(),
+        loaded: LoadedCounts::current(),
+        config,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        handlers::{
+            admin::{
+                diagnostics, get_acl, init_effective_config, list_sessions, list_uploads, put_acl,
+                reload, rename_repository, revoke_session, stream_logs,
+            },
+            file_config::get_config,
+        },
+        log_stream::{log_stream, LogEvent},
+        sessions::{track_sessions, SessionInfo},
+        storage::STORAGE,
+        testing::{basic_auth_header_value, init_test_environment, server_config},
+        typed_path::RepositoryConfigPath,
+    };
+
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+        middleware,
+        routing::{delete, get, post},
+        Router,
+    };
+    use axum_extra::routing::RouterExt; // for `Router::typed_*`
+    use http_body_util::BodyExt;
+    use std::path::Path;
+    use tokio::io::AsyncWriteExt;
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/admin/sessions", get(list_sessions))
+            .route("/admin/sessions/:id", delete(revoke_session))
+            .route("/admin/acl", get(get_acl).put(put_acl))
+            .route("/admin/uploads", get(list_uploads))
+            .route("/admin/logs", get(stream_logs))
+            .route("/admin/reload", post(reload))
+            .route("/admin/diagnostics", get(diagnostics))
+            .route("/admin/repos/:repo/rename", post(rename_repository))
+            .typed_get(get_config::<RepositoryConfigPath>)
+            .layer(middleware::from_fn(track_sessions))
+    }
+
+    fn request(uri: &str, method: Method, user: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("Authorization", basic_auth_header_value(user, Some(user)))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_passes() {
+        init_test_environment(server_config());
+
+        let resp = app()
+            .oneshot(request("/admin/sessions", Method::GET, "hurl"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // our own request is listed, with the authenticated user
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let sessions: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert!(sessions
+            .iter()
+            .any(|session| { session["path"] == "/admin/sessions" && session["user"] == "hurl" }));
+
+        // a repository named `admin` is still routed to, but the name is reserved
+        let resp = app()
+            .oneshot(request("/admin/config", Method::GET, "hurl"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_need_admin_passes() {
+        init_test_environment(server_config());
+
+        let resp = app()
+            .oneshot(request("/admin/sessions", Method::GET, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = app()
+            .oneshot(request(
+                "/admin/sessions/12345678",
+                Method::DELETE,
+                "rustic",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = app()
+            .oneshot(request("/admin/sessions/12345678", Method::DELETE, "hurl"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_stream_logs_passes() {
+        init_test_environment(server_config());
+
+        let resp = app()
+            .oneshot(request("/admin/logs", Method::GET, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        log_stream().push(LogEvent {
+            time: chrono::Utc::now(),
+            level: "WARN".to_string(),
+            target: "rustic_server".to_string(),
+            message: "__stream_logs_test__".to_string(),
+        });
+
+        let resp = app()
+            .oneshot(request("/admin/logs", Method::GET, "hurl"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/event-stream");
+
+        // the stream doesn't end, so only read until the recent event
+        let mut body = resp.into_body();
+        let mut received = String::new();
+        while !received.contains("__stream_logs_test__") {
+            let frame = body.frame().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&frame.into_data().unwrap()).unwrap());
+        }
+        assert!(received.contains(r#"data: {"time":"#));
+    }
+
+    #[tokio::test]
+    async fn test_list_uploads_passes() {
+        init_test_environment(server_config());
+
+        let list = || async {
+            let resp = app()
+                .oneshot(request("/admin/uploads", Method::GET, "hurl"))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap()
+        };
+        let is_ours = |upload: &serde_json::Value| upload["name"] == "__list_uploads_test__";
+
+        let storage = STORAGE.get().unwrap();
+        let repo = Path::new("test_repo");
+        let mut file = storage
+            .create_file(repo, "keys", Some("__list_uploads_test__"))
+            .await
+            .unwrap();
+        file.write_all(b"partial").await.unwrap();
+
+        let uploads = list().await;
+        let upload = uploads.iter().find(|upload| is_ours(upload)).unwrap();
+        assert_eq!(upload["repo"], "test_repo");
+        assert_eq!(upload["type"], "keys");
+        assert_eq!(upload["bytes_written"], 7);
+
+        // aborted uploads are removed from the list
+        drop(file);
+        assert!(!list().await.iter().any(is_ours));
+
+        let resp = app()
+            .oneshot(request("/admin/uploads", Method::GET, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_get_acl_passes() {
+        init_test_environment(server_config());
+
+        let resp = app()
+            .oneshot(request("/admin/acl", Method::GET, "hurl"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let acl: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(acl["test_repo"]["rustic"], "Append");
+        assert_eq!(acl["repo_unlock_me"]["rustic"], "ForceUnlock");
+
+        let resp = app()
+            .oneshot(request("/admin/acl", Method::GET, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_put_invalid_acl_fails() {
+        init_test_environment(server_config());
+
+        let put = |user: &str, body: &'static str| {
+            let mut request = request("/admin/acl", Method::PUT, user);
+            *request.body_mut() = Body::from(body);
+            app().oneshot(request)
+        };
+
+        let resp = put("rustic", "{}").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = put("hurl", r#"{"repo": {"rustic": "Everything"}}"#)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = put("hurl", r#"{"../repo": {"rustic": "Read"}}"#)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // the ACL is unchanged
+        assert!(crate::acl::acl().sections().contains_key("test_repo"));
+    }
+
+    #[tokio::test]
+    async fn test_reload_passes() {
+        init_test_environment(server_config());
+
+        let resp = app()
+            .oneshot(request("/admin/reload", Method::POST, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = app()
+            .oneshot(request("/admin/reload", Method::POST, "hurl"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the test environment reads the same files again
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            summary["users"],
+            crate::auth::AUTH.get().unwrap().users().len()
+        );
+        assert_eq!(summary["repos"], crate::acl::acl().sections().len());
+        assert!(crate::acl::acl().sections().contains_key("test_repo"));
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_passes() {
+        init_test_environment(server_config());
+        let mut config = server_config();
+        _ = config
+            .server
+            .response_headers
+            .insert("Authorization".to_string(), "Bearer secret".to_string());
+        init_effective_config(config.redacted());
+
+        let resp = app()
+            .oneshot(request("/admin/diagnostics", Method::GET, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = app()
+            .oneshot(request("/admin/diagnostics", Method::GET, "hurl"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let diagnostics: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(diagnostics["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(diagnostics["backend"], "local");
+        assert_eq!(diagnostics["repos"], crate::acl::acl().sections().len());
+        assert_eq!(diagnostics["config"]["server"]["listen"], "127.0.0.1:8080");
+        // secrets are never revealed
+        assert!(!String::from_utf8_lossy(&body).contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_revoke_session_passes() {
+        init_test_environment(server_config());
+
+        let resp = app()
+            .oneshot(request("/test_repo/config", Method::GET, "rustic"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the session stays active until the response body was sent
+        let sessions: Vec<SessionInfo> = crate::sessions::sessions().list();
+        let session = sessions
+            .iter()
+            .find(|session| session.path == "/test_repo/config")
+            .unwrap();
+        assert_eq!(session.user.as_deref(), Some("rustic"));
+
+        let revoke = app()
+            .oneshot(request(
+                &format!("/admin/sessions/{}", session.id),
+                Method::DELETE,
+                "hurl",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(revoke.status(), StatusCode::OK);
+
+        assert!(resp.into_body().collect().await.is_err());
+        assert!(crate::sessions::sessions()
+            .list()
+            .iter()
+            .all(|info| info.id != session.id));
+    }
+
+    #[tokio::test]
+    async fn test_rename_repository_fails() {
+        init_test_environment(server_config());
+
+        let rename = |uri: &str, user: &str| app().oneshot(request(uri, Method::POST, user));
+
+        let resp = rename("/admin/repos/test_repo/rename?to=renamed", "rustic")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // the ACL section of the target exists
+        let resp = rename("/admin/repos/test_repo/rename?to=repo_unlock_me", "hurl")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = rename("/admin/repos/never_created/rename?to=renamed", "hurl")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = rename("/admin/repos/test_repo/rename?to=..%2Foutside", "hurl")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // nothing was moved
+        assert!(crate::acl::acl().sections().contains_key("test_repo"));
+        assert!(Path::new("tests/generated/test_storage/test_repo").is_dir());
+    }
+}
@@ -0,0 +1,233 @@
+use std::path::Path;
+
+use axum::{
+    extract::{multipart::Field, Multipart},
+    http::header,
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    auth::BasicAuthFromRequest,
+    error::{ApiErrorKind, ApiResult},
+    handlers::{
+        file_exchange::{get_save_file, reject_empty_uploads, save_body},
+        file_helpers::success_status,
+    },
+    provenance::Origin,
+    typed_path::{RepositoryTpeBatchPath, TpeKind},
+};
+
+/// Result of a single file of a batch upload
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct BatchStatus {
+    pub name: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `add_files`
+/// Interface: POST {path}/{type}/_batch
+///
+/// Saves each part of the multipart body as the file of the given type named
+/// like the part, as if it was uploaded on its own, and returns the status of
+/// each. A part may declare its size with a `Content-Length` header, which is
+/// then checked like the one of a single upload.
+///
+/// The files are saved one after the other, so a failing file doesn't affect
+/// the others. If the body itself is broken, the request fails, and the files
+/// saved before stay in place.
+pub async fn add_files(
+    RepositoryTpeBatchPath { repo, tpe }: RepositoryTpeBatchPath,
+    auth: BasicAuthFromRequest,
+    mut multipart: Multipart,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(%repo, %tpe, "[add_files]");
+
+    if tpe == TpeKind::Config {
+        return Err(ApiErrorKind::BadRequest(
+            "the config can't be uploaded in a batch".to_string(),
+        ));
+    }
+
+    let mut statuses = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| ApiErrorKind::BadRequest(format!("invalid multipart body: {err}")))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        let status = match add_field(&auth.user, Path::new(&repo), tpe, &name, field).await {
+            Ok(()) => BatchStatus {
+                name,
+                status: success_status().as_u16(),
+                error: None,
+            },
+            Err(err) => BatchStatus {
+                name,
+                error: Some(err.to_string()),
+                status: err.into_response().status().as_u16(),
+            },
+        };
+        statuses.push(status);
+    }
+
+    tracing::debug!(%repo, %tpe, files = statuses.len(), "[add_files] batch saved");
+
+    Ok(Json(statuses))
+}
+
+/// Saves a part of a batch upload as the file `name`
+async fn add_field(
+    user: &str,
+    repo: &Path,
+    tpe: TpeKind,
+    name: &str,
+    field: Field<'_>,
+) -> ApiResult<()> {
+    let size = field
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+
+    let (repo, file) = get_save_file(
+        user.to_string(),
+        repo.to_path_buf(),
+        Some(tpe),
+        Some(name.to_string()),
+        size,
+    )
+    .await?;
+
+    let origin = Origin {
+        user,
+        tpe: tpe.into_str(),
+        name: Some(name),
+    };
+    let _ = save_body(&repo, file, field, size, reject_empty_uploads(), origin).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+        Router,
+    };
+    use axum_extra::routing::RouterExt;
+    use http_body_util::BodyExt;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::{
+        handlers::{file_batch::add_files, file_exchange::add_file},
+        testing::{basic_auth_header_value, init_test_environment, server_config},
+        typed_path::RepositoryTpeNamePath,
+    };
+
+    const BOUNDARY: &str = "batch-boundary";
+
+    async fn post(user: &str, uri: &str, parts: &[(&str, &str)]) -> (StatusCode, Value) {
+        let mut body = String::new();
+        for (name, content) in parts {
+            body.push_str(&format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{content}\r\n"
+            ));
+        }
+        body.push_str(&format!("--{BOUNDARY}--\r\n"));
+
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::POST)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .header(
+                header::AUTHORIZATION,
+                basic_auth_header_value(user, Some(user)),
+            )
+            .body(Body::from(body))
+            .unwrap();
+
+        // next to the uploads of single files, as in the server
+        let resp = Router::new()
+            .typed_post(add_files)
+            .typed_post(add_file::<RepositoryTpeNamePath>)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_add_files_passes() {
+        init_test_environment(server_config());
+
+        let repo_dir = PathBuf::from("tests/generated/test_storage/repo_batch_me");
+        fs::create_dir_all(repo_dir.join("index")).unwrap();
+
+        let (status, body) = post(
+            "rustic",
+            "/repo_batch_me/index/_batch",
+            &[
+                ("first", "first index"),
+                ("empty", ""),
+                ("second", "second"),
+            ],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!([
+                {"name": "first", "status": 200},
+                {"name": "empty", "status": 400, "error": "Bad request: `empty uploads are not allowed`"},
+                {"name": "second", "status": 200},
+            ])
+        );
+        assert_eq!(
+            fs::read_to_string(repo_dir.join("index/first")).unwrap(),
+            "first index"
+        );
+        assert_eq!(
+            fs::read_to_string(repo_dir.join("index/second")).unwrap(),
+            "second"
+        );
+        assert!(!repo_dir.join("index/empty").exists());
+
+        // the access is checked for every file
+        let (status, body) = post(
+            "restic",
+            "/repo_batch_me/index/_batch",
+            &[("third", "third index")],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["status"], 403);
+        assert!(!repo_dir.join("index/third").exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_files_config_fails() {
+        init_test_environment(server_config());
+
+        let (status, _) = post(
+            "rustic",
+            "/repo_batch_me/config/_batch",
+            &[("config", "config")],
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
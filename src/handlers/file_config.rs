@@ -1,29 +1,124 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
-use axum::{extract::Request, http::header, response::IntoResponse};
+use axum::{
+    body::{self, HttpBody},
+    extract::{Query, Request},
+    http::header,
+    response::IntoResponse,
+    Json,
+};
 use axum_extra::{headers::Range, TypedHeader};
 use axum_macros::debug_handler;
-use axum_range::{KnownSize, Ranged};
+use futures::stream;
+use serde::Deserialize;
 
 use crate::typed_path::PathParts;
 use crate::{
     acl::AccessType,
+    as_of::AsOf,
     auth::BasicAuthFromRequest,
     error::{ApiErrorKind, ApiResult},
     handlers::{
         access_check::check_auth_and_acl,
-        file_exchange::{check_name, get_save_file, save_body},
+        file_exchange::{
+            check_content_type, check_name, content_length, full_file_response, get_save_file,
+            is_sha256_hex, save_body,
+        },
+        file_helpers::success_status,
+        file_range::ranged_response,
     },
+    provenance::Origin,
+    stats::repo_stats,
     storage::STORAGE,
-    typed_path::{RepositoryConfigPath, TpeKind},
+    typed_path::{RepositoryConfigHistoryPath, RepositoryConfigPath, TpeKind},
 };
 
+// Global that stores how many previous versions of the config are kept
+static CONFIG_HISTORY: OnceLock<usize> = OnceLock::new();
+
+pub fn init_config_history(keep: usize) {
+    let _ = CONFIG_HISTORY.get_or_init(|| keep);
+}
+
+// Global that stores whether uploaded configs are validated
+static VALIDATE_CONFIG: OnceLock<bool> = OnceLock::new();
+
+pub fn init_validate_config(validate: bool) {
+    let _ = VALIDATE_CONFIG.get_or_init(|| validate);
+}
+
+/// Maximum size of a config read into memory for validation
+const MAX_CONFIG_BYTES: usize = 64 * 1024;
+
+/// Minimum size of an encrypted config: the nonce and the MAC of 16 bytes
+/// each, and some content
+const MIN_ENCRYPTED_CONFIG_BYTES: usize = 33;
+
+/// Fields every unencrypted repository config has
+#[derive(Deserialize)]
+struct RepoConfig {
+    version: u32,
+    id: String,
+    chunker_polynomial: String,
+}
+
+/// Checks that `body` can be a repository config
+///
+/// restic encrypts the config, so only unencrypted configs, which are JSON,
+/// can be checked for their fields.
+fn validate_config(body: &[u8]) -> ApiResult<()> {
+    let invalid = |reason: String| {
+        Err(ApiErrorKind::BadRequest(format!(
+            "invalid config: {reason}"
+        )))
+    };
+
+    let json = body
+        .iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .is_some_and(|&byte| byte == b'{');
+    if !json {
+        if body.len() < MIN_ENCRYPTED_CONFIG_BYTES {
+            return invalid(format!("{} bytes are too short", body.len()));
+        }
+        return Ok(());
+    }
+
+    let config: RepoConfig = match serde_json::from_slice(body) {
+        Ok(config) => config,
+        Err(err) => return invalid(err.to_string()),
+    };
+    if !matches!(config.version, 1 | 2) {
+        return invalid(format!("unsupported version {}", config.version));
+    }
+    if !is_sha256_hex(&config.id) {
+        return invalid(format!("`{}` is no valid id", config.id));
+    }
+    if config.chunker_polynomial.is_empty()
+        || !config
+            .chunker_polynomial
+            .chars()
+            .all(|c| c.is_ascii_hexdigit())
+    {
+        return invalid(format!(
+            "`{}` is no valid chunker polynomial",
+            config.chunker_polynomial
+        ));
+    }
+
+    Ok(())
+}
+
 /// has_config
 /// Interface: HEAD {repo}/config
 #[debug_handler]
 pub async fn has_config(
     RepositoryConfigPath { repo }: RepositoryConfigPath,
     BasicAuthFromRequest { user, .. }: BasicAuthFromRequest,
+    as_of: AsOf,
 ) -> ApiResult<impl IntoResponse> {
     let tpe = TpeKind::Config;
 
@@ -31,34 +126,38 @@ pub async fn has_config(
 
     let path = Path::new(&repo);
 
-    let _ = check_auth_and_acl(user, tpe, path, AccessType::Read)?;
+    let path = &check_auth_and_acl(user, tpe, path, AccessType::Read)?;
 
     let storage = STORAGE.get().unwrap();
 
-    let path_to_storage = storage.filename(path, tpe.into_str(), None);
+    if !as_of.includes_file(storage.as_ref(), path, tpe.into_str(), None) {
+        return Err(ApiErrorKind::FileNotFound(repo));
+    }
 
-    if path_to_storage.exists() {
-        let file = storage.open_file(path, tpe.into_str(), None).await?;
+    let length = storage.file_length(path, tpe.into_str(), None).await?;
 
-        let length = file
-            .metadata()
-            .await
-            .map_err(|err| ApiErrorKind::GettingFileMetadataFailed(format!("{err:?}")))?
-            .len()
-            .to_string();
+    Ok([
+        (header::CONTENT_LENGTH, length.to_string()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+    ])
+}
 
-        Ok([(header::CONTENT_LENGTH, length)])
-    } else {
-        Err(ApiErrorKind::FileNotFound(repo))
-    }
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct ConfigVersion {
+    version: Option<usize>,
 }
 
 /// `get_config`
-/// Interface: GET {repo}/config
+/// Interface: GET {repo}/config?version={n}
+///
+/// Without a version, the current config is returned.
 pub async fn get_config<P: PathParts>(
     path: P,
     auth: BasicAuthFromRequest,
+    as_of: AsOf,
     range: Option<TypedHeader<Range>>,
+    Query(params): Query<ConfigVersion>,
 ) -> ApiResult<impl IntoResponse> {
     let tpe = TpeKind::Config;
 
@@ -69,16 +168,44 @@ pub async fn get_config<P: PathParts>(
     let _ = check_name(tpe, None)?;
     let path = Path::new(&repo);
 
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+    let path = &check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
 
     let storage = STORAGE.get().unwrap();
-    let file = storage.open_file(path, tpe.into_str(), None).await?;
+    let file = match params.version {
+        Some(version) => storage.open_config_backup(path, version).await?,
+        None => {
+            as_of.check_file(storage.as_ref(), path, tpe.into_str(), None)?;
+            storage.open_file(path, tpe.into_str(), None).await?
+        }
+    };
 
-    let body = KnownSize::file(file)
-        .await
-        .map_err(|err| ApiErrorKind::GettingFileMetadataFailed(format!("{err:?}")))?;
-    let range = range.map(|TypedHeader(range)| range);
-    Ok(Ranged::new(range, body).into_response())
+    let response = match range {
+        Some(TypedHeader(range)) => ranged_response(file, &range)?,
+        None => full_file_response(file),
+    };
+    // the size of the served range, or 0 if it was not satisfiable
+    let served = response.body().size_hint().exact().unwrap_or_default();
+    repo_stats().record_read(path, served);
+
+    Ok(response)
+}
+
+/// `get_config_history`
+/// Interface: GET {repo}/config/history
+///
+/// Returns the kept previous versions of the config, newest first.
+pub async fn get_config_history(
+    RepositoryConfigHistoryPath { repo }: RepositoryConfigHistoryPath,
+    BasicAuthFromRequest { user, .. }: BasicAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    let tpe = TpeKind::Config;
+
+    tracing::debug!(path = %repo, "[get_config_history]");
+
+    let path = &check_auth_and_acl(user, tpe, Path::new(&repo), AccessType::Read)?;
+
+    let storage = STORAGE.get().unwrap();
+    Ok(Json(storage.config_backups(path).await))
 }
 
 /// `add_config`
@@ -92,11 +219,29 @@ pub async fn add_config<P: PathParts>(
     let repo = path.repo().unwrap();
     tracing::debug!("[add_config] repository path: {repo}, tpe: {tpe}");
     let path = PathBuf::from(&repo);
-    let file = get_save_file(auth.user, path, Some(tpe), None).await?;
+    check_content_type(&request)?;
+    let size = content_length(&request);
+    let origin = Origin {
+        user: &auth.user,
+        tpe: tpe.into_str(),
+        name: None,
+    };
+    let (repo, file) = get_save_file(auth.user.clone(), path, Some(tpe), None, size).await?;
+
+    if VALIDATE_CONFIG.get().copied().unwrap_or_default() {
+        let body = body::to_bytes(request.into_body(), MAX_CONFIG_BYTES)
+            .await
+            .map_err(|err| ApiErrorKind::BadRequest(format!("invalid config: {err}")))?;
+        validate_config(&body)?;
+        let stream = stream::once(async { Ok::<_, axum::Error>(body) });
+        let _ = save_body(&repo, file, stream, size, false, origin).await?;
+        return Ok(success_status());
+    }
 
     let stream = request.into_body().into_data_stream();
-    let _ = save_body(file, stream).await?;
-    Ok(())
+    // an empty config is left to the client to judge
+    let _ = save_body(&repo, file, stream, size, false, origin).await?;
+    Ok(success_status())
 }
 
 /// `delete_config`
@@ -112,21 +257,27 @@ pub async fn delete_config<P: PathParts>(
 
     let _ = check_name(tpe, None)?;
     let path = Path::new(&repo);
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Append)?;
+    let path = &check_auth_and_acl(auth.user, tpe, path, AccessType::Delete)?;
 
     let storage = STORAGE.get().unwrap();
+    storage
+        .backup_config(path, CONFIG_HISTORY.get().copied().unwrap_or_default())
+        .await?;
     storage
         .remove_file(path, tpe.into_str(), None)
         .await
         .map_err(|err| ApiErrorKind::RemovingFileFailed(format!("{err:?}")))?;
-    Ok(())
+    Ok(success_status())
 }
 
 #[cfg(test)]
 mod test {
     use crate::{
         handlers::{
-            file_config::{add_config, delete_config, get_config, has_config},
+            file_config::{
+                add_config, delete_config, get_config, get_config_history, has_config,
+                validate_config,
+            },
             repository::{create_repository, delete_repository},
         },
         log::print_request_response,
@@ -140,7 +291,7 @@ mod test {
 
     use axum::{
         body::Body,
-        http::{Method, Request, StatusCode},
+        http::{header, HeaderValue, Method, Request, StatusCode},
         middleware, Router,
     };
     use axum_extra::routing::RouterExt; // for `Router::typed_*`
@@ -346,4 +497,143 @@ mod test {
         let body_str = byte_vec.to_vec();
         assert_eq!(body_str, test_vec);
     }
+
+    #[tokio::test]
+    async fn test_get_config_history_passes() {
+        init_test_environment(server_config());
+
+        let path = PathBuf::from("tests/generated/test_storage/repo_config_history");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("config"), "current").unwrap();
+        fs::write(path.join("config.bak.1"), "previous").unwrap();
+
+        let app = Router::new()
+            .typed_get(get_config::<RepositoryConfigPath>)
+            .typed_get(get_config_history)
+            .layer(middleware::from_fn(print_request_response));
+
+        let request = request_uri_for_test("/repo_config_history/config/history", Method::GET);
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let history: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(history, serde_json::json!([{"version": 1, "size": 8}]));
+
+        let request = request_uri_for_test("/repo_config_history/config?version=1", Method::GET);
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"previous");
+
+        let request = request_uri_for_test("/repo_config_history/config?version=2", Method::GET);
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_config_range_passes() {
+        init_test_environment(server_config());
+
+        let path = PathBuf::from("tests/generated/test_storage/repo_config_range");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("config"), "config").unwrap();
+
+        let app = Router::new()
+            .typed_get(get_config::<RepositoryConfigPath>)
+            .layer(middleware::from_fn(print_request_response));
+
+        let get = |range: &'static str| {
+            let app = app.clone();
+            async move {
+                let mut request = request_uri_for_test("/repo_config_range/config", Method::GET);
+                let _ = request
+                    .headers_mut()
+                    .insert(header::RANGE, HeaderValue::from_static(range));
+                let resp = app.oneshot(request).await.unwrap();
+                let status = resp.status();
+                let content_range = resp
+                    .headers()
+                    .get(header::CONTENT_RANGE)
+                    .map(|value| value.to_str().unwrap().to_string());
+                let body = resp.into_body().collect().await.unwrap().to_bytes();
+                (
+                    status,
+                    content_range,
+                    String::from_utf8(body.to_vec()).unwrap(),
+                )
+            }
+        };
+        let partial = |content_range: &str, body: &str| {
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Some(content_range.to_string()),
+                body.to_string(),
+            )
+        };
+        let not_satisfiable = (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            Some("bytes */6".to_string()),
+            "range not satisfiable: the file has 6 bytes".to_string(),
+        );
+
+        assert_eq!(get("bytes=0-0").await, partial("bytes 0-0/6", "c"));
+        assert_eq!(get("bytes=0-").await, partial("bytes 0-5/6", "config"));
+        assert_eq!(get("bytes=0-5").await, partial("bytes 0-5/6", "config"));
+        assert_eq!(get("bytes=-3").await, partial("bytes 3-5/6", "fig"));
+        // ranges ending past the end are cut off, e.g. when read with a buffer
+        assert_eq!(get("bytes=0-65535").await, partial("bytes 0-5/6", "config"));
+        assert_eq!(get("bytes=2-100").await, partial("bytes 2-5/6", "nfig"));
+        assert_eq!(get("bytes=5-5").await, partial("bytes 5-5/6", "g"));
+        // ranges starting past the end can't be served
+        assert_eq!(get("bytes=6-").await, not_satisfiable);
+        assert_eq!(get("bytes=100-200").await, not_satisfiable);
+        // invalid ranges are refused
+        assert_eq!(
+            get("bytes=abc").await,
+            (
+                StatusCode::BAD_REQUEST,
+                None,
+                "range not valid: `abc` is no byte range".to_string()
+            )
+        );
+
+        fs::write(path.join("config"), "").unwrap();
+        let (status, content_range, _) = get("bytes=0-0").await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(content_range.as_deref(), Some("bytes */0"));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_validate_config_passes() {
+        let id = "3f918b737a2b9f72f044d06d6009eb34e0e8d06668209be3ce86e5c18dac0295";
+        let config = |version: u32, id: &str, polynomial: &str| {
+            format!(r#"{{"version":{version},"id":"{id}","chunker_polynomial":"{polynomial}"}}"#)
+        };
+
+        assert!(validate_config(config(2, id, "3dea92648f6e83").as_bytes()).is_ok());
+        assert!(
+            validate_config(format!(" \n{}", config(1, id, "3dea92648f6e83")).as_bytes()).is_ok()
+        );
+        // encrypted configs are only checked for their size
+        assert!(validate_config(&[0x8e; 200]).is_ok());
+
+        for invalid in [
+            config(3, id, "3dea92648f6e83"),
+            config(2, "abc", "3dea92648f6e83"),
+            config(2, id, ""),
+            config(2, id, "xyz"),
+            r#"{"version":2}"#.to_string(),
+            "{garbage".to_string(),
+            "Hello World".to_string(),
+            String::new(),
+        ] {
+            assert!(validate_config(invalid.as_bytes()).is_err(), "{invalid}");
+        }
+    }
 }
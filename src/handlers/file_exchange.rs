@@ -2,25 +2,115 @@ use std::{
     io,
     path::{Path, PathBuf},
     result::Result,
+    sync::OnceLock,
 };
 
-use axum::{body::Bytes, extract::Request, http::StatusCode, response::IntoResponse, BoxError};
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    BoxError,
+};
 use axum_extra::{headers::Range, TypedHeader};
-use axum_range::{KnownSize, Ranged};
 use futures::{Stream, TryStreamExt};
 use futures_util::pin_mut;
-use tokio::io::AsyncWrite;
-use tokio_util::io::StreamReader;
+use tokio::io::{AsyncWrite, BufWriter};
+use tokio_util::io::{ReaderStream, StreamReader};
 
 use crate::{
     acl::AccessType,
+    as_of::AsOf,
     auth::BasicAuthFromRequest,
+    config::DEFAULT_IO_BUFFER_SIZE,
     error::{ApiErrorKind, ApiResult},
-    handlers::{access_check::check_auth_and_acl, file_helpers::Finalizer},
-    storage::STORAGE,
+    handlers::{
+        access_check::check_auth_and_acl,
+        file_helpers::{success_status, Finalizer},
+        file_range::ranged_response,
+    },
+    provenance::Origin,
+    stats::repo_stats,
+    storage::{Storage, StorageFile, STORAGE},
     typed_path::{PathParts, TpeKind},
 };
 
+/// Free space to be left after an upload, e.g. for metadata of the filesystem
+const FREE_SPACE_MARGIN: u64 = 16 * 1024 * 1024;
+
+// Global that stores whether empty uploads of content addressed files are rejected
+static REJECT_EMPTY_UPLOADS: OnceLock<bool> = OnceLock::new();
+
+pub fn init_reject_empty_uploads(reject: bool) {
+    let _ = REJECT_EMPTY_UPLOADS.get_or_init(|| reject);
+}
+
+/// Returns whether empty uploads of content addressed files are rejected,
+/// which is the default
+pub(crate) fn reject_empty_uploads() -> bool {
+    REJECT_EMPTY_UPLOADS.get().copied().unwrap_or(true)
+}
+
+// Global that stores whether uploads must be sent as `application/octet-stream`
+static STRICT_CONTENT_TYPE: OnceLock<bool> = OnceLock::new();
+
+pub fn init_strict_content_type(strict: bool) {
+    let _ = STRICT_CONTENT_TYPE.get_or_init(|| strict);
+}
+
+// Global that stores whether uploads require an explicitly created repository
+static REQUIRE_EXPLICIT_CREATE: OnceLock<bool> = OnceLock::new();
+
+pub fn init_require_explicit_create(require: bool) {
+    let _ = REQUIRE_EXPLICIT_CREATE.get_or_init(|| require);
+}
+
+// Global that stores the size of the buffer between the network and the disk
+static IO_BUFFER_SIZE: OnceLock<usize> = OnceLock::new();
+
+pub fn init_io_buffer_size(size: usize) {
+    let _ = IO_BUFFER_SIZE.get_or_init(|| size);
+}
+
+/// Size of the buffer between the network and the disk of each transfer
+pub fn io_buffer_size() -> usize {
+    IO_BUFFER_SIZE
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_IO_BUFFER_SIZE)
+}
+
+/// `Content-Type` of uploads, as sent by restic
+const UPLOAD_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Checks the `Content-Type` of an upload, if strict content types are enabled
+pub fn check_content_type(request: &Request) -> ApiResult<()> {
+    if STRICT_CONTENT_TYPE.get().copied().unwrap_or_default() {
+        check_upload_content_type(request.headers())
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that the `Content-Type` of an upload is `application/octet-stream`
+fn check_upload_content_type(headers: &HeaderMap) -> ApiResult<()> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    // parameters like `charset` don't matter for binary data
+    let media_type = content_type
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+
+    match media_type {
+        Some(media_type) if media_type.eq_ignore_ascii_case(UPLOAD_CONTENT_TYPE) => Ok(()),
+        _ => Err(ApiErrorKind::UnsupportedMediaType(format!(
+            "expected `{UPLOAD_CONTENT_TYPE}`, got `{}`",
+            content_type.unwrap_or_default()
+        ))),
+    }
+}
+
 /// `add_file`
 /// Interface: POST {path}/{type}/{name}
 /// Background info: <https://github.com/tokio-rs/axum/blob/main/examples/stream-to-file/src/main.rs>
@@ -37,13 +127,32 @@ pub async fn add_file<P: PathParts>(
 
     //credential & access check executed in get_save_file()
     let path = PathBuf::from(&path_str);
-    let file = get_save_file(auth.user, path, tpe, name).await?;
+
+    check_content_type(&request)?;
+
+    // Blobs are named after the hash of their content, so an existing blob of
+    // the announced size doesn't have to be transferred again
+    let size = content_length(&request);
+    if let Some(size) = size {
+        if blob_exists(auth.user.clone(), &path, tpe, name.as_deref(), size).await? {
+            tracing::debug!(?path, ?tpe, ?name, size, "[add_file] blob already exists");
+            return Ok(success_status());
+        }
+    }
+
+    let reject_empty = reject_empty_uploads() && tpe != Some(TpeKind::Config);
+    let origin = Origin {
+        user: &auth.user,
+        tpe: tpe.map_or("", TpeKind::into_str),
+        name: name.as_deref(),
+    };
+    let (repo, file) = get_save_file(auth.user.clone(), path, tpe, name.clone(), size).await?;
 
     let stream = request.into_body().into_data_stream();
-    let _ = save_body(file, stream).await?;
+    let _ = save_body(&repo, file, stream, size, reject_empty, origin).await?;
 
     //FIXME: Do we need to check if the file exists here? (For now it seems we should get an error if NOK)
-    Ok(())
+    Ok(success_status())
 }
 
 /// `delete_file`
@@ -59,7 +168,7 @@ pub async fn delete_file<P: PathParts>(
     let path = Path::new(&path_str);
 
     let _ = check_name(tpe, name.as_deref())?;
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Append)?;
+    let path = &check_auth_and_acl(auth.user, tpe, path, AccessType::Delete)?;
 
     let tpe = if let Some(tpe) = tpe {
         tpe.into_str()
@@ -70,16 +179,26 @@ pub async fn delete_file<P: PathParts>(
     let storage = STORAGE.get().unwrap();
 
     storage.remove_file(path, tpe, name.as_deref()).await?;
+    if let Err(err) = storage.remove_provenance(path, tpe, name.as_deref()).await {
+        tracing::warn!(?path, tpe, ?name, "Could not remove the provenance: {err}");
+    }
 
-    Ok(())
+    Ok(success_status())
 }
 
 /// `get_file`
 /// Interface: GET {path}/{type}/{name}
+///
+/// If the client accepts gzip and a precompressed `<name>.gz` is stored
+/// alongside the file, that one is served with `Content-Encoding: gzip`.
+/// Ranges always refer to the plain file, and without it the variant is not
+/// served either.
 pub async fn get_file<P: PathParts>(
     path: P,
     auth: BasicAuthFromRequest,
+    as_of: AsOf,
     range: Option<TypedHeader<Range>>,
+    headers: HeaderMap,
 ) -> ApiResult<impl IntoResponse> {
     let (path, tpe, name) = path.parts();
 
@@ -91,7 +210,7 @@ pub async fn get_file<P: PathParts>(
 
     let path = Path::new(&path_str);
 
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+    let path = &check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
 
     let tpe = if let Some(tpe) = tpe {
         tpe.into_str()
@@ -100,22 +219,57 @@ pub async fn get_file<P: PathParts>(
     };
 
     let storage = STORAGE.get().unwrap();
+    as_of.check_file(storage.as_ref(), path, tpe, name.as_deref())?;
 
     let file = storage.open_file(path, tpe, name.as_deref()).await?;
 
-    let body = KnownSize::file(file)
-        .await
-        .map_err(|err| ApiErrorKind::GettingFileMetadataFailed(format!("{err:?}")))?;
-
-    let range = range.map(|TypedHeader(range)| range);
+    if range.is_none() && accepts_gzip(&headers) {
+        if let Some(file) = storage.open_gzip_variant(path, tpe, name.as_deref()).await {
+            tracing::debug!(
+                ?path,
+                "type" = tpe,
+                ?name,
+                "[get_file] serving gzip variant"
+            );
+            repo_stats().record_read(path, file.len());
+            let mut response = full_file_response(file);
+            let headers = response.headers_mut();
+            let _ = headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            let _ = headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+            return Ok(response);
+        }
+    }
 
-    let status_code = if range.is_some() {
-        StatusCode::PARTIAL_CONTENT
-    } else {
-        StatusCode::OK
+    let Some(TypedHeader(range)) = range else {
+        repo_stats().record_read(path, file.len());
+        return Ok(full_file_response(file));
     };
 
-    Ok((status_code, Ranged::new(range, body)).into_response())
+    let response = ranged_response(file, &range)?;
+    // the size of the served range, or 0 if it was not satisfiable
+    let served = response.body().size_hint().exact().unwrap_or_default();
+    repo_stats().record_read(path, served);
+
+    Ok(response)
+}
+
+/// Streams the complete file straight into the response body.
+///
+/// Without a range there is nothing to seek, so we skip the `Ranged` machinery
+/// and hand the file to the body in large chunks.
+pub fn full_file_response(file: StorageFile) -> Response {
+    let length = file.len();
+
+    let body = Body::from_stream(ReaderStream::with_capacity(file, io_buffer_size()));
+
+    (
+        [
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+        ],
+        body,
+    )
+        .into_response()
 }
 
 //==============================================================================
@@ -123,17 +277,69 @@ pub async fn get_file<P: PathParts>(
 //
 //==============================================================================
 
-/// Returns a stream for the given path in the repository.
+/// Returns `true` if the `Accept-Encoding` header of the request allows gzip
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            // `q=0` explicitly rules a coding out
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/// Returns `true` if the content addressed file `name` already exists in the
+/// repository with the given size.
+async fn blob_exists(
+    user: String,
+    path: &Path,
+    tpe: Option<TpeKind>,
+    name: Option<&str>,
+    size: u64,
+) -> ApiResult<bool> {
+    let (Some(tpe), Some(name)) = (tpe, name) else {
+        return Ok(false);
+    };
+    if tpe == TpeKind::Config || !is_sha256_hex(name) {
+        return Ok(false);
+    }
+
+    let path = check_auth_and_acl(user, tpe, path, AccessType::Append)?;
+
+    let storage = STORAGE.get().unwrap();
+    match storage.file_length(&path, tpe.into_str(), Some(name)).await {
+        Ok(length) => Ok(length == size),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Returns the path of the repository in the storage and a stream for the
+/// given file in it.
+///
+/// If the client declared the `size` of the upload, it is refused with
+/// `507 Insufficient Storage` before the body is read if it doesn't fit into
+/// the free space of the storage.
 pub async fn get_save_file(
     user: String,
     path: PathBuf,
     tpe: Option<TpeKind>,
     name: Option<String>,
-) -> ApiResult<impl AsyncWrite + Unpin + Finalizer> {
+    size: Option<u64>,
+) -> ApiResult<(PathBuf, impl AsyncWrite + Unpin + Finalizer)> {
     tracing::debug!("[get_save_file] path: {path:?}, tpe: {tpe:?}, name: {name:?}");
 
     let _ = check_name(tpe, name.as_deref())?;
-    let _ = check_auth_and_acl(user, tpe, path.as_path(), AccessType::Append)?;
+    let path = check_auth_and_acl(user, tpe, path.as_path(), AccessType::Append)?;
 
     let tpe = if let Some(tpe) = tpe {
         tpe.into_str()
@@ -142,31 +348,136 @@ pub async fn get_save_file(
     };
 
     let storage = STORAGE.get().unwrap();
-    storage.create_file(&path, tpe, name.as_deref()).await
+    check_repository_created(
+        REQUIRE_EXPLICIT_CREATE.get().copied().unwrap_or_default(),
+        storage.as_ref(),
+        &path,
+        tpe,
+    )?;
+    if let Some(size) = size {
+        check_available_space(storage.as_ref(), &path, size)?;
+    }
+    let file = storage.create_file(&path, tpe, name.as_deref()).await?;
+    Ok((path, file))
+}
+
+/// Fails with `RepositoryNotFound` if uploads `require` an explicitly created
+/// repository and the repository at `path` lacks the directory of `tpe`
+///
+/// The config is the first file written to a new repository, so for it the
+/// `keys` directory has to exist.
+fn check_repository_created(
+    require: bool,
+    storage: &dyn Storage,
+    path: &Path,
+    tpe: &str,
+) -> ApiResult<()> {
+    let dir = if tpe == TpeKind::Config.into_str() {
+        TpeKind::Keys.into_str()
+    } else {
+        tpe
+    };
+
+    if require && !storage.filename(path, dir, None).is_dir() {
+        tracing::warn!(
+            ?path,
+            "Refusing upload to a repository which wasn't created."
+        );
+        return Err(ApiErrorKind::RepositoryNotFound(format!(
+            "{} has to be created first",
+            path.display()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Fails with `InsufficientStorage` if an upload of `size` bytes would leave
+/// less than [`FREE_SPACE_MARGIN`] of the free space for the repository
+fn check_available_space(storage: &dyn Storage, path: &Path, size: u64) -> ApiResult<()> {
+    let Some(available) = storage.available_space(path) else {
+        return Ok(());
+    };
+
+    if size.saturating_add(FREE_SPACE_MARGIN) > available {
+        tracing::warn!(
+            ?path,
+            size,
+            available,
+            "Refusing upload exceeding the free space."
+        );
+        return Err(ApiErrorKind::InsufficientStorage(format!(
+            "upload of {size} bytes exceeds the free space of {available} bytes"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the `Content-Length` declared by the request, if any
+pub fn content_length(request: &Request) -> Option<u64> {
+    request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
 }
 
 /// saves the content in the HTML request body to a file stream.
+///
+/// The written bytes are counted for the repository at `repo`, and the time
+/// of the write once the file is finalized. If the body doesn't have the
+/// `expected_len` declared by the client, e.g. because the connection dropped,
+/// or is empty although `reject_empty` is set, the file is not finalized and
+/// thus removed.
 pub async fn save_body<S, E>(
+    repo: &Path,
     mut write_stream: impl AsyncWrite + Unpin + Finalizer + Send,
     stream: S,
+    expected_len: Option<u64>,
+    reject_empty: bool,
+    origin: Origin<'_>,
 ) -> ApiResult<impl IntoResponse>
 where
     S: Stream<Item = Result<Bytes, E>> + Send,
     E: Into<BoxError>,
 {
     // Convert the stream into an `AsyncRead`.
-    let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+    let body_with_io_error = stream.map_err(io::Error::other);
     let body_reader = StreamReader::new(body_with_io_error);
     pin_mut!(body_reader);
-    let byte_count = match tokio::io::copy(&mut body_reader, &mut write_stream).await {
+    // The body is only polled for more data once the buffer was written, so a
+    // slow disk slows down the client instead of piling up data in memory.
+    let mut writer = BufWriter::with_capacity(io_buffer_size(), &mut write_stream);
+    let byte_count = match tokio::io::copy_buf(&mut body_reader, &mut writer).await {
         Ok(b) => b,
-        Err(err) => return Err(ApiErrorKind::FinalizingFileFailed(format!("{:?}", err))),
+        Err(err) => {
+            return Err(ApiErrorKind::from_io_error(
+                &err,
+                "Could not write file",
+                ApiErrorKind::FinalizingFileFailed,
+            ))
+        }
     };
 
     tracing::debug!("[file written] bytes: {byte_count}");
-    write_stream.finalize().await.map_err(|err| {
-        ApiErrorKind::FinalizingFileFailed(format!("Could not finalize file: {}", err))
-    })
+    repo_stats().record_written(repo, byte_count);
+
+    if let Some(expected_len) = expected_len.filter(|&len| len != byte_count) {
+        return Err(ApiErrorKind::BadRequest(format!(
+            "received {byte_count} bytes, but Content-Length is {expected_len}"
+        )));
+    }
+    if reject_empty && byte_count == 0 {
+        return Err(ApiErrorKind::BadRequest(
+            "empty uploads are not allowed".to_string(),
+        ));
+    }
+
+    write_stream.finalize().await?;
+    repo_stats().record_write_time(repo);
+    origin.record(repo, byte_count).await;
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -176,6 +487,12 @@ const fn check_string_sha256(_name: &str) -> bool {
 
 #[cfg(not(test))]
 fn check_string_sha256(name: &str) -> bool {
+    is_sha256_hex(name)
+}
+
+/// Returns `true` if `name` looks like a lowercase hex encoded SHA256 hash,
+/// which is how restic names all blobs except the `config`.
+pub fn is_sha256_hex(name: &str) -> bool {
     if name.len() != 64 {
         return false;
     }
@@ -205,19 +522,31 @@ pub fn check_name(
 #[cfg(test)]
 mod test {
     use crate::{
-        handlers::file_exchange::{add_file, delete_file, get_file},
+        as_of::{reject_writes_as_of, AS_OF_HEADER},
+        error::ApiErrorKind,
+        handlers::{
+            file_config::delete_config,
+            file_exchange::{
+                add_file, check_repository_created, check_upload_content_type, delete_file,
+                get_file,
+            },
+        },
         log::print_request_response,
+        storage::{LocalStorage, Storage},
         testing::{
             basic_auth_header_value, init_test_environment, request_uri_for_test, server_config,
         },
-        typed_path::RepositoryTpeNamePath,
+        typed_path::{RepositoryConfigPath, RepositoryTpeNamePath},
     };
 
-    use std::{fs, path::PathBuf};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
 
     use axum::{
         body::Body,
-        http::{header, Method, Request, StatusCode},
+        http::{header, HeaderMap, Method, Request, StatusCode},
         middleware, Router,
     };
     use axum_extra::routing::RouterExt; // for `Router::typed_*`
@@ -296,6 +625,209 @@ mod test {
         assert!(!path.exists());
     }
 
+    #[tokio::test]
+    async fn test_add_existing_blob_skips_write_passes() {
+        init_test_environment(server_config());
+
+        let file_name = "0ddba11ca5ca0ddba11ca5ca0ddba11ca5ca0ddba11ca5ca0ddba11ca5ca0dd0";
+        let path = PathBuf::from("tests/generated/test_storage/test_repo/keys").join(file_name);
+        if path.exists() {
+            fs::remove_file(&path).unwrap();
+        }
+
+        let upload = |content: &'static str| {
+            let app = Router::new()
+                .typed_post(add_file::<RepositoryTpeNamePath>)
+                .layer(middleware::from_fn(print_request_response));
+
+            let request = Request::builder()
+                .uri(["/test_repo/keys/", file_name].concat())
+                .method(Method::POST)
+                .header(header::CONTENT_LENGTH, content.len())
+                .header(
+                    "Authorization",
+                    basic_auth_header_value("rustic", Some("rustic")),
+                )
+                .body(Body::new(content.to_string()))
+                .unwrap();
+
+            app.oneshot(request)
+        };
+
+        let resp = upload("Hello World").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // same name and size: accepted, but not written again
+        let resp = upload("Hello_World").await.unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(content, "Hello World");
+    }
+
+    #[tokio::test]
+    async fn test_add_truncated_file_fails() {
+        init_test_environment(server_config());
+
+        let file_name = "__add_file_test_truncated__";
+        let path = PathBuf::from("tests/generated/test_storage/test_repo/keys").join(file_name);
+
+        let app = Router::new()
+            .typed_post(add_file::<RepositoryTpeNamePath>)
+            .layer(middleware::from_fn(print_request_response));
+
+        // the body ends before the declared length
+        let request = Request::builder()
+            .uri(["/test_repo/keys/", file_name].concat())
+            .method(Method::POST)
+            .header(header::CONTENT_LENGTH, 11)
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::new("Hello".to_string()))
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_add_file_exceeding_free_space_fails() {
+        init_test_environment(server_config());
+
+        let file_name = "__add_file_test_too_large__";
+        let path = PathBuf::from("tests/generated/test_storage/test_repo/keys").join(file_name);
+
+        let app = Router::new()
+            .typed_post(add_file::<RepositoryTpeNamePath>)
+            .layer(middleware::from_fn(print_request_response));
+
+        // no disk is that large, so the upload is refused without a body
+        let request = Request::builder()
+            .uri(["/test_repo/keys/", file_name].concat())
+            .method(Method::POST)
+            .header(header::CONTENT_LENGTH, 1_u64 << 60)
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_add_empty_file_fails() {
+        init_test_environment(server_config());
+
+        let file_name = "__add_file_test_empty__";
+        let path = PathBuf::from("tests/generated/test_storage/test_repo/keys").join(file_name);
+
+        let app = Router::new()
+            .typed_post(add_file::<RepositoryTpeNamePath>)
+            .layer(middleware::from_fn(print_request_response));
+
+        let request = Request::builder()
+            .uri(["/test_repo/keys/", file_name].concat())
+            .method(Method::POST)
+            .header(header::CONTENT_LENGTH, 0)
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_check_repository_created_passes() {
+        let storage = LocalStorage::init(Path::new("tests/generated/test_storage")).unwrap();
+        let missing = Path::new("repo_never_created");
+
+        // uploads create the repository by default
+        assert!(check_repository_created(false, &storage, missing, "keys").is_ok());
+        assert!(check_repository_created(false, &storage, missing, "config").is_ok());
+
+        for tpe in ["keys", "config"] {
+            assert!(matches!(
+                check_repository_created(true, &storage, missing, tpe),
+                Err(ApiErrorKind::RepositoryNotFound(_))
+            ));
+        }
+
+        // the fixture repository only has a config and keys
+        let repo = Path::new("test_repo");
+        assert!(check_repository_created(true, &storage, repo, "keys").is_ok());
+        assert!(check_repository_created(true, &storage, repo, "config").is_ok());
+        assert!(check_repository_created(true, &storage, repo, "data").is_err());
+    }
+
+    #[test]
+    fn test_check_upload_content_type_passes() {
+        let mut headers = HeaderMap::new();
+        assert!(check_upload_content_type(&headers).is_err());
+
+        for content_type in [
+            "application/octet-stream",
+            "Application/Octet-Stream",
+            "application/octet-stream; charset=binary",
+        ] {
+            _ = headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            assert!(
+                check_upload_content_type(&headers).is_ok(),
+                "{content_type}"
+            );
+        }
+
+        for content_type in ["text/plain", "application/x-www-form-urlencoded"] {
+            _ = headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            assert!(
+                check_upload_content_type(&headers).is_err(),
+                "{content_type}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_file_to_reserved_repo_fails() {
+        init_test_environment(server_config());
+
+        // `tooling` is reserved in the test config, though the ACL allows access
+        let repo_dir = PathBuf::from("tests/generated/test_storage/tooling");
+
+        let app = Router::new()
+            .typed_post(add_file::<RepositoryTpeNamePath>)
+            .layer(middleware::from_fn(print_request_response));
+
+        let request = Request::builder()
+            .uri("/tooling/keys/__reserved_repo_file__")
+            .method(Method::POST)
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::new("Hello World".to_string()))
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(!repo_dir.exists());
+    }
+
     #[tokio::test]
     async fn test_get_file_passes() {
         init_test_environment(server_config());
@@ -363,6 +895,10 @@ mod test {
         let resp = app.clone().oneshot(request).await.unwrap();
 
         assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_LENGTH).unwrap(),
+            &test_vec.len().to_string()
+        );
 
         let (_parts, body) = resp.into_parts();
 
@@ -402,6 +938,47 @@ mod test {
 
         assert_eq!(body_str, test_vec);
 
+        //----------------------------------------
+        // Read past the end of the file
+        //----------------------------------------
+        let uri = ["/test_repo/keys/", file_name].concat();
+
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::GET)
+            .header(header::RANGE, "bytes=17-")
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.clone().oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */17");
+
+        //----------------------------------------
+        // Read a range ending before it starts
+        //----------------------------------------
+        let uri = ["/test_repo/keys/", file_name].concat();
+
+        let request = Request::builder()
+            .uri(uri)
+            .method(Method::GET)
+            .header(header::RANGE, "bytes=12-6")
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.clone().oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
         //----------------------------------------------
         // Clean up -> Delete test file
         //----------------------------------------------
@@ -418,4 +995,195 @@ mod test {
         assert_eq!(resp.status(), StatusCode::OK);
         assert!(!path.exists());
     }
+
+    #[tokio::test]
+    async fn test_delete_file_append_only_fails() {
+        init_test_environment(server_config());
+
+        let repo_dir = PathBuf::from("tests/generated/test_storage/repo_append_only_me");
+        let key = repo_dir.join("keys").join("__append_only_key__");
+        let lock = repo_dir.join("locks").join("__append_only_lock__");
+        let config = repo_dir.join("config");
+        for path in [&key, &lock, &config] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "content").unwrap();
+        }
+
+        let app = Router::new().typed_delete(delete_file::<RepositoryTpeNamePath>);
+
+        // even with `Modify` access
+        let request = request_uri_for_test(
+            "/repo_append_only_me/keys/__append_only_key__",
+            Method::DELETE,
+        );
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(key.exists());
+
+        // nor the config
+        let app_config = Router::new().typed_delete(delete_config::<RepositoryConfigPath>);
+        let request = request_uri_for_test("/repo_append_only_me/config", Method::DELETE);
+        let resp = app_config.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(config.exists());
+
+        // clients still remove their locks
+        let request = request_uri_for_test(
+            "/repo_append_only_me/locks/__append_only_lock__",
+            Method::DELETE,
+        );
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!lock.exists());
+
+        fs::remove_dir_all(repo_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_file_gzip_variant_passes() {
+        init_test_environment(server_config());
+
+        let file_name = "__get_file_test_gzip_variant__";
+        let keys_dir = PathBuf::from("tests/generated/test_storage/test_repo/keys");
+        let path = keys_dir.join(file_name);
+        let gz_path = keys_dir.join(format!("{file_name}.gz"));
+        fs::create_dir_all(&keys_dir).unwrap();
+        fs::write(&path, "plain").unwrap();
+        fs::write(&gz_path, "compressed").unwrap();
+
+        let app = Router::new().typed_get(get_file::<RepositoryTpeNamePath>);
+        let uri = ["/test_repo/keys/", file_name].concat();
+
+        let get = |accept_encoding: Option<&str>, range: Option<&str>| {
+            let mut request = Request::builder().uri(&uri).method(Method::GET).header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            );
+            if let Some(accept_encoding) = accept_encoding {
+                request = request.header(header::ACCEPT_ENCODING, accept_encoding);
+            }
+            if let Some(range) = range {
+                request = request.header(header::RANGE, range);
+            }
+            let request = request.body(Body::empty()).unwrap();
+
+            let app = app.clone();
+            async move {
+                let resp = app.oneshot(request).await.unwrap();
+                let status = resp.status();
+                let encoding = resp.headers().get(header::CONTENT_ENCODING).cloned();
+                let body = resp.into_body().collect().await.unwrap().to_bytes();
+                (status, encoding, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let (_, encoding, body) = get(Some("br, gzip;q=0.8"), None).await;
+        assert_eq!(encoding.unwrap(), "gzip");
+        assert_eq!(body, "compressed");
+
+        // the plain file is served if the client doesn't accept gzip ...
+        let (_, encoding, body) = get(None, None).await;
+        assert!(encoding.is_none());
+        assert_eq!(body, "plain");
+
+        let (_, encoding, body) = get(Some("gzip;q=0"), None).await;
+        assert!(encoding.is_none());
+        assert_eq!(body, "plain");
+
+        // ... or requests a range of it
+        let (_, encoding, body) = get(Some("gzip"), Some("bytes=1-2")).await;
+        assert!(encoding.is_none());
+        assert_eq!(body, "la");
+
+        fs::remove_file(&gz_path).unwrap();
+        let (_, encoding, body) = get(Some("gzip"), None).await;
+        assert!(encoding.is_none());
+        assert_eq!(body, "plain");
+
+        // deleting a file removes its variant, so it isn't served anymore
+        fs::write(&gz_path, "compressed").unwrap();
+        let request = Request::builder()
+            .uri(&uri)
+            .method(Method::DELETE)
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let resp = Router::new()
+            .typed_delete(delete_file::<RepositoryTpeNamePath>)
+            .oneshot(request)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!gz_path.exists());
+
+        let (status, encoding, _) = get(Some("gzip"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(encoding.is_none());
+
+        // a left over variant of a missing file is not served either
+        fs::write(&gz_path, "compressed").unwrap();
+        let (status, encoding, _) = get(Some("gzip"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(encoding.is_none());
+
+        fs::remove_file(&gz_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_file_as_of_passes() {
+        init_test_environment(server_config());
+
+        let file_name = "__get_file_test_as_of__";
+        let keys_dir = PathBuf::from("tests/generated/test_storage/test_repo/keys");
+        let path = keys_dir.join(file_name);
+        fs::create_dir_all(&keys_dir).unwrap();
+        fs::write(&path, "now").unwrap();
+
+        let app = Router::new()
+            .typed_get(get_file::<RepositoryTpeNamePath>)
+            .typed_post(add_file::<RepositoryTpeNamePath>)
+            .layer(middleware::from_fn(reject_writes_as_of));
+        let uri = ["/test_repo/keys/", file_name].concat();
+
+        let status = |method: Method, as_of: &str| {
+            let request = Request::builder()
+                .uri(&uri)
+                .method(method)
+                .header(
+                    "Authorization",
+                    basic_auth_header_value("rustic", Some("rustic")),
+                )
+                .header(&AS_OF_HEADER, as_of)
+                .body(Body::empty())
+                .unwrap();
+
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        // the file didn't exist back then
+        assert_eq!(
+            status(Method::GET, "2000-01-01T00:00:00Z").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(Method::GET, "2999-01-01T00:00:00Z").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Method::GET, "last week").await,
+            StatusCode::BAD_REQUEST
+        );
+
+        // the past can't be changed
+        assert_eq!(
+            status(Method::POST, "2999-01-01T00:00:00Z").await,
+            StatusCode::BAD_REQUEST
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -1,20 +1,42 @@
 use std::{
     cell::RefCell,
-    fs,
+    fs, io,
     io::Result as IoResult,
-    path::PathBuf,
+    path::{Path, PathBuf},
     pin::Pin,
     result::Result,
+    sync::OnceLock,
     task::{Context, Poll},
 };
 
+use axum::http::StatusCode;
 use serde::{Serialize, Serializer};
 use tokio::{
     fs::{File, OpenOptions},
     io::AsyncWrite,
 };
 
-use crate::error::{ApiErrorKind, ApiResult};
+use crate::{
+    error::{is_cross_device, ApiErrorKind, ApiResult},
+    uploads::ActiveUpload,
+};
+
+// Global that stores whether successes without a body are answered with `204 No Content`
+static NO_CONTENT_ON_SUCCESS: OnceLock<bool> = OnceLock::new();
+
+pub fn init_success_status(no_content: bool) {
+    let _ = NO_CONTENT_ON_SUCCESS.get_or_init(|| no_content);
+}
+
+/// Returns the status of successful responses without a body, i.e. of uploads
+/// and deletions
+pub fn success_status() -> StatusCode {
+    if NO_CONTENT_ON_SUCCESS.get().copied().unwrap_or_default() {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::OK
+    }
+}
 
 // helper struct which is like a async_std|tokio::fs::File but removes the file
 // if finalize() was not called.
@@ -22,6 +44,12 @@ use crate::error::{ApiErrorKind, ApiResult};
 pub struct WriteOrDeleteFile {
     file: File,
     path: PathBuf,
+    /// Final path of the file, if it is written to a temporary directory first
+    target: Option<PathBuf>,
+    /// Sync the directory of the file when it is finalized
+    fsync_dir: bool,
+    /// Registration of the upload, until the file is finalized or dropped
+    upload: Option<ActiveUpload>,
     finalized: bool,
 }
 
@@ -31,50 +59,197 @@ pub trait Finalizer {
 }
 
 impl WriteOrDeleteFile {
-    pub async fn new(path: PathBuf) -> ApiResult<Self> {
-        tracing::debug!("[WriteOrDeleteFile] path: {path:?}");
+    /// Creates the file at `path`, or a `.part` file in `temp_dir` which is
+    /// moved to `path` when the file is finalized.
+    pub async fn new(path: PathBuf, temp_dir: Option<&Path>) -> ApiResult<Self> {
+        tracing::debug!("[WriteOrDeleteFile] path: {path:?}, temp_dir: {temp_dir:?}");
 
         if !path.exists() {
             let parent = path.parent().ok_or_else(|| {
                 ApiErrorKind::WritingToFileFailed("Could not get parent directory".to_string())
             })?;
 
-            fs::create_dir_all(parent).map_err(|err| {
-                ApiErrorKind::WritingToFileFailed(format!("Could not create directory: {}", err))
-            })?;
+            create_dir_all(parent)?;
         }
 
-        let file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&path)
-            .await
-            .map_err(|err| {
-                ApiErrorKind::WritingToFileFailed(format!("Could not write to file: {}", err))
-            })?;
+        let Some(temp_dir) = temp_dir else {
+            let file = create_new(&path).await?;
+            return Ok(Self {
+                file,
+                path,
+                target: None,
+                fsync_dir: false,
+                upload: None,
+                finalized: false,
+            });
+        };
+
+        // the existing file would only be overwritten on finalize otherwise
+        if path.exists() {
+            return Err(ApiErrorKind::WritingToFileFailed(format!(
+                "Could not write to file: {} already exists",
+                path.display()
+            )));
+        }
+
+        create_dir_all(temp_dir)?;
+        let part = temp_dir.join(format!("{}.part", uuid::Uuid::new_v4()));
+        let file = create_new(&part).await?;
 
         Ok(Self {
             file,
-            path,
+            path: part,
+            target: Some(path),
+            fsync_dir: false,
+            upload: None,
             finalized: false,
         })
     }
+
+    /// Sync the directory of the file when it is finalized, so its directory
+    /// entry survives a crash, too
+    pub fn set_fsync_dir(mut self, fsync_dir: bool) -> Self {
+        self.fsync_dir = fsync_dir;
+        self
+    }
+
+    /// Lists the file as active upload until it is finalized
+    pub fn set_upload(mut self, upload: ActiveUpload) -> Self {
+        self.upload = Some(upload);
+        self
+    }
+
+    /// Returns the path the content is written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Syncs the directory containing `path`, making the creation, renaming or
+/// removal of `path` durable
+///
+/// Directories can't be opened for syncing on Windows, so this does nothing there.
+pub async fn sync_parent_dir(path: &Path) -> ApiResult<()> {
+    let Some(dir) = path.parent() else {
+        return Ok(());
+    };
+
+    if cfg!(unix) {
+        let synced = async { File::open(dir).await?.sync_all().await }.await;
+        synced.map_err(|err| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not sync directory",
+                ApiErrorKind::FinalizingFileFailed,
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+fn create_dir_all(dir: &Path) -> ApiResult<()> {
+    fs::create_dir_all(dir).map_err(|err| {
+        ApiErrorKind::from_io_error(
+            &err,
+            "Could not create directory",
+            ApiErrorKind::WritingToFileFailed,
+        )
+    })
+}
+
+async fn create_new(path: &Path) -> ApiResult<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await
+        .map_err(|err| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not write to file",
+                ApiErrorKind::WritingToFileFailed,
+            )
+        })
+}
+
+/// Moves the file `from` to `to` within a filesystem, failing if `to` exists
+async fn rename_new(from: &Path, to: &Path) -> io::Result<()> {
+    // unlike `rename`, linking never replaces the target
+    match tokio::fs::hard_link(from, to).await {
+        Ok(()) => tokio::fs::remove_file(from).await,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists || is_cross_device(&err) => Err(err),
+        // filesystems without hard links, e.g. FAT
+        Err(_) if tokio::fs::try_exists(to).await? => Err(io::ErrorKind::AlreadyExists.into()),
+        Err(_) => tokio::fs::rename(from, to).await,
+    }
+}
+
+/// Moves the file `from` to `to` without replacing an existing file, copying
+/// it if they are on different filesystems
+async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match rename_new(from, to).await {
+        Err(err) if is_cross_device(&err) => {}
+        moved => return moved,
+    }
+
+    // copy to a `.part` file next to the target first, so the target is
+    // complete once it shows up
+    let part = to.with_file_name(format!("{}.part", uuid::Uuid::new_v4()));
+    let copied = async {
+        let _ = tokio::fs::copy(from, &part).await?;
+        File::open(&part).await?.sync_all().await?;
+        rename_new(&part, to).await
+    }
+    .await;
+
+    if let Err(err) = copied {
+        _ = tokio::fs::remove_file(&part).await;
+        return Err(err);
+    }
+
+    tokio::fs::remove_file(from).await
 }
 
 #[async_trait::async_trait]
 impl Finalizer for WriteOrDeleteFile {
     async fn finalize(&mut self) -> ApiResult<()> {
         self.file.sync_all().await.map_err(|err| {
-            ApiErrorKind::FinalizingFileFailed(format!("Could not sync file: {}", err))
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not sync file",
+                ApiErrorKind::FinalizingFileFailed,
+            )
         })?;
+
+        if let Some(target) = &self.target {
+            move_file(&self.path, target).await.map_err(|err| {
+                ApiErrorKind::from_io_error(
+                    &err,
+                    "Could not move file out of the temporary directory",
+                    ApiErrorKind::FinalizingFileFailed,
+                )
+            })?;
+        }
+
+        if self.fsync_dir {
+            sync_parent_dir(self.target.as_ref().unwrap_or(&self.path)).await?;
+        }
+
         self.finalized = true;
+        self.upload = None;
         Ok(())
     }
 }
 
 impl AsyncWrite for WriteOrDeleteFile {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
-        Pin::new(&mut self.get_mut().file).poll_write(cx, buf)
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.file).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(bytes)), Some(upload)) = (&written, &this.upload) {
+            upload.record_written(*bytes);
+        }
+        written
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
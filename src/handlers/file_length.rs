@@ -5,6 +5,7 @@ use axum::{http::header, response::IntoResponse};
 
 use crate::{
     acl::AccessType,
+    as_of::AsOf,
     auth::BasicAuthFromRequest,
     error::{ApiErrorKind, ApiResult},
     handlers::access_check::check_auth_and_acl,
@@ -17,6 +18,7 @@ use crate::{
 pub async fn file_length<P: PathParts>(
     path: P,
     auth: BasicAuthFromRequest,
+    as_of: AsOf,
 ) -> ApiResult<impl IntoResponse> {
     let (path, tpe, name) = path.parts();
 
@@ -26,7 +28,7 @@ pub async fn file_length<P: PathParts>(
 
     let path = Path::new(&path_str);
 
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+    let path = &check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
 
     let tpe = if let Some(tpe) = tpe {
         tpe.into_str()
@@ -35,34 +37,16 @@ pub async fn file_length<P: PathParts>(
     };
 
     let storage = STORAGE.get().unwrap();
+    as_of.check_file(storage.as_ref(), path, tpe, name.as_deref())?;
 
-    let file = storage.filename(path, tpe, name.as_deref());
+    // Files may not exist on their own (e.g. when packed), so we ask the storage
+    // for them. A missing file is reported as `FileNotFound`.
+    let length = storage.file_length(path, tpe, name.as_deref()).await?;
 
-    if file.exists() {
-        let storage = STORAGE.get().unwrap();
-
-        let file = storage
-            .open_file(path, tpe, name.as_deref())
-            .await
-            .map_err(|err| {
-                ApiErrorKind::OpeningFileFailed(format!("Could not open file: {err}"))
-            })?;
-
-        let length = file
-            .metadata()
-            .await
-            .map_err(|err| {
-                ApiErrorKind::GettingFileMetadataFailed(format!(
-                    "path: {path:?}, tpe: {tpe}, name: {name:?}, err: {err}"
-                ))
-            })?
-            .len()
-            .to_string();
-
-        Ok([(header::CONTENT_LENGTH, length)])
-    } else {
-        Err(ApiErrorKind::FileNotFound(path_str))
-    }
+    Ok([
+        (header::CONTENT_LENGTH, length.to_string()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+    ])
 }
 
 #[cfg(test)]
@@ -110,6 +94,7 @@ mod test {
             .unwrap();
 
         assert_eq!(length, "460");
+        assert_eq!(resp.headers()[header::ACCEPT_RANGES], "bytes");
 
         let b = resp
             .into_body()
@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use axum::{response::IntoResponse, Json};
+
+use crate::{
+    acl::AccessType,
+    auth::BasicAuthFromRequest,
+    error::ApiResult,
+    handlers::{access_check::check_auth_and_acl, file_exchange::check_name},
+    storage::STORAGE,
+    typed_path::RepositoryTpeNameMetaPath,
+};
+
+/// `file_meta`
+/// Interface: GET {path}/{type}/{name}/meta
+///
+/// Returns who uploaded the file, when and its size. Files saved while the
+/// provenance wasn't recorded have no record and are `404 Not Found`.
+pub async fn file_meta(
+    RepositoryTpeNameMetaPath { repo, tpe, name }: RepositoryTpeNameMetaPath,
+    auth: BasicAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!("[file_meta] repo: {repo:?}, tpe: {tpe:?}, name: {name:?}");
+
+    let _ = check_name(tpe, Some(&name))?;
+    let path = Path::new(&repo);
+    let path = &check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+
+    let tpe = tpe.into_str();
+    let storage = STORAGE.get().unwrap();
+
+    // records of removed files are not served
+    let _ = storage.file_length(path, tpe, Some(&name)).await?;
+    let provenance = storage.read_provenance(path, tpe, Some(&name)).await?;
+
+    Ok(Json(provenance))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use axum::{
+        http::{Method, StatusCode},
+        middleware, Router,
+    };
+    use axum_extra::routing::RouterExt; // for `Router::typed_*`
+    use chrono::{TimeZone, Utc};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+    use crate::{
+        handlers::file_meta::file_meta,
+        log::print_request_response,
+        provenance::{Provenance, PROVENANCE_DIR},
+        storage::STORAGE,
+        testing::{init_test_environment, request_uri_for_test, server_config},
+    };
+
+    #[tokio::test]
+    async fn test_file_meta_passes() {
+        init_test_environment(server_config());
+
+        let name = "3f918b737a2b9f72f044d06d6009eb34e0e8d06668209be3ce86e5c18dac0295";
+        let uri = format!("/test_repo/keys/{name}/meta");
+        let storage = STORAGE.get().unwrap();
+        let repo = Path::new("test_repo");
+        let records = storage.filename(repo, PROVENANCE_DIR, Some("keys"));
+
+        // ----------------------------------
+        // File without a record
+        // ----------------------------------
+        let app = Router::new()
+            .typed_get(file_meta)
+            .layer(middleware::from_fn(print_request_response));
+
+        let resp = app
+            .oneshot(request_uri_for_test(&uri, Method::GET))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // ----------------------------------
+        // File with a record
+        // ----------------------------------
+        let provenance = Provenance {
+            uploaded_by: "rustic".to_string(),
+            uploaded_at: Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap(),
+            size: 460,
+        };
+        storage
+            .write_provenance(repo, "keys", Some(name), &provenance)
+            .await
+            .unwrap();
+
+        let app = Router::new()
+            .typed_get(file_meta)
+            .layer(middleware::from_fn(print_request_response));
+
+        let resp = app
+            .oneshot(request_uri_for_test(&uri, Method::GET))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["uploaded_by"], "rustic");
+        assert_eq!(json["uploaded_at"], "2024-05-01T12:00:00Z");
+        assert_eq!(json["size"], 460);
+
+        // ----------------------------------
+        // Record of a missing file
+        // ----------------------------------
+        let missing = "0000000000000000000000000000000000000000000000000000000000000000";
+        storage
+            .write_provenance(repo, "keys", Some(missing), &provenance)
+            .await
+            .unwrap();
+
+        let app = Router::new()
+            .typed_get(file_meta)
+            .layer(middleware::from_fn(print_request_response));
+
+        let resp = app
+            .oneshot(request_uri_for_test(
+                &format!("/test_repo/keys/{missing}/meta"),
+                Method::GET,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        fs::remove_dir_all(records.parent().unwrap()).unwrap();
+    }
+}
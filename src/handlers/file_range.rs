@@ -0,0 +1,255 @@
+//! Byte ranges of downloads
+//!
+//! The `Range` header is parsed here instead of by `axum_range`, which
+//! silently drops specs it can't parse and only ever looks at the first one.
+//! Specs ending before they start or not following the grammar of RFC 9110
+//! (section 14.1.1) are refused with a message naming them, and the number of
+//! specs per request is limited. The satisfiable ranges are sorted and
+//! overlapping or adjacent ones are coalesced, so a header with many tiny
+//! ranges over the same bytes can't multiply the work done for it.
+
+use std::{ops::RangeInclusive, sync::OnceLock};
+
+use axum::response::{IntoResponse, Response};
+use axum_extra::headers::{Header, Range};
+use axum_range::{KnownSize, Ranged};
+
+use crate::{
+    config::DEFAULT_MAX_RANGES,
+    error::{ApiErrorKind, ApiResult},
+    storage::StorageFile,
+};
+
+// Global that stores the maximum number of ranges of a download
+static MAX_RANGES: OnceLock<usize> = OnceLock::new();
+
+pub fn init_max_ranges(max_ranges: usize) {
+    let _ = MAX_RANGES.get_or_init(|| max_ranges);
+}
+
+/// Returns the maximum number of ranges in the `Range` header of a download
+fn max_ranges() -> usize {
+    MAX_RANGES.get().copied().unwrap_or(DEFAULT_MAX_RANGES)
+}
+
+/// Responds with the first range of the file requested by `range`
+///
+/// Clients may read a small file with a range as large as their buffer, so the
+/// end of a range beyond the end of the file is clamped to it (RFC 9110,
+/// section 14.1.2) instead of refusing the range. If all ranges start at or
+/// after the end, the request is refused with `416 Range Not Satisfiable`.
+///
+/// Multipart responses are not supported, so of several ranges only the one
+/// at the lowest offset is served, after coalescing it with the ranges
+/// overlapping or adjoining it.
+pub fn ranged_response(file: StorageFile, range: &Range) -> ApiResult<Response> {
+    let length = file.len();
+    let ranges = ByteRanges::parse(range, length, max_ranges())?;
+
+    let mut satisfiable = ranges.iter();
+    let Some(first) = satisfiable.next() else {
+        return Err(ApiErrorKind::RangeNotSatisfiable(length));
+    };
+    if satisfiable.next().is_some() {
+        tracing::debug!(?ranges, "[ranged_response] only serving the first range");
+    }
+    let range = Range::bytes(first.clone())
+        .map_err(|_| ApiErrorKind::InternalError(format!("range {first:?} could not be served")))?;
+
+    Ok(Ranged::new(Some(range), KnownSize::sized(file, length)).into_response())
+}
+
+/// Satisfiable ranges requested by a `Range` header, sorted by their start
+/// and with overlapping or adjacent ones coalesced
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ByteRanges(Vec<RangeInclusive<u64>>);
+
+impl ByteRanges {
+    /// Parses the specs of `range` for a file of `length` bytes
+    ///
+    /// Specs starting at or after the end of the file are left out, so the
+    /// result is empty if none of them can be satisfied.
+    ///
+    /// # Errors
+    ///
+    /// * [`ApiErrorKind::RangeNotValid`] - If there are more than `max_ranges`
+    ///   specs or one of them is not valid, e.g. as it ends before it starts.
+    pub fn parse(range: &Range, length: u64, max_ranges: usize) -> ApiResult<Self> {
+        let mut values = Vec::new();
+        range.encode(&mut values);
+        // `Range` only decodes headers starting with `bytes=`
+        let specs = values
+            .first()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("bytes="))
+            .unwrap_or_default();
+
+        // empty list elements are allowed, see RFC 9110, section 5.6.1.2
+        let specs: Vec<_> = specs
+            .split(',')
+            .map(str::trim)
+            .filter(|spec| !spec.is_empty())
+            .collect();
+
+        if specs.is_empty() {
+            return Err(ApiErrorKind::RangeNotValid(
+                "no range was requested".to_string(),
+            ));
+        }
+        if specs.len() > max_ranges {
+            return Err(ApiErrorKind::RangeNotValid(format!(
+                "{} ranges were requested, at most {max_ranges} are allowed",
+                specs.len()
+            )));
+        }
+
+        let mut ranges = Vec::with_capacity(specs.len());
+        for spec in specs {
+            if let Some(range) = parse_spec(spec, length)? {
+                ranges.push(range);
+            }
+        }
+
+        Ok(Self(coalesce(ranges)))
+    }
+
+    /// Returns the ranges in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = &RangeInclusive<u64>> {
+        self.0.iter()
+    }
+}
+
+/// Parses a single range spec for a file of `length` bytes
+///
+/// Returns `None` if the range is not satisfiable, i.e. it starts at or after
+/// the end of the file, or it is an empty suffix.
+fn parse_spec(spec: &str, length: u64) -> ApiResult<Option<RangeInclusive<u64>>> {
+    let not_valid = || ApiErrorKind::RangeNotValid(format!("`{spec}` is no byte range"));
+
+    let (first, last) = spec.split_once('-').ok_or_else(not_valid)?;
+    let parse = |pos: &str| -> ApiResult<Option<u64>> {
+        let pos = pos.trim();
+        if pos.is_empty() {
+            return Ok(None);
+        }
+        // `u64::from_str` would also accept a leading `+`
+        if !pos.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(not_valid());
+        }
+        pos.parse().map(Some).map_err(|_| not_valid())
+    };
+
+    let range = match (parse(first)?, parse(last)?) {
+        (Some(first), Some(last)) if first > last => {
+            return Err(ApiErrorKind::RangeNotValid(format!(
+                "`{spec}` ends before it starts"
+            )));
+        }
+        (Some(first), last) => {
+            let last = last.unwrap_or(u64::MAX);
+            (first < length).then(|| first..=last.min(length - 1))
+        }
+        // the last `suffix` bytes, or the whole file if it is shorter
+        (None, Some(suffix)) => {
+            (suffix > 0 && length > 0).then(|| length.saturating_sub(suffix)..=length - 1)
+        }
+        (None, None) => return Err(not_valid()),
+    };
+
+    Ok(range)
+}
+
+/// Sorts `ranges` and merges the ones overlapping or adjoining each other
+pub fn coalesce(mut ranges: Vec<RangeInclusive<u64>>) -> Vec<RangeInclusive<u64>> {
+    ranges.sort_by_key(|range| *range.start());
+
+    let mut coalesced: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                *last = *last.start()..=*last.end().max(range.end());
+            }
+            _ => coalesced.push(range),
+        }
+    }
+
+    coalesced
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+    use axum_extra::headers::{Header, Range};
+
+    use crate::{
+        error::ApiErrorKind,
+        handlers::file_range::{coalesce, ByteRanges},
+    };
+
+    fn parse(header: &'static str, length: u64) -> Result<Vec<(u64, u64)>, ApiErrorKind> {
+        let value = HeaderValue::from_static(header);
+        let range = Range::decode(&mut [&value].into_iter()).unwrap();
+        ByteRanges::parse(&range, length, 4).map(|ranges| {
+            ranges
+                .iter()
+                .map(|range| (*range.start(), *range.end()))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_parse_ranges_passes() {
+        assert_eq!(parse("bytes=6-12", 17).unwrap(), [(6, 12)]);
+        // open and too long ranges are clamped to the end of the file
+        assert_eq!(parse("bytes=6-", 17).unwrap(), [(6, 16)]);
+        assert_eq!(parse("bytes=6-100", 17).unwrap(), [(6, 16)]);
+        // suffixes
+        assert_eq!(parse("bytes=-5", 17).unwrap(), [(12, 16)]);
+        assert_eq!(parse("bytes=-100", 17).unwrap(), [(0, 16)]);
+        // unsatisfiable ranges are left out
+        assert_eq!(parse("bytes=17-, 0-0", 17).unwrap(), [(0, 0)]);
+        assert!(parse("bytes=17-20", 17).unwrap().is_empty());
+        assert!(parse("bytes=-0", 17).unwrap().is_empty());
+        assert!(parse("bytes=0-", 0).unwrap().is_empty());
+        // overlapping and adjacent ranges are coalesced
+        assert_eq!(
+            parse("bytes=10-12, 0-1,2-3 ,,11-14", 17).unwrap(),
+            [(0, 3), (10, 14)]
+        );
+    }
+
+    #[test]
+    fn test_parse_ranges_fails() {
+        for header in [
+            "bytes=12-6",
+            "bytes=-",
+            "bytes=abc",
+            "bytes=1-2-3",
+            "bytes=+1-2",
+            "bytes=",
+            "bytes=0-1,2-3,4-5,6-7,8-9",
+        ] {
+            assert!(
+                matches!(parse(header, 17), Err(ApiErrorKind::RangeNotValid(_))),
+                "{header}"
+            );
+        }
+
+        let Err(err) = parse("bytes=12-6", 17) else {
+            panic!("range is not valid");
+        };
+        assert_eq!(
+            err.to_string(),
+            "Range not valid: `12-6` ends before it starts"
+        );
+    }
+
+    #[test]
+    fn test_coalesce_passes() {
+        assert_eq!(coalesce(vec![]), vec![]);
+        assert_eq!(coalesce(vec![5..=9, 0..=4]), vec![0..=9]);
+        assert_eq!(coalesce(vec![0..=9, 2..=3]), vec![0..=9]);
+        assert_eq!(coalesce(vec![0..=3, 5..=9]), vec![0..=3, 5..=9]);
+        assert_eq!(coalesce(vec![0..=u64::MAX, 5..=9]), vec![0..=u64::MAX]);
+    }
+}
@@ -1,22 +1,33 @@
-use std::{path::Path, str::FromStr};
+use std::{
+    io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
 
 use axum::{
+    body::{Body, Bytes},
+    extract::Query,
     http::{
         header::{self, AUTHORIZATION},
-        StatusCode,
+        HeaderName, HeaderValue, StatusCode,
     },
-    response::IntoResponse,
-    Json,
+    response::{IntoResponse, Response},
 };
 use axum_extra::headers::HeaderMap;
+use futures::stream;
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{sync::mpsc, task};
 
 use crate::{
     acl::AccessType,
+    as_of::AsOf,
     auth::BasicAuthFromRequest,
     error::{ApiErrorKind, ApiResult},
     handlers::{access_check::check_auth_and_acl, file_helpers::IteratorAdapter},
-    storage::STORAGE,
+    storage::{Storage, StorageEntry, GZIP_SUFFIX, STORAGE},
     typed_path::PathParts,
 };
 
@@ -35,6 +46,29 @@ impl ApiVersionKind {
     }
 }
 
+impl ApiVersionKind {
+    /// Returns the version of the listing for the `Accept` header of a request,
+    /// if it is one of the enabled `versions`
+    ///
+    /// A disabled V2 is answered with V1, as by servers without V2, so clients
+    /// fall back to it. Clients only accepting a disabled V1 are refused.
+    fn negotiate(accept: Option<&str>, versions: &RangeInclusive<u8>) -> ApiResult<Self> {
+        let requested = match accept {
+            Some(accept) if accept == Self::V2.to_static_str() => Self::V2,
+            _ => Self::V1,
+        };
+
+        match requested {
+            Self::V2 if !versions.contains(&2) => Ok(Self::V1),
+            Self::V1 if !versions.contains(&1) => Err(ApiErrorKind::InvalidApiVersion(format!(
+                "`{requested}` is disabled, accept `{}` instead",
+                Self::V2
+            ))),
+            version => Ok(version),
+        }
+    }
+}
+
 impl std::fmt::Display for ApiVersionKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -56,6 +90,25 @@ impl FromStr for ApiVersionKind {
     }
 }
 
+/// API versions of listings supported by the server
+pub const SUPPORTED_API_VERSIONS: RangeInclusive<u8> = 1..=2;
+
+// Global that stores the API versions of listings clients may use
+static API_VERSIONS: OnceLock<RangeInclusive<u8>> = OnceLock::new();
+
+pub fn init_api_versions(versions: RangeInclusive<u8>) {
+    let _ = API_VERSIONS.get_or_init(|| versions);
+}
+
+/// `Accept` header of clients which want a listing as newline-delimited JSON
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Number of NDJSON lines buffered while the client doesn't keep up
+const NDJSON_BUFFERED_LINES: usize = 256;
+
+/// Header with the cursor of the next page of a listing cut off by `limit`
+pub static NEXT_CURSOR_HEADER: HeaderName = HeaderName::from_static("x-next-cursor");
+
 /// List files
 /// Interface: GET {path}/{type}/
 #[derive(Serialize, Deserialize)]
@@ -64,9 +117,30 @@ struct RepoPathEntry {
     size: u64,
 }
 
+/// Query parameters of resumed listings
+///
+/// Listings with a cursor or a limit are sorted by name and only contain the
+/// files after the cursor, so they can be resumed even if files were added or
+/// removed in between.
+#[derive(Debug, Default, Deserialize)]
+pub struct ListQuery {
+    /// Cursor the listing continues after, as returned in `X-Next-Cursor`
+    cursor: Option<String>,
+    /// Maximum number of files listed
+    limit: Option<usize>,
+}
+
+impl ListQuery {
+    const fn is_paged(&self) -> bool {
+        self.cursor.is_some() || self.limit.is_some()
+    }
+}
+
 pub async fn list_files<P: PathParts>(
     path: P,
     auth: BasicAuthFromRequest,
+    as_of: AsOf,
+    Query(query): Query<ListQuery>,
     headers: HeaderMap,
 ) -> ApiResult<impl IntoResponse> {
     let (path, tpe, _) = path.parts();
@@ -77,89 +151,262 @@ pub async fn list_files<P: PathParts>(
 
     let path = Path::new(&path);
 
-    let _ = check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
+    let path = &check_auth_and_acl(auth.user, tpe, path, AccessType::Read)?;
 
-    let storage = STORAGE.get().unwrap();
+    if query.limit == Some(0) {
+        return Err(ApiErrorKind::BadRequest(
+            "`limit` must be at least 1".to_string(),
+        ));
+    }
 
-    let read_dir = storage.read_dir(path, tpe.map(|f| f.into()));
+    let storage = STORAGE.get().unwrap();
+    let tpe = tpe.map(|f| f.into_str());
+    let mut next_cursor = None;
 
-    let mut res = match headers
+    let accept = headers
         .get(header::ACCEPT)
-        .and_then(|header| header.to_str().ok())
-    {
-        Some(version) if version == ApiVersionKind::V2.to_static_str() => {
-            let read_dir_version = read_dir.map(|entry| {
-                RepoPathEntry {
-                    name: entry.file_name().to_str().unwrap().to_string(),
-                    size: entry.metadata().unwrap().len(),
-                    // FIXME:  return Err(WebErrorKind::GettingFileMetadataFailed.into());
-                }
+        .and_then(|header| header.to_str().ok());
+    let version = match accept {
+        Some(accept) if accept == NDJSON_CONTENT_TYPE => None,
+        accept => Some(ApiVersionKind::negotiate(
+            accept,
+            API_VERSIONS.get().unwrap_or(&SUPPORTED_API_VERSIONS),
+        )?),
+    };
+
+    let mut res = match version {
+        None => {
+            tracing::debug!("[list_files::dir_content] NDJSON");
+            ndjson_response(storage, path.clone(), tpe, as_of, query)
+        }
+        Some(ApiVersionKind::V2) => {
+            let read_dir = list_entries(storage.as_ref(), path, tpe, as_of, &query);
+            let read_dir = paginate(read_dir, query.limit, &mut next_cursor);
+            let read_dir_version = read_dir.map(|entry| RepoPathEntry {
+                name: entry.name,
+                size: entry.size,
             });
 
-            let mut response = Json(&IteratorAdapter::new(read_dir_version)).into_response();
+            tracing::debug!("[list_files::dir_content] Api V2");
 
-            tracing::debug!("[list_files::dir_content] Api V2 | {:?}", response.body());
+            json_listing(read_dir_version, ApiVersionKind::V2, &headers)?
+        }
+        Some(ApiVersionKind::V1) => {
+            let read_dir = list_entries(storage.as_ref(), path, tpe, as_of, &query);
+            let read_dir = paginate(read_dir, query.limit, &mut next_cursor);
+            let read_dir_version = read_dir.map(|entry| entry.name);
 
-            let _ = response.headers_mut().insert(
-                header::CONTENT_TYPE,
-                header::HeaderValue::from_static(ApiVersionKind::V2.to_static_str()),
-            );
+            tracing::debug!("[list_files::dir_content] Fallback to V1");
 
-            let status = response.status_mut();
+            json_listing(read_dir_version, ApiVersionKind::V1, &headers)?
+        }
+    };
 
-            *status = StatusCode::OK;
+    // anonymous requests have no credentials to echo
+    if let Some(authorization) = headers.get(AUTHORIZATION) {
+        let _ = res
+            .headers_mut()
+            .insert(AUTHORIZATION, authorization.clone());
+    }
 
-            response
-        }
-        _ => {
-            let read_dir_version = read_dir.map(|e| e.file_name().to_str().unwrap().to_string());
+    if let Some(cursor) = next_cursor.and_then(|cursor| HeaderValue::try_from(cursor).ok()) {
+        let _ = res.headers_mut().insert(&NEXT_CURSOR_HEADER, cursor);
+    }
+
+    Ok(res)
+}
+
+/// Answers with the listing as JSON in the given version, tagged with an
+/// `ETag` of its content
+///
+/// If the client already has the listing, as the tag is in its
+/// `If-None-Match` header, `304 Not Modified` is returned without the listing.
+/// The tag is weak, as the listing may be compressed on the way to the client.
+fn json_listing<T: serde::Serialize>(
+    entries: impl Iterator<Item = T>,
+    version: ApiVersionKind,
+    headers: &HeaderMap,
+) -> ApiResult<Response> {
+    let body = serde_json::to_vec(&IteratorAdapter::new(entries)).map_err(|err| {
+        ApiErrorKind::InternalError(format!("listing could not be serialized: {err}"))
+    })?;
+    let etag = listing_etag(version, &body);
+
+    let not_modified = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| etag_matches(value, &etag));
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
 
-            let mut response = Json(&IteratorAdapter::new(read_dir_version)).into_response();
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, version.to_static_str().to_string()),
+            (header::ETAG, etag),
+        ],
+        body,
+    )
+        .into_response())
+}
 
-            tracing::debug!(
-                "[list_files::dir_content] Fallback to V1 | {:?}",
-                response.body()
-            );
+/// Returns the weak `ETag` of a listing in the given version
+///
+/// The version is part of the tag, as an empty listing is the same in both.
+fn listing_etag(version: ApiVersionKind, body: &[u8]) -> String {
+    let hash = Sha256::new()
+        .chain_update(version.to_static_str())
+        .chain_update(body)
+        .finalize();
+    let hash: String = hash[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("W/\"{hash}\"")
+}
 
-            let _ = response.headers_mut().insert(
-                header::CONTENT_TYPE,
-                header::HeaderValue::from_static(ApiVersionKind::V1.to_static_str()),
-            );
+/// Returns whether the value of an `If-None-Match` header matches `etag`
+///
+/// Tags are compared weakly (RFC 9110, section 13.1.2), so a strong tag sent by
+/// the client matches the weak tag of the same listing.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
 
-            let status = response.status_mut();
+/// Returns the files of the type, as listed to clients
+fn list_entries<'a>(
+    storage: &'a dyn Storage,
+    path: &'a Path,
+    tpe: Option<&'a str>,
+    as_of: AsOf,
+    query: &'a ListQuery,
+) -> impl Iterator<Item = StorageEntry> + 'a {
+    let read_dir = if query.is_paged() {
+        storage.read_dir_after(path, tpe, query.cursor.as_deref())
+    } else {
+        storage.read_dir(path, tpe)
+    };
 
-            *status = StatusCode::OK;
+    // precompressed variants are served in place of their file, but are no
+    // files of the repository themselves
+    read_dir
+        .filter(|entry| !entry.name.ends_with(GZIP_SUFFIX))
+        .filter(move |entry| {
+            tpe.map_or(true, |tpe| {
+                as_of.includes_file(storage, path, tpe, Some(&entry.name))
+            })
+        })
+}
 
-            response
-        }
+/// Cuts the listing off after `limit` files
+///
+/// If files are left, the name of the last file listed is returned in
+/// `next_cursor`, so the listing can be resumed after it.
+fn paginate<'a>(
+    entries: impl Iterator<Item = StorageEntry> + 'a,
+    limit: Option<usize>,
+    next_cursor: &mut Option<String>,
+) -> Box<dyn Iterator<Item = StorageEntry> + 'a> {
+    let Some(limit) = limit else {
+        return Box::new(entries);
     };
 
-    let _ = res
-        .headers_mut()
-        .insert(AUTHORIZATION, headers.get(AUTHORIZATION).unwrap().clone());
+    let mut page: Vec<_> = entries.take(limit + 1).collect();
+    if page.len() > limit {
+        page.truncate(limit);
+        *next_cursor = page.last().map(|entry| entry.name.clone());
+    }
+    Box::new(page.into_iter())
+}
 
-    Ok(res)
+/// Streams the files of the type as one JSON object per line
+///
+/// The directory is read on a blocking thread while the lines are sent, so
+/// huge listings are never held in memory as a whole. As the lines are sent
+/// before the listing is complete, no `X-Next-Cursor` is returned: the name
+/// of the last line is the cursor to resume with.
+fn ndjson_response(
+    storage: &'static Arc<dyn Storage>,
+    path: PathBuf,
+    tpe: Option<&'static str>,
+    as_of: AsOf,
+    query: ListQuery,
+) -> Response {
+    let (sender, receiver) = mpsc::channel::<Bytes>(NDJSON_BUFFERED_LINES);
+
+    drop(task::spawn_blocking(move || {
+        let limit = query.limit.unwrap_or(usize::MAX);
+        for entry in list_entries(storage.as_ref(), &path, tpe, as_of, &query).take(limit) {
+            let entry = RepoPathEntry {
+                name: entry.name,
+                size: entry.size,
+            };
+            let Ok(mut line) = serde_json::to_vec(&entry) else {
+                continue;
+            };
+            line.push(b'\n');
+            // the client went away
+            if sender.blocking_send(line.into()).is_err() {
+                break;
+            }
+        }
+    }));
+
+    let lines = stream::unfold(receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|line| (Ok::<_, io::Error>(line), receiver))
+    });
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        Body::from_stream(lines),
+    )
+        .into_response()
 }
 
 #[cfg(test)]
 mod test {
+    use std::{fs, path::Path};
+
     use axum::{
         body::Body,
         http::{
-            header::{ACCEPT, CONTENT_TYPE},
+            header::{
+                ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, ETAG,
+                IF_NONE_MATCH,
+            },
             Request, StatusCode,
         },
-        middleware, Router,
+        middleware,
+        response::IntoResponse,
+        Router,
     };
     use axum_extra::routing::RouterExt; // for `Router::typed_*`
     use http_body_util::BodyExt;
     use tower::ServiceExt; // for `call`, `oneshot`, and `ready`
 
     use crate::{
-        handlers::files_list::{list_files, ApiVersionKind, RepoPathEntry},
+        as_of::AS_OF_HEADER,
+        error::ApiErrorKind,
+        handlers::files_list::{
+            list_files, paginate, ApiVersionKind, RepoPathEntry, NDJSON_CONTENT_TYPE,
+            NEXT_CURSOR_HEADER,
+        },
         log::print_request_response,
+        storage::StorageEntry,
         testing::{basic_auth_header_value, init_test_environment, server_config},
         typed_path::RepositoryTpePath,
+        web::compression_layer,
     };
 
     #[tokio::test]
@@ -267,4 +514,297 @@ mod test {
         // assert_eq!( rr.name, "3f918b737a2b9f72f044d06d6009eb34e0e8d06668209be3ce86e5c18dac0295");
         // assert_eq!(rr.size, 363);
     }
+
+    #[tokio::test]
+    async fn test_list_files_compressed_passes() {
+        init_test_environment(server_config());
+
+        let app = Router::new()
+            .typed_get(list_files::<RepositoryTpePath>)
+            .layer(compression_layer(1));
+        let request = |accept_encoding: &str| {
+            Request::builder()
+                .uri("/test_repo/keys/")
+                .header(ACCEPT, ApiVersionKind::V2.to_static_str())
+                .header(ACCEPT_ENCODING, accept_encoding)
+                .header(
+                    "Authorization",
+                    basic_auth_header_value("rustic", Some("rustic")),
+                )
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for encoding in ["gzip", "zstd"] {
+            let resp = app.clone().oneshot(request(encoding)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()[CONTENT_ENCODING], encoding);
+        }
+
+        let resp = app.oneshot(request("identity")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(serde_json::from_slice::<Vec<RepoPathEntry>>(&body).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_list_files_etag_passes() {
+        init_test_environment(server_config());
+
+        let app = Router::new().typed_get(list_files::<RepositoryTpePath>);
+        let list = |version: ApiVersionKind, if_none_match: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("/test_repo/keys/")
+                .header(ACCEPT, version.to_static_str())
+                .header(
+                    "Authorization",
+                    basic_auth_header_value("rustic", Some("rustic")),
+                );
+            if let Some(if_none_match) = if_none_match {
+                request = request.header(IF_NONE_MATCH, if_none_match);
+            }
+
+            let app = app.clone();
+            let request = request.body(Body::empty()).unwrap();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+
+        let resp = list(ApiVersionKind::V2, None).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/\""), "{etag}");
+
+        // unchanged listings are not sent again
+        let resp = list(ApiVersionKind::V2, Some(&etag)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[ETAG], etag.as_str());
+        assert!(resp
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .is_empty());
+
+        // tags are compared weakly, and any of several tags may match
+        let strong = etag.trim_start_matches("W/");
+        let resp = list(ApiVersionKind::V2, Some(strong)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        let tags = format!("\"other\", {etag}");
+        let resp = list(ApiVersionKind::V2, Some(&tags)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // the V1 listing has another tag
+        let resp = list(ApiVersionKind::V1, Some(&etag)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers()[ETAG], etag.as_str());
+
+        let resp = list(ApiVersionKind::V2, Some("\"other\"")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(serde_json::from_slice::<Vec<RepoPathEntry>>(&body).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_list_files_anonymous_passes() {
+        init_test_environment(server_config());
+
+        let repo_dir = Path::new("tests/generated/test_storage/repo_public_me");
+        fs::create_dir_all(repo_dir.join("keys")).unwrap();
+        fs::write(repo_dir.join("keys/ab"), "key file").unwrap();
+
+        let app = Router::new()
+            .typed_get(list_files::<RepositoryTpePath>)
+            .layer(middleware::from_fn(print_request_response));
+        let request = Request::builder()
+            .uri("/repo_public_me/keys/")
+            .header(ACCEPT, ApiVersionKind::V2.to_static_str())
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.oneshot(request).await.unwrap();
+
+        fs::remove_dir_all(repo_dir).unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(AUTHORIZATION));
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let entries: Vec<RepoPathEntry> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_files_ndjson_passes() {
+        init_test_environment(server_config());
+
+        let app = Router::new().typed_get(list_files::<RepositoryTpePath>);
+        let request = Request::builder()
+            .uri("/test_repo/keys/")
+            .header(ACCEPT, NDJSON_CONTENT_TYPE)
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], NDJSON_CONTENT_TYPE);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.ends_with('\n'));
+
+        // one object per line
+        let entries: Vec<RepoPathEntry> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(entries.iter().any(|entry| entry.name
+            == "3f918b737a2b9f72f044d06d6009eb34e0e8d06668209be3ce86e5c18dac0295"
+            && entry.size == 460));
+    }
+
+    #[tokio::test]
+    async fn test_list_files_as_of_passes() {
+        init_test_environment(server_config());
+
+        let app = Router::new().typed_get(list_files::<RepositoryTpePath>);
+        let list = |as_of: &str| {
+            let request = Request::builder()
+                .uri("/test_repo/keys/")
+                .header(ACCEPT, ApiVersionKind::V1.to_static_str())
+                .header(&AS_OF_HEADER, as_of)
+                .header(
+                    "Authorization",
+                    basic_auth_header_value("rustic", Some("rustic")),
+                )
+                .body(Body::empty())
+                .unwrap();
+
+            let app = app.clone();
+            async move {
+                let resp = app.oneshot(request).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+                let body = resp.into_body().collect().await.unwrap().to_bytes();
+                serde_json::from_slice::<Vec<String>>(&body).unwrap()
+            }
+        };
+
+        // no key existed back then
+        assert!(list("2000-01-01T00:00:00Z").await.is_empty());
+        assert!(list("2999-01-01T00:00:00Z").await.contains(
+            &"3f918b737a2b9f72f044d06d6009eb34e0e8d06668209be3ce86e5c18dac0295".to_string()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_files_cursor_passes() {
+        init_test_environment(server_config());
+
+        let app = Router::new().typed_get(list_files::<RepositoryTpePath>);
+        let list = |query: &str| {
+            let request = Request::builder()
+                .uri(format!("/test_repo/keys/{query}"))
+                .header(ACCEPT, ApiVersionKind::V1.to_static_str())
+                .header(
+                    "Authorization",
+                    basic_auth_header_value("rustic", Some("rustic")),
+                )
+                .body(Body::empty())
+                .unwrap();
+
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+        let name = "3f918b737a2b9f72f044d06d6009eb34e0e8d06668209be3ce86e5c18dac0295";
+
+        let resp = list("?limit=1").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let next_cursor = resp.headers().get(&NEXT_CURSOR_HEADER).cloned();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            serde_json::from_slice::<Vec<String>>(&body).unwrap(),
+            [name]
+        );
+        // the only key fits the page
+        assert!(next_cursor.is_none());
+
+        let resp = list(&format!("?cursor={name}")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(serde_json::from_slice::<Vec<String>>(&body)
+            .unwrap()
+            .is_empty());
+
+        let resp = list("?cursor=0&limit=1").await;
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            serde_json::from_slice::<Vec<String>>(&body).unwrap(),
+            [name]
+        );
+
+        assert_eq!(list("?limit=0").await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_negotiate_api_version_passes() {
+        let v1 = Some(ApiVersionKind::V1.to_static_str());
+        let v2 = Some(ApiVersionKind::V2.to_static_str());
+        let negotiate = |accept, versions| ApiVersionKind::negotiate(accept, &versions);
+
+        assert!(matches!(negotiate(v1, 1..=2), Ok(ApiVersionKind::V1)));
+        assert!(matches!(negotiate(v2, 1..=2), Ok(ApiVersionKind::V2)));
+        assert!(matches!(negotiate(None, 1..=2), Ok(ApiVersionKind::V1)));
+
+        // V1 disabled
+        assert!(matches!(
+            negotiate(v1, 2..=2),
+            Err(ApiErrorKind::InvalidApiVersion(_))
+        ));
+        assert!(negotiate(None, 2..=2).is_err());
+        assert!(matches!(negotiate(v2, 2..=2), Ok(ApiVersionKind::V2)));
+
+        // V2 disabled
+        assert!(matches!(negotiate(v2, 1..=1), Ok(ApiVersionKind::V1)));
+        assert!(matches!(negotiate(v1, 1..=1), Ok(ApiVersionKind::V1)));
+
+        let response = negotiate(v1, 2..=2).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[test]
+    fn test_paginate_passes() {
+        let entries = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| StorageEntry {
+                    name: name.to_string(),
+                    size: 0,
+                })
+                .collect::<Vec<_>>()
+        };
+        let names = |entries: Box<dyn Iterator<Item = StorageEntry>>| -> Vec<_> {
+            entries.map(|entry| entry.name).collect()
+        };
+
+        let mut next_cursor = None;
+        let page = paginate(
+            entries(&["a", "b", "c"]).into_iter(),
+            Some(2),
+            &mut next_cursor,
+        );
+        assert_eq!(names(page), ["a", "b"]);
+        assert_eq!(next_cursor.as_deref(), Some("b"));
+
+        let mut next_cursor = None;
+        let page = paginate(entries(&["a", "b"]).into_iter(), Some(2), &mut next_cursor);
+        assert_eq!(names(page), ["a", "b"]);
+        assert!(next_cursor.is_none());
+
+        let page = paginate(entries(&["a", "b"]).into_iter(), None, &mut next_cursor);
+        assert_eq!(names(page), ["a", "b"]);
+        assert!(next_cursor.is_none());
+    }
 }
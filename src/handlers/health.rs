@@ -1,9 +1,22 @@
-use std::{sync::OnceLock, time::Instant};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
 
 use axum::{http::StatusCode, response::IntoResponse};
 use axum_extra::json;
+use strum::VariantNames;
 
-use crate::auth::BasicAuthFromRequest;
+use crate::{
+    acl::AccessType,
+    auth::BasicAuthFromRequest,
+    error::ApiResult,
+    handlers::access_check::check_auth_and_acl,
+    storage::STORAGE,
+    typed_path::{RepositoryHealthPath, TpeKind},
+};
 
 // Global that stores the current when the server started
 // This is used to check if the server is running
@@ -13,50 +26,203 @@ pub fn init_start_time() {
     let _ = START_TIME.get_or_init(Instant::now);
 }
 
-pub async fn live_check() -> impl IntoResponse {
-    let start = START_TIME.get().expect("start time not initialized");
-    let uptime = Instant::now().duration_since(*start);
+/// Returns the time since the server started
+pub fn uptime() -> Duration {
+    START_TIME.get_or_init(Instant::now).elapsed()
+}
 
+/// `live_check`
+/// Interface: GET /health/live
+///
+/// Liveness probe, which needs no authentication.
+pub async fn live_check() -> impl IntoResponse {
     (
         StatusCode::OK,
         json!({
             "status": "ok",
             "version": env!("CARGO_PKG_VERSION"),
-            "uptime": uptime.as_secs(),
+            "uptime": uptime().as_secs(),
             "timestamp": chrono::Local::now().timestamp(),
         }),
     )
         .into_response()
 }
 
-// /health/ready
-//
-// Example response as an idea of what to return:
-//
-// ```json
-// {
-//   "status": "ready",
-//   "version": "1.2.3",
-//   "uptime": 123456,
-//   "error_count": 0,
-//   "timestamp": "2024-11-16T12:34:56Z",
-//   "last_backup_status": "success",
-//   "last_backup_timestamp": "2024-11-15T23:59:59Z",
-//   "backup_queue_length": 0,
-//   "backup_size_last": "10GB",
-//   "dependencies": {
-//     "storage_status": "ok",
-//     "available_disk_space": "120GB"
-//   },
-//   "performance": {
-//     "cpu_usage": "15%",
-//     "memory_usage": "300MB",
-//     "active_tasks": 2,
-//   }
-// }
-// ```
-// TODO: Implement ready_check
-#[allow(dead_code)]
-pub async fn ready_check(_auth: BasicAuthFromRequest) -> impl IntoResponse {
-    StatusCode::NOT_IMPLEMENTED
+/// `repository_health`
+/// Interface: GET {repo}/health
+///
+/// Checks that the repository has a config and all type directories, so
+/// partially initialized repositories are detected before a backup fails.
+/// Responds with “503 Service Unavailable” if any component is missing.
+pub async fn repository_health(
+    RepositoryHealthPath { repo }: RepositoryHealthPath,
+    BasicAuthFromRequest { user, .. }: BasicAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(path = %repo, "[repository_health]");
+
+    let path = Path::new(&repo);
+
+    let path = &check_auth_and_acl(user, None, path, AccessType::Read)?;
+
+    let storage = STORAGE.get().unwrap();
+
+    let components: BTreeMap<&str, &str> = TpeKind::VARIANTS
+        .iter()
+        .map(|&tpe| {
+            let file = storage.filename(path, tpe, None);
+            let present = if tpe == TpeKind::Config.into_str() {
+                file.is_file()
+            } else {
+                file.is_dir()
+            };
+            (tpe, if present { "ok" } else { "missing" })
+        })
+        .collect();
+
+    let (status_code, status) = if components.values().all(|&status| status == "ok") {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "incomplete")
+    };
+
+    Ok((
+        status_code,
+        json!({
+            "status": status,
+            "components": components,
+        }),
+    ))
+}
+
+/// `ready_check`
+/// Interface: GET /health/ready
+///
+/// Readiness probe, which needs no authentication. Responds with
+/// “503 Service Unavailable” until the storage is initialized, or if its
+/// directory can't be accessed, e.g. because a volume isn't mounted.
+pub async fn ready_check() -> impl IntoResponse {
+    let storage = STORAGE.get().map_or("not initialized", |storage| {
+        if storage.path().is_dir() {
+            "ok"
+        } else {
+            "unavailable"
+        }
+    });
+
+    let (status_code, status) = if storage == "ok" {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    };
+
+    (
+        status_code,
+        json!({
+            "status": status,
+            "version": env!("CARGO_PKG_VERSION"),
+            "timestamp": chrono::Local::now().timestamp(),
+            "dependencies": {
+                "storage": storage,
+            },
+        }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        handlers::health::{live_check, ready_check, repository_health},
+        log::print_request_response,
+        testing::{basic_auth_header_value, init_test_environment, server_config},
+    };
+
+    use std::{fs, path::PathBuf};
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use axum_extra::routing::RouterExt; // for `Router::typed_*`
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    async fn get_health(repo: &str) -> (StatusCode, Value) {
+        let app = Router::new()
+            .typed_get(repository_health)
+            .layer(middleware::from_fn(print_request_response));
+
+        let request = Request::builder()
+            .uri(format!("/{repo}/health"))
+            .header(
+                "Authorization",
+                basic_auth_header_value("rustic", Some("rustic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_repository_health_passes() {
+        init_test_environment(server_config());
+
+        // the fixture repository only has a config and keys
+        let (status, body) = get_health("test_repo").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "incomplete");
+        assert_eq!(body["components"]["config"], "ok");
+        assert_eq!(body["components"]["keys"], "ok");
+        assert_eq!(body["components"]["data"], "missing");
+
+        let repo = "repo_health_me";
+        let repo_dir = PathBuf::from("tests/generated/test_storage").join(repo);
+        if repo_dir.exists() {
+            fs::remove_dir_all(&repo_dir).unwrap();
+        }
+        for tpe in ["data", "index", "keys", "locks", "snapshots"] {
+            fs::create_dir_all(repo_dir.join(tpe)).unwrap();
+        }
+
+        // created, but config never written
+        let (status, body) = get_health(repo).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["components"]["config"], "missing");
+
+        fs::write(repo_dir.join("config"), "config").unwrap();
+        let (status, body) = get_health(repo).await;
+
+        fs::remove_dir_all(&repo_dir).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn test_health_probes_pass() {
+        init_test_environment(server_config());
+
+        let app = Router::new()
+            .route("/health/live", get(live_check))
+            .route("/health/ready", get(ready_check));
+
+        // no authentication needed
+        for (uri, status) in [("/health/live", "ok"), ("/health/ready", "ready")] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let resp = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["status"], status);
+        }
+    }
 }
@@ -0,0 +1,350 @@
+//! JSON-RPC management interface
+//!
+//! Other services can administer the server with [JSON-RPC 2.0] requests to
+//! `POST /rpc` on the management listener (`--management-listen`). It is
+//! served on its own address, so it can be firewalled separately from the
+//! REST API, and every request needs admin credentials.
+//!
+//! Repositories are addressed by their path in the storage, without ACL or
+//! namespace mapping. Changed users are written to the htpasswd file and take
+//! effect immediately.
+//!
+//! [JSON-RPC 2.0]: https://www.jsonrpc.org/specification
+
+use std::path::{Component, Path, PathBuf};
+
+use axum::{body::Bytes, Json};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use crate::{
+    auth::{AdminAuthFromRequest, AUTH},
+    error::ApiErrorKind,
+    handlers::{
+        access_check::{check_repo_name, check_repo_path},
+        repository::create_repository_dirs,
+        stats::RepositoryStats,
+    },
+    htpasswd::Htpasswd,
+    storage::STORAGE,
+};
+
+/// Error codes defined by the JSON-RPC specification
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Error code for failed operations, from the range reserved for servers
+const SERVER_ERROR: i64 = -32000;
+
+// Serializes changes of the htpasswd file
+static HTPASSWD_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ApiErrorKind> for RpcError {
+    fn from(err: ApiErrorKind) -> Self {
+        Self::new(SERVER_ERROR, err.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RepoParams {
+    repo: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserParams {
+    user: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddUserParams {
+    user: String,
+    password: String,
+}
+
+/// `rpc`
+/// Interface: POST /rpc (management listener only)
+///
+/// Supported methods:
+/// - `repo.create`, `repo.delete` and `repo.stats` with `{"repo": <path>}`
+/// - `user.add` with `{"user": <name>, "password": <password>}`, which also
+///   changes the password of existing users
+/// - `user.remove` with `{"user": <name>}`
+/// - `user.list` without parameters
+pub async fn rpc(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+    body: Bytes,
+) -> Json<RpcResponse> {
+    let request: RpcRequest = match serde_json::from_slice::<Value>(&body) {
+        Err(err) => {
+            return Json(RpcResponse::error(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, err.to_string()),
+            ))
+        }
+        Ok(request) => match serde_json::from_value(request) {
+            Ok(request) => request,
+            Err(err) => {
+                return Json(RpcResponse::error(
+                    Value::Null,
+                    RpcError::new(INVALID_REQUEST, err.to_string()),
+                ))
+            }
+        },
+    };
+    if request.jsonrpc != "2.0" {
+        return Json(RpcResponse::error(
+            request.id,
+            RpcError::new(INVALID_REQUEST, "only JSON-RPC 2.0 is supported"),
+        ));
+    }
+
+    tracing::info!(%user, method = %request.method, "[rpc]");
+
+    Json(match call(&request.method, request.params).await {
+        Ok(result) => RpcResponse {
+            jsonrpc: "2.0",
+            result: Some(result),
+            error: None,
+            id: request.id,
+        },
+        Err(err) => RpcResponse::error(request.id, err),
+    })
+}
+
+impl RpcResponse {
+    fn error(id: Value, error: RpcError) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            result: None,
+            error: Some(error),
+            id,
+        }
+    }
+}
+
+async fn call(method: &str, params: Value) -> Result<Value, RpcError> {
+    let storage = STORAGE.get().unwrap();
+
+    match method {
+        "repo.create" => {
+            let repo = repo_path(params)?;
+            create_repository_dirs(storage.as_ref(), &repo).await?;
+            Ok(Value::Null)
+        }
+        "repo.delete" => {
+            let repo = repo_path(params)?;
+            storage.remove_repository(&repo).await?;
+            Ok(Value::Null)
+        }
+        "repo.stats" => {
+            let repo = repo_path(params)?;
+            to_value(RepositoryStats::of(&repo).await?)
+        }
+        "user.add" => {
+            let AddUserParams { user, password } = parse_params(params)?;
+            change_htpasswd(|htpasswd| {
+                htpasswd
+                    .update(&user, &password)
+                    .map_err(|err| err.to_string())
+            })
+            .await?;
+            Ok(Value::Null)
+        }
+        "user.remove" => {
+            let UserParams { user } = parse_params(params)?;
+            change_htpasswd(|htpasswd| {
+                htpasswd
+                    .delete(&user)
+                    .map(|_| ())
+                    .ok_or_else(|| format!("user `{user}` does not exist"))
+            })
+            .await?;
+            Ok(Value::Null)
+        }
+        "user.list" => {
+            let htpasswd = load_htpasswd()?;
+            to_value(htpasswd.users())
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method `{method}`"),
+        )),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn to_value(value: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|err| RpcError::new(SERVER_ERROR, err.to_string()))
+}
+
+/// Returns the validated repository path of the parameters
+fn repo_path(params: Value) -> Result<PathBuf, RpcError> {
+    let RepoParams { repo } = parse_params(params)?;
+    let storage = STORAGE.get().unwrap();
+
+    check_repo_path(&repo, storage.max_repo_depth())?;
+    check_repo_name(&repo, storage.reserved_repo_names())?;
+    // never remove the whole data directory
+    if !repo
+        .components()
+        .any(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            "the repository path is empty",
+        ));
+    }
+
+    Ok(repo)
+}
+
+/// Loads the htpasswd file the server authenticates against
+fn load_htpasswd() -> Result<Htpasswd, RpcError> {
+    let Some(path) = AUTH.get().unwrap().htpasswd_path().map(Path::to_path_buf) else {
+        return Err(RpcError::new(SERVER_ERROR, "authentication is disabled"));
+    };
+
+    Htpasswd::from_file(&path).map_err(|err| RpcError::new(SERVER_ERROR, err.to_string()))
+}
+
+/// Applies `change` to the htpasswd file and uses the changed credentials
+async fn change_htpasswd(
+    change: impl FnOnce(&mut Htpasswd) -> Result<(), String>,
+) -> Result<(), RpcError> {
+    let _guard = HTPASSWD_LOCK.lock().await;
+
+    let mut htpasswd = load_htpasswd()?;
+    change(&mut htpasswd).map_err(|err| RpcError::new(SERVER_ERROR, err))?;
+    htpasswd.to_file()?;
+
+    AUTH.get().unwrap().set_credentials(htpasswd.credentials);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        handlers::management::rpc,
+        testing::{basic_auth_header_value, init_test_environment, server_config},
+    };
+
+    use std::path::Path;
+
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+        routing::post,
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    async fn call(user: &str, body: &str) -> (StatusCode, Value) {
+        let app = Router::new().route("/rpc", post(rpc));
+        let request = Request::builder()
+            .uri("/rpc")
+            .method(Method::POST)
+            .header("Authorization", basic_auth_header_value(user, Some(user)))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn request(method: &str, params: Value) -> String {
+        json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1}).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_rpc_repositories_passes() {
+        init_test_environment(server_config());
+
+        let repo_dir = Path::new("tests/generated/test_storage/rpc_repo");
+        let params = json!({"repo": "rpc_repo"});
+
+        let (status, resp) = call("hurl", &request("repo.create", params.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(resp, json!({"jsonrpc": "2.0", "result": null, "id": 1}));
+        assert!(repo_dir.join("keys").is_dir());
+
+        let (_, resp) = call("hurl", &request("repo.stats", params.clone())).await;
+        assert_eq!(resp["result"]["size"], 0);
+
+        let (_, resp) = call("hurl", &request("repo.delete", params.clone())).await;
+        assert_eq!(resp["result"], Value::Null);
+        assert!(!repo_dir.exists());
+
+        // only admins can use the management interface
+        let (status, _) = call("rustic", &request("repo.create", params)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_rpc_errors_passes() {
+        init_test_environment(server_config());
+
+        let (_, resp) = call("hurl", "{").await;
+        assert_eq!(resp["error"]["code"], -32700);
+
+        let (_, resp) = call("hurl", &request("repo.rename", json!({}))).await;
+        assert_eq!(resp["error"]["code"], -32601);
+        assert_eq!(resp["id"], 1);
+
+        // the data directory itself is no repository
+        let (_, resp) = call("hurl", &request("repo.delete", json!({"repo": "."}))).await;
+        assert_eq!(resp["error"]["code"], -32602);
+
+        let (_, resp) = call("hurl", &request("repo.delete", json!({"repo": "../x"}))).await;
+        assert_eq!(resp["error"]["code"], -32000);
+
+        let (_, resp) = call("hurl", &request("user.add", json!({"user": "x"}))).await;
+        assert_eq!(resp["error"]["code"], -32602);
+    }
+}
@@ -0,0 +1,164 @@
+use std::{collections::BTreeMap, path::Path};
+
+use axum::{extract::Query, response::IntoResponse, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use strum::VariantNames;
+
+use crate::{
+    acl::AccessType,
+    auth::BasicAuthFromRequest,
+    error::ApiResult,
+    handlers::access_check::check_auth_and_acl,
+    storage::{Storage, GZIP_SUFFIX, STORAGE},
+    typed_path::{RepositoryManifestPath, TpeKind},
+};
+
+/// Query parameters of `GET {repo}/manifest`
+#[derive(Debug, Default, Deserialize)]
+pub struct ManifestQuery {
+    /// Whether the modification times of the files are included
+    #[serde(default)]
+    mtime: bool,
+}
+
+/// A file of the repository, as listed in its manifest
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct ManifestEntry {
+    size: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mtime: Option<DateTime<Utc>>,
+}
+
+/// `repository_manifest`
+/// Interface: GET {repo}/manifest
+///
+/// Returns the size of every file of the repository, keyed by `<type>/<name>`
+/// and `config`, so the repositories of two servers can be compared without
+/// listing each type. With `?mtime=true`, the modification times are included.
+pub async fn repository_manifest(
+    RepositoryManifestPath { repo }: RepositoryManifestPath,
+    BasicAuthFromRequest { user, .. }: BasicAuthFromRequest,
+    Query(query): Query<ManifestQuery>,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(path = %repo, "[repository_manifest]");
+
+    let path = check_auth_and_acl(user, None, Path::new(&repo), AccessType::Read)?;
+
+    let storage = STORAGE.get().unwrap();
+
+    Ok(Json(manifest(storage.as_ref(), &path, query.mtime).await))
+}
+
+/// Collects the files of all types of the repository at `path`
+async fn manifest(
+    storage: &dyn Storage,
+    path: &Path,
+    mtime: bool,
+) -> BTreeMap<String, ManifestEntry> {
+    let entry = |tpe: &str, name: Option<&str>, size| ManifestEntry {
+        size,
+        mtime: mtime
+            .then(|| storage.modified(path, tpe, name))
+            .flatten()
+            .map(DateTime::from),
+    };
+
+    let mut manifest = BTreeMap::new();
+
+    let config = TpeKind::Config.into_str();
+    if let Ok(size) = storage.file_length(path, config, None).await {
+        let _ = manifest.insert(config.to_string(), entry(config, None, size));
+    }
+
+    for &tpe in TpeKind::VARIANTS.iter().filter(|&&tpe| tpe != config) {
+        // precompressed variants are no files of the repository
+        for file in storage
+            .read_dir(path, Some(tpe))
+            .filter(|file| !file.name.ends_with(GZIP_SUFFIX))
+        {
+            let value = entry(tpe, Some(&file.name), file.size);
+            let _ = manifest.insert(format!("{tpe}/{}", file.name), value);
+        }
+    }
+
+    manifest
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        handlers::manifest::repository_manifest,
+        testing::{basic_auth_header_value, init_test_environment, server_config},
+    };
+
+    use std::{fs, path::PathBuf};
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        Router,
+    };
+    use axum_extra::routing::RouterExt; // for `Router::typed_*`
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    async fn get_manifest(uri: &str, user: &str) -> (StatusCode, Value) {
+        let app = Router::new().typed_get(repository_manifest);
+
+        let request = Request::builder()
+            .uri(uri)
+            .header("Authorization", basic_auth_header_value(user, Some(user)))
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_repository_manifest_passes() {
+        init_test_environment(server_config());
+
+        let repo = "repo_manifest_me";
+        let repo_dir = PathBuf::from("tests/generated/test_storage").join(repo);
+        if repo_dir.exists() {
+            fs::remove_dir_all(&repo_dir).unwrap();
+        }
+        for tpe in ["data/ab", "index", "keys", "locks", "snapshots"] {
+            fs::create_dir_all(repo_dir.join(tpe)).unwrap();
+        }
+        fs::write(repo_dir.join("config"), "config").unwrap();
+        fs::write(repo_dir.join("data/ab/abcd"), "data").unwrap();
+        fs::write(repo_dir.join("data/ab/abcd.gz"), "compressed").unwrap();
+        fs::write(repo_dir.join("keys/ef"), "key file").unwrap();
+
+        let (status, body) = get_manifest(&format!("/{repo}/manifest"), "rustic").await;
+        let (_, with_mtime) = get_manifest(&format!("/{repo}/manifest?mtime=true"), "rustic").await;
+        let (forbidden, _) = get_manifest(&format!("/{repo}/manifest"), "restic").await;
+
+        fs::remove_dir_all(&repo_dir).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "config": { "size": 6 },
+                "data/abcd": { "size": 4 },
+                "keys/ef": { "size": 8 },
+            })
+        );
+
+        assert_eq!(with_mtime["data/abcd"]["size"], 4);
+        assert!(with_mtime["data/abcd"]["mtime"].is_string());
+        assert!(with_mtime["config"]["mtime"].is_string());
+
+        // no access to the repository
+        assert_eq!(forbidden, StatusCode::FORBIDDEN);
+    }
+}
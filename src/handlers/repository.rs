@@ -1,11 +1,17 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use axum::{extract::Query, http::StatusCode, response::IntoResponse};
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Json};
 use serde_derive::Deserialize;
 
 use crate::{
-    acl::AccessType, auth::BasicAuthFromRequest, error::ApiResult,
-    handlers::access_check::check_auth_and_acl, storage::STORAGE, typed_path::TpeKind,
+    acl::AccessType,
+    auth::BasicAuthFromRequest,
+    commands::parse_age,
+    error::{ApiErrorKind, ApiResult},
+    handlers::{access_check::check_auth_and_acl, file_helpers::success_status},
+    maintenance::remove_stale_locks,
+    storage::{Storage, STORAGE},
+    typed_path::{RepositoryRestorePath, RepositoryUnlockPath, TpeKind},
 };
 
 // used for using auto-generated TpeKind variant names
@@ -30,20 +36,11 @@ pub async fn create_repository<P: PathParts>(
         path.repo().unwrap()
     );
     let path = PathBuf::new().join(path.repo().unwrap());
-    let _ = check_auth_and_acl(auth.user, None, &path, AccessType::Append)?;
+    let path = check_auth_and_acl(auth.user, None, &path, AccessType::Append)?;
 
-    let storage = STORAGE.get().unwrap();
     match params.create {
         true => {
-            for tpe in TpeKind::VARIANTS.iter() {
-                // config is not a directory, but a file
-                // it is handled separately
-                if tpe == &TpeKind::Config.into_str() {
-                    continue;
-                }
-
-                storage.create_dir(&path, Some(tpe)).await?;
-            }
+            create_repository_dirs(STORAGE.get().unwrap().as_ref(), &path).await?;
 
             Ok((
                 StatusCode::OK,
@@ -57,9 +54,26 @@ pub async fn create_repository<P: PathParts>(
     }
 }
 
+/// Creates the directories of all types of the repository at `path`
+pub(crate) async fn create_repository_dirs(storage: &dyn Storage, path: &Path) -> ApiResult<()> {
+    for tpe in TpeKind::VARIANTS.iter() {
+        // config is not a directory, but a file
+        // it is handled separately
+        if tpe == &TpeKind::Config.into_str() {
+            continue;
+        }
+
+        storage.create_dir(path, Some(tpe)).await?;
+    }
+
+    Ok(())
+}
+
 /// `Delete_repository`
 /// Interface: Delete {path}
-// FIXME: The input path should at least NOT point to a file in any repository
+///
+/// The storage refuses to remove anything but a directory below the data
+/// directory, so paths like `.` never remove the data directory itself.
 pub async fn delete_repository<P: PathParts>(
     path: P,
     auth: BasicAuthFromRequest,
@@ -69,12 +83,70 @@ pub async fn delete_repository<P: PathParts>(
         &path.repo().unwrap()
     );
     let path = PathBuf::new().join(path.repo().unwrap());
-    let _ = check_auth_and_acl(auth.user, None, &path, AccessType::Modify)?;
+    let path = check_auth_and_acl(auth.user, None, &path, AccessType::Modify)?;
 
     let storage = STORAGE.get().unwrap();
     storage.remove_repository(&path).await?;
 
-    Ok(())
+    Ok(success_status())
+}
+
+/// Locks older than this are removed if no age is given. restic refreshes its
+/// locks every 5 minutes and considers them stale after 30 minutes.
+const DEFAULT_STALE_LOCK_AGE: &str = "30m";
+
+/// `unlock_repository`
+/// Interface: POST {path}/unlock?older_than={age}
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct Unlock {
+    older_than: Option<String>,
+}
+
+/// Removes the locks of the repository which were not modified within
+/// `older_than` (e.g. `30m`, default: 30 minutes) and returns their names.
+pub async fn unlock_repository(
+    RepositoryUnlockPath { repo }: RepositoryUnlockPath,
+    auth: BasicAuthFromRequest,
+    Query(params): Query<Unlock>,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(%repo, older_than = ?params.older_than, "[unlock_repository]");
+
+    let older_than = params
+        .older_than
+        .as_deref()
+        .unwrap_or(DEFAULT_STALE_LOCK_AGE);
+    let max_age = parse_age(older_than)
+        .and_then(|age| Ok(age.to_std()?))
+        .map_err(|err| ApiErrorKind::BadRequest(format!("invalid `older_than`: {err}")))?;
+
+    let path = check_auth_and_acl(auth.user, None, Path::new(&repo), AccessType::ForceUnlock)?;
+
+    let removed = remove_stale_locks(STORAGE.get().unwrap().as_ref(), &path, max_age).await?;
+
+    tracing::info!(?path, removed = removed.len(), "Removed stale locks.");
+
+    Ok(Json(removed))
+}
+
+/// `restore_repository`
+/// Interface: POST {path}/restore
+///
+/// Moves the most recently deleted repository at this path back from the
+/// trash directory. Fails if a repository exists at the path.
+pub async fn restore_repository(
+    RepositoryRestorePath { repo }: RepositoryRestorePath,
+    auth: BasicAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(%repo, "[restore_repository]");
+
+    let path = check_auth_and_acl(auth.user, None, Path::new(&repo), AccessType::Modify)?;
+
+    STORAGE.get().unwrap().restore_repository(&path).await?;
+
+    tracing::info!(?path, "Restored repository from the trash.");
+
+    Ok(success_status())
 }
 
 #[cfg(test)]
@@ -83,7 +155,9 @@ mod test {
     use crate::testing::{basic_auth_header_value, init_test_environment, request_uri_for_test};
     use crate::typed_path::RepositoryPath;
     use crate::{
-        handlers::repository::{create_repository, delete_repository},
+        handlers::repository::{
+            create_repository, delete_repository, restore_repository, unlock_repository,
+        },
         testing::server_config,
     };
     use axum::http::Method;
@@ -93,6 +167,7 @@ mod test {
     };
     use axum::{middleware, Router};
     use axum_extra::routing::RouterExt;
+    use http_body_util::BodyExt;
     use pretty_assertions::assert_eq;
     use std::path::PathBuf;
     use tokio::fs;
@@ -155,6 +230,22 @@ mod test {
         assert_eq!(resp.status(), StatusCode::FORBIDDEN);
         assert!(!not_allowed_path.exists());
 
+        // ------------------------------------------
+        // Create a repository with a reserved name
+        // ------------------------------------------
+        for repo in ["tooling", "admin"] {
+            let repo_name_uri = format!("/{repo}/?create=true");
+            let app = Router::new()
+                .typed_post(create_repository::<RepositoryPath>)
+                .layer(middleware::from_fn(print_request_response));
+
+            let request = request_uri_for_test(&repo_name_uri, Method::POST);
+            let resp = app.oneshot(request).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            assert!(!path.with_file_name(repo).exists());
+        }
+
         // ------------------------------------------
         // Delete a repository WITHOUT ACL access
         // ------------------------------------------
@@ -175,7 +266,7 @@ mod test {
 
         let resp = app.oneshot(request).await.unwrap();
 
-        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
         assert!(path.exists());
 
         // ------------------------------------------
@@ -193,4 +284,100 @@ mod test {
         assert_eq!(resp.status(), StatusCode::OK);
         assert!(!path.exists());
     }
+
+    #[tokio::test]
+    async fn test_delete_data_dir_fails() {
+        init_test_environment(server_config());
+
+        let data_dir = PathBuf::from("tests/generated/test_storage");
+        let app = Router::new()
+            .typed_delete(delete_repository::<RepositoryPath>)
+            .layer(middleware::from_fn(print_request_response));
+
+        for uri in ["/", "/.", "//", "/%2E", "/.%2F"] {
+            let request = request_uri_for_test(uri, Method::DELETE);
+            let resp = app.clone().oneshot(request).await.unwrap();
+
+            assert!(!resp.status().is_success(), "{uri}");
+            assert!(data_dir.is_dir(), "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unlock_repository_passes() {
+        init_test_environment(server_config());
+
+        let locks = PathBuf::from("tests/generated/test_storage/repo_unlock_me/locks");
+        if locks.exists() {
+            fs::remove_dir_all(&locks).await.unwrap();
+        }
+        fs::create_dir_all(&locks).await.unwrap();
+        fs::write(locks.join("stale"), "lock").await.unwrap();
+
+        let app = Router::new()
+            .typed_post(unlock_repository)
+            .layer(middleware::from_fn(print_request_response));
+
+        // the lock is too young
+        let request = request_uri_for_test("/repo_unlock_me/unlock", Method::POST);
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"[]");
+        assert!(locks.join("stale").exists());
+
+        // only `ForceUnlock` access is needed, but no access is not enough
+        let request = Request::builder()
+            .uri("/repo_unlock_me/unlock?older_than=0s")
+            .method(Method::POST)
+            .header(
+                "Authorization",
+                basic_auth_header_value("restic", Some("restic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(locks.join("stale").exists());
+
+        let request = request_uri_for_test("/repo_unlock_me/unlock?older_than=1x", Method::POST);
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let request = request_uri_for_test("/repo_unlock_me/unlock?older_than=0s", Method::POST);
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], br#"["stale"]"#);
+        assert!(!locks.join("stale").exists());
+
+        fs::remove_dir_all(locks.parent().unwrap()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_repository_without_trash_fails() {
+        init_test_environment(server_config());
+
+        let app = Router::new()
+            .typed_post(restore_repository)
+            .layer(middleware::from_fn(print_request_response));
+
+        // the test storage has no trash directory
+        let request = request_uri_for_test("/repo_remove_me/restore", Method::POST);
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+
+        // `Append` access is not enough
+        let request = Request::builder()
+            .uri("/test_repo/restore")
+            .method(Method::POST)
+            .header(
+                "Authorization",
+                basic_auth_header_value("restic", Some("restic")),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
 }
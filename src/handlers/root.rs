@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+/// What `GET /` responds with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RootResponse {
+    /// A short text, e.g. the server name and version
+    Banner(String),
+
+    /// `403 Forbidden`, revealing nothing about the server
+    Deny,
+}
+
+impl RootResponse {
+    /// Returns the response for the configured banner, or the server name and
+    /// version if none is configured
+    pub fn new(banner: Option<String>, deny: bool) -> Self {
+        if deny {
+            Self::Deny
+        } else {
+            Self::Banner(
+                banner.unwrap_or_else(|| format!("rustic-server {}", env!("CARGO_PKG_VERSION"))),
+            )
+        }
+    }
+}
+
+/// `root`
+/// Interface: GET /
+///
+/// Responds with the configured banner, or `403 Forbidden` in strict mode.
+/// This needs no authentication, so tools can use it to check that the
+/// server is up.
+pub async fn root(State(response): State<Arc<RootResponse>>) -> Response {
+    match response.as_ref() {
+        RootResponse::Banner(banner) => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            format!("{banner}\n"),
+        )
+            .into_response(),
+        RootResponse::Deny => StatusCode::FORBIDDEN.into_response(),
+    }
+}
+
+/// `favicon`
+/// Interface: GET /favicon.ico
+///
+/// Responds with `204 No Content`, so browsers don't ask again.
+pub async fn favicon() -> StatusCode {
+    StatusCode::NO_CONTENT
+}
+
+/// `robots_txt`
+/// Interface: GET /robots.txt
+///
+/// Disallows crawling the whole server.
+pub async fn robots_txt() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        "User-agent: *\nDisallow: /\n",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use crate::handlers::root::{favicon, robots_txt, root, RootResponse};
+
+    async fn get_root(response: RootResponse) -> (StatusCode, String) {
+        let app = Router::new().route("/", get(root).with_state(Arc::new(response)));
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let resp = app.oneshot(request).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_root_passes() {
+        let (status, body) = get_root(RootResponse::new(None, false)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("rustic-server "));
+
+        let banner = RootResponse::new(Some("Backups of ACME".to_string()), false);
+        let (status, body) = get_root(banner).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "Backups of ACME\n");
+
+        // strict mode wins over a banner
+        let deny = RootResponse::new(Some("Backups of ACME".to_string()), true);
+        let (status, body) = get_root(deny).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_favicon_and_robots_txt_passes() {
+        let app = Router::new()
+            .route("/favicon.ico", get(favicon))
+            .route("/robots.txt", get(robots_txt));
+
+        let request = Request::builder()
+            .uri("/favicon.ico")
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let request = Request::builder()
+            .uri("/robots.txt")
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"User-agent: *\nDisallow: /\n");
+    }
+}
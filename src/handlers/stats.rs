@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use axum::{http::header, response::IntoResponse, Json};
+use serde::Serialize;
+
+use crate::{
+    acl::AccessType,
+    auth::{AdminAuthFromRequest, BasicAuthFromRequest},
+    error::ApiResult,
+    handlers::access_check::check_auth_and_acl,
+    stats::{repo_stats, storage_latencies, RepoThroughput},
+    storage::STORAGE,
+    typed_path::RepositoryStatsPath,
+};
+
+/// Statistics of a repository, as returned by `GET {repo}/stats`
+#[derive(Debug, Serialize)]
+pub(crate) struct RepositoryStats {
+    #[serde(flatten)]
+    throughput: RepoThroughput,
+
+    /// Size of all files of the repository in bytes, as configured by
+    /// `size-accounting`
+    size: u64,
+}
+
+/// `repository_stats`
+/// Interface: GET {repo}/stats
+///
+/// Returns the bytes read from and written to the files of the repository
+/// since the server started, and the size of the repository.
+pub async fn repository_stats(
+    RepositoryStatsPath { repo }: RepositoryStatsPath,
+    BasicAuthFromRequest { user, .. }: BasicAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(path = %repo, "[repository_stats]");
+
+    let path = check_auth_and_acl(user, None, Path::new(&repo), AccessType::Read)?;
+
+    Ok(Json(RepositoryStats::of(&path).await?))
+}
+
+impl RepositoryStats {
+    /// Returns the statistics of the repository at `path` in the storage
+    pub(crate) async fn of(path: &Path) -> ApiResult<Self> {
+        Ok(Self {
+            throughput: repo_stats().get(path),
+            size: STORAGE.get().unwrap().repository_size(path).await?,
+        })
+    }
+}
+
+/// `metrics`
+/// Interface: GET /metrics
+///
+/// Returns the throughput of all repositories and the latencies of the storage
+/// operations in the Prometheus text format.
+/// As this reveals all repository names, it is only allowed for admin users.
+pub async fn metrics(
+    AdminAuthFromRequest { user }: AdminAuthFromRequest,
+) -> ApiResult<impl IntoResponse> {
+    tracing::debug!(%user, "[metrics]");
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        repo_stats().render_metrics() + &storage_latencies().render_metrics(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        handlers::stats::{metrics, repository_stats},
+        stats::repo_stats,
+        testing::{basic_auth_header_value, init_test_environment, server_config},
+    };
+
+    use std::path::Path;
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use axum_extra::routing::RouterExt; // for `Router::typed_*`
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    async fn request(uri: &str, user: &str) -> (StatusCode, String) {
+        let app = Router::new()
+            .typed_get(repository_stats)
+            .route("/metrics", get(metrics));
+
+        let request = Request::builder()
+            .uri(uri)
+            .header("Authorization", basic_auth_header_value(user, Some(user)))
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = app.oneshot(request).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_repository_stats_passes() {
+        init_test_environment(server_config());
+
+        let repo = Path::new("repo_stats_me");
+        repo_stats().record_read(repo, 42);
+        repo_stats().record_written(repo, 7);
+
+        let (status, body) = request("/repo_stats_me/stats", "rustic").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"bytes_read":42,"bytes_written":7,"size":0}"#);
+
+        // no access to the repository
+        let (status, _) = request("/repo_stats_me/stats", "restic").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = request("/metrics", "hurl").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("rustic_server_repo_read_bytes_total{repo=\"repo_stats_me\"} 42\n"));
+        assert!(body.contains("rustic_server_repo_written_bytes_total{repo=\"repo_stats_me\"} 7\n"));
+        assert!(
+            body.contains("# TYPE rustic_server_storage_operation_duration_seconds histogram\n")
+        );
+
+        // only admins can see the metrics
+        let (status, _) = request("/metrics", "rustic").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
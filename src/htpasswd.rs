@@ -3,12 +3,13 @@ use std::{
     fmt::{Display, Formatter},
     fs::{self, read_to_string},
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
+use chrono::{DateTime, Utc};
 use htpasswd_verify::md5::{format_hash, md5_apr1_encode};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::error::{ApiErrorKind, ApiResult, AppResult, ErrorKind};
 
@@ -39,10 +40,25 @@ impl std::ops::Deref for CredentialMap {
     }
 }
 
+/// Metadata of a credential, which the htpasswd format can't hold
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CredentialMeta {
+    /// When the password was set
+    pub created: DateTime<Utc>,
+}
+
+/// Metadata of all credentials, stored in a JSON file next to the htpasswd file
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HtpasswdMeta {
+    pub users: BTreeMap<String, CredentialMeta>,
+}
+
 #[derive(Clone, Debug, Default, Serialize)]
 pub struct Htpasswd {
     pub path: PathBuf,
     pub credentials: CredentialMap,
+    #[serde(skip)]
+    pub meta: HtpasswdMeta,
 }
 
 impl Htpasswd {
@@ -70,12 +86,42 @@ impl Htpasswd {
                 });
         }
 
+        let meta_path = Self::meta_path_for(pth);
+        let meta = if meta_path.exists() {
+            let content = read_to_string(&meta_path).map_err(|err| {
+                ErrorKind::Io.context(format!(
+                    "Could not read htpasswd metadata file: {} at {:?}",
+                    err, meta_path
+                ))
+            })?;
+            serde_json::from_str(&content).map_err(|err| {
+                ErrorKind::Config.context(format!(
+                    "Could not parse htpasswd metadata file: {} at {:?}",
+                    err, meta_path
+                ))
+            })?
+        } else {
+            HtpasswdMeta::default()
+        };
+
         Ok(Self {
             path: pth.clone(),
             credentials: c,
+            meta,
         })
     }
 
+    /// Returns the path of the metadata file, e.g. `.htpasswd.meta`
+    pub fn meta_path(&self) -> PathBuf {
+        Self::meta_path_for(&self.path)
+    }
+
+    fn meta_path_for(path: &Path) -> PathBuf {
+        let mut meta_path = path.to_path_buf().into_os_string();
+        meta_path.push(".meta");
+        meta_path.into()
+    }
+
     pub fn users(&self) -> Vec<String> {
         self.credentials.keys().cloned().collect()
     }
@@ -101,14 +147,47 @@ impl Htpasswd {
             .and_modify(|entry| *entry = cred.clone())
             .or_insert(cred);
 
+        let _ = self.meta.users.insert(
+            name.to_owned(),
+            CredentialMeta {
+                created: Utc::now(),
+            },
+        );
+
         Ok(())
     }
 
     /// Removes one credential by username
     pub fn delete(&mut self, name: &str) -> Option<Credential> {
+        let _ = self.meta.users.remove(name);
         self.credentials.remove(name)
     }
 
+    /// Returns the users whose password was set before `created_before`
+    ///
+    /// Users without metadata have an unknown age and are never returned.
+    pub fn expired_users(&self, created_before: DateTime<Utc>) -> Vec<String> {
+        self.credentials
+            .keys()
+            .filter(|name| {
+                self.meta
+                    .users
+                    .get(*name)
+                    .is_some_and(|meta| meta.created < created_before)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the users without metadata, i.e. with an unknown age
+    pub fn users_without_meta(&self) -> Vec<String> {
+        self.credentials
+            .keys()
+            .filter(|name| !self.meta.users.contains_key(*name))
+            .cloned()
+            .collect()
+    }
+
     pub fn insert(&mut self, cred: Credential) -> AppResult<()> {
         let Entry::Vacant(entry) = self.credentials.entry(cred.name.clone()) else {
             return Err(ErrorKind::Io
@@ -119,15 +198,23 @@ impl Htpasswd {
                     .into());
         };
 
+        let _ = self.meta.users.insert(
+            cred.name.clone(),
+            CredentialMeta {
+                created: Utc::now(),
+            },
+        );
         let _ = entry.insert(cred);
 
         Ok(())
     }
 
+    /// Writes the htpasswd file and its metadata file
     pub fn to_file(&self) -> ApiResult<()> {
+        // truncate, so deleted credentials don't survive at the end of the file
         let mut file = fs::OpenOptions::new()
             .create(true)
-            .truncate(false)
+            .truncate(true)
             .write(true)
             .open(&self.path)
             .map_err(|err| {
@@ -138,13 +225,27 @@ impl Htpasswd {
             })?;
 
         for (_n, c) in self.credentials.iter() {
-            let _e = file.write(c.to_string().as_bytes()).map_err(|err| {
+            file.write_all(c.to_string().as_bytes()).map_err(|err| {
                 ApiErrorKind::WritingToFileFailed(format!(
                     "Could not write to htpasswd file: {} at {:?}",
                     err, self.path
                 ))
-            });
+            })?;
         }
+
+        let meta = serde_json::to_string_pretty(&self.meta).map_err(|err| {
+            ApiErrorKind::WritingToFileFailed(format!(
+                "Could not serialize htpasswd metadata: {err}"
+            ))
+        })?;
+        fs::write(self.meta_path(), meta).map_err(|err| {
+            ApiErrorKind::WritingToFileFailed(format!(
+                "Could not write htpasswd metadata file: {} at {:?}",
+                err,
+                self.meta_path()
+            ))
+        })?;
+
         Ok(())
     }
 }
@@ -189,6 +290,19 @@ impl Credential {
             hash: split[1].to_string(),
         })
     }
+
+    /// Returns the name of the hash scheme of the password
+    pub fn hash_scheme(&self) -> &'static str {
+        match &self.hash {
+            hash if hash.starts_with("$apr1$") => "MD5 (apr1)",
+            hash if hash.starts_with("$2y$") => "bcrypt",
+            hash if hash.starts_with("$2a$") || hash.starts_with("$2b$") => {
+                "bcrypt with unsupported `$2a$`/`$2b$` prefix, use `htpasswd -B`"
+            }
+            hash if hash.starts_with("{SHA}") => "SHA1",
+            _ => "crypt",
+        }
+    }
 }
 
 impl Display for Credential {
@@ -199,11 +313,46 @@ impl Display for Credential {
 
 #[cfg(test)]
 mod test {
+    use std::{fs, path::PathBuf};
+
     use crate::auth::Auth;
     use crate::htpasswd::Htpasswd;
     use anyhow::Result;
+    use chrono::{TimeDelta, Utc};
     use insta::assert_toml_snapshot;
 
+    #[test]
+    fn test_htpasswd_meta_expiry_passes() -> Result<()> {
+        let path = PathBuf::from("tests/generated/expiry.htpasswd");
+
+        let mut htpasswd = Htpasswd::from_file(&path)?;
+        let _ = htpasswd.update("old-user", "old");
+        let _ = htpasswd.update("new-user", "new");
+        htpasswd.to_file()?;
+
+        // pretend the old user was created 100 days ago, the other user has no metadata
+        let mut htpasswd = Htpasswd::from_file(&path)?;
+        htpasswd.meta.users.get_mut("old-user").unwrap().created =
+            Utc::now() - TimeDelta::days(100);
+        let _ = htpasswd.meta.users.remove("new-user");
+
+        let cutoff = Utc::now() - TimeDelta::days(90);
+        assert_eq!(htpasswd.expired_users(cutoff), vec!["old-user".to_string()]);
+        assert_eq!(htpasswd.users_without_meta(), vec!["new-user".to_string()]);
+
+        let _ = htpasswd.delete("old-user");
+        htpasswd.to_file()?;
+
+        let reread = Htpasswd::from_file(&path)?;
+        fs::remove_file(&path)?;
+        fs::remove_file(reread.meta_path())?;
+
+        assert_eq!(reread.users(), vec!["new-user".to_string()]);
+        assert!(reread.meta.users.is_empty());
+
+        Ok(())
+    }
+
     #[test]
     fn test_htpasswd_passes() -> Result<()> {
         let mut htpasswd = Htpasswd::new();
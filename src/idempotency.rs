@@ -0,0 +1,232 @@
+//! Deduplication of retried uploads and deletions
+//!
+//! On flaky networks, a client may not get the answer to an upload or a
+//! deletion and retry it, which then fails although the first attempt
+//! succeeded. If `idempotency-window` is configured, the status of successful
+//! requests carrying an `Idempotency-Key` header is kept for that long, and
+//! retries with the same key are answered with it without being executed again.
+//!
+//! Keys are only matched for the same method, path and credentials, so a key
+//! can't be used to learn about the requests of other users.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+/// Header identifying the retries of a request
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Header marking a response as the answer to an earlier request
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// Identity of a request, as far as retries are concerned
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RequestKey {
+    method: Method,
+    path: String,
+    /// Hash of the `Authorization` header, so no credentials are kept
+    credentials: [u8; 32],
+    key: HeaderValue,
+}
+
+impl RequestKey {
+    /// Returns the key of mutating requests with an `Idempotency-Key` header
+    fn of(request: &Request) -> Option<Self> {
+        if !matches!(*request.method(), Method::POST | Method::DELETE) {
+            return None;
+        }
+        let key = request.headers().get(&IDEMPOTENCY_KEY)?.clone();
+
+        let credentials = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .map(|value| Sha256::digest(value.as_bytes()).into())
+            .unwrap_or_default();
+
+        Some(Self {
+            method: request.method().clone(),
+            path: request.uri().path().to_string(),
+            credentials,
+            key,
+        })
+    }
+}
+
+/// Status of the successful requests within the window, by their key
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    window: Duration,
+    answered: Mutex<HashMap<RequestKey, (Instant, StatusCode)>>,
+}
+
+impl IdempotencyCache {
+    pub fn new(window: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            window,
+            answered: Mutex::default(),
+        })
+    }
+
+    /// Returns the status of an earlier request with this key, if it was
+    /// answered within the window
+    fn get(&self, key: &RequestKey) -> Option<StatusCode> {
+        let answered = self.answered.lock().unwrap_or_else(PoisonError::into_inner);
+        answered
+            .get(key)
+            .filter(|(at, _)| at.elapsed() < self.window)
+            .map(|&(_, status)| status)
+    }
+
+    /// Keeps the status of a request, dropping those outside the window
+    fn insert(&self, key: RequestKey, status: StatusCode) {
+        let mut answered = self.answered.lock().unwrap_or_else(PoisonError::into_inner);
+        answered.retain(|_, (at, _)| at.elapsed() < self.window);
+        let _ = answered.insert(key, (Instant::now(), status));
+    }
+}
+
+/// Router middleware function answering retries of successful uploads and
+/// deletions with the status of the first attempt
+///
+/// Failed requests are not kept, as a retry might succeed.
+pub async fn deduplicate_requests(
+    State(cache): State<Arc<IdempotencyCache>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = RequestKey::of(&request) else {
+        return next.run(request).await;
+    };
+
+    if let Some(status) = cache.get(&key) {
+        tracing::debug!(
+            method = %key.method,
+            path = %key.path,
+            %status,
+            "Replaying answer of a retried request."
+        );
+        return (
+            status,
+            [(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"))],
+        )
+            .into_response();
+    }
+
+    let response = next.run(request).await;
+    if response.status().is_success() {
+        cache.insert(key, response.status());
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        body::Body,
+        extract::Path,
+        http::{header, Method, Request, StatusCode},
+        middleware,
+        response::Response,
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::idempotency::{
+        deduplicate_requests, IdempotencyCache, IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED,
+    };
+
+    /// Returns a router counting the executed requests, which fail for `/fail`
+    fn app(window: Duration, executed: Arc<AtomicUsize>) -> Router {
+        Router::new()
+            .route(
+                "/:name",
+                post(move |Path(name): Path<String>| async move {
+                    let _ = executed.fetch_add(1, Ordering::Relaxed);
+                    if name == "fail" {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    } else {
+                        StatusCode::OK
+                    }
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                IdempotencyCache::new(window),
+                deduplicate_requests,
+            ))
+    }
+
+    async fn send(app: &Router, uri: &str, key: Option<&str>, user: &str) -> Response {
+        let mut request = Request::builder()
+            .uri(uri)
+            .method(Method::POST)
+            .header(header::AUTHORIZATION, user);
+        if let Some(key) = key {
+            request = request.header(&IDEMPOTENCY_KEY, key);
+        }
+        app.clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_deduplicate_requests_passes() {
+        let executed = Arc::new(AtomicUsize::new(0));
+        let app = app(Duration::from_secs(60), executed.clone());
+
+        let first = send(&app, "/file", Some("abc"), "alice").await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(!first.headers().contains_key(&IDEMPOTENT_REPLAYED));
+
+        // the retry is answered without executing it again
+        let retry = send(&app, "/file", Some("abc"), "alice").await;
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(retry.headers()[&IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(executed.load(Ordering::Relaxed), 1);
+
+        // other keys, paths and users and requests without key are executed
+        let _ = send(&app, "/file", Some("def"), "alice").await;
+        let _ = send(&app, "/other", Some("abc"), "alice").await;
+        let _ = send(&app, "/file", Some("abc"), "bob").await;
+        let _ = send(&app, "/file", None, "alice").await;
+        let _ = send(&app, "/file", None, "alice").await;
+        assert_eq!(executed.load(Ordering::Relaxed), 6);
+
+        // failures are not kept
+        let _ = send(&app, "/fail", Some("abc"), "alice").await;
+        let retry = send(&app, "/fail", Some("abc"), "alice").await;
+        assert_eq!(retry.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(executed.load(Ordering::Relaxed), 8);
+    }
+
+    #[tokio::test]
+    async fn test_deduplicate_requests_expire_passes() {
+        let executed = Arc::new(AtomicUsize::new(0));
+        let app = app(Duration::ZERO, executed.clone());
+
+        let _ = send(&app, "/file", Some("abc"), "alice").await;
+        let retry = send(&app, "/file", Some("abc"), "alice").await;
+        assert!(!retry.headers().contains_key(&IDEMPOTENT_REPLAYED));
+        assert_eq!(executed.load(Ordering::Relaxed), 2);
+    }
+}
@@ -8,17 +8,31 @@
 
 pub mod acl;
 pub mod application;
+pub mod as_of;
 pub mod auth;
 pub mod commands;
 pub mod config;
+pub mod connections;
 pub mod context;
 pub mod error;
 pub mod handlers;
 pub mod htpasswd;
+pub mod idempotency;
 pub mod log;
+pub mod log_stream;
+pub mod maintenance;
+pub mod options;
+pub mod overload;
 pub mod prelude;
+pub mod provenance;
+pub mod proxy;
+pub mod sessions;
+pub mod stats;
 pub mod storage;
+pub mod tls;
 pub mod typed_path;
+pub mod uploads;
+pub mod user_agent;
 /// Web module
 ///
 /// implements a REST server as specified by
@@ -1,39 +1,103 @@
+use std::{
+    io::{self, Write},
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+use abscissa_core::{
+    component::Id, Application, Component, FrameworkError, FrameworkErrorKind, Shutdown, Version,
+};
 use axum::{
     body::{Body, Bytes},
     extract::Request,
+    http::{header, HeaderMap, Method, Uri},
     middleware::Next,
     response::{IntoResponse, Response},
 };
 use http_body_util::BodyExt;
+use rand::{thread_rng, Rng};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
 
-use crate::error::ApiErrorKind;
+use crate::{error::ApiErrorKind, log_stream::LogStreamLayer};
+
+/// Log levels we can toggle between at runtime, ordered from least to most verbose
+const LOG_LEVELS: [LevelFilter; 5] = [
+    LevelFilter::ERROR,
+    LevelFilter::WARN,
+    LevelFilter::INFO,
+    LevelFilter::DEBUG,
+    LevelFilter::TRACE,
+];
 
 // Add the `#[debug_middleware]` attribute to the function to make debugging easier.
 // use axum_macros::debug_middleware;
 //
 // #[debug_middleware]
 /// Router middleware function to print additional information on the request and response.
+///
+/// Only the configured share of requests is logged (see [`init_log_sample_rate`]),
+/// failed ones are always logged, though without the request body.
 pub async fn print_request_response(
     req: Request,
     next: Next,
 ) -> Result<impl IntoResponse, ApiErrorKind> {
+    if !is_sampled() {
+        return print_failed_request(req, next).await;
+    }
+
     let (parts, body) = req.into_parts();
     let uuid = uuid::Uuid::new_v4();
 
+    print_request_head(&uuid, &parts.method, &parts.uri, &parts.headers);
+
+    // Reading the body makes hyper confirm an `Expect: 100-continue`, so leave it
+    // to the handler, which may still reject the request based on its headers.
+    let body = if expects_continue(&parts.headers) {
+        tracing::debug!(id = %uuid, "[BODY] not logged, client expects 100-continue");
+        body
+    } else {
+        Body::from(buffer_and_print(&uuid, body).await?)
+    };
+
+    let req = Request::from_parts(parts, body);
+
+    let res = next.run(req).await;
+    print_response(&uuid, res).await
+}
+
+/// Runs a request that wasn't sampled and only logs it if it failed
+async fn print_failed_request(req: Request, next: Next) -> Result<Response, ApiErrorKind> {
+    let (method, uri, headers) = (
+        req.method().clone(),
+        req.uri().clone(),
+        req.headers().clone(),
+    );
+
+    let res = next.run(req).await;
+    if !res.status().is_client_error() && !res.status().is_server_error() {
+        return Ok(res);
+    }
+
+    let uuid = uuid::Uuid::new_v4();
+    print_request_head(&uuid, &method, &uri, &headers);
+    tracing::debug!(id = %uuid, "[BODY] not logged, request was not sampled");
+    print_response(&uuid, res).await
+}
+
+fn print_request_head(uuid: &uuid::Uuid, method: &Method, uri: &Uri, headers: &HeaderMap) {
     tracing::debug!(
         id = %uuid,
-        method = %parts.method,
-        uri = %parts.uri,
+        method = %method,
+        uri = %uri,
         "[REQUEST]",
     );
 
-    tracing::debug!(id = %uuid, headers = ?parts.headers, "[HEADERS]");
-
-    let bytes = buffer_and_print(&uuid, body).await?;
-
-    let req = Request::from_parts(parts, Body::from(bytes));
+    tracing::debug!(id = %uuid, headers = ?headers, "[HEADERS]");
+}
 
-    let res = next.run(req).await;
+async fn print_response(uuid: &uuid::Uuid, res: Response) -> Result<Response, ApiErrorKind> {
     let (parts, body) = res.into_parts();
 
     tracing::debug!(
@@ -43,10 +107,44 @@ pub async fn print_request_response(
         "[RESPONSE]",
     );
 
-    let bytes = buffer_and_print(&uuid, body).await?;
-    let res = Response::from_parts(parts, Body::from(bytes));
+    // server-sent events don't end, e.g. those of `GET /admin/logs`
+    if is_event_stream(&parts.headers) {
+        tracing::debug!(id = %uuid, "[BODY] not logged, response is an event stream");
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    let bytes = buffer_and_print(uuid, body).await?;
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
 
-    Ok(res)
+/// Returns `true` if the response is a stream of server-sent events
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/event-stream"))
+}
+
+// Global that stores the share of requests logged by `print_request_response`
+static LOG_SAMPLE_RATE: OnceLock<f64> = OnceLock::new();
+
+/// Sets the share of requests (between 0.0 and 1.0) that are logged in detail
+pub fn init_log_sample_rate(sample_rate: f64) {
+    let _ = LOG_SAMPLE_RATE.get_or_init(|| sample_rate);
+}
+
+/// Returns whether the current request is logged in detail, which all are by default
+fn is_sampled() -> bool {
+    match LOG_SAMPLE_RATE.get() {
+        Some(&rate) if rate < 1.0 => thread_rng().gen_bool(rate.max(0.0)),
+        _ => true,
+    }
+}
+
+/// Returns `true` if the client waits for `100 Continue` before sending the body
+fn expects_continue(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::EXPECT)
+        .is_some_and(|expect| expect.as_bytes().eq_ignore_ascii_case(b"100-continue"))
 }
 
 async fn buffer_and_print<B>(uuid: &uuid::Uuid, body: B) -> Result<Bytes, ApiErrorKind>
@@ -69,3 +167,245 @@ where
 
     Ok(bytes)
 }
+
+/// Flushes a buffered log writer, e.g. of an access log
+pub type FlushHook = Box<dyn Fn() -> io::Result<()> + Send + Sync>;
+
+// Log writers to flush before the application exits
+static FLUSH_HOOKS: Mutex<Vec<(&'static str, FlushHook)>> = Mutex::new(Vec::new());
+
+/// Registers a log writer to be flushed when the application shuts down.
+pub fn register_flush_hook(
+    name: &'static str,
+    hook: impl Fn() -> io::Result<()> + Send + Sync + 'static,
+) {
+    FLUSH_HOOKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push((name, Box::new(hook)));
+}
+
+/// Flushes all registered log writers and the standard output streams.
+///
+/// Errors are printed to stderr, as the logs themselves can't be trusted anymore.
+pub fn flush_logs() {
+    for (name, hook) in FLUSH_HOOKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        if let Err(err) = hook() {
+            eprintln!("Could not flush {name}: {err}");
+        }
+    }
+
+    _ = io::stdout().flush();
+    _ = io::stderr().flush();
+}
+
+/// Abscissa component flushing the logs before the application exits
+///
+/// `Application::shutdown` exits the process right after notifying the
+/// components, so buffered log lines would be lost otherwise.
+#[derive(Debug, Default)]
+pub struct LogFlushComponent;
+
+impl<A: Application> Component<A> for LogFlushComponent {
+    fn id(&self) -> Id {
+        Id::new(concat!(module_path!(), "::LogFlushComponent"))
+    }
+
+    fn version(&self) -> Version {
+        Version::parse(env!("CARGO_PKG_VERSION")).unwrap()
+    }
+
+    fn before_shutdown(&self, kind: Shutdown) -> Result<(), FrameworkError> {
+        tracing::debug!(?kind, "Flushing logs before shutdown.");
+        flush_logs();
+        Ok(())
+    }
+}
+
+/// Returns the next more (or less) verbose log level, saturating at both ends.
+pub(crate) fn step_log_level(current: LevelFilter, more_verbose: bool) -> LevelFilter {
+    // logging is off, so it can only be turned on again
+    if current == LevelFilter::OFF {
+        return if more_verbose {
+            LOG_LEVELS[0]
+        } else {
+            LevelFilter::OFF
+        };
+    }
+
+    let idx = LOG_LEVELS
+        .iter()
+        .position(|level| *level == current)
+        .unwrap_or(0);
+
+    let idx = if more_verbose {
+        (idx + 1).min(LOG_LEVELS.len() - 1)
+    } else {
+        idx.saturating_sub(1)
+    };
+
+    LOG_LEVELS[idx]
+}
+
+// Global handle to swap the filter of the tracing subscriber
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Sets up the global tracing subscriber, which prints the events passing
+/// `filter` and hands them to the [`log_stream`](crate::log_stream::log_stream)
+///
+/// It takes the place of the subscriber of Abscissa's `Tracing` component,
+/// which can't be extended, and keeps its output and reloadable filter.
+pub fn init_tracing(filter: &str, ansi: bool) -> Result<(), FrameworkError> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(filter));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_ansi(ansi))
+        .with(LogStreamLayer)
+        .try_init()
+        .map_err(|err| FrameworkErrorKind::ComponentError.context(err))?;
+
+    let _ = LOG_FILTER.set(handle);
+    Ok(())
+}
+
+/// Swap the filter of the global tracing subscriber.
+fn reload_log_level(level: LevelFilter) {
+    let Some(handle) = LOG_FILTER.get() else {
+        tracing::warn!("Tracing not initialized, can't change log level.");
+        return;
+    };
+
+    match handle.reload(EnvFilter::new(level.to_string())) {
+        Ok(()) => tracing::warn!(%level, "Changed log level at runtime."),
+        Err(err) => tracing::warn!("Could not change log level: {err}"),
+    }
+}
+
+/// Listen for `SIGUSR1`/`SIGUSR2` and raise/lower the log level accordingly.
+///
+/// This lets us get debug logs out of a running server without restarting it.
+#[cfg(unix)]
+pub async fn log_level_signal_handler() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut more_verbose = signal(SignalKind::user_defined1())?;
+    let mut less_verbose = signal(SignalKind::user_defined2())?;
+
+    loop {
+        let up = tokio::select! {
+            Some(()) = more_verbose.recv() => true,
+            Some(()) = less_verbose.recv() => false,
+            else => return Ok(()),
+        };
+
+        reload_log_level(step_log_level(LevelFilter::current(), up));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        body::{Body, Bytes},
+        http::{header, Request, StatusCode},
+        middleware,
+        response::sse::{Event, Sse},
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+    use tracing::level_filters::LevelFilter;
+
+    use super::{flush_logs, print_request_response, register_flush_hook, step_log_level};
+
+    #[test]
+    fn test_flush_logs_runs_hooks_passes() {
+        static FLUSHED: AtomicBool = AtomicBool::new(false);
+
+        register_flush_hook("test log", || {
+            FLUSHED.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+        register_flush_hook("failing log", || Err(std::io::Error::other("closed")));
+
+        flush_logs();
+        assert!(FLUSHED.load(Ordering::SeqCst));
+    }
+
+    /// Sends an upload, which is rejected without reading it, and returns
+    /// whether its body was read anyway
+    async fn body_read_on_rejected_upload(expect_continue: bool) -> bool {
+        let app = Router::new()
+            .route("/", post(|| async { StatusCode::FORBIDDEN }))
+            .layer(middleware::from_fn(print_request_response));
+
+        let read = Arc::new(AtomicBool::new(false));
+        let read_in_body = read.clone();
+        let body = Body::from_stream(futures::stream::once(async move {
+            read_in_body.store(true, Ordering::SeqCst);
+            Ok::<_, std::io::Error>(Bytes::from_static(b"pack"))
+        }));
+
+        let mut request = Request::builder().method("POST").uri("/");
+        if expect_continue {
+            request = request.header(header::EXPECT, "100-continue");
+        }
+
+        let resp = app.oneshot(request.body(body).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        read.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_body_not_read_passes() {
+        assert!(!body_read_on_rejected_upload(true).await);
+        assert!(body_read_on_rejected_upload(false).await);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_not_buffered_passes() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    // never ends
+                    Sse::new(futures::stream::pending::<Result<Event, Infallible>>())
+                }),
+            )
+            .layer(middleware::from_fn(print_request_response));
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let resp = tokio::time::timeout(Duration::from_secs(5), app.oneshot(request))
+            .await
+            .expect("response is not buffered")
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_step_log_level_passes() {
+        assert_eq!(step_log_level(LevelFilter::INFO, true), LevelFilter::DEBUG);
+        assert_eq!(step_log_level(LevelFilter::INFO, false), LevelFilter::WARN);
+        assert_eq!(step_log_level(LevelFilter::TRACE, true), LevelFilter::TRACE);
+        assert_eq!(
+            step_log_level(LevelFilter::ERROR, false),
+            LevelFilter::ERROR
+        );
+        assert_eq!(step_log_level(LevelFilter::OFF, true), LevelFilter::ERROR);
+        assert_eq!(step_log_level(LevelFilter::OFF, false), LevelFilter::OFF);
+    }
+}
@@ -0,0 +1,162 @@
+//! Log events of the server, for the admin endpoint streaming them
+//!
+//! A `tracing` layer hands every event passing the log level to the global
+//! [`LogStream`], which keeps the most recent ones and broadcasts new ones to
+//! all subscribers. This way admins can watch the logs of a remote deployment
+//! without shell access, starting with what happened just before.
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Number of recent events a new subscriber gets first
+const RECENT_EVENTS: usize = 1000;
+
+/// Number of events buffered for a subscriber which doesn't keep up
+const BUFFERED_EVENTS: usize = 1024;
+
+// Global stream of the log events
+static LOG_STREAM: OnceLock<LogStream> = OnceLock::new();
+
+/// Returns the global stream of the log events
+pub fn log_stream() -> &'static LogStream {
+    LOG_STREAM.get_or_init(LogStream::default)
+}
+
+/// A log event, as streamed by the admin endpoint
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct LogEvent {
+    pub time: DateTime<Utc>,
+    pub level: String,
+    pub target: String,
+    /// The message, followed by the other fields as `name=value`
+    pub message: String,
+}
+
+impl LogEvent {
+    fn from_event(event: &Event<'_>) -> Self {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        Self {
+            time: Utc::now(),
+            level: event.metadata().level().to_string(),
+            target: event.metadata().target().to_string(),
+            message: visitor.message + &visitor.fields,
+        }
+    }
+}
+
+/// Collects the message and the other fields of an event
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={value}", field.name());
+        }
+    }
+}
+
+/// The recent log events and the subscribers to new ones
+#[derive(Debug)]
+pub struct LogStream {
+    recent: Mutex<VecDeque<LogEvent>>,
+    sender: broadcast::Sender<LogEvent>,
+}
+
+impl Default for LogStream {
+    fn default() -> Self {
+        Self {
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_EVENTS)),
+            sender: broadcast::channel(BUFFERED_EVENTS).0,
+        }
+    }
+}
+
+impl LogStream {
+    /// Keeps the event and sends it to all subscribers
+    pub fn push(&self, event: LogEvent) {
+        let mut recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        if recent.len() == RECENT_EVENTS {
+            let _ = recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // there may be no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    /// Returns the recent events and a receiver of all events after them
+    pub fn subscribe(&self) -> (Vec<LogEvent>, broadcast::Receiver<LogEvent>) {
+        let recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        (recent.iter().cloned().collect(), self.sender.subscribe())
+    }
+}
+
+/// Layer handing all log events to the global [`LogStream`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogStreamLayer;
+
+impl<S: Subscriber> Layer<S> for LogStreamLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        log_stream().push(LogEvent::from_event(event));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::log_stream::{LogStream, LogStreamLayer};
+
+    #[test]
+    fn test_log_stream_passes() {
+        let stream = LogStream::default();
+        let subscriber = tracing_subscriber::registry().with(LogStreamLayer);
+
+        // events are recorded by the global stream
+        let (_, mut global) = super::log_stream().subscribe();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(repo = "test_repo", size = 42, "Quota exceeded.");
+        });
+        // other tests may log to it at the same time
+        let event = std::iter::from_fn(|| global.try_recv().ok())
+            .find(|event| event.message.starts_with("Quota exceeded."))
+            .unwrap();
+        assert_eq!(event.level, "WARN");
+        assert_eq!(event.message, "Quota exceeded. repo=test_repo size=42");
+
+        // new subscribers get the recent events, then the new ones
+        stream.push(event.clone());
+        let (recent, mut receiver) = stream.subscribe();
+        assert_eq!(recent, vec![event.clone()]);
+        assert!(receiver.try_recv().is_err());
+        stream.push(event.clone());
+        assert_eq!(receiver.try_recv().unwrap(), event);
+    }
+}
@@ -0,0 +1,241 @@
+//! Background maintenance of the repositories
+//!
+//! Clients that crash leave their locks behind, which block other clients
+//! until they are removed. If `lock-max-age` is configured, a background task
+//! periodically removes the locks of all repositories that are older than that.
+//!
+//! If `trash-dir` is configured, deleted repositories are moved there instead
+//! of being removed, and a background task purges them after `trash-retention`.
+
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+use crate::{
+    error::{ApiErrorKind, ApiResult},
+    storage::Storage,
+    typed_path::TpeKind,
+};
+
+/// Removes the locks of the repository at `path` which were not modified
+/// within `max_age` and returns their names
+pub async fn remove_stale_locks(
+    storage: &dyn Storage,
+    path: &Path,
+    max_age: Duration,
+) -> ApiResult<Vec<String>> {
+    let tpe = TpeKind::Locks.into_str();
+    let now = SystemTime::now();
+
+    let locks: Vec<_> = storage.read_dir(path, Some(tpe)).collect();
+    let mut removed = Vec::new();
+    for entry in locks {
+        let filename = storage.filename(path, tpe, Some(&entry.name));
+        // skip locks that vanished in the meantime
+        let Ok(modified) = tokio::fs::metadata(filename)
+            .await
+            .and_then(|metadata| metadata.modified())
+        else {
+            continue;
+        };
+        if now.duration_since(modified).unwrap_or_default() < max_age {
+            continue;
+        }
+
+        match storage.remove_file(path, tpe, Some(&entry.name)).await {
+            Ok(()) => removed.push(entry.name),
+            // removed by the client in the meantime
+            Err(ApiErrorKind::FileNotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Periodically removes stale locks of all repositories
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockSweeper {
+    /// Locks older than this are removed
+    pub max_age: Duration,
+
+    /// Time between two sweeps
+    pub interval: Duration,
+}
+
+impl LockSweeper {
+    /// Runs the sweeper in the background, starting with a sweep right away
+    pub fn spawn(self, storage: Arc<dyn Storage>) {
+        info!(
+            max_age = ?self.max_age,
+            interval = ?self.interval,
+            "Removing stale locks in the background."
+        );
+
+        drop(tokio::spawn(async move {
+            let mut ticks = interval(self.interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                _ = ticks.tick().await;
+                _ = self.sweep(storage.as_ref()).await;
+            }
+        }));
+    }
+
+    /// Removes the stale locks of all repositories and returns their number
+    pub async fn sweep(&self, storage: &dyn Storage) -> usize {
+        let mut count = 0;
+
+        for repo in storage.repositories() {
+            match remove_stale_locks(storage, &repo, self.max_age).await {
+                Ok(removed) if removed.is_empty() => {}
+                Ok(removed) => {
+                    info!(?repo, locks = ?removed, "Removed stale locks.");
+                    count += removed.len();
+                }
+                Err(err) => warn!(?repo, "Could not remove stale locks: {err}"),
+            }
+        }
+
+        debug!(count, "Lock sweep finished.");
+        count
+    }
+}
+
+/// Periodically removes the repositories in the trash directory which were
+/// deleted longer than the retention ago
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrashPurger {
+    /// Deleted repositories are kept this long
+    pub retention: Duration,
+
+    /// Time between two purges
+    pub interval: Duration,
+}
+
+impl TrashPurger {
+    /// Runs the purger in the background, starting with a purge right away
+    pub fn spawn(self, storage: Arc<dyn Storage>) {
+        info!(
+            retention = ?self.retention,
+            interval = ?self.interval,
+            "Purging deleted repositories in the background."
+        );
+
+        drop(tokio::spawn(async move {
+            let mut ticks = interval(self.interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                _ = ticks.tick().await;
+                _ = self.purge(storage.as_ref()).await;
+            }
+        }));
+    }
+
+    /// Removes the expired repositories from the trash and returns their number
+    pub async fn purge(&self, storage: &dyn Storage) -> usize {
+        match storage.purge_trash(self.retention).await {
+            Ok(purged) if purged.is_empty() => 0,
+            Ok(purged) => {
+                info!(repos = ?purged, "Purged deleted repositories.");
+                purged.len()
+            }
+            Err(err) => {
+                warn!("Could not purge deleted repositories: {err}");
+                0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use crate::{
+        maintenance::{LockSweeper, TrashPurger},
+        storage::{LocalStorage, Storage},
+    };
+
+    #[tokio::test]
+    async fn test_lock_sweeper_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_lock_sweeper");
+        if data_dir.exists() {
+            fs::remove_dir_all(&data_dir).unwrap();
+        }
+        for repo in ["repo", "team/repo"] {
+            fs::create_dir_all(data_dir.join(repo).join("keys")).unwrap();
+            fs::create_dir_all(data_dir.join(repo).join("locks")).unwrap();
+            fs::write(data_dir.join(repo).join("locks/lock"), "lock").unwrap();
+        }
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        assert_eq!(
+            storage.repositories(),
+            [PathBuf::from("repo"), PathBuf::from("team/repo")]
+        );
+
+        // the locks are too young
+        let sweeper = LockSweeper {
+            max_age: Duration::from_secs(3600),
+            interval: Duration::from_secs(60),
+        };
+        assert_eq!(sweeper.sweep(&storage).await, 0);
+        assert!(data_dir.join("repo/locks/lock").exists());
+
+        let sweeper = LockSweeper {
+            max_age: Duration::ZERO,
+            ..sweeper
+        };
+        assert_eq!(sweeper.sweep(&storage).await, 2);
+        assert!(!data_dir.join("repo/locks/lock").exists());
+        assert!(!data_dir.join("team/repo/locks/lock").exists());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trash_purger_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_trash_purger");
+        let trash_dir = PathBuf::from("tests/generated/test_trash_purger_trash");
+        for dir in [&data_dir, &trash_dir] {
+            if dir.exists() {
+                fs::remove_dir_all(dir).unwrap();
+            }
+        }
+        fs::create_dir_all(data_dir.join("repo/keys")).unwrap();
+        let storage = LocalStorage::init(&data_dir)
+            .unwrap()
+            .set_trash_dir(Some(trash_dir.clone()));
+        storage.remove_repository(Path::new("repo")).await.unwrap();
+
+        // the repository was deleted too recently
+        let purger = TrashPurger {
+            retention: Duration::from_secs(3600),
+            interval: Duration::from_secs(60),
+        };
+        assert_eq!(purger.purge(&storage).await, 0);
+        assert_eq!(fs::read_dir(&trash_dir).unwrap().count(), 1);
+
+        let purger = TrashPurger {
+            retention: Duration::ZERO,
+            ..purger
+        };
+        assert_eq!(purger.purge(&storage).await, 1);
+        assert_eq!(fs::read_dir(&trash_dir).unwrap().count(), 0);
+        assert!(storage.restore_repository(Path::new("repo")).await.is_err());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+        fs::remove_dir_all(&trash_dir).unwrap();
+    }
+}
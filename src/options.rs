@@ -0,0 +1,118 @@
+//! Answers to `OPTIONS` requests
+//!
+//! Tools and CORS preflights send `OPTIONS` to learn which methods a path
+//! supports. No route handles `OPTIONS` itself, so the router refuses it with
+//! `405 Method Not Allowed` and an `Allow` header listing the methods of the
+//! matched route. That refusal is turned into the answer here, so the allowed
+//! methods always match the routes registered in `web.rs`.
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Router middleware function answering `OPTIONS` requests with the methods
+/// allowed for the path
+///
+/// The router adds the `Allow` header outside of its layers, so this must
+/// wrap the whole router instead of being a layer of it. Preflights, i.e.
+/// requests with an `Access-Control-Request-Method` header, get the methods
+/// as `Access-Control-Allow-Methods`, too. Paths without a route are still
+/// answered with `404 Not Found`.
+pub async fn answer_options(request: Request, next: Next) -> Response {
+    if request.method() != Method::OPTIONS {
+        return next.run(request).await;
+    }
+
+    let preflight = request
+        .headers()
+        .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+    let response = next.run(request).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+    let Some(allow) = response
+        .headers()
+        .get(header::ALLOW)
+        .and_then(|methods| methods.to_str().ok())
+        .filter(|methods| !methods.is_empty())
+        .and_then(|methods| HeaderValue::try_from(format!("{methods},OPTIONS")).ok())
+    else {
+        return response;
+    };
+
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    if preflight {
+        _ = response
+            .headers_mut()
+            .insert(header::ACCESS_CONTROL_ALLOW_METHODS, allow.clone());
+    }
+    _ = response.headers_mut().insert(header::ALLOW, allow);
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+        middleware,
+        response::Response,
+        routing::{delete, get},
+        Router,
+    };
+    use tower::{Layer, ServiceExt};
+
+    use crate::options::answer_options;
+
+    async fn send(method: Method, uri: &str, preflight: bool) -> Response {
+        let app = middleware::from_fn(answer_options).layer(
+            Router::new()
+                .route("/:repo/config", get(|| async {}).post(|| async {}))
+                .route("/:repo/", delete(|| async {})),
+        );
+
+        let mut request = Request::builder().uri(uri).method(method);
+        if preflight {
+            request = request.header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST");
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_answer_options_passes() {
+        let resp = send(Method::OPTIONS, "/repo/config", false).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[header::ALLOW], "GET,HEAD,POST,OPTIONS");
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+
+        let resp = send(Method::OPTIONS, "/repo/", true).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[header::ALLOW], "DELETE,OPTIONS");
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "DELETE,OPTIONS"
+        );
+
+        // other methods are left alone
+        let resp = send(Method::GET, "/repo/config", false).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = send(Method::PUT, "/repo/config", false).await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_answer_options_unknown_path_fails() {
+        let resp = send(Method::OPTIONS, "/repo/keys/abc/def", false).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(!resp.headers().contains_key(header::ALLOW));
+    }
+}
@@ -0,0 +1,107 @@
+//! Load shedding of requests beyond `max-inflight`
+//!
+//! Under a spike of requests, queueing all of them lets memory and latency
+//! grow without bound. If `max-inflight` is configured, only that many
+//! requests are handled at the same time. Further requests are answered with
+//! `503 Service Unavailable` and a `Retry-After` header right away, so the
+//! server stays responsive and clients back off.
+
+use axum::{
+    error_handling::HandleErrorLayer,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    BoxError, Router,
+};
+use tower::{limit::GlobalConcurrencyLimitLayer, load_shed::error::Overloaded, ServiceBuilder};
+
+/// Seconds clients are asked to wait before retrying a shed request
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Sheds the requests to `app` beyond `max_inflight` handled at the same time
+pub fn shed_load(app: Router, max_inflight: usize) -> Router {
+    app.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(shed_request))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::new(max_inflight)),
+    )
+}
+
+/// Answers a request refused by the load shedding
+async fn shed_request(err: BoxError) -> Response {
+    if err.is::<Overloaded>() {
+        tracing::debug!("Shedding request, too many requests in flight.");
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            "too many requests in flight",
+        )
+            .into_response()
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        response::Response,
+        routing::get,
+        Router,
+    };
+    use tokio::{
+        sync::{mpsc, Notify},
+        task::JoinHandle,
+    };
+    use tower::ServiceExt;
+
+    use crate::overload::shed_load;
+
+    async fn send(app: Router) -> Response {
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shed_load_passes() {
+        let (started, mut handling) = mpsc::unbounded_channel();
+        let release = Arc::new(Notify::new());
+        let waiting = release.clone();
+        let app = shed_load(
+            Router::new().route(
+                "/",
+                get(move || {
+                    let started = started.clone();
+                    let waiting = waiting.clone();
+                    async move {
+                        _ = started.send(());
+                        waiting.notified().await;
+                    }
+                }),
+            ),
+            1,
+        );
+        // sends a request which stays in flight until it is released
+        let in_flight = || -> JoinHandle<Response> { tokio::spawn(send(app.clone())) };
+
+        let first = in_flight();
+        handling.recv().await.unwrap();
+
+        let shed = send(app.clone()).await;
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.headers()[header::RETRY_AFTER], "1");
+
+        release.notify_one();
+        assert_eq!(first.await.unwrap().status(), StatusCode::OK);
+
+        // requests are handled again once there is room
+        let next = in_flight();
+        handling.recv().await.unwrap();
+        release.notify_one();
+        assert_eq!(next.await.unwrap().status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,69 @@
+//! Provenance of uploaded files, for auditing
+//!
+//! If enabled, the server records who uploaded each file, when and how many
+//! bytes, once the upload was finalized. The record is kept in a sidecar file
+//! below `<repo>/.provenance/<type>/`, so the file itself is not changed and
+//! listings don't show the records. Files which were already stored and
+//! weren't transferred again keep the record of their first upload.
+
+use std::{path::Path, sync::OnceLock};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::STORAGE;
+
+/// Directory of a repository holding the provenance records
+pub const PROVENANCE_DIR: &str = ".provenance";
+
+// Global that stores whether the provenance of uploads is recorded
+static RECORD_PROVENANCE: OnceLock<bool> = OnceLock::new();
+
+pub fn init_record_provenance(record: bool) {
+    let _ = RECORD_PROVENANCE.get_or_init(|| record);
+}
+
+/// Who uploaded a file, and when
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Authenticated user of the upload
+    pub uploaded_by: String,
+    /// Time the upload was finalized
+    pub uploaded_at: DateTime<Utc>,
+    /// Size of the file in bytes
+    pub size: u64,
+}
+
+/// User and file of an upload, whose provenance is recorded once it is saved
+#[derive(Debug, Clone, Copy)]
+pub struct Origin<'a> {
+    pub user: &'a str,
+    pub tpe: &'a str,
+    pub name: Option<&'a str>,
+}
+
+impl Origin<'_> {
+    /// Records the provenance of the finalized upload of `size` bytes to the
+    /// repository at `repo`, if enabled
+    ///
+    /// The file is saved already, so a record that can't be written is only
+    /// logged.
+    pub async fn record(&self, repo: &Path, size: u64) {
+        if !RECORD_PROVENANCE.get().copied().unwrap_or_default() {
+            return;
+        }
+
+        let provenance = Provenance {
+            uploaded_by: self.user.to_string(),
+            uploaded_at: Utc::now(),
+            size,
+        };
+        let storage = STORAGE.get().unwrap();
+        if let Err(err) = storage
+            .write_provenance(repo, self.tpe, self.name, &provenance)
+            .await
+        {
+            tracing::warn!(?repo, tpe = self.tpe, name = ?self.name, "Could not record the provenance: {err}");
+        }
+    }
+}
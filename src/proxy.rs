@@ -0,0 +1,137 @@
+//! Support for running behind a reverse proxy
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{uri::PathAndQuery, HeaderValue, Uri},
+    middleware::Next,
+    response::Response,
+};
+
+/// Header set by reverse proxies serving the server below a path prefix
+pub const X_FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// Reverse proxies whose forwarding headers we trust
+pub type TrustedProxies = Arc<Vec<IpAddr>>;
+
+/// Router middleware function to strip the `X-Forwarded-Prefix` from the request path.
+///
+/// The header is only honoured if the request comes directly from one of the
+/// trusted proxies, as any client could set it otherwise. This has to run
+/// before routing, so it must wrap the router instead of being a layer of it.
+pub async fn strip_forwarded_prefix(
+    State(trusted_proxies): State<TrustedProxies>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let is_trusted = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .is_some_and(|ConnectInfo(peer)| trusted_proxies.contains(&canonical_ip(peer.ip())));
+
+    if is_trusted {
+        if let Some(uri) = req
+            .headers()
+            .get(X_FORWARDED_PREFIX)
+            .and_then(|prefix| strip_prefix(req.uri(), prefix))
+        {
+            tracing::debug!(from = %req.uri(), to = %uri, "Stripped forwarded prefix.");
+            *req.uri_mut() = uri;
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Returns IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) as IPv4 addresses,
+/// as dual-stack sockets report IPv4 peers that way
+pub(crate) fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Returns `uri` without the path `prefix`, or `None` if the path is not below it
+fn strip_prefix(uri: &Uri, prefix: &HeaderValue) -> Option<Uri> {
+    let prefix = prefix.to_str().ok()?.trim_end_matches('/');
+    if !prefix.starts_with('/') {
+        return None;
+    }
+
+    let rest = uri.path().strip_prefix(prefix)?;
+    let path = match rest {
+        "" => "/",
+        rest if rest.starts_with('/') => rest,
+        // e.g. prefix `/foo` and path `/foobar`
+        _ => return None,
+    };
+
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use http_body_util::BodyExt;
+    use tower::{Layer, ServiceExt};
+
+    async fn request_path(peer: [u8; 4], prefix: &str, path: &str) -> String {
+        let app = Router::new().fallback(get(|uri: Uri| async move { uri.to_string() }));
+        let trusted: TrustedProxies = Arc::new(vec![IpAddr::from([10, 0, 0, 1])]);
+        let app = middleware::from_fn_with_state(trusted, strip_forwarded_prefix).layer(app);
+
+        let mut request = Request::builder()
+            .uri(path)
+            .header(X_FORWARDED_PREFIX, prefix)
+            .body(Body::empty())
+            .unwrap();
+        let _ = request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((peer, 1234))));
+
+        let resp = app.oneshot(request).await.unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_strip_forwarded_prefix_passes() {
+        let trusted = [10, 0, 0, 1];
+        assert_eq!(
+            request_path(trusted, "/backup", "/backup/repo/config").await,
+            "/repo/config"
+        );
+        assert_eq!(
+            request_path(trusted, "/backup/", "/backup/repo/keys/?a=b").await,
+            "/repo/keys/?a=b"
+        );
+        assert_eq!(request_path(trusted, "/backup", "/backup").await, "/");
+        assert_eq!(
+            request_path(trusted, "/backup", "/backupfoo/config").await,
+            "/backupfoo/config"
+        );
+        assert_eq!(
+            request_path(trusted, "backup", "/backup/config").await,
+            "/backup/config"
+        );
+
+        // untrusted clients can't change the path
+        assert_eq!(
+            request_path([10, 0, 0, 2], "/backup", "/backup/repo/config").await,
+            "/backup/repo/config"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Registry of the requests currently served, for the admin endpoints
+//!
+//! Every request is registered by the [`track_sessions`] middleware while it
+//! is served, i.e. until its response body was sent completely. Sessions can
+//! be revoked, which aborts the request and closes its connection.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    task::{ready, Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::{Frame, SizeHint};
+use pin_project::pin_project;
+use serde::Serialize;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+// Global registry of the active sessions
+static SESSIONS: OnceLock<SessionRegistry> = OnceLock::new();
+
+/// Returns the global registry of the active sessions
+pub fn sessions() -> &'static SessionRegistry {
+    SESSIONS.get_or_init(SessionRegistry::default)
+}
+
+/// A request currently served
+#[derive(Debug)]
+struct Session {
+    client: Option<IpAddr>,
+    user: OnceLock<String>,
+    method: String,
+    path: String,
+    started: Instant,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    revoked: CancellationToken,
+}
+
+/// Information about an active session, as reported by the admin endpoint
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub client: Option<IpAddr>,
+    /// Authenticated user, if the request was authenticated yet
+    pub user: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub duration_secs: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    next_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+}
+
+impl SessionRegistry {
+    fn register(&'static self, session: Session) -> ActiveSession {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let session = Arc::new(session);
+        let _ = self
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, session.clone());
+
+        ActiveSession(Arc::new(SessionGuard {
+            registry: self,
+            id,
+            session,
+        }))
+    }
+
+    /// Returns all active sessions, ordered by their start
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(&id, session)| SessionInfo {
+                id,
+                client: session.client,
+                user: session.user.get().cloned(),
+                method: session.method.clone(),
+                path: session.path.clone(),
+                bytes_received: session.bytes_received.load(Ordering::Relaxed),
+                bytes_sent: session.bytes_sent.load(Ordering::Relaxed),
+                duration_secs: session.started.elapsed().as_secs_f64(),
+            })
+            .collect()
+    }
+
+    /// Aborts the session with the given id.
+    ///
+    /// Returns `false`, if there is no such session.
+    pub fn revoke(&self, id: u64) -> bool {
+        let sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(session) = sessions.get(&id) else {
+            return false;
+        };
+
+        tracing::info!(id, path = %session.path, "Revoking session.");
+        session.revoked.cancel();
+        true
+    }
+}
+
+/// Removes the session from the registry once the request was served
+#[derive(Debug)]
+struct SessionGuard {
+    registry: &'static SessionRegistry,
+    id: u64,
+    session: Arc<Session>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let _ = self
+            .registry
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.id);
+    }
+}
+
+/// The session of a request, available as request extension to the handlers
+#[derive(Debug, Clone)]
+pub struct ActiveSession(Arc<SessionGuard>);
+
+impl ActiveSession {
+    /// Records the user the request was authenticated as
+    pub fn set_user(&self, user: &str) {
+        let _ = self.0.session.user.set(user.to_string());
+    }
+
+    fn wrap_body(&self, body: Body, direction: Direction) -> Body {
+        Body::new(TrackedBody {
+            inner: body,
+            revoked: self.0.session.revoked.clone().cancelled_owned(),
+            session: self.clone(),
+            direction,
+        })
+    }
+}
+
+/// Router middleware function to register the request as active session.
+pub async fn track_sessions(req: Request, next: Next) -> Response {
+    let client = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| peer.ip());
+
+    let session = sessions().register(Session {
+        client,
+        user: OnceLock::new(),
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        started: Instant::now(),
+        bytes_received: AtomicU64::new(0),
+        bytes_sent: AtomicU64::new(0),
+        revoked: CancellationToken::new(),
+    });
+
+    let (mut parts, body) = req.into_parts();
+    let _ = parts.extensions.insert(session.clone());
+    let req = Request::from_parts(parts, session.wrap_body(body, Direction::Received));
+
+    let revoked = session.0.session.revoked.clone();
+    let res = tokio::select! {
+        res = next.run(req) => res,
+        () = revoked.cancelled() => {
+            return (StatusCode::SERVICE_UNAVAILABLE, "session revoked").into_response();
+        }
+    };
+
+    let (parts, body) = res.into_parts();
+    Response::from_parts(parts, session.wrap_body(body, Direction::Sent))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Received,
+    Sent,
+}
+
+/// Body counting the transferred bytes, which fails once the session is revoked
+#[pin_project]
+struct TrackedBody {
+    #[pin]
+    inner: Body,
+    #[pin]
+    revoked: WaitForCancellationFutureOwned,
+    session: ActiveSession,
+    direction: Direction,
+}
+
+impl HttpBody for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+
+        // the error makes hyper close the connection
+        if this.revoked.poll(cx).is_ready() {
+            return Poll::Ready(Some(Err(axum::Error::new("session revoked"))));
+        }
+
+        let frame = ready!(this.inner.poll_frame(cx));
+
+        if let Some(data) = frame
+            .as_ref()
+            .and_then(|frame| frame.as_ref().ok())
+            .and_then(Frame::data_ref)
+        {
+            let session = &this.session.0.session;
+            let counter = match this.direction {
+                Direction::Received => &session.bytes_received,
+                Direction::Sent => &session.bytes_sent,
+            };
+            let _ = counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,509 @@
+//! Per-repository throughput statistics and storage latencies
+//!
+//! The bytes read from and written to the files of each repository are counted
+//! while serving requests. They are reported by `GET /:repo/stats` and, for all
+//! repositories, by `GET /metrics` in the Prometheus text format. The metrics
+//! also include the time of the last completed upload to each repository, so
+//! stale backups can be alerted on.
+//!
+//! The durations of storage operations are recorded in histograms, which are
+//! reported by `GET /metrics`, too.
+//!
+//! The number and size of the files of all repositories are counted on demand
+//! by the `stats` subcommand, so they can be exported without a running server.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, PoisonError, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use strum::VariantNames;
+
+use crate::{storage::Storage, typed_path::TpeKind};
+
+// Global statistics of all repositories
+static REPO_STATS: OnceLock<RepoStats> = OnceLock::new();
+
+/// Returns the global throughput statistics
+pub fn repo_stats() -> &'static RepoStats {
+    REPO_STATS.get_or_init(RepoStats::default)
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Unix timestamp of the last completed upload, `0` if there was none
+    last_write: AtomicU64,
+}
+
+/// Throughput of a repository since the server started
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+pub struct RepoThroughput {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct RepoStats {
+    repos: RwLock<BTreeMap<String, Arc<Counters>>>,
+}
+
+impl RepoStats {
+    /// Returns the counters of the repository, creating them on first use
+    fn counters(&self, repo: &Path) -> Arc<Counters> {
+        let key = repo_key(repo);
+
+        if let Some(counters) = self
+            .repos
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            return counters.clone();
+        }
+
+        self.repos
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key)
+            .or_default()
+            .clone()
+    }
+
+    /// Counts bytes read from a file of the repository
+    pub fn record_read(&self, repo: &Path, bytes: u64) {
+        let _ = self
+            .counters(repo)
+            .bytes_read
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts bytes written to a file of the repository
+    pub fn record_written(&self, repo: &Path, bytes: u64) {
+        let _ = self
+            .counters(repo)
+            .bytes_written
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Notes that a file of the repository was written completely, now
+    pub fn record_write_time(&self, repo: &Path) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let _ = self
+            .counters(repo)
+            .last_write
+            .fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// Returns the Unix timestamps of the last completed uploads of all
+    /// repositories written since the server started, ordered by name
+    pub fn last_writes(&self) -> BTreeMap<String, u64> {
+        self.repos
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(repo, counters)| (repo.clone(), counters.last_write.load(Ordering::Relaxed)))
+            .filter(|(_, last_write)| *last_write > 0)
+            .collect()
+    }
+
+    /// Returns the throughput of the repository
+    pub fn get(&self, repo: &Path) -> RepoThroughput {
+        self.repos
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&repo_key(repo))
+            .map(|counters| counters.throughput())
+            .unwrap_or_default()
+    }
+
+    /// Returns the throughput of all repositories, ordered by name
+    pub fn all(&self) -> BTreeMap<String, RepoThroughput> {
+        self.repos
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(repo, counters)| (repo.clone(), counters.throughput()))
+            .collect()
+    }
+
+    /// Renders the counters of all repositories in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        let all = self.all();
+        let mut out = String::new();
+
+        for (metric, help, value) in [
+            (
+                "rustic_server_repo_read_bytes_total",
+                "Bytes read from the files of the repository",
+                (|t: &RepoThroughput| t.bytes_read) as fn(&RepoThroughput) -> u64,
+            ),
+            (
+                "rustic_server_repo_written_bytes_total",
+                "Bytes written to the files of the repository",
+                |t: &RepoThroughput| t.bytes_written,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {metric} {help}");
+            let _ = writeln!(out, "# TYPE {metric} counter");
+            for (repo, throughput) in &all {
+                let _ = writeln!(
+                    out,
+                    "{metric}{{repo=\"{}\"}} {}",
+                    escape_label(repo),
+                    value(throughput)
+                );
+            }
+        }
+
+        let metric = "rustic_server_repo_last_write_timestamp";
+        let _ = writeln!(
+            out,
+            "# HELP {metric} Unix timestamp of the last completed upload to the repository"
+        );
+        let _ = writeln!(out, "# TYPE {metric} gauge");
+        for (repo, last_write) in self.last_writes() {
+            let _ = writeln!(
+                out,
+                "{metric}{{repo=\"{}\"}} {last_write}",
+                escape_label(&repo)
+            );
+        }
+
+        out
+    }
+}
+
+impl Counters {
+    fn throughput(&self) -> RepoThroughput {
+        RepoThroughput {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Global latencies of the storage operations
+static STORAGE_LATENCIES: OnceLock<StorageLatencies> = OnceLock::new();
+
+/// Returns the global latencies of the storage operations
+pub fn storage_latencies() -> &'static StorageLatencies {
+    STORAGE_LATENCIES.get_or_init(StorageLatencies::default)
+}
+
+/// Upper bounds of the latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Storage operations whose latency is recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageOperation {
+    CreateFile,
+    OpenFile,
+    ReadDir,
+    RemoveFile,
+}
+
+impl StorageOperation {
+    const ALL: [Self; 4] = [
+        Self::CreateFile,
+        Self::OpenFile,
+        Self::ReadDir,
+        Self::RemoveFile,
+    ];
+
+    /// Returns the name of the operation, as used in the metrics
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::CreateFile => "create_file",
+            Self::OpenFile => "open_file",
+            Self::ReadDir => "read_dir",
+            Self::RemoveFile => "remove_file",
+        }
+    }
+}
+
+/// Histogram of durations, with the buckets of [`LATENCY_BUCKETS`]
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    /// Number of durations per bucket, not cumulative; the last one counts
+    /// the durations above the largest bound
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        let _ = self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum_micros.fetch_add(
+            u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Returns the number of recorded durations
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// Latencies of the storage operations since the server started
+#[derive(Debug, Default)]
+pub struct StorageLatencies {
+    create_file: LatencyHistogram,
+    open_file: LatencyHistogram,
+    read_dir: LatencyHistogram,
+    remove_file: LatencyHistogram,
+}
+
+impl StorageLatencies {
+    /// Returns the histogram of the operation
+    pub const fn get(&self, operation: StorageOperation) -> &LatencyHistogram {
+        match operation {
+            StorageOperation::CreateFile => &self.create_file,
+            StorageOperation::OpenFile => &self.open_file,
+            StorageOperation::ReadDir => &self.read_dir,
+            StorageOperation::RemoveFile => &self.remove_file,
+        }
+    }
+
+    /// Records the duration of an operation
+    pub fn record(&self, operation: StorageOperation, duration: Duration) {
+        self.get(operation).record(duration);
+    }
+
+    /// Renders the histograms in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        let metric = "rustic_server_storage_operation_duration_seconds";
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP {metric} Duration of storage operations");
+        let _ = writeln!(out, "# TYPE {metric} histogram");
+        for operation in StorageOperation::ALL {
+            let histogram = self.get(operation);
+            let name = operation.name();
+
+            let mut cumulative = 0;
+            for (bucket, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count.load(Ordering::Relaxed);
+                let le = LATENCY_BUCKETS
+                    .get(bucket)
+                    .map_or_else(|| "+Inf".to_string(), ToString::to_string);
+                let _ = writeln!(
+                    out,
+                    "{metric}_bucket{{operation=\"{name}\",le=\"{le}\"}} {cumulative}"
+                );
+            }
+
+            #[allow(clippy::cast_precision_loss)]
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(out, "{metric}_sum{{operation=\"{name}\"}} {sum}");
+            let _ = writeln!(out, "{metric}_count{{operation=\"{name}\"}} {cumulative}");
+        }
+
+        out
+    }
+}
+
+/// Number and size of the files of one type of a repository
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileCount {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Files of all repositories of a storage, by repository and type
+#[derive(Debug, Default)]
+pub struct RepoFiles {
+    repos: BTreeMap<String, BTreeMap<&'static str, FileCount>>,
+}
+
+impl RepoFiles {
+    /// Counts the files of all repositories in the storage
+    pub fn count(storage: &impl Storage) -> Self {
+        let repos = storage
+            .repositories()
+            .into_iter()
+            .map(|repo| {
+                let types = TpeKind::VARIANTS
+                    .iter()
+                    .map(|&tpe| {
+                        let count = storage.read_dir(&repo, Some(tpe)).fold(
+                            FileCount::default(),
+                            |count, entry| FileCount {
+                                files: count.files + 1,
+                                bytes: count.bytes + entry.size,
+                            },
+                        );
+                        (tpe, count)
+                    })
+                    .collect();
+                (repo_key(&repo), types)
+            })
+            .collect();
+
+        Self { repos }
+    }
+
+    /// Returns the files of each type of all repositories, ordered by name
+    pub const fn all(&self) -> &BTreeMap<String, BTreeMap<&'static str, FileCount>> {
+        &self.repos
+    }
+
+    /// Renders the file counts in the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+
+        for (metric, help, value) in [
+            (
+                "rustic_server_repo_files",
+                "Number of files of the repository",
+                (|c: &FileCount| c.files) as fn(&FileCount) -> u64,
+            ),
+            (
+                "rustic_server_repo_file_bytes",
+                "Size of the files of the repository in bytes",
+                |c: &FileCount| c.bytes,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {metric} {help}");
+            let _ = writeln!(out, "# TYPE {metric} gauge");
+            for (repo, types) in &self.repos {
+                for (tpe, count) in types {
+                    let _ = writeln!(
+                        out,
+                        "{metric}{{repo=\"{}\",type=\"{tpe}\"}} {}",
+                        escape_label(repo),
+                        value(count)
+                    );
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Normalizes the repository path, so e.g. `repo` and `repo/` are counted together
+fn repo_key(repo: &Path) -> String {
+    repo.components()
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Escapes a Prometheus label value
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    #[test]
+    fn test_repo_stats_passes() {
+        let stats = RepoStats::default();
+        stats.record_read(Path::new("repo"), 10);
+        stats.record_read(Path::new("repo/"), 5);
+        stats.record_written(Path::new("team/\"repo\""), 7);
+
+        assert_eq!(
+            stats.get(Path::new("repo")),
+            RepoThroughput {
+                bytes_read: 15,
+                bytes_written: 0
+            }
+        );
+        assert_eq!(stats.get(Path::new("unknown")), RepoThroughput::default());
+
+        let metrics = stats.render_metrics();
+        assert!(metrics.contains("# TYPE rustic_server_repo_read_bytes_total counter\n"));
+        assert!(metrics.contains("rustic_server_repo_read_bytes_total{repo=\"repo\"} 15\n"));
+        assert!(metrics
+            .contains("rustic_server_repo_written_bytes_total{repo=\"team/\\\"repo\\\"\"} 7\n"));
+
+        // only repositories with a completed upload have a last write
+        assert!(stats.last_writes().is_empty());
+        stats.record_write_time(Path::new("team/\"repo\""));
+        let last_writes = stats.last_writes();
+        assert_eq!(last_writes.keys().collect::<Vec<_>>(), ["team/\"repo\""]);
+        let metrics = stats.render_metrics();
+        assert!(metrics.contains("# TYPE rustic_server_repo_last_write_timestamp gauge\n"));
+        assert!(metrics.contains(&format!(
+            "rustic_server_repo_last_write_timestamp{{repo=\"team/\\\"repo\\\"\"}} {}\n",
+            last_writes["team/\"repo\""]
+        )));
+    }
+
+    #[test]
+    fn test_repo_files_passes() {
+        let storage = LocalStorage::init(Path::new("tests/generated/test_storage")).unwrap();
+        let files = RepoFiles::count(&storage);
+
+        let keys = files.all()["test_repo"]["keys"];
+        assert!(keys.files >= 1);
+        assert!(keys.bytes >= 460);
+
+        let metrics = files.render_metrics();
+        assert!(metrics.contains("# TYPE rustic_server_repo_files gauge\n"));
+        assert!(metrics.contains(&format!(
+            "rustic_server_repo_files{{repo=\"test_repo\",type=\"keys\"}} {}\n",
+            keys.files
+        )));
+        assert!(metrics.contains(&format!(
+            "rustic_server_repo_file_bytes{{repo=\"test_repo\",type=\"keys\"}} {}\n",
+            keys.bytes
+        )));
+    }
+
+    #[test]
+    fn test_storage_latencies_passes() {
+        let latencies = StorageLatencies::default();
+        latencies.record(StorageOperation::OpenFile, Duration::from_micros(700));
+        latencies.record(StorageOperation::OpenFile, Duration::from_millis(20));
+        latencies.record(StorageOperation::OpenFile, Duration::from_secs(60));
+
+        assert_eq!(latencies.get(StorageOperation::OpenFile).count(), 3);
+        assert_eq!(latencies.get(StorageOperation::ReadDir).count(), 0);
+
+        let metrics = latencies.render_metrics();
+        let metric = "rustic_server_storage_operation_duration_seconds";
+        for line in [
+            format!("# TYPE {metric} histogram\n"),
+            format!("{metric}_bucket{{operation=\"open_file\",le=\"0.0005\"}} 0\n"),
+            format!("{metric}_bucket{{operation=\"open_file\",le=\"0.001\"}} 1\n"),
+            format!("{metric}_bucket{{operation=\"open_file\",le=\"0.025\"}} 2\n"),
+            format!("{metric}_bucket{{operation=\"open_file\",le=\"+Inf\"}} 3\n"),
+            format!("{metric}_sum{{operation=\"open_file\"}} 60.0207\n"),
+            format!("{metric}_count{{operation=\"open_file\"}} 3\n"),
+            format!("{metric}_count{{operation=\"read_dir\"}} 0\n"),
+        ] {
+            assert!(metrics.contains(&line), "{line}");
+        }
+    }
+}
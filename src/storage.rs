@@ -1,25 +1,162 @@
 use std::{
-    path::{Path, PathBuf},
-    sync::{Arc, OnceLock},
+    collections::HashMap,
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use tokio::fs::{create_dir_all, remove_dir_all, remove_file, File};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use strum::VariantNames;
+use tokio::{
+    fs::{copy, create_dir_all, remove_dir_all, remove_file, rename, write, File},
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, ReadBuf, Take},
+};
 use walkdir::WalkDir;
 
 use crate::{
-    config::default_data_dir,
-    error::{ApiErrorKind, ApiResult, AppResult},
-    handlers::file_helpers::WriteOrDeleteFile,
+    config::{default_data_dir, SizeAccounting, StorageSettings},
+    error::{is_cross_device, ApiErrorKind, ApiResult, AppResult},
+    handlers::file_helpers::{sync_parent_dir, Finalizer, WriteOrDeleteFile},
+    provenance::{Provenance, PROVENANCE_DIR},
+    typed_path::TpeKind,
+    uploads::uploads,
 };
 
+mod locked;
+mod metered;
+mod packed;
+
+pub use locked::LockedStorage;
+pub use metered::MeteredStorage;
+pub use packed::PackedLocalStorage;
+
 //Static storage of our credentials
 pub static STORAGE: OnceLock<Arc<dyn Storage>> = OnceLock::new();
 
 pub(crate) fn init_storage(storage: impl Storage) -> AppResult<()> {
-    let _ = STORAGE.get_or_init(|| Arc::new(storage));
+    let _ = STORAGE.get_or_init(|| Arc::new(LockedStorage::new(MeteredStorage::new(storage))));
     Ok(())
 }
 
+/// A file in the storage, as returned when listing a directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageEntry {
+    /// Name of the file
+    pub name: String,
+
+    /// Size of the file in bytes
+    pub size: u64,
+}
+
+/// A file in the storage, opened for reading
+///
+/// This is either a complete file, or a section of a larger file (e.g. a pack
+/// file). Reading and seeking are relative to the start of the section.
+#[derive(Debug)]
+pub struct StorageFile {
+    inner: Take<File>,
+    offset: u64,
+    len: u64,
+    seek_target: Option<u64>,
+}
+
+impl StorageFile {
+    /// Use the complete file
+    pub async fn from_file(file: File) -> io::Result<Self> {
+        let len = file.metadata().await?.len();
+        Ok(Self::section_unchecked(file, 0, len))
+    }
+
+    /// Use `len` bytes of the file, starting at `offset`
+    pub async fn section(mut file: File, offset: u64, len: u64) -> io::Result<Self> {
+        _ = file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Self::section_unchecked(file, offset, len))
+    }
+
+    fn section_unchecked(file: File, offset: u64, len: u64) -> Self {
+        Self {
+            inner: file.take(len),
+            offset,
+            len,
+            seek_target: None,
+        }
+    }
+
+    /// Returns the size of the file in bytes
+    pub const fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns whether the file is empty
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl AsyncRead for StorageFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncSeek for StorageFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let current = this.len - this.inner.limit();
+
+        let target = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => this.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => current.checked_add_signed(delta),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        Pin::new(this.inner.get_mut()).start_seek(SeekFrom::Start(this.offset + target))?;
+        this.seek_target = Some(target);
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        _ = ready!(Pin::new(this.inner.get_mut()).poll_complete(cx))?;
+
+        let position = this
+            .seek_target
+            .take()
+            .unwrap_or_else(|| this.len - this.inner.limit());
+        this.inner.set_limit(this.len.saturating_sub(position));
+
+        Poll::Ready(Ok(position))
+    }
+}
+
+/// A file in the storage, opened for writing
+///
+/// The file is only kept, if it was finalized.
+pub trait StorageWriter: AsyncWrite + Finalizer + Unpin + Send {}
+
+impl<T: AsyncWrite + Finalizer + Unpin + Send> StorageWriter for T {}
+
+#[async_trait::async_trait]
+impl Finalizer for Box<dyn StorageWriter> {
+    async fn finalize(&mut self) -> ApiResult<()> {
+        (**self).finalize().await
+    }
+}
+
 #[async_trait::async_trait]
 //#[enum_dispatch(StorageEnum)]
 pub trait Storage: Send + Sync + 'static {
@@ -28,121 +165,950 @@ pub trait Storage: Send + Sync + 'static {
     where
         Self: Sized;
 
+    /// Initialize the storage, applying the given storage settings
+    fn from_settings(path: &Path, _settings: &StorageSettings) -> ApiResult<Self>
+    where
+        Self: Sized,
+    {
+        Self::init(path)
+    }
+
     /// Returns the path of the storage
     fn path(&self) -> &Path;
 
+    /// Returns the maximum number of directories a repository path may consist of
+    fn max_repo_depth(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the configured repository names that can't be used
+    fn reserved_repo_names(&self) -> &[String] {
+        &[]
+    }
+
     async fn create_dir(&self, path: &Path, tpe: Option<&str>) -> ApiResult<()>;
 
-    fn read_dir(
+    fn read_dir(&self, path: &Path, tpe: Option<&str>) -> Box<dyn Iterator<Item = StorageEntry>>;
+
+    /// Lists the files in the order of their names, starting after the file
+    /// `after`
+    ///
+    /// Unlike with [`Storage::read_dir`], the order is stable, so a listing can
+    /// be resumed after the last file seen, even if files were added or
+    /// removed in between.
+    fn read_dir_after(
         &self,
         path: &Path,
         tpe: Option<&str>,
-    ) -> Box<dyn Iterator<Item = walkdir::DirEntry>>;
+        after: Option<&str>,
+    ) -> Box<dyn Iterator<Item = StorageEntry>> {
+        let mut entries: Vec<_> = self
+            .read_dir(path, tpe)
+            .filter(|entry| after.map_or(true, |after| entry.name.as_str() > after))
+            .collect();
+        entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Box::new(entries.into_iter())
+    }
 
     fn filename(&self, path: &Path, tpe: &str, name: Option<&str>) -> PathBuf;
 
-    async fn open_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<File>;
+    async fn open_file(&self, path: &Path, tpe: &str, name: Option<&str>)
+        -> ApiResult<StorageFile>;
 
     async fn create_file(
         &self,
         path: &Path,
         tpe: &str,
         name: Option<&str>,
-    ) -> ApiResult<WriteOrDeleteFile>;
+    ) -> ApiResult<Box<dyn StorageWriter>>;
 
     async fn remove_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()>;
 
     async fn remove_repository(&self, path: &Path) -> ApiResult<()>;
+
+    /// Moves the most recently removed repository at `path` back from the
+    /// trash directory
+    async fn restore_repository(&self, _path: &Path) -> ApiResult<()> {
+        Err(ApiErrorKind::NotImplemented)
+    }
+
+    /// Moves the repository at `from`, with all repositories below it, to
+    /// `to`, which must not exist yet
+    async fn rename_repository(&self, _from: &Path, _to: &Path) -> ApiResult<()> {
+        Err(ApiErrorKind::NotImplemented)
+    }
+
+    /// Removes the repositories which were moved to the trash directory more
+    /// than `max_age` ago and returns their paths
+    async fn purge_trash(&self, _max_age: Duration) -> ApiResult<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Returns the free space in bytes for new files of the repository, if it
+    /// can be determined
+    fn available_space(&self, _path: &Path) -> Option<u64> {
+        None
+    }
+
+    /// Returns the size of all files of the repository in bytes
+    async fn repository_size(&self, path: &Path) -> ApiResult<u64>;
+
+    /// Returns the paths of all repositories in the storage
+    fn repositories(&self) -> Vec<PathBuf>;
+
+    /// Returns the size of a file in bytes, without opening it
+    async fn file_length(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<u64> {
+        let metadata = tokio::fs::metadata(self.filename(path, tpe, name))
+            .await
+            .map_err(|err| {
+                ApiErrorKind::from_io_error(
+                    &err,
+                    "Could not get file metadata",
+                    ApiErrorKind::GettingFileMetadataFailed,
+                )
+            })?;
+
+        if !metadata.is_file() {
+            return Err(ApiErrorKind::FileNotFound(name.unwrap_or(tpe).to_string()));
+        }
+        Ok(metadata.len())
+    }
+
+    /// Returns the time a file was last modified, if it exists and the time
+    /// is known
+    fn modified(&self, path: &Path, tpe: &str, name: Option<&str>) -> Option<SystemTime> {
+        std::fs::metadata(self.filename(path, tpe, name))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Opens the gzip compressed variant `<name>.gz` stored alongside a file,
+    /// if there is one
+    async fn open_gzip_variant(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> Option<StorageFile> {
+        let mut filename = self.filename(path, tpe, name).into_os_string();
+        filename.push(GZIP_SUFFIX);
+
+        let file = File::open(filename).await.ok()?;
+        StorageFile::from_file(file).await.ok()
+    }
+
+    /// Records the provenance of the file, replacing an earlier record
+    async fn write_provenance(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+        provenance: &Provenance,
+    ) -> ApiResult<()> {
+        let record = provenance_filename(self, path, tpe, name);
+        let failed = |err: io::Error| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not record provenance",
+                ApiErrorKind::WritingToFileFailed,
+            )
+        };
+        let json = serde_json::to_vec(provenance)
+            .map_err(|err| ApiErrorKind::InternalError(err.to_string()))?;
+
+        if let Some(dir) = record.parent() {
+            create_dir_all(dir).await.map_err(failed)?;
+        }
+        // readers never see a partial record
+        let mut partial = record.clone().into_os_string();
+        partial.push(".tmp");
+        write(&partial, json).await.map_err(failed)?;
+        rename(&partial, &record).await.map_err(failed)
+    }
+
+    /// Returns the provenance recorded for the file
+    async fn read_provenance(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<Provenance> {
+        let record = provenance_filename(self, path, tpe, name);
+        let json = tokio::fs::read(&record).await.map_err(|err| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not read provenance",
+                ApiErrorKind::OpeningFileFailed,
+            )
+        })?;
+
+        serde_json::from_slice(&json).map_err(|err| {
+            ApiErrorKind::InternalError(format!(
+                "provenance `{}` is not valid: {err}",
+                record.display()
+            ))
+        })
+    }
+
+    /// Removes the provenance recorded for the file, if there is one
+    async fn remove_provenance(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()> {
+        match remove_file(provenance_filename(self, path, tpe, name)).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(ApiErrorKind::from_io_error(
+                &err,
+                "Could not remove provenance",
+                ApiErrorKind::RemovingFileFailed,
+            )),
+        }
+    }
+
+    /// Keeps a copy of the config of the repository as `config.bak.1`,
+    /// shifting older copies up to `config.bak.<keep>`
+    async fn backup_config(&self, path: &Path, keep: usize) -> ApiResult<()> {
+        let config = self.filename(path, TpeKind::Config.into_str(), None);
+        if keep == 0 || !config.exists() {
+            return Ok(());
+        }
+
+        let failed = |err: io::Error| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not keep a copy of the config",
+                ApiErrorKind::WritingToFileFailed,
+            )
+        };
+        for version in (1..keep).rev() {
+            let backup = config_backup_filename(&config, version);
+            if backup.exists() {
+                rename(backup, config_backup_filename(&config, version + 1))
+                    .await
+                    .map_err(failed)?;
+            }
+        }
+        let _ = copy(&config, config_backup_filename(&config, 1))
+            .await
+            .map_err(failed)?;
+
+        Ok(())
+    }
+
+    /// Returns the kept copies of the config of the repository, newest first
+    async fn config_backups(&self, path: &Path) -> Vec<ConfigBackup> {
+        let config = self.filename(path, TpeKind::Config.into_str(), None);
+
+        let mut backups = Vec::new();
+        for version in 1.. {
+            let Ok(metadata) = tokio::fs::metadata(config_backup_filename(&config, version)).await
+            else {
+                break;
+            };
+            backups.push(ConfigBackup {
+                version,
+                size: metadata.len(),
+            });
+        }
+        backups
+    }
+
+    /// Opens the kept copy `config.bak.<version>` of the config of the
+    /// repository
+    async fn open_config_backup(&self, path: &Path, version: usize) -> ApiResult<StorageFile> {
+        let config = self.filename(path, TpeKind::Config.into_str(), None);
+        let file = File::open(config_backup_filename(&config, version))
+            .await
+            .map_err(|err| {
+                ApiErrorKind::from_io_error(
+                    &err,
+                    "Could not open config backup",
+                    ApiErrorKind::OpeningFileFailed,
+                )
+            })?;
+
+        StorageFile::from_file(file)
+            .await
+            .map_err(|err| ApiErrorKind::GettingFileMetadataFailed(format!("{err:?}")))
+    }
+}
+
+/// Suffix of the gzip compressed variants of files
+pub const GZIP_SUFFIX: &str = ".gz";
+
+/// A kept copy of the config of a repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ConfigBackup {
+    /// Number of the copy, starting with `1` for the newest one
+    pub version: usize,
+
+    /// Size of the copy in bytes
+    pub size: u64,
+}
+
+/// Returns the file name of the provenance record of a file, which is
+/// `<repo>/.provenance/<type>/<name>`, or `<repo>/.provenance/config`
+fn provenance_filename<S: Storage + ?Sized>(
+    storage: &S,
+    path: &Path,
+    tpe: &str,
+    name: Option<&str>,
+) -> PathBuf {
+    let record = match name {
+        Some(name) => format!("{tpe}/{name}"),
+        None => tpe.to_string(),
+    };
+    storage.filename(path, PROVENANCE_DIR, Some(&record))
+}
+
+/// Returns the file of a walk as listed to clients
+fn storage_entry(entry: walkdir::DirEntry) -> Option<StorageEntry> {
+    // FIXME: Why do we filter out directories!?
+    if !entry.file_type().is_file() {
+        return None;
+    }
+    // valid names are hex, so such files were put there by other tools
+    let Some(name) = entry.file_name().to_str() else {
+        tracing::warn!(
+            "Skipping file with a name that isn't valid UTF-8: {}",
+            entry.path().display()
+        );
+        return None;
+    };
+    // skip files that vanished while listing
+    Some(StorageEntry {
+        name: name.to_string(),
+        size: entry.metadata().ok()?.len(),
+    })
+}
+
+/// Returns the file name of the copy `version` of the config at `config`
+fn config_backup_filename(config: &Path, version: usize) -> PathBuf {
+    let mut filename = config.as_os_str().to_owned();
+    filename.push(format!(".bak.{version}"));
+    filename.into()
+}
+
+/// Returns all directories below `data_dir` that look like a repository,
+/// relative to `data_dir`.
+fn find_repositories(data_dir: &Path) -> Vec<PathBuf> {
+    let mut repos: Vec<PathBuf> = WalkDir::new(data_dir)
+        .min_depth(1)
+        .into_iter()
+        // don't descend into the type directories of a repository
+        .filter_entry(|entry| {
+            !entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| TpeKind::VARIANTS.contains(&name))
+        })
+        .filter_map(walkdir::Result::ok)
+        .filter(|entry| {
+            entry.file_type().is_dir()
+                && (entry.path().join("config").is_file() || entry.path().join("keys").is_dir())
+        })
+        .filter_map(|entry| {
+            entry
+                .path()
+                .strip_prefix(data_dir)
+                .ok()
+                .map(Path::to_path_buf)
+        })
+        .collect();
+
+    repos.sort();
+    repos
+}
+
+/// Returns the entries of the trash directory with the time they were
+/// created at in milliseconds since the Unix epoch, oldest first
+///
+/// Every removal creates such an entry, named after its time, which holds
+/// the removed repository at its path.
+fn trash_entries(trash_dir: &Path) -> Vec<(u128, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(trash_dir) else {
+        return Vec::new();
+    };
+
+    let mut entries: Vec<_> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            let removed_at = entry.file_name().to_str()?.parse().ok()?;
+            Some((removed_at, entry.path()))
+        })
+        .collect();
+
+    entries.sort();
+    entries
+}
+
+/// Moves the directory `from` to `to`, copying it if they are on different
+/// filesystems
+async fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    match rename(from, to).await {
+        Err(err) if is_cross_device(&err) => {}
+        moved => return moved,
+    }
+
+    let (from, to) = (from.to_path_buf(), to.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        // copy next to the target first, so it is complete once it shows up
+        let part = to.with_file_name(format!(".{}.part", uuid::Uuid::new_v4()));
+        let copied = copy_dir_all(&from, &part).and_then(|()| std::fs::rename(&part, &to));
+        if let Err(err) = copied {
+            _ = std::fs::remove_dir_all(&part);
+            return Err(err);
+        }
+
+        std::fs::remove_dir_all(&from)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Copies the directory `from` with all its contents to `to`, syncing the
+/// copied files
+fn copy_dir_all(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(from).map_err(io::Error::other)?;
+        let target = to.join(relative);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else {
+            let _ = std::fs::copy(entry.path(), &target)?;
+            std::fs::File::open(&target)?.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the milliseconds since the Unix epoch
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Returns the size of a file in bytes, as accounted by `accounting`
+pub fn file_size(metadata: &std::fs::Metadata, accounting: SizeAccounting) -> u64 {
+    match accounting {
+        SizeAccounting::Logical => metadata.len(),
+        #[cfg(unix)]
+        SizeAccounting::Allocated => {
+            use std::os::unix::fs::MetadataExt;
+
+            // `blocks` is always in units of 512 bytes, whatever the block
+            // size of the filesystem is
+            metadata.blocks() * 512
+        }
+        #[cfg(not(unix))]
+        SizeAccounting::Allocated => metadata.len(),
+    }
+}
+
+/// Name of the file recording the shard layout of a repository
+const SHARD_LAYOUT_FILE: &str = ".shard-layout.toml";
+
+/// Maximum number of hex characters used for sharding, i.e. at most 65536
+/// shard directories are created per repository
+pub const MAX_SHARD_CHARS: u8 = 4;
+
+/// Layout of the shard directories `data` files are stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShardLayout {
+    /// Number of nested shard directories
+    pub depth: u8,
+
+    /// Number of hex characters of the file name per shard directory
+    pub width: u8,
+}
+
+impl Default for ShardLayout {
+    /// The layout used by restic, e.g. `data/ab/abcdef...`
+    fn default() -> Self {
+        Self { depth: 1, width: 2 }
+    }
+}
+
+impl ShardLayout {
+    /// Returns the shard directories of the file `name`, e.g. `ab/cd` for
+    /// `abcdef...` with two levels of width 2
+    fn shard_dir(&self, name: &str) -> PathBuf {
+        let width = usize::from(self.width);
+        (0..usize::from(self.depth))
+            .filter_map(|level| name.get(level * width..(level + 1) * width))
+            .collect()
+    }
+
+    /// Returns all shard directories of this layout
+    fn all_shard_dirs(&self) -> Vec<PathBuf> {
+        let chars = usize::from(self.depth) * usize::from(self.width);
+        (0..16_u32.pow(u32::try_from(chars).unwrap_or(u32::MAX)))
+            .map(|i| self.shard_dir(&format!("{i:0chars$x}")))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct LocalStorage {
     path: PathBuf,
+    data_dirs: Vec<PathBuf>,
+    /// Shard layout for new repositories
+    shard_layout: ShardLayout,
+    /// Shard layouts of the repositories, by repository directory
+    layouts: Arc<Mutex<HashMap<PathBuf, ShardLayout>>>,
+    max_repo_depth: Option<usize>,
+    reserved_repo_names: Vec<String>,
+    /// Directory uploads are written to until they are finalized
+    temp_dir: Option<PathBuf>,
+    /// Directory removed repositories are moved to
+    trash_dir: Option<PathBuf>,
+    /// Sync directories after adding or removing files
+    fsync_dirs: bool,
+    /// How the size of repositories is computed
+    size_accounting: SizeAccounting,
 }
 
 impl Default for LocalStorage {
     fn default() -> Self {
         Self {
             path: default_data_dir(),
+            data_dirs: Vec::new(),
+            shard_layout: ShardLayout::default(),
+            layouts: Arc::default(),
+            max_repo_depth: None,
+            reserved_repo_names: Vec::new(),
+            temp_dir: None,
+            trash_dir: None,
+            fsync_dirs: false,
+            size_accounting: SizeAccounting::default(),
         }
     }
 }
 
-impl LocalStorage {}
+impl LocalStorage {
+    /// Returns the directory holding the files of the type
+    fn type_dir(&self, path: &Path, tpe: Option<&str>) -> PathBuf {
+        tpe.map_or_else(
+            || self.base_dir(path).join(path),
+            |tpe| self.base_dir(path).join(path).join(tpe),
+        )
+    }
+
+    /// Returns a walk over the files of the type
+    fn walk_dir(&self, path: &Path, tpe: Option<&str>) -> WalkDir {
+        // `data` files are only stored in the shard directories
+        let max_depth = match tpe {
+            Some("data") => usize::from(self.repo_shard_layout(path).depth) + 1,
+            _ => usize::MAX,
+        };
+
+        WalkDir::new(self.type_dir(path, tpe)).max_depth(max_depth)
+    }
+
+    pub fn set_data_dirs(self, data_dirs: Vec<PathBuf>) -> Self {
+        Self { data_dirs, ..self }
+    }
+
+    pub fn set_shard_layout(self, shard_layout: ShardLayout) -> Self {
+        Self {
+            shard_layout,
+            ..self
+        }
+    }
+
+    pub fn set_max_repo_depth(self, max_repo_depth: Option<usize>) -> Self {
+        Self {
+            max_repo_depth,
+            ..self
+        }
+    }
+
+    pub fn set_reserved_repo_names(self, reserved_repo_names: Vec<String>) -> Self {
+        Self {
+            reserved_repo_names,
+            ..self
+        }
+    }
+
+    pub fn set_temp_dir(self, temp_dir: Option<PathBuf>) -> Self {
+        Self { temp_dir, ..self }
+    }
+
+    /// Returns the directory uploads are written to until they are finalized
+    pub fn temp_dir(&self) -> Option<&Path> {
+        self.temp_dir.as_deref()
+    }
+
+    pub fn set_trash_dir(self, trash_dir: Option<PathBuf>) -> Self {
+        Self { trash_dir, ..self }
+    }
+
+    pub fn set_fsync_dirs(self, fsync_dirs: bool) -> Self {
+        Self { fsync_dirs, ..self }
+    }
+
+    /// Returns whether directories are synced after adding or removing files
+    pub const fn fsync_dirs(&self) -> bool {
+        self.fsync_dirs
+    }
+
+    pub fn set_size_accounting(self, size_accounting: SizeAccounting) -> Self {
+        Self {
+            size_accounting,
+            ..self
+        }
+    }
+
+    /// Returns the shard layout of the `data` files of the given repository.
+    ///
+    /// Repositories without a recorded layout use the default layout.
+    fn repo_shard_layout(&self, repo: &Path) -> ShardLayout {
+        let repo_dir = self.base_dir(repo).join(repo);
+        let mut layouts = self.layouts.lock().unwrap_or_else(PoisonError::into_inner);
+
+        *layouts.entry(repo_dir).or_insert_with_key(|repo_dir| {
+            let Ok(content) = std::fs::read_to_string(repo_dir.join(SHARD_LAYOUT_FILE)) else {
+                return ShardLayout::default();
+            };
+            toml::from_str(&content).unwrap_or_else(|err| {
+                tracing::warn!(
+                    "Invalid shard layout in {}, using the default: {err}",
+                    repo_dir.display()
+                );
+                ShardLayout::default()
+            })
+        })
+    }
+
+    /// Determines the shard layout when creating the `data` directory of a repository.
+    ///
+    /// Existing repositories keep their layout, new repositories get the configured
+    /// one, which is recorded if it isn't the default.
+    async fn init_shard_layout(&self, repo: &Path) -> ApiResult<ShardLayout> {
+        let repo_dir = self.base_dir(repo).join(repo);
+        let layout_file = repo_dir.join(SHARD_LAYOUT_FILE);
+
+        if layout_file.exists() || repo_dir.join("data").exists() {
+            return Ok(self.repo_shard_layout(repo));
+        }
+
+        if self.shard_layout != ShardLayout::default() {
+            let content = toml::to_string(&self.shard_layout).map_err(|err| {
+                ApiErrorKind::WritingToFileFailed(format!(
+                    "Could not serialize shard layout: {err}"
+                ))
+            })?;
+            create_dir_all(&repo_dir).await.map_err(|err| {
+                ApiErrorKind::from_io_error(
+                    &err,
+                    "Could not create directory",
+                    ApiErrorKind::CreatingDirectoryFailed,
+                )
+            })?;
+            write(&layout_file, content).await.map_err(|err| {
+                ApiErrorKind::from_io_error(
+                    &err,
+                    "Could not write shard layout",
+                    ApiErrorKind::WritingToFileFailed,
+                )
+            })?;
+        }
+
+        let _ = self
+            .layouts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(repo_dir, self.shard_layout);
+
+        Ok(self.shard_layout)
+    }
+
+    /// Returns the directory the given repository is stored in.
+    ///
+    /// If multiple data directories are configured, the directory is selected
+    /// by hashing the repository name, so a repository always stays in the
+    /// same directory.
+    fn base_dir(&self, repo: &Path) -> &Path {
+        if self.data_dirs.is_empty() {
+            return &self.path;
+        }
+
+        // normalize the name, so e.g. `repo` and `repo/` end up in the same place
+        let repo: PathBuf = repo.components().collect();
+        let hash = Sha256::digest(repo.to_string_lossy().as_bytes());
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&hash[..8]);
+        let idx = u64::from_be_bytes(bytes) % self.data_dirs.len() as u64;
+
+        #[allow(clippy::cast_possible_truncation)]
+        &self.data_dirs[idx as usize]
+    }
+
+    /// Returns the directory of the repository to remove
+    ///
+    /// Fails with `InvalidPath` unless the directory lies below the data
+    /// directory, so a path like `""`, `.` or `/` can never remove the data
+    /// directory itself.
+    fn removable_repo_dir(&self, path: &Path) -> ApiResult<PathBuf> {
+        let invalid = || ApiErrorKind::InvalidPath(path.display().to_string());
+
+        let mut relative = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => relative.push(part),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(invalid());
+                }
+            }
+        }
+        if relative.as_os_str().is_empty() {
+            return Err(invalid());
+        }
+
+        let base_dir = self.base_dir(path);
+        let repo_dir = base_dir.join(relative);
+        if repo_dir == base_dir || !repo_dir.starts_with(base_dir) {
+            return Err(invalid());
+        }
+        Ok(repo_dir)
+    }
+
+    /// Returns the path of the repository directory `repo_dir` relative to
+    /// its data directory, as it is stored in the trash directory
+    fn trash_path<'a>(&self, path: &Path, repo_dir: &'a Path) -> ApiResult<&'a Path> {
+        repo_dir
+            .strip_prefix(self.base_dir(path))
+            .map_err(|_| ApiErrorKind::InvalidPath(path.display().to_string()))
+    }
+
+    /// Checks that the repository `from` and all repositories below it stay in
+    /// their data directory when they are moved to `to`
+    ///
+    /// Repositories are spread over the data directories by the hashes of
+    /// their names, so they would get lost by a rename within one directory
+    /// otherwise.
+    fn check_same_data_dir(&self, from: &Path, to: &Path) -> ApiResult<()> {
+        if self.data_dirs.len() < 2 {
+            return Ok(());
+        }
+
+        let base_dir = self.base_dir(from);
+        let nested = self.repositories().into_iter().filter_map(|repo| {
+            let new = to.join(repo.strip_prefix(from).ok()?);
+            Some((repo, new))
+        });
+
+        for (old, new) in std::iter::once((from.to_path_buf(), to.to_path_buf())).chain(nested) {
+            if self.base_dir(&old) != base_dir || self.base_dir(&new) != base_dir {
+                return Err(ApiErrorKind::BadRequest(format!(
+                    "repository {} can't be moved to {}, as it would change its data directory",
+                    old.display(),
+                    new.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the directory of a removed repository into a new entry of the
+    /// trash directory, copying it if the trash is on another filesystem
+    async fn move_to_trash(&self, path: &Path, repo_dir: &Path, trash_dir: &Path) -> ApiResult<()> {
+        let failed = |err: io::Error| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not move repository to the trash",
+                ApiErrorKind::RemovingRepositoryFailed,
+            )
+        };
+
+        // every removal gets its own entry, even within the same millisecond
+        let mut removed_at = unix_millis(SystemTime::now());
+        while trash_dir.join(removed_at.to_string()).exists() {
+            removed_at += 1;
+        }
+
+        let trashed = trash_dir
+            .join(removed_at.to_string())
+            .join(self.trash_path(path, repo_dir)?);
+        if let Some(parent) = trashed.parent() {
+            create_dir_all(parent).await.map_err(failed)?;
+        }
+        move_dir(repo_dir, &trashed).await.map_err(failed)
+    }
+}
 
 #[async_trait::async_trait]
 impl Storage for LocalStorage {
     fn init(path: &Path) -> ApiResult<Self> {
         Ok(Self {
             path: path.to_path_buf(),
+            ..Default::default()
         })
     }
 
+    fn from_settings(path: &Path, settings: &StorageSettings) -> ApiResult<Self> {
+        let default_layout = ShardLayout::default();
+        let shard_layout = ShardLayout {
+            depth: settings.shard_depth.unwrap_or(default_layout.depth),
+            width: settings.shard_width.unwrap_or(default_layout.width),
+        };
+
+        Ok(Self::init(path)?
+            .set_data_dirs(settings.data_dirs.clone())
+            .set_shard_layout(shard_layout)
+            .set_max_repo_depth(settings.max_repo_depth)
+            .set_reserved_repo_names(settings.reserved_repo_names.clone())
+            .set_temp_dir(settings.temp_dir.clone())
+            .set_trash_dir(settings.trash_dir.clone())
+            .set_fsync_dirs(settings.fsync_dirs)
+            .set_size_accounting(settings.size_accounting.unwrap_or_default()))
+    }
+
     fn path(&self) -> &Path {
         &self.path
     }
 
+    fn max_repo_depth(&self) -> Option<usize> {
+        self.max_repo_depth
+    }
+
+    fn reserved_repo_names(&self) -> &[String] {
+        &self.reserved_repo_names
+    }
+
+    /// Uploads are written to the temporary directory first, if there is one,
+    /// so they need room in both
+    fn available_space(&self, path: &Path) -> Option<u64> {
+        [Some(self.base_dir(path)), self.temp_dir()]
+            .into_iter()
+            .flatten()
+            .filter_map(|dir| fs4::available_space(dir).ok())
+            .min()
+    }
+
     async fn create_dir(&self, path: &Path, tpe: Option<&str>) -> ApiResult<()> {
         match tpe {
             Some(tpe) if tpe == "data" => {
-                for i in 0..256 {
-                    create_dir_all(self.path.join(path).join(tpe).join(format!("{:02x}", i)))
+                let data_dir = self.base_dir(path).join(path).join(tpe);
+                for shard_dir in self.init_shard_layout(path).await?.all_shard_dirs() {
+                    create_dir_all(data_dir.join(shard_dir))
                         .await
                         .map_err(|err| {
-                            ApiErrorKind::CreatingDirectoryFailed(format!(
-                                "Could not create directory: {err}"
-                            ))
+                            ApiErrorKind::from_io_error(
+                                &err,
+                                "Could not create directory",
+                                ApiErrorKind::CreatingDirectoryFailed,
+                            )
                         })?;
                 }
                 Ok(())
             }
-            Some(tpe) => create_dir_all(self.path.join(path).join(tpe))
+            Some(tpe) => create_dir_all(self.base_dir(path).join(path).join(tpe))
                 .await
                 .map_err(|err| {
-                    ApiErrorKind::CreatingDirectoryFailed(format!(
-                        "Could not create directory: {err}"
-                    ))
+                    ApiErrorKind::from_io_error(
+                        &err,
+                        "Could not create directory",
+                        ApiErrorKind::CreatingDirectoryFailed,
+                    )
+                }),
+            None => create_dir_all(self.base_dir(path).join(path))
+                .await
+                .map_err(|err| {
+                    ApiErrorKind::from_io_error(
+                        &err,
+                        "Could not create directory",
+                        ApiErrorKind::CreatingDirectoryFailed,
+                    )
                 }),
-            None => create_dir_all(self.path.join(path)).await.map_err(|err| {
-                ApiErrorKind::CreatingDirectoryFailed(format!("Could not create directory: {err}"))
-            }),
         }
     }
 
     // FIXME: Make async?
-    fn read_dir(
+    fn read_dir(&self, path: &Path, tpe: Option<&str>) -> Box<dyn Iterator<Item = StorageEntry>> {
+        let walker = self
+            .walk_dir(path, tpe)
+            .into_iter()
+            .filter_map(walkdir::Result::ok)
+            .filter_map(storage_entry);
+
+        Box::new(walker)
+    }
+
+    fn read_dir_after(
         &self,
         path: &Path,
         tpe: Option<&str>,
-    ) -> Box<dyn Iterator<Item = walkdir::DirEntry>> {
-        let path = tpe.map_or_else(
-            || self.path.join(path),
-            |tpe| self.path.join(path).join(tpe),
-        );
+        after: Option<&str>,
+    ) -> Box<dyn Iterator<Item = StorageEntry>> {
+        let walker = self.walk_dir(path, tpe).sort_by_file_name().into_iter();
+        let Some(after) = after.map(ToString::to_string) else {
+            return Box::new(
+                walker
+                    .filter_map(walkdir::Result::ok)
+                    .filter_map(storage_entry),
+            );
+        };
 
-        let walker = WalkDir::new(path)
-            .into_iter()
+        // shard directories are named after the start of their files, so
+        // those only holding files up to the cursor are skipped unread
+        let sharded = tpe == Some("data");
+        let dir = self.type_dir(path, tpe);
+        let walker = walker.filter_entry(move |entry| {
+            if !entry.file_type().is_dir() {
+                return entry.file_name().to_string_lossy().as_ref() > after.as_str();
+            }
+            let Ok(shard) = entry.path().strip_prefix(&dir) else {
+                return true;
+            };
+            let prefix: String = shard.iter().map(|part| part.to_string_lossy()).collect();
+            !sharded || prefix.as_str() > after.as_str() || after.starts_with(&prefix)
+        });
+
+        let walker = walker
             .filter_map(walkdir::Result::ok)
-            // FIXME: Why do we filter out directories!?
-            .filter(|e| e.file_type().is_file());
+            .filter_map(storage_entry);
 
         Box::new(walker)
     }
 
     fn filename(&self, path: &Path, tpe: &str, name: Option<&str>) -> PathBuf {
         match (tpe, name) {
-            ("config", _) => self.path.join(path).join("config"),
-            ("data", Some(name)) => self.path.join(path).join(tpe).join(&name[0..2]).join(name),
-            (tpe, Some(name)) => self.path.join(path).join(tpe).join(name),
-            (path, None) => self.path.join(path),
+            ("config", _) => self.base_dir(path).join(path).join("config"),
+            ("data", Some(name)) => self
+                .base_dir(path)
+                .join(path)
+                .join(tpe)
+                .join(self.repo_shard_layout(path).shard_dir(name))
+                .join(name),
+            (tpe, Some(name)) => self.base_dir(path).join(path).join(tpe).join(name),
+            (tpe, None) => self.base_dir(path).join(path).join(tpe),
         }
     }
 
-    async fn open_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<File> {
+    async fn open_file(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<StorageFile> {
         let file_path = self.filename(path, tpe, name);
-        Ok(File::open(file_path).await.map_err(|err| {
-            ApiErrorKind::OpeningFileFailed(format!("Could not open file: {}", err))
-        })?)
+        let file = File::open(file_path).await.map_err(|err| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not open file",
+                ApiErrorKind::OpeningFileFailed,
+            )
+        })?;
+
+        StorageFile::from_file(file)
+            .await
+            .map_err(|err| ApiErrorKind::GettingFileMetadataFailed(format!("{err:?}")))
     }
 
     async fn create_file(
@@ -150,33 +1116,483 @@ impl Storage for LocalStorage {
         path: &Path,
         tpe: &str,
         name: Option<&str>,
-    ) -> ApiResult<WriteOrDeleteFile> {
+    ) -> ApiResult<Box<dyn StorageWriter>> {
         let file_path = self.filename(path, tpe, name);
-        WriteOrDeleteFile::new(file_path).await
+        Ok(Box::new(
+            WriteOrDeleteFile::new(file_path, self.temp_dir())
+                .await?
+                .set_fsync_dir(self.fsync_dirs)
+                .set_upload(uploads().register(path, tpe, name)),
+        ))
     }
 
     async fn remove_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()> {
         let file_path = self.filename(path, tpe, name);
-        remove_file(file_path).await.map_err(|err| {
-            ApiErrorKind::RemovingFileFailed(format!("Could not remove file: {err}"))
-        })
+        let failed = |err: io::Error| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not remove file",
+                ApiErrorKind::RemovingFileFailed,
+            )
+        };
+
+        // the gzip variant goes first, so it is never served without the file
+        let mut gzip_variant = file_path.clone().into_os_string();
+        gzip_variant.push(GZIP_SUFFIX);
+        match remove_file(&gzip_variant).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(failed(err)),
+            _ => {}
+        }
+        remove_file(&file_path).await.map_err(failed)?;
+
+        if self.fsync_dirs {
+            sync_parent_dir(&file_path).await?;
+        }
+        Ok(())
     }
 
     async fn remove_repository(&self, path: &Path) -> ApiResult<()> {
+        let repo_dir = self.removable_repo_dir(path)?;
+        tracing::debug!("Deleting repository: {}", repo_dir.to_string_lossy());
+
+        self.layouts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|dir, _| !dir.starts_with(&repo_dir));
+
+        if let Some(trash_dir) = &self.trash_dir {
+            self.move_to_trash(path, &repo_dir, trash_dir).await?;
+        } else {
+            remove_dir_all(&repo_dir).await.map_err(|err| {
+                ApiErrorKind::from_io_error(
+                    &err,
+                    "Could not remove repository",
+                    ApiErrorKind::RemovingRepositoryFailed,
+                )
+            })?;
+        }
+
+        if self.fsync_dirs {
+            sync_parent_dir(&repo_dir).await?;
+        }
+        Ok(())
+    }
+
+    async fn restore_repository(&self, path: &Path) -> ApiResult<()> {
+        let Some(trash_dir) = &self.trash_dir else {
+            return Err(ApiErrorKind::NotImplemented);
+        };
+        let repo_dir = self.removable_repo_dir(path)?;
+        if repo_dir.exists() {
+            return Err(ApiErrorKind::BadRequest(format!(
+                "repository {} exists and can't be restored",
+                path.display()
+            )));
+        }
+
+        let trash_path = self.trash_path(path, &repo_dir)?;
+        let trashed = trash_entries(trash_dir)
+            .into_iter()
+            .rev()
+            .map(|(_, entry)| entry.join(trash_path))
+            .find(|trashed| trashed.is_dir())
+            .ok_or_else(|| ApiErrorKind::FileNotFound(path.display().to_string()))?;
+        tracing::debug!("Restoring repository from: {}", trashed.to_string_lossy());
+
+        let failed = |err: io::Error| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not restore repository",
+                ApiErrorKind::GeneralStorageError,
+            )
+        };
+        if let Some(parent) = repo_dir.parent() {
+            create_dir_all(parent).await.map_err(failed)?;
+        }
+        move_dir(&trashed, &repo_dir).await.map_err(failed)?;
+
+        // remove the directories left empty in the trash entry
+        for dir in trashed
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| *dir != trash_dir.as_path())
+        {
+            if std::fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+
+        if self.fsync_dirs {
+            sync_parent_dir(&repo_dir).await?;
+        }
+        Ok(())
+    }
+
+    async fn rename_repository(&self, from: &Path, to: &Path) -> ApiResult<()> {
+        let from_dir = self.removable_repo_dir(from)?;
+        let to_dir = self.removable_repo_dir(to)?;
+        if !from_dir.is_dir() {
+            return Err(ApiErrorKind::RepositoryNotFound(from.display().to_string()));
+        }
+        if to_dir.starts_with(&from_dir) {
+            return Err(ApiErrorKind::BadRequest(format!(
+                "repository {} can't be moved into itself",
+                from.display()
+            )));
+        }
+        if to_dir.exists() {
+            return Err(ApiErrorKind::RepositoryExists(to.display().to_string()));
+        }
+        self.check_same_data_dir(from, to)?;
         tracing::debug!(
-            "Deleting repository: {}",
-            self.path.join(path).to_string_lossy()
+            "Renaming repository: {} to {}",
+            from_dir.to_string_lossy(),
+            to_dir.to_string_lossy()
         );
-        remove_dir_all(self.path.join(path)).await.map_err(|err| {
-            ApiErrorKind::RemovingRepositoryFailed(format!("Could not remove repository: {err}"))
+
+        self.layouts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|dir, _| !dir.starts_with(&from_dir));
+
+        let failed = |err: io::Error| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not rename repository",
+                ApiErrorKind::GeneralStorageError,
+            )
+        };
+        if let Some(parent) = to_dir.parent() {
+            create_dir_all(parent).await.map_err(failed)?;
+        }
+        rename(&from_dir, &to_dir).await.map_err(failed)?;
+
+        if self.fsync_dirs {
+            sync_parent_dir(&from_dir).await?;
+            sync_parent_dir(&to_dir).await?;
+        }
+        Ok(())
+    }
+
+    async fn purge_trash(&self, max_age: Duration) -> ApiResult<Vec<PathBuf>> {
+        let Some(trash_dir) = &self.trash_dir else {
+            return Ok(Vec::new());
+        };
+        let now = unix_millis(SystemTime::now());
+
+        let mut purged = Vec::new();
+        for (removed_at, entry) in trash_entries(trash_dir) {
+            if now.saturating_sub(removed_at) < max_age.as_millis() {
+                continue;
+            }
+
+            let repos = find_repositories(&entry);
+            remove_dir_all(&entry).await.map_err(|err| {
+                ApiErrorKind::from_io_error(
+                    &err,
+                    "Could not purge trash",
+                    ApiErrorKind::RemovingRepositoryFailed,
+                )
+            })?;
+            purged.extend(repos);
+        }
+
+        Ok(purged)
+    }
+
+    async fn repository_size(&self, path: &Path) -> ApiResult<u64> {
+        let repo_dir = self.base_dir(path).join(path);
+        let accounting = self.size_accounting;
+
+        // walking a large repository takes a while, so don't block the runtime
+        tokio::task::spawn_blocking(move || {
+            WalkDir::new(repo_dir)
+                .into_iter()
+                .filter_map(walkdir::Result::ok)
+                .filter(|e| e.file_type().is_file())
+                // skip files that vanished while walking
+                .filter_map(|e| Some(file_size(&e.metadata().ok()?, accounting)))
+                .sum()
         })
+        .await
+        .map_err(|err| ApiErrorKind::InternalError(format!("Computing the size failed: {err}")))
+    }
+
+    fn repositories(&self) -> Vec<PathBuf> {
+        let mut repos: Vec<PathBuf> = [&self.path]
+            .into_iter()
+            .chain(&self.data_dirs)
+            .flat_map(|data_dir| find_repositories(data_dir))
+            .collect();
+
+        repos.sort();
+        repos.dedup();
+        repos
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::storage::{init_storage, LocalStorage, Storage, STORAGE};
-    use std::path::PathBuf;
+    use crate::{
+        config::SizeAccounting,
+        error::ApiErrorKind,
+        provenance::Provenance,
+        storage::{
+            init_storage, ConfigBackup, LocalStorage, ShardLayout, Storage, StorageEntry, STORAGE,
+        },
+    };
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_shard_layout_is_kept_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_shards");
+        if data_dir.exists() {
+            fs::remove_dir_all(&data_dir).unwrap();
+        }
+        let repo = Path::new("repo");
+        let name = "abcdef";
+
+        let layout = ShardLayout { depth: 2, width: 1 };
+        let storage = LocalStorage::init(&data_dir)
+            .unwrap()
+            .set_shard_layout(layout);
+        storage.create_dir(repo, Some("data")).await.unwrap();
+        assert!(data_dir.join("repo/data/f/f").is_dir());
+        assert!(data_dir.join("repo/.shard-layout.toml").is_file());
+
+        let mut file = storage.create_file(repo, "data", Some(name)).await.unwrap();
+        file.write_all(b"Hello World").await.unwrap();
+        file.finalize().await.unwrap();
+
+        // a storage configured with another layout keeps the recorded one
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        assert_eq!(
+            storage.filename(repo, "data", Some(name)),
+            data_dir.join("repo/data/a/b").join(name)
+        );
+        let names: Vec<_> = storage
+            .read_dir(repo, Some("data"))
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, vec![name.to_string()]);
+
+        // new repositories get the configured layout, which isn't recorded for the default
+        storage
+            .create_dir(Path::new("other"), Some("data"))
+            .await
+            .unwrap();
+        assert!(data_dir.join("other/data/ff").is_dir());
+        assert!(!data_dir.join("other/.shard-layout.toml").exists());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_dir_after_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_read_dir_after");
+        if data_dir.exists() {
+            fs::remove_dir_all(&data_dir).unwrap();
+        }
+        let repo = Path::new("repo");
+
+        let storage = LocalStorage::init(&data_dir)
+            .unwrap()
+            .set_shard_layout(ShardLayout { depth: 2, width: 1 });
+        storage.create_dir(repo, Some("data")).await.unwrap();
+        for name in ["ba01", "ab02", "ab01", "ff00", "c000"] {
+            let mut file = storage.create_file(repo, "data", Some(name)).await.unwrap();
+            file.finalize().await.unwrap();
+        }
+
+        let list = |after| -> Vec<_> {
+            storage
+                .read_dir_after(repo, Some("data"), after)
+                .map(|entry| entry.name)
+                .collect()
+        };
+        assert_eq!(list(None), vec!["ab01", "ab02", "ba01", "c000", "ff00"]);
+        assert_eq!(list(Some("ab01")), vec!["ab02", "ba01", "c000", "ff00"]);
+        // the cursor doesn't need to exist anymore
+        assert_eq!(list(Some("b")), vec!["ba01", "c000", "ff00"]);
+        assert!(list(Some("ff00")).is_empty());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_read_dir_skips_non_utf8_names_passes() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let data_dir = PathBuf::from("tests/generated/test_storage_non_utf8");
+        if data_dir.exists() {
+            fs::remove_dir_all(&data_dir).unwrap();
+        }
+        let repo = Path::new("repo");
+        let keys = data_dir.join(repo).join("keys");
+        fs::create_dir_all(&keys).unwrap();
+        fs::write(keys.join("ab01"), "key").unwrap();
+        fs::write(keys.join(OsStr::from_bytes(b"ab\xff02")), "junk").unwrap();
+
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        let names = |entries: Box<dyn Iterator<Item = StorageEntry>>| -> Vec<_> {
+            entries.map(|entry| entry.name).collect()
+        };
+        assert_eq!(names(storage.read_dir(repo, Some("keys"))), vec!["ab01"]);
+        assert_eq!(
+            names(storage.read_dir_after(repo, Some("keys"), Some("a"))),
+            vec!["ab01"]
+        );
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_available_space_passes() {
+        let storage = LocalStorage::init(Path::new("tests/generated/test_storage")).unwrap();
+        assert!(storage.available_space(Path::new("test_repo")).unwrap() > 0);
+
+        // a temporary directory that can't be checked is ignored
+        let storage =
+            storage.set_temp_dir(Some(PathBuf::from("tests/generated/test_storage_missing")));
+        assert!(storage.available_space(Path::new("test_repo")).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_temp_dir_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_temp");
+        let temp_dir = PathBuf::from("tests/generated/test_storage_temp_uploads");
+        for dir in [&data_dir, &temp_dir] {
+            if dir.exists() {
+                fs::remove_dir_all(dir).unwrap();
+            }
+        }
+        let repo = Path::new("repo");
+        let target = data_dir.join("repo/keys/abcdef");
+
+        let storage = LocalStorage::init(&data_dir)
+            .unwrap()
+            .set_temp_dir(Some(temp_dir.clone()));
+
+        let mut file = storage
+            .create_file(repo, "keys", Some("abcdef"))
+            .await
+            .unwrap();
+        file.write_all(b"Hello World").await.unwrap();
+
+        // the upload is only in the temporary directory until it's finalized
+        assert!(!target.exists());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 1);
+
+        file.finalize().await.unwrap();
+        drop(file);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "Hello World");
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        // existing files aren't overwritten
+        assert!(storage
+            .create_file(repo, "keys", Some("abcdef"))
+            .await
+            .is_err());
+
+        // ... not even if they were written while the upload was running
+        let mut file = storage
+            .create_file(repo, "keys", Some("fedcba"))
+            .await
+            .unwrap();
+        file.write_all(b"Hello World").await.unwrap();
+        fs::write(data_dir.join("repo/keys/fedcba"), "first").unwrap();
+        assert!(file.finalize().await.is_err());
+        drop(file);
+        assert_eq!(
+            fs::read_to_string(data_dir.join("repo/keys/fedcba")).unwrap(),
+            "first"
+        );
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        // aborted uploads are removed
+        let mut file = storage
+            .create_file(repo, "keys", Some("012345"))
+            .await
+            .unwrap();
+        file.write_all(b"Hello").await.unwrap();
+        drop(file);
+        assert!(!data_dir.join("repo/keys/012345").exists());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&data_dir).unwrap();
+        fs::remove_dir_all(&temp_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fsync_dirs_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_fsync");
+        if data_dir.exists() {
+            fs::remove_dir_all(&data_dir).unwrap();
+        }
+        let repo = Path::new("repo");
+
+        let storage = LocalStorage::init(&data_dir).unwrap().set_fsync_dirs(true);
+
+        let mut file = storage
+            .create_file(repo, "keys", Some("abcdef"))
+            .await
+            .unwrap();
+        file.write_all(b"Hello World").await.unwrap();
+        file.finalize().await.unwrap();
+        drop(file);
+        assert!(data_dir.join("repo/keys/abcdef").exists());
+
+        storage
+            .remove_file(repo, "keys", Some("abcdef"))
+            .await
+            .unwrap();
+        assert!(!data_dir.join("repo/keys/abcdef").exists());
+
+        storage.remove_repository(repo).await.unwrap();
+        assert!(!data_dir.join("repo").exists());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_repository_size_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_size");
+        if data_dir.exists() {
+            fs::remove_dir_all(&data_dir).unwrap();
+        }
+        let repo = Path::new("repo");
+        fs::create_dir_all(data_dir.join("repo/keys")).unwrap();
+        fs::write(data_dir.join("repo/config"), "config").unwrap();
+        // a sparse file of 1 MiB
+        fs::File::create(data_dir.join("repo/keys/sparse"))
+            .unwrap()
+            .set_len(1024 * 1024)
+            .unwrap();
+
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        let logical = storage.repository_size(repo).await.unwrap();
+        assert_eq!(logical, 1024 * 1024 + 6);
+
+        let storage = storage.set_size_accounting(SizeAccounting::Allocated);
+        let allocated = storage.repository_size(repo).await.unwrap();
+        #[cfg(unix)]
+        assert!(allocated < logical, "{allocated} >= {logical}");
+        assert_eq!(allocated % 512, 0);
+
+        // a missing repository has no size
+        assert_eq!(
+            storage.repository_size(Path::new("missing")).await.unwrap(),
+            0
+        );
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
 
     #[test]
     fn test_file_access_passes() {
@@ -191,7 +1607,7 @@ mod test {
         let c = storage.read_dir(&path, Some("keys"));
         let mut found = false;
         for a in c.into_iter() {
-            let file_name = a.file_name().to_string_lossy();
+            let file_name = a.name;
             if file_name == "3f918b737a2b9f72f044d06d6009eb34e0e8d06668209be3ce86e5c18dac0295" {
                 found = true;
                 break;
@@ -213,4 +1629,331 @@ mod test {
         let c = storage.open_file(&path, "", Some("config")).await;
         assert!(c.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_missing_file_is_not_found_passes() {
+        let local_storage =
+            LocalStorage::init(&PathBuf::from("tests/generated/test_storage")).unwrap();
+
+        let err = local_storage
+            .open_file(Path::new("test_repo"), "keys", Some("does_not_exist"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ApiErrorKind::FileNotFound(_)));
+    }
+
+    #[test]
+    fn test_data_dirs_spread_repositories_passes() {
+        let data_dirs = vec![
+            PathBuf::from("tests/generated/disk_a"),
+            PathBuf::from("tests/generated/disk_b"),
+        ];
+        let local_storage = LocalStorage::init(&PathBuf::from("tests/generated/test_storage"))
+            .unwrap()
+            .set_data_dirs(data_dirs.clone());
+
+        let repos = ["alice", "bob", "carol", "dave", "eve", "frank"];
+        let bases: Vec<_> = repos
+            .iter()
+            .map(|repo| local_storage.base_dir(Path::new(repo)).to_path_buf())
+            .collect();
+
+        // every repo ends up on one of the configured disks, and we use all disks
+        assert!(bases.iter().all(|base| data_dirs.contains(base)));
+        assert!(data_dirs.iter().all(|dir| bases.contains(dir)));
+
+        // the same repo always resolves to the same disk
+        assert_eq!(
+            local_storage.base_dir(Path::new("alice")),
+            local_storage.base_dir(Path::new("alice/"))
+        );
+        let base = local_storage.base_dir(Path::new("alice"));
+        assert_eq!(
+            local_storage.filename(Path::new("alice"), "keys", Some("abc")),
+            base.join("alice").join("keys").join("abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_data_dir_fails() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_remove_root");
+        fs::create_dir_all(data_dir.join("repo/keys")).unwrap();
+        let storage = LocalStorage::init(&data_dir).unwrap();
+
+        for path in [
+            "",
+            ".",
+            "./",
+            "/",
+            "//",
+            "/.",
+            "repo/..",
+            "../test_storage_remove_root",
+        ] {
+            assert!(
+                matches!(
+                    storage.remove_repository(Path::new(path)).await,
+                    Err(ApiErrorKind::InvalidPath(_))
+                ),
+                "{path}"
+            );
+        }
+        assert!(data_dir.join("repo/keys").is_dir());
+
+        storage
+            .remove_repository(Path::new("./repo/"))
+            .await
+            .unwrap();
+        assert!(!data_dir.join("repo").exists());
+        assert!(data_dir.is_dir());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rename_repository_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_rename");
+        if data_dir.exists() {
+            fs::remove_dir_all(&data_dir).unwrap();
+        }
+        fs::create_dir_all(data_dir.join("team/repo/keys")).unwrap();
+        fs::create_dir_all(data_dir.join("team/repo/nested/keys")).unwrap();
+        fs::create_dir_all(data_dir.join("taken")).unwrap();
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        let rename = |from: &'static str, to: &'static str| {
+            storage.rename_repository(Path::new(from), Path::new(to))
+        };
+
+        assert!(matches!(
+            rename("missing", "new").await,
+            Err(ApiErrorKind::RepositoryNotFound(_))
+        ));
+        assert!(matches!(
+            rename("team/repo", "taken").await,
+            Err(ApiErrorKind::RepositoryExists(_))
+        ));
+        assert!(matches!(
+            rename("team/repo", "team/repo/inner").await,
+            Err(ApiErrorKind::BadRequest(_))
+        ));
+        assert!(matches!(
+            rename("team/repo", "../outside").await,
+            Err(ApiErrorKind::InvalidPath(_))
+        ));
+        assert!(data_dir.join("team/repo/keys").is_dir());
+
+        // nested repositories are moved along
+        rename("team/repo", "other/repo").await.unwrap();
+        assert!(!data_dir.join("team/repo").exists());
+        assert!(data_dir.join("other/repo/keys").is_dir());
+        assert!(data_dir.join("other/repo/nested/keys").is_dir());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rename_repository_data_dirs_fails() {
+        let data_dirs = vec![
+            PathBuf::from("tests/generated/test_storage_rename_1"),
+            PathBuf::from("tests/generated/test_storage_rename_2"),
+        ];
+        for dir in &data_dirs {
+            if dir.exists() {
+                fs::remove_dir_all(dir).unwrap();
+            }
+        }
+        let storage = LocalStorage::init(Path::new("tests/generated/test_storage_rename_1"))
+            .unwrap()
+            .set_data_dirs(data_dirs.clone());
+
+        let repo = Path::new("repo");
+        let base_dir = storage.base_dir(repo).to_path_buf();
+        // the first name of the pattern which is in the data directory of
+        // `repo` or not
+        let name = |pattern: &str, same: bool| {
+            (0..)
+                .map(|i| PathBuf::from(pattern.replace("{}", &i.to_string())))
+                .find(|name| (storage.base_dir(name) == base_dir) == same)
+                .unwrap()
+        };
+        fs::create_dir_all(base_dir.join("repo/keys")).unwrap();
+
+        let elsewhere = name("moved{}", false);
+        assert!(matches!(
+            storage.rename_repository(repo, &elsewhere).await,
+            Err(ApiErrorKind::BadRequest(_))
+        ));
+
+        // a nested repository in another data directory would be left behind
+        let nested = name("repo/nested{}", false);
+        let nested_dir = storage.base_dir(&nested).join(&nested);
+        fs::create_dir_all(nested_dir.join("keys")).unwrap();
+        let same = name("moved{}", true);
+        assert!(matches!(
+            storage.rename_repository(repo, &same).await,
+            Err(ApiErrorKind::BadRequest(_))
+        ));
+        assert!(base_dir.join("repo/keys").is_dir());
+
+        fs::remove_dir_all(nested_dir).unwrap();
+        storage.rename_repository(repo, &same).await.unwrap();
+        assert!(base_dir.join(&same).join("keys").is_dir());
+
+        for dir in &data_dirs {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trash_dir_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_trash");
+        let trash_dir = PathBuf::from("tests/generated/test_storage_trash_removed");
+        for dir in [&data_dir, &trash_dir] {
+            if dir.exists() {
+                fs::remove_dir_all(dir).unwrap();
+            }
+        }
+        fs::create_dir_all(data_dir.join("team/repo/keys")).unwrap();
+        fs::write(data_dir.join("team/repo/config"), "old").unwrap();
+        let storage = LocalStorage::init(&data_dir)
+            .unwrap()
+            .set_trash_dir(Some(trash_dir.clone()));
+        let repo = Path::new("team/repo");
+
+        // nothing to restore yet
+        assert!(matches!(
+            storage.restore_repository(Path::new("team/other")).await,
+            Err(ApiErrorKind::FileNotFound(_))
+        ));
+
+        storage.remove_repository(repo).await.unwrap();
+        assert!(!data_dir.join("team/repo").exists());
+        assert!(storage.repositories().is_empty());
+
+        // a new repository with the same name can't be overwritten
+        fs::create_dir_all(data_dir.join("team/repo/keys")).unwrap();
+        fs::write(data_dir.join("team/repo/config"), "new").unwrap();
+        assert!(matches!(
+            storage.restore_repository(repo).await,
+            Err(ApiErrorKind::BadRequest(_))
+        ));
+        storage.remove_repository(repo).await.unwrap();
+        assert_eq!(fs::read_dir(&trash_dir).unwrap().count(), 2);
+
+        // the most recently removed repository is restored
+        storage.restore_repository(repo).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(data_dir.join("team/repo/config")).unwrap(),
+            "new"
+        );
+        assert_eq!(fs::read_dir(&trash_dir).unwrap().count(), 1);
+
+        // nothing is purged before the retention ended
+        assert!(storage
+            .purge_trash(Duration::from_secs(3600))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage.purge_trash(Duration::ZERO).await.unwrap(),
+            [PathBuf::from("team/repo")]
+        );
+        assert_eq!(fs::read_dir(&trash_dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&data_dir).unwrap();
+        fs::remove_dir_all(&trash_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backup_config_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_config_history");
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(data_dir.join("repo")).unwrap();
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        let repo = Path::new("repo");
+        let config = data_dir.join("repo/config");
+
+        // nothing to keep yet
+        storage.backup_config(repo, 2).await.unwrap();
+        assert!(storage.config_backups(repo).await.is_empty());
+
+        for version in ["one", "two", "three"] {
+            fs::write(&config, version).unwrap();
+            storage.backup_config(repo, 2).await.unwrap();
+        }
+        let backups = storage.config_backups(repo).await;
+        assert_eq!(
+            backups,
+            [
+                ConfigBackup {
+                    version: 1,
+                    size: 5
+                },
+                ConfigBackup {
+                    version: 2,
+                    size: 3
+                }
+            ]
+        );
+
+        let mut content = String::new();
+        let _ = storage
+            .open_config_backup(repo, 2)
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, "two");
+        assert!(matches!(
+            storage.open_config_backup(repo, 3).await,
+            Err(ApiErrorKind::FileNotFound(_))
+        ));
+
+        // without history, nothing is kept
+        storage.backup_config(repo, 0).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(data_dir.join("repo/config.bak.1")).unwrap(),
+            "three"
+        );
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_provenance_passes() {
+        let data_dir = PathBuf::from("tests/generated/test_storage_provenance");
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(data_dir.join("repo")).unwrap();
+        let storage = LocalStorage::init(&data_dir).unwrap();
+        let repo = Path::new("repo");
+        let name = Some("abcd");
+
+        assert!(matches!(
+            storage.read_provenance(repo, "keys", name).await,
+            Err(ApiErrorKind::FileNotFound(_))
+        ));
+
+        let provenance = Provenance {
+            uploaded_by: "alice".to_string(),
+            uploaded_at: chrono::Utc::now(),
+            size: 4,
+        };
+        storage
+            .write_provenance(repo, "keys", name, &provenance)
+            .await
+            .unwrap();
+        assert!(data_dir.join("repo/.provenance/keys/abcd").is_file());
+        assert_eq!(
+            storage.read_provenance(repo, "keys", name).await.unwrap(),
+            provenance
+        );
+
+        storage.remove_provenance(repo, "keys", name).await.unwrap();
+        // removing a missing record is fine
+        storage.remove_provenance(repo, "keys", name).await.unwrap();
+        assert!(storage.read_provenance(repo, "keys", name).await.is_err());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
 }
@@ -0,0 +1,467 @@
+//! Repository-level locking of storage operations
+//!
+//! Deleting a repository while files are uploaded to or removed from it can
+//! leave half-written files behind in a directory that no longer exists. To
+//! prevent this, file operations hold a shared lock of their repository while
+//! `remove_repository` holds an exclusive one.
+//!
+//! Repositories can be nested (e.g. `team/repo`), so file operations also hold
+//! shared locks of all parent paths, and deleting `team` waits for uploads to
+//! `team/repo`. Locks are always taken from the root down to the repository,
+//! so they can't deadlock. Renaming a repository locks both the old and the
+//! new path, taking all locks in the order of their paths.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError, Weak},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::AsyncWrite,
+    sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock},
+};
+
+use crate::{
+    config::StorageSettings,
+    error::ApiResult,
+    handlers::file_helpers::Finalizer,
+    provenance::Provenance,
+    storage::{ConfigBackup, Storage, StorageEntry, StorageFile, StorageWriter},
+};
+
+/// A storage whose file operations and repository removals are serialized
+/// per repository
+#[derive(Debug)]
+pub struct LockedStorage<S> {
+    inner: S,
+    /// Locks of the repositories, by normalized repository path
+    locks: Mutex<LockMap>,
+}
+
+/// Shared locks of a repository and all its parents
+type SharedGuards = Vec<OwnedRwLockReadGuard<()>>;
+
+/// Dead locks are not pruned before there are this many
+const MIN_PRUNE_AT: usize = 64;
+
+/// Locks of the repositories which are currently held or waited for
+///
+/// Holders and waiters keep their lock alive, so they always share the same
+/// one. Locks nobody uses anymore are pruned once the map has doubled in size,
+/// so it doesn't grow with every path a client ever used.
+#[derive(Debug, Default)]
+struct LockMap {
+    locks: HashMap<PathBuf, Weak<RwLock<()>>>,
+    /// Number of entries at which dead locks are pruned next
+    prune_at: usize,
+}
+
+impl LockMap {
+    /// Returns the lock of `path`, creating it if nobody uses it
+    fn get(&mut self, path: PathBuf) -> Arc<RwLock<()>> {
+        if let Some(lock) = self.locks.get(&path).and_then(Weak::upgrade) {
+            return lock;
+        }
+
+        let lock = Arc::default();
+        _ = self.locks.insert(path, Arc::downgrade(&lock));
+        if self.locks.len() >= self.prune_at {
+            self.locks.retain(|_, lock| lock.strong_count() > 0);
+            self.prune_at = (2 * self.locks.len()).max(MIN_PRUNE_AT);
+        }
+        lock
+    }
+}
+
+impl<S: Storage> LockedStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            locks: Mutex::default(),
+        }
+    }
+
+    /// Returns the locks of all parents of the repository, starting at the
+    /// root, and the lock of the repository itself
+    fn locks(&self, repo: &Path) -> (Vec<Arc<RwLock<()>>>, Arc<RwLock<()>>) {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut lock = |path: PathBuf| locks.get(path);
+
+        let mut key = PathBuf::new();
+        let mut parents = Vec::new();
+        for component in repo.components() {
+            parents.push(lock(key.clone()));
+            key.push(component);
+        }
+        (parents, lock(key))
+    }
+
+    /// Takes shared locks of the repository and all its parents
+    async fn lock_shared(&self, repo: &Path) -> SharedGuards {
+        let (parents, lock) = self.locks(repo);
+        let mut guards = Vec::with_capacity(parents.len() + 1);
+        for lock in parents.into_iter().chain([lock]) {
+            guards.push(lock.read_owned().await);
+        }
+        guards
+    }
+
+    /// Takes shared locks of all parents and an exclusive lock of the repository
+    async fn lock_exclusive(&self, repo: &Path) -> (SharedGuards, OwnedRwLockWriteGuard<()>) {
+        let (parents, lock) = self.locks(repo);
+        let mut guards = Vec::with_capacity(parents.len());
+        for lock in parents {
+            guards.push(lock.read_owned().await);
+        }
+        (guards, lock.write_owned().await)
+    }
+
+    /// Takes exclusive locks of all given repositories and shared locks of
+    /// their parents
+    ///
+    /// The locks are taken in the order of their paths, so parents come before
+    /// their children, as with all other locks.
+    async fn lock_exclusive_all(
+        &self,
+        repos: &[&Path],
+    ) -> (SharedGuards, Vec<OwnedRwLockWriteGuard<()>>) {
+        // whether each path is locked exclusively
+        let mut paths = BTreeMap::new();
+        for repo in repos {
+            let mut key = PathBuf::new();
+            for component in repo.components() {
+                _ = paths.entry(key.clone()).or_insert(false);
+                key.push(component);
+            }
+            _ = paths.insert(key, true);
+        }
+
+        let locks: Vec<_> = {
+            let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            paths
+                .into_iter()
+                .map(|(path, exclusive)| (locks.get(path), exclusive))
+                .collect()
+        };
+
+        let mut shared = Vec::new();
+        let mut exclusive = Vec::new();
+        for (lock, is_exclusive) in locks {
+            if is_exclusive {
+                exclusive.push(lock.write_owned().await);
+            } else {
+                shared.push(lock.read_owned().await);
+            }
+        }
+        (shared, exclusive)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Storage> Storage for LockedStorage<S> {
+    fn init(path: &Path) -> ApiResult<Self> {
+        Ok(Self::new(S::init(path)?))
+    }
+
+    fn from_settings(path: &Path, settings: &StorageSettings) -> ApiResult<Self> {
+        Ok(Self::new(S::from_settings(path, settings)?))
+    }
+
+    fn path(&self) -> &Path {
+        self.inner.path()
+    }
+
+    fn max_repo_depth(&self) -> Option<usize> {
+        self.inner.max_repo_depth()
+    }
+
+    fn reserved_repo_names(&self) -> &[String] {
+        self.inner.reserved_repo_names()
+    }
+
+    async fn create_dir(&self, path: &Path, tpe: Option<&str>) -> ApiResult<()> {
+        let _guards = self.lock_shared(path).await;
+        self.inner.create_dir(path, tpe).await
+    }
+
+    fn read_dir(&self, path: &Path, tpe: Option<&str>) -> Box<dyn Iterator<Item = StorageEntry>> {
+        self.inner.read_dir(path, tpe)
+    }
+
+    fn read_dir_after(
+        &self,
+        path: &Path,
+        tpe: Option<&str>,
+        after: Option<&str>,
+    ) -> Box<dyn Iterator<Item = StorageEntry>> {
+        self.inner.read_dir_after(path, tpe, after)
+    }
+
+    fn filename(&self, path: &Path, tpe: &str, name: Option<&str>) -> PathBuf {
+        self.inner.filename(path, tpe, name)
+    }
+
+    fn available_space(&self, path: &Path) -> Option<u64> {
+        self.inner.available_space(path)
+    }
+
+    fn modified(&self, path: &Path, tpe: &str, name: Option<&str>) -> Option<SystemTime> {
+        self.inner.modified(path, tpe, name)
+    }
+
+    async fn file_length(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<u64> {
+        self.inner.file_length(path, tpe, name).await
+    }
+
+    async fn open_file(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<StorageFile> {
+        self.inner.open_file(path, tpe, name).await
+    }
+
+    async fn create_file(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<Box<dyn StorageWriter>> {
+        let guards = self.lock_shared(path).await;
+        let inner = self.inner.create_file(path, tpe, name).await?;
+        Ok(Box::new(LockedWriter {
+            inner,
+            _guards: guards,
+        }))
+    }
+
+    async fn remove_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()> {
+        let _guards = self.lock_shared(path).await;
+        self.inner.remove_file(path, tpe, name).await
+    }
+
+    async fn remove_repository(&self, path: &Path) -> ApiResult<()> {
+        let _guards = self.lock_exclusive(path).await;
+        self.inner.remove_repository(path).await
+    }
+
+    async fn restore_repository(&self, path: &Path) -> ApiResult<()> {
+        let _guards = self.lock_exclusive(path).await;
+        self.inner.restore_repository(path).await
+    }
+
+    async fn rename_repository(&self, from: &Path, to: &Path) -> ApiResult<()> {
+        let _guards = self.lock_exclusive_all(&[from, to]).await;
+        self.inner.rename_repository(from, to).await
+    }
+
+    async fn open_gzip_variant(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> Option<StorageFile> {
+        self.inner.open_gzip_variant(path, tpe, name).await
+    }
+
+    async fn read_provenance(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<Provenance> {
+        self.inner.read_provenance(path, tpe, name).await
+    }
+
+    async fn config_backups(&self, path: &Path) -> Vec<ConfigBackup> {
+        self.inner.config_backups(path).await
+    }
+
+    async fn open_config_backup(&self, path: &Path, version: usize) -> ApiResult<StorageFile> {
+        self.inner.open_config_backup(path, version).await
+    }
+
+    async fn write_provenance(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+        provenance: &Provenance,
+    ) -> ApiResult<()> {
+        let _guards = self.lock_shared(path).await;
+        self.inner
+            .write_provenance(path, tpe, name, provenance)
+            .await
+    }
+
+    async fn remove_provenance(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()> {
+        let _guards = self.lock_shared(path).await;
+        self.inner.remove_provenance(path, tpe, name).await
+    }
+
+    async fn purge_trash(&self, max_age: Duration) -> ApiResult<Vec<PathBuf>> {
+        self.inner.purge_trash(max_age).await
+    }
+
+    async fn repository_size(&self, path: &Path) -> ApiResult<u64> {
+        self.inner.repository_size(path).await
+    }
+
+    fn repositories(&self) -> Vec<PathBuf> {
+        self.inner.repositories()
+    }
+
+    async fn backup_config(&self, path: &Path, keep: usize) -> ApiResult<()> {
+        let _guards = self.lock_shared(path).await;
+        self.inner.backup_config(path, keep).await
+    }
+}
+
+/// A writer holding the locks of its repository until it is dropped
+struct LockedWriter {
+    inner: Box<dyn StorageWriter>,
+    _guards: SharedGuards,
+}
+
+impl AsyncWrite for LockedWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait::async_trait]
+impl Finalizer for LockedWriter {
+    async fn finalize(&mut self) -> ApiResult<()> {
+        self.inner.finalize().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use tokio::{io::AsyncWriteExt, time::timeout};
+
+    use crate::{
+        handlers::file_helpers::Finalizer,
+        storage::{LocalStorage, LockedStorage, Storage},
+    };
+
+    use super::{LockMap, MIN_PRUNE_AT};
+
+    #[tokio::test]
+    async fn test_remove_repository_waits_for_writers_passes() {
+        let dir = Path::new("tests/generated/locked_storage");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let storage = LockedStorage::new(LocalStorage::init(dir).unwrap());
+
+        let repo = Path::new("team/repo");
+        storage.create_dir(repo, None).await.unwrap();
+        let mut writer = storage
+            .create_file(repo, "keys", Some("key"))
+            .await
+            .unwrap();
+        writer.write_all(b"key").await.unwrap();
+
+        // neither the repository nor its parent can be removed during the upload
+        let wait = Duration::from_millis(100);
+        assert!(timeout(wait, storage.remove_repository(repo))
+            .await
+            .is_err());
+        assert!(timeout(wait, storage.remove_repository(Path::new("team")))
+            .await
+            .is_err());
+
+        // other repositories are not affected
+        storage.create_dir(Path::new("other"), None).await.unwrap();
+        timeout(wait, storage.remove_repository(Path::new("other")))
+            .await
+            .unwrap()
+            .unwrap();
+
+        writer.finalize().await.unwrap();
+        drop(writer);
+        assert!(dir.join("team/repo/keys/key").exists());
+
+        timeout(wait, storage.remove_repository(repo))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!dir.join("team/repo").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rename_repository_waits_for_writers_passes() {
+        let dir = Path::new("tests/generated/locked_storage_rename");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let storage = LockedStorage::new(LocalStorage::init(dir).unwrap());
+
+        let repo = Path::new("team/repo");
+        let renamed = Path::new("other/repo");
+        storage.create_dir(repo, None).await.unwrap();
+        let mut writer = storage
+            .create_file(repo, "keys", Some("key"))
+            .await
+            .unwrap();
+        writer.write_all(b"key").await.unwrap();
+
+        // the repository can't be moved away during the upload
+        let wait = Duration::from_millis(100);
+        assert!(timeout(wait, storage.rename_repository(repo, renamed))
+            .await
+            .is_err());
+
+        writer.finalize().await.unwrap();
+        drop(writer);
+
+        timeout(wait, storage.rename_repository(repo, renamed))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!dir.join("team/repo").exists());
+        assert!(dir.join("other/repo/keys/key").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_map_prunes_unused_locks_passes() {
+        let mut map = LockMap::default();
+
+        // holders and waiters share the lock
+        let held = map.get(PathBuf::from("repo"));
+        assert!(Arc::ptr_eq(&held, &map.get(PathBuf::from("repo"))));
+
+        for n in 0..10 * MIN_PRUNE_AT {
+            drop(map.get(PathBuf::from(format!("repo{n}"))));
+        }
+        assert!(map.locks.len() <= MIN_PRUNE_AT, "{}", map.locks.len());
+        assert!(Arc::ptr_eq(&held, &map.get(PathBuf::from("repo"))));
+    }
+}
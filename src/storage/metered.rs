@@ -0,0 +1,242 @@
+//! Latency metrics of storage operations
+//!
+//! Wraps a storage backend and records the duration of its operations in the
+//! global [`storage_latencies`], so slow backends show up in `GET /metrics`
+//! without instrumenting every backend.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    config::StorageSettings,
+    error::ApiResult,
+    provenance::Provenance,
+    stats::{storage_latencies, StorageOperation},
+    storage::{ConfigBackup, Storage, StorageEntry, StorageFile, StorageWriter},
+};
+
+/// A storage recording the latency of its file operations
+#[derive(Debug, Clone)]
+pub struct MeteredStorage<S> {
+    inner: S,
+}
+
+impl<S: Storage> MeteredStorage<S> {
+    pub const fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+/// Records the time since `start` for the operation
+fn record(operation: StorageOperation, start: Instant) {
+    storage_latencies().record(operation, start.elapsed());
+}
+
+#[async_trait::async_trait]
+impl<S: Storage> Storage for MeteredStorage<S> {
+    fn init(path: &Path) -> ApiResult<Self> {
+        Ok(Self::new(S::init(path)?))
+    }
+
+    fn from_settings(path: &Path, settings: &StorageSettings) -> ApiResult<Self> {
+        Ok(Self::new(S::from_settings(path, settings)?))
+    }
+
+    fn path(&self) -> &Path {
+        self.inner.path()
+    }
+
+    fn max_repo_depth(&self) -> Option<usize> {
+        self.inner.max_repo_depth()
+    }
+
+    fn reserved_repo_names(&self) -> &[String] {
+        self.inner.reserved_repo_names()
+    }
+
+    async fn create_dir(&self, path: &Path, tpe: Option<&str>) -> ApiResult<()> {
+        self.inner.create_dir(path, tpe).await
+    }
+
+    fn read_dir(&self, path: &Path, tpe: Option<&str>) -> Box<dyn Iterator<Item = StorageEntry>> {
+        // directories are read lazily, so the listing is timed until the
+        // iterator is dropped
+        Box::new(MeteredIter {
+            inner: self.inner.read_dir(path, tpe),
+            start: Instant::now(),
+        })
+    }
+
+    fn read_dir_after(
+        &self,
+        path: &Path,
+        tpe: Option<&str>,
+        after: Option<&str>,
+    ) -> Box<dyn Iterator<Item = StorageEntry>> {
+        Box::new(MeteredIter {
+            inner: self.inner.read_dir_after(path, tpe, after),
+            start: Instant::now(),
+        })
+    }
+
+    fn filename(&self, path: &Path, tpe: &str, name: Option<&str>) -> PathBuf {
+        self.inner.filename(path, tpe, name)
+    }
+
+    fn available_space(&self, path: &Path) -> Option<u64> {
+        self.inner.available_space(path)
+    }
+
+    fn modified(&self, path: &Path, tpe: &str, name: Option<&str>) -> Option<SystemTime> {
+        self.inner.modified(path, tpe, name)
+    }
+
+    async fn file_length(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<u64> {
+        self.inner.file_length(path, tpe, name).await
+    }
+
+    async fn open_file(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<StorageFile> {
+        let start = Instant::now();
+        let file = self.inner.open_file(path, tpe, name).await;
+        record(StorageOperation::OpenFile, start);
+        file
+    }
+
+    async fn create_file(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<Box<dyn StorageWriter>> {
+        let start = Instant::now();
+        let writer = self.inner.create_file(path, tpe, name).await;
+        record(StorageOperation::CreateFile, start);
+        writer
+    }
+
+    async fn remove_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()> {
+        let start = Instant::now();
+        let removed = self.inner.remove_file(path, tpe, name).await;
+        record(StorageOperation::RemoveFile, start);
+        removed
+    }
+
+    async fn open_gzip_variant(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> Option<StorageFile> {
+        let start = Instant::now();
+        let file = self.inner.open_gzip_variant(path, tpe, name).await;
+        record(StorageOperation::OpenFile, start);
+        file
+    }
+
+    async fn write_provenance(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+        provenance: &Provenance,
+    ) -> ApiResult<()> {
+        let start = Instant::now();
+        let written = self
+            .inner
+            .write_provenance(path, tpe, name, provenance)
+            .await;
+        record(StorageOperation::CreateFile, start);
+        written
+    }
+
+    async fn read_provenance(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<Provenance> {
+        let start = Instant::now();
+        let provenance = self.inner.read_provenance(path, tpe, name).await;
+        record(StorageOperation::OpenFile, start);
+        provenance
+    }
+
+    async fn remove_provenance(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()> {
+        let start = Instant::now();
+        let removed = self.inner.remove_provenance(path, tpe, name).await;
+        record(StorageOperation::RemoveFile, start);
+        removed
+    }
+
+    async fn backup_config(&self, path: &Path, keep: usize) -> ApiResult<()> {
+        let start = Instant::now();
+        let backed_up = self.inner.backup_config(path, keep).await;
+        record(StorageOperation::CreateFile, start);
+        backed_up
+    }
+
+    async fn config_backups(&self, path: &Path) -> Vec<ConfigBackup> {
+        let start = Instant::now();
+        let backups = self.inner.config_backups(path).await;
+        record(StorageOperation::ReadDir, start);
+        backups
+    }
+
+    async fn open_config_backup(&self, path: &Path, version: usize) -> ApiResult<StorageFile> {
+        let start = Instant::now();
+        let file = self.inner.open_config_backup(path, version).await;
+        record(StorageOperation::OpenFile, start);
+        file
+    }
+
+    async fn remove_repository(&self, path: &Path) -> ApiResult<()> {
+        self.inner.remove_repository(path).await
+    }
+
+    async fn restore_repository(&self, path: &Path) -> ApiResult<()> {
+        self.inner.restore_repository(path).await
+    }
+
+    async fn rename_repository(&self, from: &Path, to: &Path) -> ApiResult<()> {
+        self.inner.rename_repository(from, to).await
+    }
+
+    async fn purge_trash(&self, max_age: Duration) -> ApiResult<Vec<PathBuf>> {
+        self.inner.purge_trash(max_age).await
+    }
+
+    async fn repository_size(&self, path: &Path) -> ApiResult<u64> {
+        self.inner.repository_size(path).await
+    }
+
+    fn repositories(&self) -> Vec<PathBuf> {
+        self.inner.repositories()
+    }
+}
+
+/// A directory listing recording its duration when it is dropped
+struct MeteredIter {
+    inner: Box<dyn Iterator<Item = StorageEntry>>,
+    start: Instant,
+}
+
+impl Iterator for MeteredIter {
+    type Item = StorageEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl Drop for MeteredIter {
+    fn drop(&mut self) {
+        record(StorageOperation::ReadDir, self.start);
+    }
+}
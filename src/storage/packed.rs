@@ -0,0 +1,862 @@
+//! Storage that packs small files into larger pack files
+//!
+//! Filesystems struggle with millions of tiny files, so small files of some
+//! types are appended to pack files instead of being stored on their own.
+//! Each type directory of a repository has its own packs and an append-only
+//! index, which records where each file is stored:
+//!
+//! ```text
+//! <repo>/.packs/<tpe>/index
+//! <repo>/.packs/<tpe>/00000000.pack
+//! <repo>/.packs/<tpe>/00000001.pack
+//! ```
+//!
+//! Packed files are served, listed and removed transparently by their names.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    iter,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{fs::File, io::AsyncWrite, io::AsyncWriteExt};
+
+use crate::{
+    config::StorageSettings,
+    error::{ApiErrorKind, ApiResult},
+    handlers::file_helpers::{Finalizer, WriteOrDeleteFile},
+    storage::{LocalStorage, Storage, StorageEntry, StorageFile, StorageWriter},
+    uploads::uploads,
+};
+
+/// Types whose small files are packed
+const PACKED_TPES: [&str; 3] = ["data", "index", "snapshots"];
+
+/// Files up to this size (in bytes) are packed
+const PACK_THRESHOLD: u64 = 64 * 1024;
+
+/// Packs are not grown beyond this size (in bytes)
+const MAX_PACK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Directory in a repository holding the packs
+const PACKS_DIR: &str = ".packs";
+
+/// Name of the index file in a pack directory
+const INDEX_FILE: &str = "index";
+
+/// Index of a pack directory, loaded on first use
+type IndexSlot = Arc<Mutex<Option<PackIndex>>>;
+
+/// Storage that packs small files of the `data`, `index` and `snapshots` types
+///
+/// All other files are stored like in [`LocalStorage`].
+#[derive(Debug, Clone, Default)]
+pub struct PackedLocalStorage {
+    local: LocalStorage,
+    /// Index of each pack directory, each with a lock of its own, so only
+    /// files packed into the same directory wait for each other
+    indexes: Arc<Mutex<HashMap<PathBuf, IndexSlot>>>,
+}
+
+impl PackedLocalStorage {
+    /// Returns the pack directory for the given type, if its files are packed
+    fn pack_dir(&self, path: &Path, tpe: &str) -> Option<PathBuf> {
+        PACKED_TPES.contains(&tpe).then(|| {
+            self.local
+                .base_dir(path)
+                .join(path)
+                .join(PACKS_DIR)
+                .join(tpe)
+        })
+    }
+
+    /// Runs `f` on the index of the pack directory `dir`, loading it if necessary
+    ///
+    /// This blocks while another file of the directory is packed, so async
+    /// code uses [`Self::with_index`] instead.
+    fn with_index_blocking<T>(
+        &self,
+        dir: &Path,
+        f: impl FnOnce(&mut PackIndex) -> io::Result<T>,
+    ) -> ApiResult<T> {
+        // the map is only locked to look up the index
+        let slot = self
+            .indexes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(dir.to_path_buf())
+            .or_default()
+            .clone();
+        let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let index = match &mut *slot {
+            Some(index) => index,
+            None => slot.insert(PackIndex::load(dir).map_err(|err| {
+                ApiErrorKind::from_io_error(
+                    &err,
+                    "Could not load pack index",
+                    ApiErrorKind::GeneralStorageError,
+                )
+            })?),
+        };
+
+        f(index).map_err(|err| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not update pack index",
+                ApiErrorKind::GeneralStorageError,
+            )
+        })
+    }
+
+    /// Runs `f` on the index of the pack directory `dir` without blocking the
+    /// runtime, as updating the index syncs it to disk
+    async fn with_index<T: Send + 'static>(
+        &self,
+        dir: &Path,
+        f: impl FnOnce(&mut PackIndex) -> io::Result<T> + Send + 'static,
+    ) -> ApiResult<T> {
+        let storage = self.clone();
+        let dir = dir.to_path_buf();
+
+        tokio::task::spawn_blocking(move || storage.with_index_blocking(&dir, f))
+            .await
+            .map_err(|err| {
+                ApiErrorKind::InternalError(format!("Accessing the pack index failed: {err}"))
+            })?
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for PackedLocalStorage {
+    fn init(path: &Path) -> ApiResult<Self> {
+        Ok(Self {
+            local: LocalStorage::init(path)?,
+            indexes: Arc::default(),
+        })
+    }
+
+    fn from_settings(path: &Path, settings: &StorageSettings) -> ApiResult<Self> {
+        Ok(Self {
+            local: LocalStorage::from_settings(path, settings)?,
+            indexes: Arc::default(),
+        })
+    }
+
+    fn path(&self) -> &Path {
+        self.local.path()
+    }
+
+    fn max_repo_depth(&self) -> Option<usize> {
+        self.local.max_repo_depth()
+    }
+
+    fn reserved_repo_names(&self) -> &[String] {
+        self.local.reserved_repo_names()
+    }
+
+    async fn create_dir(&self, path: &Path, tpe: Option<&str>) -> ApiResult<()> {
+        self.local.create_dir(path, tpe).await
+    }
+
+    fn read_dir(&self, path: &Path, tpe: Option<&str>) -> Box<dyn Iterator<Item = StorageEntry>> {
+        let Some(dir) = tpe.and_then(|tpe| self.pack_dir(path, tpe)) else {
+            return self.local.read_dir(path, tpe);
+        };
+
+        let packed = self
+            .with_index_blocking(&dir, |index| Ok(index.list()))
+            .unwrap_or_else(|err| {
+                tracing::warn!("Could not list packed files in {}: {err}", dir.display());
+                Vec::new()
+            });
+
+        // a file might briefly exist both packed and loose while it is being packed
+        let names: HashSet<String> = packed.iter().map(|entry| entry.name.clone()).collect();
+        let loose = self
+            .local
+            .read_dir(path, tpe)
+            .filter(move |entry| !names.contains(&entry.name));
+
+        Box::new(packed.into_iter().chain(loose))
+    }
+
+    fn read_dir_after(
+        &self,
+        path: &Path,
+        tpe: Option<&str>,
+        after: Option<&str>,
+    ) -> Box<dyn Iterator<Item = StorageEntry>> {
+        let Some(dir) = tpe.and_then(|tpe| self.pack_dir(path, tpe)) else {
+            return self.local.read_dir_after(path, tpe, after);
+        };
+
+        let mut packed: Vec<_> = self
+            .with_index_blocking(&dir, |index| Ok(index.list()))
+            .unwrap_or_else(|err| {
+                tracing::warn!("Could not list packed files in {}: {err}", dir.display());
+                Vec::new()
+            })
+            .into_iter()
+            .filter(|entry| after.map_or(true, |after| entry.name.as_str() > after))
+            .collect();
+        packed.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        // merge both sorted listings, preferring packed files over loose ones
+        let mut packed = packed.into_iter().peekable();
+        let mut loose = self.local.read_dir_after(path, tpe, after).peekable();
+        Box::new(iter::from_fn(move || match (packed.peek(), loose.peek()) {
+            (Some(p), Some(l)) if l.name < p.name => loose.next(),
+            (Some(p), Some(l)) if l.name == p.name => {
+                _ = loose.next();
+                packed.next()
+            }
+            (Some(_), _) => packed.next(),
+            (None, _) => loose.next(),
+        }))
+    }
+
+    fn filename(&self, path: &Path, tpe: &str, name: Option<&str>) -> PathBuf {
+        self.local.filename(path, tpe, name)
+    }
+
+    fn available_space(&self, path: &Path) -> Option<u64> {
+        self.local.available_space(path)
+    }
+
+    async fn file_length(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<u64> {
+        if let (Some(dir), Some(name)) = (self.pack_dir(path, tpe), name) {
+            let name = name.to_string();
+            if let Some(entry) = self
+                .with_index(&dir, move |index| Ok(index.get(&name)))
+                .await?
+            {
+                return Ok(entry.len);
+            }
+        }
+
+        self.local.file_length(path, tpe, name).await
+    }
+
+    /// Packed files have no modification time of their own, so the time their
+    /// pack was last appended to is used instead
+    fn modified(&self, path: &Path, tpe: &str, name: Option<&str>) -> Option<SystemTime> {
+        if let (Some(dir), Some(name)) = (self.pack_dir(path, tpe), name) {
+            let pack_path = self
+                .with_index_blocking(&dir, |index| {
+                    Ok(index.get(name).map(|entry| index.pack_path(entry.pack)))
+                })
+                .ok()?;
+
+            if let Some(pack_path) = pack_path {
+                return fs::metadata(pack_path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok();
+            }
+        }
+
+        self.local.modified(path, tpe, name)
+    }
+
+    async fn open_file(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<StorageFile> {
+        if let (Some(dir), Some(name)) = (self.pack_dir(path, tpe), name) {
+            let name = name.to_string();
+            let packed = self
+                .with_index(&dir, move |index| {
+                    Ok(index
+                        .get(&name)
+                        .map(|entry| (index.pack_path(entry.pack), entry)))
+                })
+                .await?;
+
+            if let Some((pack_path, entry)) = packed {
+                let file = File::open(pack_path).await.map_err(|err| {
+                    ApiErrorKind::from_io_error(
+                        &err,
+                        "Could not open pack",
+                        ApiErrorKind::OpeningFileFailed,
+                    )
+                })?;
+
+                return StorageFile::section(file, entry.offset, entry.len)
+                    .await
+                    .map_err(|_| ApiErrorKind::SeekingFileFailed);
+            }
+        }
+
+        self.local.open_file(path, tpe, name).await
+    }
+
+    async fn create_file(
+        &self,
+        path: &Path,
+        tpe: &str,
+        name: Option<&str>,
+    ) -> ApiResult<Box<dyn StorageWriter>> {
+        let (Some(dir), Some(name)) = (self.pack_dir(path, tpe), name) else {
+            return self.local.create_file(path, tpe, name).await;
+        };
+
+        let packed = name.to_string();
+        if self
+            .with_index(&dir, move |index| Ok(index.get(&packed).is_some()))
+            .await?
+        {
+            return Err(ApiErrorKind::WritingToFileFailed(format!(
+                "Could not write to file: {name} already exists"
+            )));
+        }
+
+        let file = WriteOrDeleteFile::new(
+            self.local.filename(path, tpe, Some(name)),
+            self.local.temp_dir(),
+        )
+        .await?
+        .set_fsync_dir(self.local.fsync_dirs())
+        .set_upload(uploads().register(path, tpe, Some(name)));
+
+        Ok(Box::new(PackWriter {
+            file: Some(file),
+            written: 0,
+            storage: self.clone(),
+            dir,
+            name: name.to_string(),
+        }))
+    }
+
+    async fn remove_file(&self, path: &Path, tpe: &str, name: Option<&str>) -> ApiResult<()> {
+        if let (Some(dir), Some(name)) = (self.pack_dir(path, tpe), name) {
+            let packed = name.to_string();
+            if self
+                .with_index(&dir, move |index| index.remove(&packed))
+                .await?
+            {
+                // remove a loose leftover of an interrupted packing, too
+                return match self.local.remove_file(path, tpe, Some(name)).await {
+                    Ok(()) | Err(ApiErrorKind::FileNotFound(_)) => Ok(()),
+                    Err(err) => Err(err),
+                };
+            }
+        }
+
+        self.local.remove_file(path, tpe, name).await
+    }
+
+    async fn remove_repository(&self, path: &Path) -> ApiResult<()> {
+        self.local.remove_repository(path).await?;
+
+        let repo_dir = self.local.base_dir(path).join(path);
+        self.indexes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|dir, _| !dir.starts_with(&repo_dir));
+
+        Ok(())
+    }
+
+    async fn restore_repository(&self, path: &Path) -> ApiResult<()> {
+        self.local.restore_repository(path).await?;
+
+        // the restored packs replace whatever was cached in the meantime
+        let repo_dir = self.local.base_dir(path).join(path);
+        self.indexes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|dir, _| !dir.starts_with(&repo_dir));
+
+        Ok(())
+    }
+
+    async fn rename_repository(&self, from: &Path, to: &Path) -> ApiResult<()> {
+        self.local.rename_repository(from, to).await?;
+
+        // the indexes are loaded again from their new place
+        let from_dir = self.local.base_dir(from).join(from);
+        self.indexes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|dir, _| !dir.starts_with(&from_dir));
+
+        Ok(())
+    }
+
+    async fn purge_trash(&self, max_age: Duration) -> ApiResult<Vec<PathBuf>> {
+        self.local.purge_trash(max_age).await
+    }
+
+    async fn repository_size(&self, path: &Path) -> ApiResult<u64> {
+        // the packs are stored in the repository, so they are accounted for
+        self.local.repository_size(path).await
+    }
+
+    fn repositories(&self) -> Vec<PathBuf> {
+        self.local.repositories()
+    }
+}
+
+/// Location of a packed file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PackEntry {
+    pack: u32,
+    offset: u64,
+    len: u64,
+}
+
+/// Index of all packed files of a pack directory
+#[derive(Debug)]
+struct PackIndex {
+    /// The pack directory
+    dir: PathBuf,
+
+    /// Location of every packed file by name
+    entries: BTreeMap<String, PackEntry>,
+
+    /// Number of files stored in each pack
+    live: BTreeMap<u32, usize>,
+
+    /// Pack we are currently appending to, and its size
+    current: Option<(u32, u64)>,
+
+    /// Number of the next new pack
+    next_pack: u32,
+}
+
+impl PackIndex {
+    /// Loads the index of the pack directory `dir` by replaying its index file
+    fn load(dir: &Path) -> io::Result<Self> {
+        let mut index = Self {
+            dir: dir.to_path_buf(),
+            entries: BTreeMap::new(),
+            live: BTreeMap::new(),
+            current: None,
+            next_pack: 0,
+        };
+
+        let content = match fs::read_to_string(dir.join(INDEX_FILE)) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(index),
+            Err(err) => return Err(err),
+        };
+
+        let mut has_removals = false;
+
+        for line in content.lines() {
+            match line.split(' ').collect::<Vec<_>>().as_slice() {
+                ["add", name, pack, offset, len] => {
+                    let entry = PackEntry {
+                        pack: parse(pack, line)?,
+                        offset: parse(offset, line)?,
+                        len: parse(len, line)?,
+                    };
+                    index.next_pack = index.next_pack.max(entry.pack + 1);
+                    index.insert(name, entry);
+                }
+                ["remove", name] => {
+                    _ = index.forget(name);
+                    has_removals = true;
+                }
+                _ => return Err(invalid_line(line)),
+            }
+        }
+
+        // continue appending to the last pack, if it still exists
+        if let Some(last) = index.next_pack.checked_sub(1) {
+            if let Ok(metadata) = fs::metadata(index.pack_path(last)) {
+                index.current = Some((last, metadata.len()));
+            }
+        }
+
+        // don't let the index file grow with every removal
+        if has_removals {
+            index.write_compacted()?;
+        }
+
+        Ok(index)
+    }
+
+    fn pack_path(&self, pack: u32) -> PathBuf {
+        self.dir.join(format!("{pack:08}.pack"))
+    }
+
+    fn get(&self, name: &str) -> Option<PackEntry> {
+        self.entries.get(name).copied()
+    }
+
+    fn list(&self) -> Vec<StorageEntry> {
+        self.entries
+            .iter()
+            .map(|(name, entry)| StorageEntry {
+                name: name.clone(),
+                size: entry.len,
+            })
+            .collect()
+    }
+
+    /// Appends `data` as file `name` to the current pack
+    fn add(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{name}` can't be stored in a pack"),
+            ));
+        }
+
+        if self.entries.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{name} already exists"),
+            ));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+
+        let len = data.len() as u64;
+        let pack = match self.current {
+            Some((pack, size)) if size + len <= MAX_PACK_SIZE => pack,
+            _ => {
+                let pack = self.next_pack;
+                self.next_pack += 1;
+                pack
+            }
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.pack_path(pack))?;
+        let offset = file.metadata()?.len();
+        file.write_all(data)?;
+        file.sync_data()?;
+
+        // The file is only known once it's in the index. If we crash before,
+        // the pack just contains some unused bytes.
+        self.append_to_index_file(&format!("add {name} {pack} {offset} {len}"))?;
+        self.insert(name, PackEntry { pack, offset, len });
+        self.current = Some((pack, offset + len));
+
+        Ok(())
+    }
+
+    /// Removes file `name`, returns `false` if it isn't packed
+    ///
+    /// Packs are deleted as soon as they don't contain any file anymore.
+    fn remove(&mut self, name: &str) -> io::Result<bool> {
+        if !self.entries.contains_key(name) {
+            return Ok(false);
+        }
+
+        self.append_to_index_file(&format!("remove {name}"))?;
+
+        let Some(entry) = self.forget(name) else {
+            return Ok(false);
+        };
+
+        if !self.live.contains_key(&entry.pack) {
+            if self.current.is_some_and(|(pack, _)| pack == entry.pack) {
+                self.current = None;
+            }
+
+            match fs::remove_file(self.pack_path(entry.pack)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn insert(&mut self, name: &str, entry: PackEntry) {
+        if let Some(old) = self.entries.insert(name.to_string(), entry) {
+            self.release(old.pack);
+        }
+        *self.live.entry(entry.pack).or_default() += 1;
+    }
+
+    fn forget(&mut self, name: &str) -> Option<PackEntry> {
+        let entry = self.entries.remove(name)?;
+        self.release(entry.pack);
+        Some(entry)
+    }
+
+    fn release(&mut self, pack: u32) {
+        if let Some(count) = self.live.get_mut(&pack) {
+            *count -= 1;
+            if *count == 0 {
+                _ = self.live.remove(&pack);
+            }
+        }
+    }
+
+    fn append_to_index_file(&self, line: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(INDEX_FILE))?;
+        writeln!(file, "{line}")?;
+        file.sync_data()
+    }
+
+    /// Rewrites the index file with only the files which are still packed
+    fn write_compacted(&self) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!("{INDEX_FILE}.tmp"));
+
+        let mut file = fs::File::create(&tmp_path)?;
+        for (name, entry) in &self.entries {
+            writeln!(
+                file,
+                "add {name} {} {} {}",
+                entry.pack, entry.offset, entry.len
+            )?;
+        }
+        file.sync_all()?;
+
+        fs::rename(tmp_path, self.dir.join(INDEX_FILE))
+    }
+}
+
+fn parse<T: FromStr>(value: &str, line: &str) -> io::Result<T> {
+    value.parse().map_err(|_| invalid_line(line))
+}
+
+fn invalid_line(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid line in pack index: `{line}`"),
+    )
+}
+
+/// Writes a file like [`LocalStorage`] does, but moves it into a pack when it
+/// is finalized and turned out to be small
+struct PackWriter {
+    file: Option<WriteOrDeleteFile>,
+    written: u64,
+    storage: PackedLocalStorage,
+    dir: PathBuf,
+    name: String,
+}
+
+impl PackWriter {
+    fn file(&mut self) -> io::Result<&mut WriteOrDeleteFile> {
+        self.file
+            .as_mut()
+            .ok_or_else(|| io::Error::other("file was already finalized"))
+    }
+}
+
+impl AsyncWrite for PackWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(this.file()?).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.written += written as u64;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().file()?).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().file()?).poll_shutdown(cx)
+    }
+}
+
+#[async_trait::async_trait]
+impl Finalizer for PackWriter {
+    async fn finalize(&mut self) -> ApiResult<()> {
+        let Some(mut file) = self.file.take() else {
+            return Err(ApiErrorKind::FinalizingFileFailed(
+                "File was already finalized".to_string(),
+            ));
+        };
+
+        if self.written > PACK_THRESHOLD {
+            return file.finalize().await;
+        }
+
+        file.flush().await.map_err(|err| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not flush file",
+                ApiErrorKind::FinalizingFileFailed,
+            )
+        })?;
+
+        let data = tokio::fs::read(file.path()).await.map_err(|err| {
+            ApiErrorKind::from_io_error(
+                &err,
+                "Could not read file",
+                ApiErrorKind::FinalizingFileFailed,
+            )
+        })?;
+
+        let name = self.name.clone();
+        self.storage
+            .with_index(&self.dir, move |index| index.add(&name, &data))
+            .await?;
+
+        // the loose file is removed when dropped without being finalized
+        drop(file);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::SeekFrom, path::Path};
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn write(storage: &PackedLocalStorage, repo: &Path, name: &str, content: &[u8]) {
+        let mut file = storage.create_file(repo, "data", Some(name)).await.unwrap();
+        file.write_all(content).await.unwrap();
+        file.finalize().await.unwrap();
+    }
+
+    async fn read(storage: &PackedLocalStorage, repo: &Path, name: &str) -> Vec<u8> {
+        let mut file = storage.open_file(repo, "data", Some(name)).await.unwrap();
+        let mut content = Vec::new();
+        _ = file.read_to_end(&mut content).await.unwrap();
+        content
+    }
+
+    #[tokio::test]
+    async fn test_packed_local_storage_passes() {
+        let data_dir = Path::new("tests/generated/test_storage");
+        let repo = Path::new("repo_packed_me");
+
+        if data_dir.join(repo).exists() {
+            fs::remove_dir_all(data_dir.join(repo)).unwrap();
+        }
+
+        let storage = PackedLocalStorage::init(data_dir).unwrap();
+        storage.create_dir(repo, Some("data")).await.unwrap();
+
+        let large = vec![42u8; PACK_THRESHOLD as usize + 1];
+        write(&storage, repo, "aa_small_one", b"Hello World").await;
+        write(&storage, repo, "bb_small_two", b"Hello Sweet World").await;
+        write(&storage, repo, "cc_large", &large).await;
+
+        // small files are packed, large files are stored on their own
+        let pack_dir = data_dir.join(repo).join(PACKS_DIR).join("data");
+        assert!(pack_dir.join("00000000.pack").exists());
+        assert!(!storage
+            .filename(repo, "data", Some("aa_small_one"))
+            .exists());
+        assert!(storage.filename(repo, "data", Some("cc_large")).exists());
+
+        // packed files are read, listed and seeked like any other file
+        assert_eq!(read(&storage, repo, "aa_small_one").await, b"Hello World");
+        assert_eq!(read(&storage, repo, "cc_large").await, large);
+
+        let mut file = storage
+            .open_file(repo, "data", Some("bb_small_two"))
+            .await
+            .unwrap();
+        assert_eq!(file.len(), 17);
+        assert_eq!(file.seek(SeekFrom::Start(6)).await.unwrap(), 6);
+        let mut content = String::new();
+        _ = file.read_to_string(&mut content).await.unwrap();
+        assert_eq!(content, "Sweet World");
+
+        // lengths are known without opening the files
+        assert_eq!(
+            storage
+                .file_length(repo, "data", Some("bb_small_two"))
+                .await
+                .unwrap(),
+            17
+        );
+        assert_eq!(
+            storage
+                .file_length(repo, "data", Some("cc_large"))
+                .await
+                .unwrap(),
+            large.len() as u64
+        );
+        assert!(matches!(
+            storage.file_length(repo, "data", Some("missing")).await,
+            Err(ApiErrorKind::FileNotFound(_))
+        ));
+
+        let mut entries: Vec<_> = storage.read_dir(repo, Some("data")).collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(
+            entries,
+            vec![
+                StorageEntry {
+                    name: "aa_small_one".to_string(),
+                    size: 11
+                },
+                StorageEntry {
+                    name: "bb_small_two".to_string(),
+                    size: 17
+                },
+                StorageEntry {
+                    name: "cc_large".to_string(),
+                    size: large.len() as u64
+                },
+            ]
+        );
+
+        // packed and loose files are listed in one order
+        let names: Vec<_> = storage
+            .read_dir_after(repo, Some("data"), Some("aa_small_one"))
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, vec!["bb_small_two", "cc_large"]);
+
+        // files can't be overwritten
+        assert!(storage
+            .create_file(repo, "data", Some("aa_small_one"))
+            .await
+            .is_err());
+
+        // the index is persisted
+        storage
+            .remove_file(repo, "data", Some("aa_small_one"))
+            .await
+            .unwrap();
+        let reloaded = PackedLocalStorage::init(data_dir).unwrap();
+        assert!(reloaded
+            .open_file(repo, "data", Some("aa_small_one"))
+            .await
+            .is_err());
+        assert_eq!(
+            read(&reloaded, repo, "bb_small_two").await,
+            b"Hello Sweet World"
+        );
+
+        // empty packs are deleted
+        reloaded
+            .remove_file(repo, "data", Some("bb_small_two"))
+            .await
+            .unwrap();
+        assert!(!pack_dir.join("00000000.pack").exists());
+
+        reloaded.remove_repository(repo).await.unwrap();
+        assert!(!data_dir.join(repo).exists());
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     path::PathBuf,
     sync::{Mutex, OnceLock},
 };
@@ -15,8 +16,10 @@ use crate::{
     acl::{init_acl, Acl},
     auth::{init_auth, Auth},
     config::{
-        default_data_dir, AclSettings, HtpasswdSettings, RusticServerConfig, StorageSettings,
+        default_data_dir, AclSettings, HtpasswdSettings, RepoSettings, RusticServerConfig,
+        StorageSettings,
     },
+    context::ServerRuntimeContext,
     storage::{init_storage, LocalStorage, Storage},
 };
 
@@ -80,7 +83,7 @@ pub(crate) fn server_config() -> RusticServerConfig {
 pub(crate) fn init_test_environment(server_config: RusticServerConfig) {
     init_tracing();
     init_static_htpasswd(server_config.auth);
-    init_static_auth(server_config.acl);
+    init_static_auth(server_config.acl, &server_config.repos);
     init_static_storage(server_config.storage);
 }
 
@@ -90,8 +93,18 @@ fn init_static_htpasswd(htpasswd_settings: HtpasswdSettings) {
     init_auth(auth).unwrap();
 }
 
-fn init_static_auth(acl_settings: AclSettings) {
-    let acl = Acl::from_config(&acl_settings.clone(), acl_settings.acl_path).unwrap();
+/// Validates the repository settings and returns the repositories configured
+/// as read-only, the same way the server does on startup
+pub(crate) fn read_only_repos(repos: &BTreeMap<String, RepoSettings>) -> BTreeSet<PathBuf> {
+    ServerRuntimeContext::<LocalStorage>::read_only_repos(repos).unwrap()
+}
+
+fn init_static_auth(acl_settings: AclSettings, repos: &BTreeMap<String, RepoSettings>) {
+    // authentication is enabled, so reads without credentials are anonymous
+    let acl = Acl::from_config(&acl_settings.clone(), acl_settings.acl_path)
+        .unwrap()
+        .set_anonymous_user(Some(String::new()))
+        .set_read_only_repos(read_only_repos(repos));
     debug!(?acl, "Loaded Acl.");
     init_acl(acl).unwrap();
 }
@@ -103,7 +116,8 @@ fn init_static_storage(storage_settings: StorageSettings) {
             .unwrap_or_else(default_data_dir)
             .as_ref(),
     )
-    .unwrap();
+    .unwrap()
+    .set_reserved_repo_names(storage_settings.reserved_repo_names);
 
     debug!(?local_storage, "Loaded Storage.");
 
@@ -0,0 +1,407 @@
+//! TLS configuration of the listeners
+//!
+//! Besides the default certificate (`tls-cert` and `tls-key`, or a PKCS#12
+//! bundle in `tls-pkcs12`), further certificates can be configured per host
+//! name. They are selected by the host name the client sends in the TLS
+//! handshake (SNI), so a single server can serve several domains.
+//!
+//! Certificates and keys are read as PEM or DER, see [`TlsFormat`].
+//!
+//! An OCSP response can be stapled to the default certificate, so clients
+//! checking for revocation don't have to ask the OCSP responder themselves.
+//! The server doesn't fetch the response, but reloads the file periodically if
+//! a refresh interval is configured, so it can be renewed by an external tool.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use p12_keystore::KeyStore;
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+
+use crate::{
+    config::TlsFormat,
+    context::{TlsIdentity, TlsOptions},
+};
+
+/// Builds the rustls configuration for the given TLS options
+pub async fn rustls_config(tls: TlsOptions) -> io::Result<RustlsConfig> {
+    let ocsp = tls.ocsp.clone();
+    let resolver = tokio::task::spawn_blocking(move || SniResolver::new(&tls))
+        .await
+        .map_err(io::Error::other)??;
+    let resolver = Arc::new(resolver);
+
+    if let Some(ocsp) = ocsp {
+        if let Some(interval) = ocsp.refresh_interval {
+            spawn_ocsp_refresher(resolver.clone(), ocsp.response, interval);
+        }
+    }
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// Reloads the OCSP response from `path` in the background every `interval`
+///
+/// If the file can't be read, the previous response is kept, so a renewal
+/// that failed or is still in progress doesn't drop the stapling.
+fn spawn_ocsp_refresher(resolver: Arc<SniResolver>, path: PathBuf, interval: Duration) {
+    tracing::info!(
+        ?path,
+        ?interval,
+        "Reloading the OCSP response in the background."
+    );
+
+    drop(tokio::spawn(async move {
+        // the response was just loaded at startup
+        let mut ticks = interval_at(Instant::now() + interval, interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            _ = ticks.tick().await;
+            let reloaded = tokio::task::spawn_blocking({
+                let resolver = resolver.clone();
+                let path = path.clone();
+                move || resolver.reload_ocsp(&path)
+            })
+            .await
+            .map_err(io::Error::other)
+            .and_then(|reloaded| reloaded);
+
+            match reloaded {
+                Ok(()) => tracing::debug!(?path, "Reloaded the OCSP response."),
+                Err(err) => tracing::warn!(
+                    ?path,
+                    "Could not reload the OCSP response, keeping the previous one: {err}"
+                ),
+            }
+        }
+    }));
+}
+
+/// Selects the certificate by the host name the client asks for, falling back
+/// to the default certificate
+#[derive(Debug)]
+pub struct SniResolver {
+    /// Certificates by lowercase host name
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    /// Default certificate, replaced when its OCSP response is reloaded
+    default: RwLock<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    /// Loads all certificates of the given TLS options
+    pub fn new(tls: &TlsOptions) -> io::Result<Self> {
+        let default = match &tls.identity {
+            TlsIdentity::Files { cert, key, format } => load_certified_key(cert, key, *format)?,
+            TlsIdentity::Pkcs12 { path, password } => load_pkcs12(path, password)?,
+        };
+
+        let by_name = tls
+            .certificates
+            .iter()
+            .map(|certificate| {
+                let key = load_certified_key(&certificate.cert, &certificate.key, None)?;
+                Ok((certificate.hostname.to_ascii_lowercase(), key))
+            })
+            .collect::<io::Result<_>>()?;
+
+        let resolver = Self {
+            by_name,
+            default: RwLock::new(default),
+        };
+        if let Some(ocsp) = &tls.ocsp {
+            resolver.reload_ocsp(&ocsp.response)?;
+        }
+
+        Ok(resolver)
+    }
+
+    /// Returns the certificate for the host name `server_name`
+    pub fn resolve_name(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        match server_name.and_then(|name| self.by_name.get(&name.to_ascii_lowercase())) {
+            Some(key) => key.clone(),
+            None => self
+                .default
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone(),
+        }
+    }
+
+    /// Staples the OCSP response in the file `path` to the default certificate
+    ///
+    /// # Errors
+    ///
+    /// * If the file can't be read or is empty, in which case the previous
+    ///   response is kept.
+    pub fn reload_ocsp(&self, path: &Path) -> io::Result<()> {
+        let response = read(path)?;
+        if response.is_empty() {
+            return Err(invalid_data(path, "the OCSP response is empty"));
+        }
+
+        let mut default = self
+            .default
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut key = CertifiedKey::clone(&default);
+        key.ocsp = Some(response);
+        *default = Arc::new(key);
+
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.resolve_name(client_hello.server_name()))
+    }
+}
+
+/// Loads a certificate chain and its private key from PEM or DER files
+///
+/// The format of each file is detected by its extension if none is given.
+fn load_certified_key(
+    cert: &Path,
+    key: &Path,
+    format: Option<TlsFormat>,
+) -> io::Result<Arc<CertifiedKey>> {
+    let format_of = |path| format.unwrap_or_else(|| TlsFormat::detect(path));
+
+    let certs = match format_of(cert) {
+        TlsFormat::Pem => CertificateDer::pem_file_iter(cert)
+            .map_err(|err| invalid_data(cert, err))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| invalid_data(cert, err))?,
+        TlsFormat::Der => vec![CertificateDer::from(read(cert)?)],
+    };
+    let key = match format_of(key) {
+        TlsFormat::Pem => {
+            PrivateKeyDer::from_pem_file(key).map_err(|err| invalid_data(key, err))?
+        }
+        TlsFormat::Der => {
+            PrivateKeyDer::try_from(read(key)?).map_err(|err| invalid_data(key, err))?
+        }
+    };
+
+    certified_key(certs, &key)
+}
+
+/// Loads the certificate chain and private key of a PKCS#12 bundle
+fn load_pkcs12(path: &Path, password: &str) -> io::Result<Arc<CertifiedKey>> {
+    let keystore =
+        KeyStore::from_pkcs12(&read(path)?, password).map_err(|err| invalid_data(path, err))?;
+    let Some((_, chain)) = keystore.private_key_chain() else {
+        return Err(invalid_data(path, "no private key found"));
+    };
+
+    let certs = chain
+        .chain()
+        .iter()
+        .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+        .collect();
+    let key =
+        PrivateKeyDer::try_from(chain.key().to_vec()).map_err(|err| invalid_data(path, err))?;
+
+    certified_key(certs, &key)
+}
+
+fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    key: &PrivateKeyDer<'_>,
+) -> io::Result<Arc<CertifiedKey>> {
+    let signing_key =
+        any_supported_type(key).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|err| io::Error::new(err.kind(), format!("`{}`: {err}", path.display())))
+}
+
+fn invalid_data(path: &Path, err: impl Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("`{}`: {err}", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    use crate::{
+        config::{SniCertificate, TlsFormat},
+        context::{OcspStapling, TlsIdentity, TlsOptions},
+        tls::SniResolver,
+    };
+
+    #[test]
+    fn test_sni_resolver_passes() {
+        let cert = PathBuf::from("tests/fixtures/test_data/certs/test.crt");
+        let key = PathBuf::from("tests/fixtures/test_data/certs/test.key");
+        let tls = TlsOptions {
+            identity: TlsIdentity::Files {
+                cert: cert.clone(),
+                key: key.clone(),
+                format: None,
+            },
+            certificates: vec![SniCertificate {
+                hostname: "backup.example.com".to_string(),
+                cert,
+                key,
+            }],
+            ocsp: None,
+        };
+
+        let resolver = SniResolver::new(&tls).unwrap();
+        let default = resolver.resolve_name(None);
+        let backup = resolver.resolve_name(Some("Backup.Example.com"));
+
+        assert!(!Arc::ptr_eq(&default, &backup));
+        assert!(Arc::ptr_eq(
+            &resolver.resolve_name(Some("other.example.com")),
+            &default
+        ));
+    }
+
+    #[test]
+    fn test_load_der_and_pkcs12_passes() {
+        let certs = PathBuf::from("tests/fixtures/test_data/certs");
+        let pem = SniResolver::new(&TlsOptions {
+            identity: TlsIdentity::Files {
+                cert: certs.join("test.crt"),
+                key: certs.join("test.key"),
+                format: None,
+            },
+            certificates: vec![],
+            ocsp: None,
+        })
+        .unwrap()
+        .resolve_name(None);
+
+        let identities = [
+            // detected by extension
+            TlsIdentity::Files {
+                cert: certs.join("test.der"),
+                key: certs.join("test.key.der"),
+                format: None,
+            },
+            TlsIdentity::Files {
+                cert: certs.join("test.der"),
+                key: certs.join("test.key.der"),
+                format: Some(TlsFormat::Der),
+            },
+            TlsIdentity::Pkcs12 {
+                path: certs.join("test.p12"),
+                password: "test".to_string(),
+            },
+        ];
+        for identity in identities {
+            let tls = TlsOptions {
+                identity,
+                certificates: vec![],
+                ocsp: None,
+            };
+            let loaded = SniResolver::new(&tls).unwrap().resolve_name(None);
+            assert_eq!(loaded.cert, pem.cert, "{:?}", tls.identity);
+        }
+    }
+
+    #[test]
+    fn test_load_der_and_pkcs12_fails() {
+        let certs = PathBuf::from("tests/fixtures/test_data/certs");
+        let identities = [
+            // PEM files read as DER
+            TlsIdentity::Files {
+                cert: certs.join("test.crt"),
+                key: certs.join("test.key"),
+                format: Some(TlsFormat::Der),
+            },
+            TlsIdentity::Pkcs12 {
+                path: certs.join("test.p12"),
+                password: "wrong".to_string(),
+            },
+        ];
+        for identity in identities {
+            let tls = TlsOptions {
+                identity,
+                certificates: vec![],
+                ocsp: None,
+            };
+            assert!(SniResolver::new(&tls).is_err(), "{:?}", tls.identity);
+        }
+    }
+
+    #[test]
+    fn test_ocsp_stapling_passes() {
+        let certs = PathBuf::from("tests/fixtures/test_data/certs");
+        let dir = PathBuf::from("tests/generated/ocsp_stapling");
+        fs::create_dir_all(&dir).unwrap();
+        let response = dir.join("test.ocsp");
+        fs::write(&response, b"first response").unwrap();
+
+        let tls = TlsOptions {
+            identity: TlsIdentity::Files {
+                cert: certs.join("test.crt"),
+                key: certs.join("test.key"),
+                format: None,
+            },
+            certificates: vec![SniCertificate {
+                hostname: "backup.example.com".to_string(),
+                cert: certs.join("test.crt"),
+                key: certs.join("test.key"),
+            }],
+            ocsp: Some(OcspStapling {
+                response: response.clone(),
+                refresh_interval: None,
+            }),
+        };
+        let resolver = SniResolver::new(&tls).unwrap();
+        let stapled = resolver.resolve_name(None);
+        assert_eq!(stapled.ocsp.as_deref(), Some(&b"first response"[..]));
+        // only the default certificate gets the response
+        assert!(resolver
+            .resolve_name(Some("backup.example.com"))
+            .ocsp
+            .is_none());
+
+        fs::write(&response, b"second response").unwrap();
+        resolver.reload_ocsp(&response).unwrap();
+        let reloaded = resolver.resolve_name(None);
+        assert_eq!(reloaded.ocsp.as_deref(), Some(&b"second response"[..]));
+        assert_eq!(reloaded.cert, stapled.cert);
+
+        // a broken renewal keeps the previous response
+        fs::write(&response, b"").unwrap();
+        assert!(resolver.reload_ocsp(&response).is_err());
+        assert!(resolver.reload_ocsp(&dir.join("missing.ocsp")).is_err());
+        assert_eq!(
+            resolver.resolve_name(None).ocsp.as_deref(),
+            Some(&b"second response"[..])
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -67,6 +67,78 @@ impl PathParts for RepositoryConfigPath {
     }
 }
 
+// A type safe route with `"/:repo/config/history"` as its associated path.
+#[derive(TypedPath, Deserialize, Debug)]
+#[typed_path("/:repo/config/history")]
+pub struct RepositoryConfigHistoryPath {
+    pub repo: String,
+}
+
+// A type safe route with `"/:repo/health"` as its associated path.
+#[derive(TypedPath, Deserialize, Debug)]
+#[typed_path("/:repo/health")]
+pub struct RepositoryHealthPath {
+    pub repo: String,
+}
+
+impl PathParts for RepositoryHealthPath {
+    fn repo(&self) -> Option<String> {
+        Some(self.repo.clone())
+    }
+}
+
+// A type safe route with `"/:repo/stats"` as its associated path.
+#[derive(TypedPath, Deserialize, Debug)]
+#[typed_path("/:repo/stats")]
+pub struct RepositoryStatsPath {
+    pub repo: String,
+}
+
+impl PathParts for RepositoryStatsPath {
+    fn repo(&self) -> Option<String> {
+        Some(self.repo.clone())
+    }
+}
+
+// A type safe route with `"/:repo/manifest"` as its associated path.
+#[derive(TypedPath, Deserialize, Debug)]
+#[typed_path("/:repo/manifest")]
+pub struct RepositoryManifestPath {
+    pub repo: String,
+}
+
+impl PathParts for RepositoryManifestPath {
+    fn repo(&self) -> Option<String> {
+        Some(self.repo.clone())
+    }
+}
+
+// A type safe route with `"/:repo/unlock"` as its associated path.
+#[derive(TypedPath, Deserialize, Debug)]
+#[typed_path("/:repo/unlock")]
+pub struct RepositoryUnlockPath {
+    pub repo: String,
+}
+
+impl PathParts for RepositoryUnlockPath {
+    fn repo(&self) -> Option<String> {
+        Some(self.repo.clone())
+    }
+}
+
+// A type safe route with `"/:repo/restore"` as its associated path.
+#[derive(TypedPath, Deserialize, Debug)]
+#[typed_path("/:repo/restore")]
+pub struct RepositoryRestorePath {
+    pub repo: String,
+}
+
+impl PathParts for RepositoryRestorePath {
+    fn repo(&self) -> Option<String> {
+        Some(self.repo.clone())
+    }
+}
+
 // A type safe route with `"/:repo/"` as its associated path.
 #[derive(TypedPath, Deserialize, Debug)]
 #[typed_path("/:repo/")]
@@ -111,6 +183,33 @@ impl PathParts for RepositoryTpePath {
     }
 }
 
+// A type safe route with `"/:repo/:tpe/:name/meta"` as its associated path.
+#[derive(TypedPath, Deserialize, Debug)]
+#[typed_path("/:repo/:tpe/:name/meta")]
+pub struct RepositoryTpeNameMetaPath {
+    pub repo: String,
+    pub tpe: TpeKind,
+    pub name: String,
+}
+
+// A type safe route with `"/:repo/:tpe/_batch"` as its associated path.
+#[derive(TypedPath, Deserialize, Debug)]
+#[typed_path("/:repo/:tpe/_batch")]
+pub struct RepositoryTpeBatchPath {
+    pub repo: String,
+    pub tpe: TpeKind,
+}
+
+impl PathParts for RepositoryTpeBatchPath {
+    fn repo(&self) -> Option<String> {
+        Some(self.repo.clone())
+    }
+
+    fn tpe(&self) -> Option<TpeKind> {
+        Some(self.tpe)
+    }
+}
+
 // A type safe route with `"/:tpe/:name"` as its associated path.
 #[derive(TypedPath, Deserialize, Debug)]
 #[typed_path("/:tpe/:name")]
@@ -0,0 +1,126 @@
+//! Registry of the uploads currently written, for the admin endpoints
+//!
+//! Every file is registered from its creation until it was finalized or
+//! dropped. Uploads of clients that hang stay registered, so they can be told
+//! apart from slow ones by their age and the bytes written so far.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+
+// Global registry of the active uploads
+static UPLOADS: OnceLock<UploadRegistry> = OnceLock::new();
+
+/// Returns the global registry of the active uploads
+pub fn uploads() -> &'static UploadRegistry {
+    UPLOADS.get_or_init(UploadRegistry::default)
+}
+
+/// A file currently written
+#[derive(Debug)]
+struct Upload {
+    repo: PathBuf,
+    tpe: String,
+    name: Option<String>,
+    started: Instant,
+    bytes_written: AtomicU64,
+}
+
+/// Information about an active upload, as reported by the admin endpoint
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct UploadInfo {
+    pub id: u64,
+    /// Path of the repository in the storage
+    pub repo: PathBuf,
+    #[serde(rename = "type")]
+    pub tpe: String,
+    pub name: Option<String>,
+    pub bytes_written: u64,
+    pub age_secs: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct UploadRegistry {
+    next_id: AtomicU64,
+    uploads: Mutex<BTreeMap<u64, Arc<Upload>>>,
+}
+
+impl UploadRegistry {
+    /// Registers an upload of the file `name` of type `tpe` to the repository
+    /// at `repo`, until the returned handle is dropped
+    pub fn register(&'static self, repo: &Path, tpe: &str, name: Option<&str>) -> ActiveUpload {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let upload = Arc::new(Upload {
+            repo: repo.to_path_buf(),
+            tpe: tpe.to_string(),
+            name: name.map(ToString::to_string),
+            started: Instant::now(),
+            bytes_written: AtomicU64::new(0),
+        });
+        let _ = self
+            .uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, upload.clone());
+
+        ActiveUpload {
+            registry: self,
+            id,
+            upload,
+        }
+    }
+
+    /// Returns all active uploads, ordered by their start
+    pub fn list(&self) -> Vec<UploadInfo> {
+        self.uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(&id, upload)| UploadInfo {
+                id,
+                repo: upload.repo.clone(),
+                tpe: upload.tpe.clone(),
+                name: upload.name.clone(),
+                bytes_written: upload.bytes_written.load(Ordering::Relaxed),
+                age_secs: upload.started.elapsed().as_secs_f64(),
+            })
+            .collect()
+    }
+}
+
+/// An upload in the registry, which is removed from it when this is dropped
+#[derive(Debug)]
+pub struct ActiveUpload {
+    registry: &'static UploadRegistry,
+    id: u64,
+    upload: Arc<Upload>,
+}
+
+impl ActiveUpload {
+    /// Records that `bytes` more bytes were written
+    pub fn record_written(&self, bytes: usize) {
+        let _ = self
+            .upload
+            .bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        let _ = self
+            .registry
+            .uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.id);
+    }
+}
@@ -0,0 +1,93 @@
+//! Allowlist of client user agents
+//!
+//! With `allowed-user-agents` configured, only requests whose `User-Agent`
+//! header matches one of its patterns are served, e.g. `restic/*`. All others,
+//! including requests without the header, are refused with `403 Forbidden`
+//! before routing and authentication, which keeps scanners and misrouted
+//! traffic away from them. Clients can send any user agent, so this doesn't
+//! replace authentication.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::acl::matches_pattern;
+
+/// Patterns of the user agents whose requests are served
+pub type AllowedUserAgents = Arc<Vec<String>>;
+
+/// Router middleware function refusing requests from user agents which don't
+/// match any of the allowed patterns
+///
+/// Patterns may contain the wildcards `*` (any characters) and `?` (one
+/// character), like user names in the ACL.
+pub async fn check_user_agent(
+    State(allowed): State<AllowedUserAgents>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+
+    if user_agent.is_some_and(|user_agent| is_allowed(&allowed, user_agent)) {
+        return next.run(req).await;
+    }
+
+    tracing::debug!(?user_agent, "Refusing request from user agent not allowed.");
+    (StatusCode::FORBIDDEN, "user agent not allowed").into_response()
+}
+
+/// Returns whether `user_agent` matches one of the `allowed` patterns
+fn is_allowed(allowed: &[String], user_agent: &str) -> bool {
+    allowed
+        .iter()
+        .any(|pattern| matches_pattern(pattern, user_agent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::{Layer, ServiceExt};
+
+    async fn request_status(user_agent: Option<&str>) -> StatusCode {
+        let app = Router::new().fallback(get(|| async {}));
+        let allowed: AllowedUserAgents =
+            Arc::new(vec!["restic/*".to_string(), "rustic v?.*".to_string()]);
+        let app = middleware::from_fn_with_state(allowed, check_user_agent).layer(app);
+
+        let mut request = Request::builder().uri("/repo/config");
+        if let Some(user_agent) = user_agent {
+            request = request.header(header::USER_AGENT, user_agent);
+        }
+
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_check_user_agent_passes() {
+        assert_eq!(request_status(Some("restic/0.17.3")).await, StatusCode::OK);
+        assert_eq!(request_status(Some("rustic v0.9.5")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_check_user_agent_fails() {
+        for user_agent in [Some("curl/8.5.0"), Some("xrestic/0.17.3"), None] {
+            assert_eq!(
+                request_status(user_agent).await,
+                StatusCode::FORBIDDEN,
+                "{user_agent:?}"
+            );
+        }
+    }
+}
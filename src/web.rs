@@ -1,27 +1,112 @@
-use axum::{middleware, routing::get, Router};
-use axum_extra::routing::RouterExt;
-use axum_server::tls_rustls::RustlsConfig;
-use tokio::net::TcpListener;
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::DefaultBodyLimit,
+    http::{header, Extensions, HeaderMap, StatusCode, Version},
+    middleware,
+    response::Response,
+    routing::{delete, get, post},
+    Router,
+};
+use axum_extra::routing::{RouterExt, TypedPath};
+use axum_server::{tls_rustls::RustlsConfig, Handle, Server};
+use hyper_util::rt::TokioTimer;
+use listenfd::ListenFd;
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+use socket2::{Domain, Socket, Type};
+use tower::{util::MapResponseLayer, Layer};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, Predicate},
+        CompressionLayer,
+    },
+    CompressionLevel,
+};
 use tracing::{info, level_filters::LevelFilter};
 
 use crate::{
     acl::init_acl,
-    auth::init_auth,
-    context::ServerRuntimeContext,
-    error::{AppResult, ErrorKind},
+    as_of::reject_writes_as_of,
+    auth::{init_admin_auth, init_auth},
+    connections::LimitPeerConnections,
+    context::{ConnectionLimits, ServerRuntimeContext},
+    error::{AppResult, Error, ErrorKind},
     handlers::{
-        file_config::{add_config, delete_config, get_config, has_config},
-        file_exchange::{add_file, delete_file, get_file},
+        admin::{
+            diagnostics, get_acl, init_effective_config, list_sessions, list_uploads, put_acl,
+            reload, rename_repository, revoke_session, stream_logs,
+        },
+        file_batch::add_files,
+        file_config::{
+            add_config, delete_config, get_config, get_config_history, has_config,
+            init_config_history, init_validate_config,
+        },
+        file_exchange::{
+            add_file, delete_file, get_file, init_io_buffer_size, init_reject_empty_uploads,
+            init_require_explicit_create, init_strict_content_type,
+        },
+        file_helpers::init_success_status,
         file_length::file_length,
-        files_list::list_files,
-        health::{init_start_time, live_check},
-        repository::{create_repository, delete_repository},
+        file_meta::file_meta,
+        file_range::init_max_ranges,
+        files_list::{init_api_versions, list_files},
+        health::{init_start_time, live_check, ready_check, repository_health},
+        management::rpc,
+        manifest::repository_manifest,
+        repository::{create_repository, delete_repository, restore_repository, unlock_repository},
+        root::{favicon, robots_txt, root},
+        stats::{metrics, repository_stats},
+    },
+    idempotency::{deduplicate_requests, IdempotencyCache},
+    log::{init_log_sample_rate, print_request_response},
+    options::answer_options,
+    overload::shed_load,
+    provenance::init_record_provenance,
+    proxy::strip_forwarded_prefix,
+    sessions::track_sessions,
+    storage::{init_storage, Storage, STORAGE},
+    tls::rustls_config,
+    typed_path::{
+        RepositoryConfigPath, RepositoryPath, RepositoryTpeBatchPath, RepositoryTpeNamePath,
+        RepositoryTpePath,
     },
-    log::print_request_response,
-    storage::{init_storage, Storage},
-    typed_path::{RepositoryConfigPath, RepositoryPath, RepositoryTpeNamePath, RepositoryTpePath},
+    user_agent::check_user_agent,
 };
 
+// Handle of the web server, to shut it down gracefully
+static SERVER_HANDLE: OnceLock<Handle> = OnceLock::new();
+
+// Time in-flight requests may take to finish on shutdown
+static SHUTDOWN_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+fn server_handle() -> &'static Handle {
+    SERVER_HANDLE.get_or_init(Handle::new)
+}
+
+pub(crate) fn init_shutdown_timeout(shutdown_timeout: Duration) {
+    let _ = SHUTDOWN_TIMEOUT.set(shutdown_timeout);
+}
+
+/// Stop accepting connections and let the web server return once all
+/// in-flight requests finished
+///
+/// Connections still busy after the configured shutdown timeout are closed,
+/// so a stuck upload can't block the shutdown. Requested before the server
+/// started, it returns right after starting.
+pub fn shutdown_gracefully() {
+    let timeout = SHUTDOWN_TIMEOUT.get().copied().unwrap_or_default();
+    info!(
+        connections = server_handle().connection_count(),
+        ?timeout,
+        "Shutting down gracefully ..."
+    );
+    server_handle().graceful_shutdown(Some(timeout));
+}
+
 /// Start the web server
 ///
 /// # Arguments
@@ -33,20 +118,81 @@ where
 {
     let ServerRuntimeContext {
         socket_address,
+        management_address,
         acl,
         auth,
+        admin_auth,
         storage,
         tls,
+        limits,
+        trusted_proxies,
+        allowed_user_agents,
+        systemd_socket,
+        reuse_port,
+        no_content_on_success,
+        reject_empty_uploads,
+        config_history,
+        validate_config,
+        require_explicit_create,
+        record_provenance,
+        io_buffer_size,
+        strict_content_type,
+        batch_uploads,
+        api_versions,
+        response_headers,
+        compression_level,
+        idempotency_window,
+        root_response,
+        lock_sweeper,
+        trash_purger,
+        log_sample_rate,
+        effective_config,
         ..
     } = runtime_ctx;
 
     init_start_time();
     init_acl(acl)?;
     init_auth(auth)?;
+    init_admin_auth(admin_auth)?;
     init_storage(storage)?;
+    init_success_status(no_content_on_success);
+    init_reject_empty_uploads(reject_empty_uploads);
+    init_config_history(config_history);
+    init_validate_config(validate_config);
+    init_require_explicit_create(require_explicit_create);
+    init_record_provenance(record_provenance);
+    init_io_buffer_size(io_buffer_size);
+    init_strict_content_type(strict_content_type);
+    init_api_versions(api_versions);
+    init_log_sample_rate(log_sample_rate);
+    init_shutdown_timeout(limits.shutdown_timeout);
+    init_max_ranges(limits.max_ranges);
+    init_effective_config(effective_config);
+
+    if let Some(lock_sweeper) = lock_sweeper {
+        lock_sweeper.spawn(STORAGE.get().unwrap().clone());
+    }
+
+    if let Some(trash_purger) = trash_purger {
+        trash_purger.spawn(STORAGE.get().unwrap().clone());
+    }
 
     let mut app = Router::new();
 
+    // /
+    //
+    // Returns the configured banner, or “403 Forbidden” in strict mode.
+    // No authentication needed.
+    app = app.route("/", get(root).with_state(Arc::new(root_response)));
+
+    // /favicon.ico and /robots.txt
+    //
+    // Answered for browsers and crawlers behind a proxy, so they are not taken
+    // for repositories. No authentication needed.
+    app = app
+        .route("/favicon.ico", get(favicon))
+        .route("/robots.txt", get(robots_txt));
+
     // /health/live
     //
     // Liveness probe. This is used to check if the server is running.
@@ -56,8 +202,80 @@ where
     // /health/ready
     //
     // Readiness probe. This is used to check if the server is ready to accept requests.
-    // Returns “200 OK” if the server is ready to accept requests.
-    // app = app.route("/health/ready", get(ready_check));
+    // Returns “200 OK” if the server is ready to accept requests, i.e. the storage
+    // is accessible, “503 Service Unavailable” otherwise.
+    app = app.route("/health/ready", get(ready_check));
+
+    // /:repo/health
+    //
+    // Returns “200 OK” if the repository has a config and all type directories,
+    // “503 Service Unavailable” otherwise. The body contains the status of each component.
+    app = app.typed_get(repository_health);
+
+    // /:repo/stats
+    //
+    // Returns the bytes read from and written to the repository since the server started.
+    app = app.typed_get(repository_stats);
+
+    // /:repo/manifest
+    //
+    // Returns a JSON object with the size of every file of the repository, keyed
+    // by `<type>/<name>`, so the repositories of two servers can be compared.
+    app = app.typed_get(repository_manifest);
+
+    // /metrics
+    //
+    // Returns the throughput of all repositories in the Prometheus text format.
+    // Only allowed for admin users.
+    app = app.route("/metrics", get(metrics));
+
+    // /admin/sessions
+    //
+    // Returns a JSON array of the requests currently served, with client, user,
+    // method, path, transferred bytes and duration. Only allowed for admin users.
+    app = app.route("/admin/sessions", get(list_sessions));
+
+    // /admin/sessions/:id
+    //
+    // Aborts the request with the given id and closes its connection.
+    // Only allowed for admin users.
+    app = app.route("/admin/sessions/:id", delete(revoke_session));
+
+    // /admin/uploads
+    //
+    // Returns a JSON array of the files currently written, with repository,
+    // type, name, bytes written so far and age. Only allowed for admin users.
+    app = app.route("/admin/uploads", get(list_uploads));
+
+    // /admin/logs
+    //
+    // Streams the recent log events and all new ones as server-sent events.
+    // Only allowed for admin users.
+    app = app.route("/admin/logs", get(stream_logs));
+
+    // /admin/acl
+    //
+    // Returns the ACLs of all repositories as JSON, or replaces them and writes
+    // them to the ACL file. Only allowed for admin users.
+    app = app.route("/admin/acl", get(get_acl).put(put_acl));
+
+    // /admin/reload
+    //
+    // Reads the htpasswd and ACL files again and uses them, or returns why
+    // they couldn't be read. Only allowed for admin users.
+    app = app.route("/admin/reload", post(reload));
+
+    // /admin/diagnostics
+    //
+    // Returns the effective configuration, with secrets redacted, and the
+    // state of the running server. Only allowed for admin users.
+    app = app.route("/admin/diagnostics", get(diagnostics));
+
+    // /admin/repos/:repo/rename?to=new
+    //
+    // Moves the repository to another path and renames its ACL sections along.
+    // Only allowed for admin users.
+    app = app.route("/admin/repos/:repo/rename", post(rename_repository));
 
     // /:repo/:tpe/:name
     app = app
@@ -82,6 +300,25 @@ where
         // the repository, an HTTP error otherwise.
         .typed_delete(delete_file::<RepositoryTpeNamePath>);
 
+    // /:repo/:tpe/:name/meta
+    //
+    // Returns a JSON object with the user who uploaded the blob, when and its
+    // size, if the provenance of uploads is recorded.
+    app = app.typed_get(file_meta);
+
+    // /:repo/:tpe/_batch
+    //
+    // Saves each part of the multipart request body as a blob with the part's
+    // name and the given type, and returns a JSON array with the status of each.
+    // The body may be larger than the default limit of multipart bodies.
+    // Only if enabled, as it is not part of the REST API of restic.
+    if batch_uploads {
+        app = app.route(
+            RepositoryTpeBatchPath::PATH,
+            post(add_files).layer(DefaultBodyLimit::disable()),
+        );
+    }
+
     // /:repo/config
     app = app
         // Returns “200 OK” if the repository has a configuration, an HTTP error otherwise.
@@ -100,6 +337,12 @@ where
         // to allow for the deletion of the configuration file during testing.
         .typed_delete(delete_config::<RepositoryConfigPath>);
 
+    // /:repo/config/history
+    //
+    // Returns a JSON array of the kept previous versions of the configuration,
+    // which can be fetched with `GET /:repo/config?version=<n>`.
+    app = app.typed_get(get_config_history);
+
     // /:repo/:tpe/
     // # API version 1
     //
@@ -139,6 +382,18 @@ where
     // ]
     app = app.typed_get(list_files::<RepositoryTpePath>);
 
+    // /:repo/unlock?older_than=30m
+    //
+    // Removes the locks older than the given age (default: 30 minutes) and returns
+    // the names of the removed locks. Needs `ForceUnlock` access to the repository.
+    app = app.typed_post(unlock_repository);
+
+    // /:repo/restore
+    //
+    // Moves the most recently deleted repository with this name back from the
+    // trash directory, if one is configured. Needs `Modify` access to the repository.
+    app = app.typed_post(restore_repository);
+
     // /:repo/ --> note: trailing slash
     app = app
         // This request is used to initially create a new repository.
@@ -171,6 +426,25 @@ where
     //     app = app.route(path.as_str(), get(list_files::<TpePath>));
     // }
 
+    // Reading a repository at a point in time can't change it
+    app = app.layer(middleware::from_fn(reject_writes_as_of));
+
+    // Answer retries of uploads and deletions like the original request
+    if let Some(window) = idempotency_window {
+        app = app.layer(middleware::from_fn_with_state(
+            IdempotencyCache::new(window),
+            deduplicate_requests,
+        ));
+    }
+
+    // Compress listings, inside the session tracking so it counts the bytes sent
+    if let Some(level) = compression_level {
+        app = app.layer(compression_layer(level));
+    }
+
+    // Register every request, so admins can list and revoke them
+    app = app.layer(middleware::from_fn(track_sessions));
+
     // Extra logging requested. Handlers will log too
     // TODO: Use LogSettings here, this should be set from the cli by `--log`
     // TODO: and then needs to go to a file
@@ -182,36 +456,279 @@ where
         _ => {}
     };
 
+    // Layers of the router only run after routing, so the prefix has to be
+    // stripped by a middleware wrapping the whole router. The outer router
+    // converts the request body for it. `OPTIONS` is answered from the
+    // `Allow` header of the router's refusal, which is only added outside of
+    // its layers.
+    let app = Router::new().fallback_service(
+        middleware::from_fn_with_state(Arc::new(trusted_proxies), strip_forwarded_prefix)
+            .layer(middleware::from_fn(answer_options).layer(app)),
+    );
+
+    // Shed requests beyond the limit before any work is done for them
+    let app = match limits.max_inflight {
+        Some(max_inflight) => shed_load(app, max_inflight),
+        None => app,
+    };
+
+    // Refuse unknown clients before any work is done for them
+    let app = if allowed_user_agents.is_empty() {
+        app
+    } else {
+        app.layer(middleware::from_fn_with_state(
+            Arc::new(allowed_user_agents),
+            check_user_agent,
+        ))
+    };
+
+    // Configured static headers, added to every response including errors
+    let app = if response_headers.is_empty() {
+        app
+    } else {
+        let response_headers = Arc::new(response_headers);
+        app.layer(MapResponseLayer::new(move |res| {
+            add_response_headers(&response_headers, res)
+        }))
+    };
+
     info!("Starting web server ...");
 
-    if let Some(tls) = tls {
+    let listener = if systemd_socket {
+        systemd_listener()?
+    } else {
+        None
+    };
+    let listener = match listener {
+        None if reuse_port => Some(reuse_port_listener(socket_address)?),
+        listener => listener,
+    };
+    let listen_address = match &listener {
+        Some(listener) => listener.local_addr().map_err(|err| {
+            ErrorKind::Bind.context(format!("Failed to get address of systemd socket: `{err}`"))
+        })?,
+        None => socket_address,
+    };
+
+    let tls_config = match tls {
+        Some(tls) => Some(
+            rustls_config(tls)
+                .await
+                .map_err(|err| {
+                    ErrorKind::Config.context(format!(
+                        "Failed to load TLS certificate/key. Please make sure the paths are correct. `{err}`"
+                    ))
+                })?,
+        ),
+        None => None,
+    };
+
+    if let Some(management_address) = management_address {
+        serve_management(management_address, tls_config.clone(), limits)?;
+    }
+
+    if let Some(config) = tls_config {
         // Start server with or without TLS
-        let config = RustlsConfig::from_pem_file(tls.tls_cert, tls.tls_key)
-            .await
-        .map_err(|err|
-            ErrorKind::Io.context(
-                format!("Failed to load TLS certificate/key. Please make sure the paths are correct. `{err}`")
-            )
-        )?;
+        info!("Listening on: `https://{listen_address}`");
 
-        info!("Listening on: `https://{socket_address}`");
+        let mut server = match listener {
+            Some(listener) => axum_server::from_tcp_rustls(listener, config),
+            None => axum_server::bind_rustls(socket_address, config),
+        }
+        .handle(server_handle().clone());
+        apply_connection_limits(&mut server, limits);
 
-        axum_server::bind_rustls(socket_address, config)
-            .serve(app.into_make_service())
+        server
+            .serve(LimitPeerConnections::new(
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+                limits.max_connections_per_ip,
+            ))
             .await
-            .expect("Failed to start server. Is the address already in use?");
+            .map_err(|err| bind_error(listen_address, &err))?;
     } else {
-        info!("Listening on: `http://{socket_address}`");
+        info!("Listening on: `http://{listen_address}`");
 
-        axum::serve(
-            TcpListener::bind(socket_address)
-                .await
-                .expect("Failed to bind to socket. Please make sure the address is correct."),
-            app.into_make_service(),
-        )
-        .await
-        .expect("Failed to start server. Is the address already in use?");
+        let mut server = match listener {
+            Some(listener) => axum_server::from_tcp(listener),
+            None => axum_server::bind(socket_address),
+        }
+        .handle(server_handle().clone());
+        apply_connection_limits(&mut server, limits);
+
+        server
+            .serve(LimitPeerConnections::new(
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+                limits.max_connections_per_ip,
+            ))
+            .await
+            .map_err(|err| bind_error(listen_address, &err))?;
     };
 
     Ok(())
 }
+
+/// Error of a server that could not be started on `address`
+fn bind_error(address: SocketAddr, err: &std::io::Error) -> Error {
+    ErrorKind::Bind
+        .context(format!(
+            "Failed to start server on `{address}`. Is the address already in use? `{err}`"
+        ))
+        .into()
+}
+
+/// Start the JSON-RPC management interface on its own address, in the background
+fn serve_management(
+    address: SocketAddr,
+    tls_config: Option<RustlsConfig>,
+    limits: ConnectionLimits,
+) -> AppResult<()> {
+    // bind right away, so an address in use is reported at startup
+    let listener = TcpListener::bind(address)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .map_err(|err| {
+            ErrorKind::Bind.context(format!(
+                "Failed to bind the management interface to `{address}`: `{err}`"
+            ))
+        })?;
+
+    let app = Router::new()
+        .route("/rpc", post(rpc))
+        .into_make_service_with_connect_info::<SocketAddr>();
+
+    if let Some(config) = tls_config {
+        info!("Management interface listening on: `https://{address}/rpc`");
+        let mut server = axum_server::from_tcp_rustls(listener, config);
+        apply_connection_limits(&mut server, limits);
+        drop(tokio::spawn(server.serve(app)));
+    } else {
+        info!("Management interface listening on: `http://{address}/rpc`");
+        let mut server = axum_server::from_tcp(listener);
+        apply_connection_limits(&mut server, limits);
+        drop(tokio::spawn(server.serve(app)));
+    }
+
+    Ok(())
+}
+
+/// Returns a layer compressing JSON responses, e.g. file listings, at `level`
+///
+/// Files are not compressed, as their content is compressed and encrypted by
+/// the client already.
+pub(crate) fn compression_layer(level: u8) -> CompressionLayer<impl Predicate> {
+    fn is_json(_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions) -> bool {
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|content_type| {
+                content_type.starts_with("application/json")
+                    || content_type.starts_with("application/vnd.x.restic.rest")
+            })
+    }
+
+    CompressionLayer::new()
+        .quality(CompressionLevel::Precise(level.into()))
+        .compress_when(DefaultPredicate::new().and(is_json))
+}
+
+/// Add the configured headers to the response, replacing existing ones of the same name
+fn add_response_headers(headers: &HeaderMap, mut res: Response) -> Response {
+    for (name, value) in headers {
+        _ = res.headers_mut().insert(name, value.clone());
+    }
+    res
+}
+
+/// Take the TCP socket passed by systemd socket activation, if there is one
+fn systemd_listener() -> AppResult<Option<TcpListener>> {
+    let listener = ListenFd::from_env()
+        .take_tcp_listener(0)
+        .map_err(|err| ErrorKind::Bind.context(format!("Failed to use systemd socket: `{err}`")))?;
+
+    let Some(listener) = listener else {
+        info!("No systemd socket passed, binding to the configured address.");
+        return Ok(None);
+    };
+
+    // tokio needs the socket to be non-blocking
+    listener.set_nonblocking(true).map_err(|err| {
+        ErrorKind::Bind.context(format!("Failed to configure systemd socket: `{err}`"))
+    })?;
+
+    info!("Using socket passed by systemd.");
+
+    Ok(Some(listener))
+}
+
+/// Bind to `address` with `SO_REUSEADDR` and `SO_REUSEPORT` set, so other
+/// processes can listen on the same port
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn reuse_port_listener(address: SocketAddr) -> AppResult<TcpListener> {
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(1024)?;
+        // tokio needs the socket to be non-blocking
+        socket.set_nonblocking(true)?;
+        Ok(socket.into())
+    };
+
+    let listener = bind().map_err(|err| bind_error(address, &err))?;
+    info!("Listening socket allows reusing the port.");
+
+    Ok(listener)
+}
+
+/// `SO_REUSEPORT` is not available, and `SO_REUSEADDR` on Windows would allow
+/// other processes to take over the port
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn reuse_port_listener(_address: SocketAddr) -> AppResult<TcpListener> {
+    Err(ErrorKind::Config
+        .context("`reuse-port` is not supported on this platform")
+        .into())
+}
+
+/// Configure the HTTP connections of the server with the given limits
+fn apply_connection_limits<A>(server: &mut Server<A>, limits: ConnectionLimits) {
+    let builder = server.http_builder();
+
+    if let Some(max_header_bytes) = limits.max_header_bytes {
+        _ = builder.http1().max_buf_size(max_header_bytes);
+        _ = builder
+            .http2()
+            .max_header_list_size(u32::try_from(max_header_bytes).unwrap_or(u32::MAX));
+    }
+
+    if let Some(idle_timeout) = limits.idle_timeout {
+        // The header read timeout also runs while waiting for the next request
+        // on a keep-alive connection, so idle connections are closed by it.
+        _ = builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(idle_timeout);
+    }
+}
+
+#[cfg(all(test, unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+mod test {
+    use std::net::TcpListener;
+
+    use crate::web::reuse_port_listener;
+
+    #[test]
+    fn test_reuse_port_listener_passes() {
+        let first = reuse_port_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = first.local_addr().unwrap();
+
+        // a second process, e.g. the next instance, can listen on the same port
+        let second = reuse_port_listener(address).unwrap();
+        assert_eq!(second.local_addr().unwrap(), address);
+
+        // others still can't
+        assert!(TcpListener::bind(address).is_err());
+    }
+}
@@ -23,6 +23,7 @@ use std::{net::SocketAddr, path::PathBuf};
 use crate::_impl::AssertCmdExt;
 use anyhow::{Ok, Result};
 use assert_cmd::Command;
+use predicates::prelude::PredicateBooleanExt;
 use rstest::{fixture, rstest};
 use rustic_server::config::RusticServerConfig;
 use serial_test::file_serial;
@@ -166,3 +167,119 @@ fn test_version_no_args_passes(setup: Result<Command>) -> Result<()> {
 
     Ok(())
 }
+
+/// Read the configuration from stdin and from an environment variable
+#[rstest]
+fn test_config_from_stdin_and_env_passes() -> Result<()> {
+    let config = toml::to_string(&RusticServerConfig::default())?;
+    let bench_dir = std::env::temp_dir();
+    let bench = [
+        "bench",
+        "--path",
+        bench_dir.to_str().unwrap(),
+        "--size",
+        "1K",
+        "--blob-size",
+        "1K",
+    ];
+
+    setup()?
+        .args(["-v", "--config", "-"])
+        .args(bench)
+        .write_stdin(config.clone())
+        .assert()
+        .stdout(predicates::str::contains("Using configuration from: `-`"))
+        .success();
+
+    setup()?
+        .args(["-v", "--config", "env:RUSTIC_SERVER_TEST_CONFIG"])
+        .args(bench)
+        .env("RUSTIC_SERVER_TEST_CONFIG", config)
+        .assert()
+        .stdout(predicates::str::contains(
+            "Using configuration from: `env:RUSTIC_SERVER_TEST_CONFIG`",
+        ))
+        .success();
+
+    // invalid configurations are still rejected
+    setup()?
+        .args(["--config", "-"])
+        .args(bench)
+        .write_stdin("unknown-section = true")
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+/// Print the merged configuration instead of serving
+#[rstest]
+fn test_print_config_passes() -> Result<()> {
+    let config = "[server]\nlisten = '127.0.0.1:8081'\n\n[server.response-headers]\nAuthorization = 'Bearer secret'\n\n[tls]\ntls-pkcs12-password = 'secret'\n";
+
+    setup()?
+        .args(["--config", "-", "serve", "--print-config"])
+        .args(["--listen", "127.0.0.1:8001"])
+        .write_stdin(config)
+        .assert()
+        .stdout(predicates::str::contains("listen = \"127.0.0.1:8001\""))
+        .stdout(predicates::str::contains("Authorization = \"<redacted>\""))
+        .stdout(predicates::str::contains(
+            "tls-pkcs12-password = \"<redacted>\"",
+        ))
+        .stdout(predicates::str::contains("secret").not())
+        .success();
+
+    Ok(())
+}
+
+/// Exit with the documented code of the failure
+#[rstest]
+fn test_exit_codes_passes() -> Result<()> {
+    let data_dir = PathBuf::from("tests/generated/test_exit_codes");
+    std::fs::create_dir_all(&data_dir)?;
+    let data_path = data_dir.to_str().unwrap();
+
+    // configuration which can't be loaded
+    setup()?
+        .args(["--config", "-", "stats", "--path", data_path])
+        .write_stdin("unknown-section = true")
+        .assert()
+        .code(78);
+
+    // invalid configuration
+    let trash_dir = data_dir.join("trash");
+    setup()?
+        .args([
+            "serve",
+            "--path",
+            data_path,
+            "--no-auth",
+            "--allow-missing-acl",
+        ])
+        .args(["--trash-dir", trash_dir.to_str().unwrap()])
+        .assert()
+        .code(78);
+
+    // address in use
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    setup()?
+        .args([
+            "serve",
+            "--path",
+            data_path,
+            "--no-auth",
+            "--allow-missing-acl",
+        ])
+        .args(["--listen", &listener.local_addr()?.to_string()])
+        .assert()
+        .code(69);
+
+    // other failures
+    setup()?
+        .args(["stats", "--path", "does/not/exist"])
+        .assert()
+        .code(1);
+
+    Ok(())
+}